@@ -36,9 +36,9 @@
 
 #![allow(dead_code)]
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use crate::Customize;
+use crate::{Customize, ProtobufRuntime};
 use protobuf::{
     compiler_plugin::{GenRequest, GenResult},
     descriptor::*,
@@ -58,6 +58,10 @@ use super::util::{
     self, async_on, def_async_fn, fq_grpc, pub_async_fn, to_camel_case, to_snake_case, MethodType,
 };
 
+// Field number of the `ttrpc.default_timeout_ms` method option declared in
+// proto/ttrpc/options.proto. Custom options end up in the unknown fields.
+const DEFAULT_TIMEOUT_OPTION: u32 = 50051;
+
 struct MethodGen<'a> {
     proto: &'a MethodDescriptorProto,
     package_name: String,
@@ -139,6 +143,49 @@ impl<'a> MethodGen<'a> {
         )
     }
 
+    fn default_timeout_ms(&self) -> Option<u64> {
+        self.proto
+            .get_options()
+            .get_unknown_fields()
+            .get(DEFAULT_TIMEOUT_OPTION)
+            .and_then(|v| v.varint.last().copied())
+            .filter(|&ms| ms > 0)
+    }
+
+    fn const_default_timeout_name(&self) -> String {
+        format!(
+            "DEFAULT_TIMEOUT_{}_{}",
+            self.service_name().to_uppercase(),
+            self.name().to_uppercase()
+        )
+    }
+
+    fn write_default_timeout(&self, w: &mut CodeWriter) {
+        if let Some(ms) = self.default_timeout_ms() {
+            w.write_line(format!(
+                "/// Timeout of `/{}.{}/{}` when the caller doesn't set one.",
+                self.package_name,
+                self.service_name,
+                self.proto.get_name(),
+            ));
+            w.write_line(format!(
+                "pub const {}: ::std::time::Duration = ::std::time::Duration::from_millis({});",
+                self.const_default_timeout_name(),
+                ms
+            ));
+        }
+    }
+
+    // Applies the default timeout, if any, to the `ctx` of a client method.
+    fn write_apply_default_timeout(&self, w: &mut CodeWriter) {
+        if self.default_timeout_ms().is_some() {
+            w.write_line(format!(
+                "let ctx = ctx.or_timeout({});",
+                self.const_default_timeout_name()
+            ));
+        }
+    }
+
     fn write_handler(&self, w: &mut CodeWriter) {
         w.block(
             &format!("struct {}Method {{", self.struct_name()),
@@ -275,6 +322,7 @@ impl<'a> MethodGen<'a> {
             // Unary
             MethodType::Unary => {
                 w.pub_fn(&self.unary(&method_name), |w| {
+                    self.write_apply_default_timeout(w);
                     w.write_line(&format!("let mut cres = {}::new();", self.output()));
                     w.write_line(&format!(
                         "::ttrpc::client_request!(self, ctx, req, \"{}.{}\", \"{}\", cres);",
@@ -296,6 +344,7 @@ impl<'a> MethodGen<'a> {
             // Unary RPC
             MethodType::Unary => {
                 pub_async_fn(w, &self.unary(&method_name), |w| {
+                    self.write_apply_default_timeout(w);
                     w.write_line(&format!("let mut cres = {}::new();", self.output()));
                     w.write_line(&format!(
                         "::ttrpc::async_client_request!(self, ctx, req, \"{}.{}\", \"{}\", cres);",
@@ -308,6 +357,7 @@ impl<'a> MethodGen<'a> {
             // Client Streaming RPC
             MethodType::ClientStreaming => {
                 pub_async_fn(w, &self.client_streaming(&method_name), |w| {
+                    self.write_apply_default_timeout(w);
                     w.write_line(&format!(
                         "::ttrpc::async_client_stream_send!(self, ctx, \"{}.{}\", \"{}\");",
                         self.package_name,
@@ -319,6 +369,7 @@ impl<'a> MethodGen<'a> {
             // Server Streaming RPC
             MethodType::ServerStreaming => {
                 pub_async_fn(w, &self.server_streaming(&method_name), |w| {
+                    self.write_apply_default_timeout(w);
                     w.write_line(&format!(
                         "::ttrpc::async_client_stream_receive!(self, ctx, req, \"{}.{}\", \"{}\");",
                         self.package_name,
@@ -330,6 +381,7 @@ impl<'a> MethodGen<'a> {
             // Bidirectional streaming RPC
             MethodType::Duplex => {
                 pub_async_fn(w, &self.duplex_streaming(&method_name), |w| {
+                    self.write_apply_default_timeout(w);
                     w.write_line(&format!(
                         "::ttrpc::async_client_stream!(self, ctx, \"{}.{}\", \"{}\");",
                         self.package_name,
@@ -621,7 +673,68 @@ impl<'a> ServiceGen<'a> {
         }
     }
 
+    fn write_default_timeouts(&self, w: &mut CodeWriter) {
+        let mut any = false;
+        for method in &self.methods {
+            any |= method.default_timeout_ms().is_some();
+            method.write_default_timeout(w);
+        }
+        if any {
+            w.write_line("");
+        }
+    }
+
+    // Implements ttrpc's Codec for the messages of the streams, which ttrpc
+    // only does for rust-protobuf 3. `done` holds the messages implemented
+    // for by the files generated so far, an impl can only be written once.
+    fn write_protobuf_v2_codecs(&self, w: &mut CodeWriter, done: &mut HashSet<String>) {
+        if self.customize.protobuf_runtime != ProtobufRuntime::V2 {
+            return;
+        }
+        for method in &self.methods {
+            if matches!(method.method_type().0, MethodType::Unary) {
+                continue;
+            }
+            for message in [method.input(), method.output()] {
+                if !done.insert(message.clone()) {
+                    continue;
+                }
+                w.block(
+                    &format!("impl ::ttrpc::proto::Codec for {} {{", message),
+                    "}",
+                    |w| {
+                        w.write_line("type E = ::protobuf::ProtobufError;");
+                        w.write_line("");
+                        w.block("fn size(&self) -> u32 {", "}", |w| {
+                            w.write_line("::protobuf::Message::compute_size(self)");
+                        });
+                        w.write_line("");
+                        w.block(
+                            "fn encode(&self) -> ::std::result::Result<Vec<u8>, Self::E> {",
+                            "}",
+                            |w| {
+                                w.write_line("::protobuf::Message::write_to_bytes(self)");
+                            },
+                        );
+                        w.write_line("");
+                        w.block(
+                            "fn decode(buf: impl AsRef<[u8]>) -> ::std::result::Result<Self, Self::E> {",
+                            "}",
+                            |w| {
+                                w.write_line(
+                                    "<Self as ::protobuf::Message>::parse_from_bytes(buf.as_ref())",
+                                );
+                            },
+                        );
+                    },
+                );
+                w.write_line("");
+            }
+        }
+    }
+
     fn write(&self, w: &mut CodeWriter) {
+        self.write_default_timeouts(w);
         self.write_client(w);
         w.write_line("");
         self.write_method_handlers(w);
@@ -662,10 +775,22 @@ fn write_generated_common(w: &mut CodeWriter) {
     w.write_line("#![allow(unused_results)]");
 }
 
+fn write_file_header(w: &mut CodeWriter, customize: &Customize) {
+    write_generated_by(w, "ttrpc-compiler", env!("CARGO_PKG_VERSION"));
+
+    w.write_line("use protobuf::{CodedInputStream, CodedOutputStream, Message};");
+    w.write_line("use std::collections::HashMap;");
+    w.write_line("use std::sync::Arc;");
+    if customize.async_all || customize.async_client || customize.async_server {
+        w.write_line("use async_trait::async_trait;");
+    }
+}
+
 fn gen_file(
     file: &FileDescriptorProto,
     root_scope: &RootScope,
     customize: &Customize,
+    codecs: &mut HashSet<String>,
 ) -> Option<GenResult> {
     if file.get_service().is_empty() {
         return None;
@@ -677,18 +802,13 @@ fn gen_file(
     {
         let mut w = CodeWriter::new(&mut v);
 
-        write_generated_by(&mut w, "ttrpc-compiler", env!("CARGO_PKG_VERSION"));
-
-        w.write_line("use protobuf::{CodedInputStream, CodedOutputStream, Message};");
-        w.write_line("use std::collections::HashMap;");
-        w.write_line("use std::sync::Arc;");
-        if customize.async_all || customize.async_client || customize.async_server {
-            w.write_line("use async_trait::async_trait;");
-        }
+        write_file_header(&mut w, customize);
 
         for service in file.get_service() {
             w.write_line("");
-            ServiceGen::new(service, file, root_scope, customize).write(&mut w);
+            let gen = ServiceGen::new(service, file, root_scope, customize);
+            gen.write_protobuf_v2_codecs(&mut w, codecs);
+            gen.write(&mut w);
         }
     }
 
@@ -698,6 +818,39 @@ fn gen_file(
     })
 }
 
+// Generate one file per service. The files are siblings of the module
+// generated by rust-protobuf, so message paths resolve the same way as in
+// the single file layout.
+fn gen_file_by_service(
+    file: &FileDescriptorProto,
+    root_scope: &RootScope,
+    customize: &Customize,
+    codecs: &mut HashSet<String>,
+) -> Vec<GenResult> {
+    let base = protobuf::descriptorx::proto_path_to_rust_mod(file.get_name());
+
+    file.get_service()
+        .iter()
+        .map(|service| {
+            let mut v = Vec::new();
+            {
+                let mut w = CodeWriter::new(&mut v);
+
+                write_file_header(&mut w, customize);
+                w.write_line("");
+                let gen = ServiceGen::new(service, file, root_scope, customize);
+                gen.write_protobuf_v2_codecs(&mut w, codecs);
+                gen.write(&mut w);
+            }
+
+            GenResult {
+                name: format!("{}_{}_ttrpc.rs", base, to_snake_case(service.get_name())),
+                content: v,
+            }
+        })
+        .collect()
+}
+
 pub fn gen(
     file_descriptors: &[FileDescriptorProto],
     files_to_generate: &[String],
@@ -709,6 +862,7 @@ pub fn gen(
     let root_scope = RootScope { file_descriptors };
 
     let mut results = Vec::new();
+    let mut codecs = HashSet::new();
 
     for file_name in files_to_generate {
         let file = files_map[&file_name[..]];
@@ -717,7 +871,16 @@ pub fn gen(
             continue;
         }
 
-        results.extend(gen_file(file, &root_scope, customize).into_iter());
+        if customize.split_by_service {
+            results.extend(gen_file_by_service(
+                file,
+                &root_scope,
+                customize,
+                &mut codecs,
+            ));
+        } else {
+            results.extend(gen_file(file, &root_scope, customize, &mut codecs).into_iter());
+        }
     }
 
     results
@@ -36,4 +36,29 @@ pub struct Customize {
     pub async_client: bool,
     /// Indicates whether to generate async code for server.
     pub async_server: bool,
+    /// Indicates whether to generate one file per service, named
+    /// `<proto>_<service>_ttrpc.rs`, instead of a single `<proto>_ttrpc.rs`.
+    /// Messages are still shared through the rust-protobuf generated module.
+    pub split_by_service: bool,
+    /// Version of the rust-protobuf runtime the messages are generated for.
+    pub protobuf_runtime: ProtobufRuntime,
+}
+
+/// Version of the rust-protobuf runtime API.
+///
+/// ttrpc implements `ttrpc::proto::Codec`, which streams use to encode and
+/// decode messages, for rust-protobuf 3 messages. With `V2` the generated
+/// code implements it for the messages exchanged on streams, so crates still
+/// on rust-protobuf 2 can use streaming too. The rest of the generated code
+/// works with both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtobufRuntime {
+    V2,
+    V3,
+}
+
+impl Default for ProtobufRuntime {
+    fn default() -> Self {
+        ProtobufRuntime::V3
+    }
 }
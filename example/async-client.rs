@@ -14,7 +14,7 @@ use ttrpc::r#async::Client;
 async fn main() {
     let c = Client::connect(utils::SOCK_ADDR).unwrap();
     let hc = health_ttrpc::HealthClient::new(c.clone());
-    let ac = agent_ttrpc::AgentServiceClient::new(c);
+    let ac = agent_ttrpc::AgentServiceClient::new(c.clone());
 
     let thc = hc.clone();
     let tac = ac.clone();
@@ -96,6 +96,11 @@ async fn main() {
     });
 
     let _ = tokio::join!(t1, t2, t3);
+
+    println!(
+        "Client close -> {:?}",
+        c.close(std::time::Duration::from_secs(1)).await
+    );
 }
 
 fn default_ctx() -> Context {
@@ -0,0 +1,144 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Runs tasks through their life cycle on `async-task-server`, checking every
+//! step and the events they publish on the way. Exits with a panic if one
+//! goes wrong.
+
+mod protocols;
+mod tasks;
+
+use std::time::Duration;
+
+use protocols::r#async::{task, task_events, task_events_ttrpc, task_ttrpc};
+use ttrpc::context::{self, Context};
+use ttrpc::proto::Code;
+use ttrpc::r#async::Client;
+use ttrpc::Error;
+
+fn ctx(ns: &str, timeout_ms: i64) -> Context {
+    context::with_duration(Duration::from_millis(timeout_ms as u64))
+        .with_value(tasks::NAMESPACE_KEY, ns)
+}
+
+fn assert_code<T: std::fmt::Debug>(r: ttrpc::Result<T>, code: Code) {
+    match r {
+        Err(Error::RpcStatus(s)) if s.code() == code => {}
+        r => panic!("expected {:?}, got {:?}", code, r),
+    }
+}
+
+/// Runs task `id` of namespace `ns` to its end, checking the events.
+async fn run(
+    tc: task_ttrpc::TaskClient,
+    ec: task_events_ttrpc::TaskEventsClient,
+    ns: String,
+    id: String,
+    exit_status: u32,
+) {
+    // No timeout, the subscription lasts until it's closed.
+    let mut events = ec
+        .subscribe(ctx(&ns, 0), &task_events::SubscribeRequest::new())
+        .await
+        .unwrap();
+
+    let created = tc
+        .create(
+            ctx(&ns, 1000),
+            &task::CreateRequest {
+                id: id.clone(),
+                run_ms: 500,
+                exit_status,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    tc.start(
+        ctx(&ns, 1000),
+        &task::StartRequest {
+            id: id.clone(),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    let wait = task::WaitRequest {
+        id: id.clone(),
+        ..Default::default()
+    };
+    // The task runs for longer than this call may wait.
+    let r = tc.wait(ctx(&ns, 100), &wait).await;
+    println!("{}/{}: short wait -> {:?}", ns, id, r);
+    assert!(r.is_err());
+
+    let waited = tc.wait(ctx(&ns, 5000), &wait).await.unwrap();
+    assert_eq!(waited.exit_status, exit_status);
+
+    let deleted = tc
+        .delete(
+            ctx(&ns, 1000),
+            &task::DeleteRequest {
+                id: id.clone(),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(deleted.pid, created.pid);
+
+    // Only the events of the namespace arrive, in order.
+    for status in [
+        task::TaskStatus::CREATED,
+        task::TaskStatus::RUNNING,
+        task::TaskStatus::STOPPED,
+    ] {
+        let event = events.recv().await.unwrap();
+        assert_eq!(
+            (event.id.as_str(), event.status.enum_value()),
+            (id.as_str(), Ok(status))
+        );
+        if status == task::TaskStatus::STOPPED {
+            assert_eq!(event.exit_status, exit_status);
+        }
+    }
+    events.close().await.unwrap();
+    println!("{}/{}: exited with {}", ns, id, exit_status);
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let c = Client::connect(tasks::SOCK_ADDR).unwrap();
+    let tc = task_ttrpc::TaskClient::new(c.clone());
+    let ec = task_events_ttrpc::TaskEventsClient::new(c.clone());
+
+    // Tasks are only visible in their namespace.
+    assert_code(
+        tc.start(
+            ctx("other", 1000),
+            &task::StartRequest {
+                id: "demo".to_string(),
+                ..Default::default()
+            },
+        )
+        .await,
+        Code::NOT_FOUND,
+    );
+
+    // Tasks of the same name in two namespaces, side by side.
+    let t1 = tokio::spawn(run(
+        tc.clone(),
+        ec.clone(),
+        "example".to_string(),
+        "demo".to_string(),
+        3,
+    ));
+    let t2 = tokio::spawn(run(tc, ec, "other".to_string(), "demo".to_string(), 4));
+    t1.await.unwrap();
+    t2.await.unwrap();
+
+    c.close(Duration::from_secs(1)).await.unwrap();
+}
@@ -0,0 +1,215 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! A miniature task service shim on the async runtime, with the events of the
+//! tasks streamed to subscribers. See `async-task-client`.
+
+mod protocols;
+mod tasks;
+mod utils;
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::{info, LevelFilter};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, watch, Notify};
+
+use protocols::r#async::{task, task_events, task_events_ttrpc, task_ttrpc};
+use tasks::{namespace, State, Tasks};
+use ttrpc::error::get_rpc_status;
+use ttrpc::proto::Code;
+use ttrpc::r#async::{Server, ServerStreamSender, TtrpcContext};
+
+#[derive(Clone)]
+struct TaskService {
+    tasks: Arc<Tasks>,
+    exited: Arc<Notify>,
+    events: broadcast::Sender<task::TaskEvent>,
+    // Ends the subscriptions, which otherwise last as long as the clients.
+    stopping: watch::Receiver<bool>,
+}
+
+impl TaskService {
+    fn publish(&self, ns: &str, id: &str, status: task::TaskStatus, exit_status: u32) {
+        publish(&self.events, ns, id, status, exit_status);
+    }
+}
+
+fn publish(
+    events: &broadcast::Sender<task::TaskEvent>,
+    ns: &str,
+    id: &str,
+    status: task::TaskStatus,
+    exit_status: u32,
+) {
+    // Nobody listening is fine.
+    let _ = events.send(task::TaskEvent {
+        namespace: ns.to_string(),
+        id: id.to_string(),
+        status: status.into(),
+        exit_status,
+        ..Default::default()
+    });
+}
+
+#[async_trait]
+impl task_ttrpc::Task for TaskService {
+    async fn create(
+        &self,
+        ctx: &TtrpcContext,
+        req: task::CreateRequest,
+    ) -> ttrpc::Result<task::CreateResponse> {
+        let ns = namespace(&ctx.metadata);
+        let run = std::time::Duration::from_millis(req.run_ms.into());
+        let pid = self.tasks.create(&ns, &req.id, run, req.exit_status)?;
+        info!("created task {}/{} with pid {}", ns, req.id, pid);
+        self.publish(&ns, &req.id, task::TaskStatus::CREATED, 0);
+        Ok(task::CreateResponse {
+            pid,
+            ..Default::default()
+        })
+    }
+
+    async fn start(
+        &self,
+        ctx: &TtrpcContext,
+        req: task::StartRequest,
+    ) -> ttrpc::Result<task::StartResponse> {
+        let ns = namespace(&ctx.metadata);
+        let (pid, run) = self.tasks.start(&ns, &req.id)?;
+        info!("started task {}/{}", ns, req.id);
+        self.publish(&ns, &req.id, task::TaskStatus::RUNNING, 0);
+
+        let tasks = self.tasks.clone();
+        let exited = self.exited.clone();
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(run).await;
+            if let Some(status) = tasks.exit(&ns, &req.id) {
+                info!("task {}/{} exited with {}", ns, req.id, status);
+                exited.notify_waiters();
+                publish(&events, &ns, &req.id, task::TaskStatus::STOPPED, status);
+            }
+        });
+
+        Ok(task::StartResponse {
+            pid,
+            ..Default::default()
+        })
+    }
+
+    async fn wait(
+        &self,
+        ctx: &TtrpcContext,
+        req: task::WaitRequest,
+    ) -> ttrpc::Result<task::WaitResponse> {
+        let ns = namespace(&ctx.metadata);
+        loop {
+            // Registered before checking so an exit in between isn't missed.
+            let exited = self.exited.notified();
+            if let State::Stopped(exit_status) = self.tasks.state(&ns, &req.id)? {
+                return Ok(task::WaitResponse {
+                    exit_status,
+                    ..Default::default()
+                });
+            }
+            match ctx.deadline {
+                Some(deadline) => {
+                    let deadline = tokio::time::Instant::from_std(deadline);
+                    if tokio::time::timeout_at(deadline, exited).await.is_err() {
+                        return Err(get_rpc_status(
+                            Code::DEADLINE_EXCEEDED,
+                            format!("task {} is still running", req.id),
+                        ));
+                    }
+                }
+                None => exited.await,
+            }
+        }
+    }
+
+    async fn delete(
+        &self,
+        ctx: &TtrpcContext,
+        req: task::DeleteRequest,
+    ) -> ttrpc::Result<task::DeleteResponse> {
+        let ns = namespace(&ctx.metadata);
+        let (pid, exit_status) = self.tasks.delete(&ns, &req.id)?;
+        info!("deleted task {}/{}", ns, req.id);
+        Ok(task::DeleteResponse {
+            pid,
+            exit_status,
+            ..Default::default()
+        })
+    }
+}
+
+#[async_trait]
+impl task_events_ttrpc::TaskEvents for TaskService {
+    async fn subscribe(
+        &self,
+        ctx: &TtrpcContext,
+        _req: task_events::SubscribeRequest,
+        s: ServerStreamSender<task::TaskEvent>,
+    ) -> ttrpc::Result<()> {
+        let ns = namespace(&ctx.metadata);
+        let mut events = self.events.subscribe();
+        let mut stopping = self.stopping.clone();
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => event,
+                _ = stopping.changed() => return Ok(()),
+            };
+            let event = match event {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    return Err(get_rpc_status(
+                        Code::RESOURCE_EXHAUSTED,
+                        format!("missed {} events", n),
+                    ))
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            };
+            if event.namespace == ns {
+                // Fails once the subscriber is gone.
+                s.send(&event).await?;
+            }
+        }
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    simple_logging::log_to_stderr(LevelFilter::Info);
+
+    let (events, _) = broadcast::channel(64);
+    let (stop, stopping) = watch::channel(false);
+    let service = TaskService {
+        tasks: Arc::new(Tasks::default()),
+        exited: Arc::new(Notify::new()),
+        events,
+        stopping,
+    };
+
+    let t = Box::new(service.clone()) as Box<dyn task_ttrpc::Task + Send + Sync>;
+    let e = Box::new(service) as Box<dyn task_events_ttrpc::TaskEvents + Send + Sync>;
+
+    utils::remove_if_sock_exist(tasks::SOCK_ADDR).unwrap();
+    let mut server = Server::new()
+        .bind(tasks::SOCK_ADDR)
+        .unwrap()
+        .register_service(task_ttrpc::create_task(Arc::new(t)))
+        .register_service(task_events_ttrpc::create_task_events(Arc::new(e)));
+
+    let mut interrupt = signal(SignalKind::interrupt()).unwrap();
+    server.start().await.unwrap();
+    println!("Server is running, press Ctrl + C to exit");
+
+    interrupt.recv().await;
+    info!("graceful shutdown");
+    stop.send(true).unwrap();
+    server.close().await.unwrap();
+}
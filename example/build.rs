@@ -17,6 +17,7 @@ fn main() {
         "protocols/protos/health.proto",
         "protocols/protos/google/protobuf/empty.proto",
         "protocols/protos/oci.proto",
+        "protocols/protos/task.proto",
     ];
 
     let protobuf_customized = ProtobufCustomize::default().gen_mod_rs(false);
@@ -35,6 +36,7 @@ fn main() {
 
     // Only async support stream currently.
     protos.push("protocols/protos/streaming.proto");
+    protos.push("protocols/protos/task_events.proto");
 
     Codegen::new()
         .out_dir("protocols/asynchronous")
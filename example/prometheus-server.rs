@@ -0,0 +1,95 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! A server exporting its metrics to prometheus, scraped at
+//! http://127.0.0.1:9100/metrics while running, e.g. with
+//! `curl http://127.0.0.1:9100/metrics` after calling it with the client
+//! example.
+
+mod protocols;
+mod utils;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+use prometheus::{Encoder, Registry, TextEncoder};
+use protocols::sync::{health, health_ttrpc};
+use ttrpc::error::Result;
+use ttrpc::metrics::prometheus::PrometheusSink;
+use ttrpc::Server;
+
+const METRICS_ADDR: &str = "127.0.0.1:9100";
+
+struct HealthService;
+impl health_ttrpc::Health for HealthService {
+    fn check(
+        &self,
+        _ctx: &::ttrpc::TtrpcContext,
+        _req: health::CheckRequest,
+    ) -> Result<health::HealthCheckResponse> {
+        Ok(health::HealthCheckResponse::new())
+    }
+}
+
+// Answers every HTTP request with the metrics in the text format.
+fn serve_metrics(registry: Registry) {
+    let listener = TcpListener::bind(METRICS_ADDR).unwrap();
+    for stream in listener.incoming().flatten() {
+        if let Err(e) = scrape(&registry, stream) {
+            eprintln!("scrape failed: {}", e);
+        }
+    }
+}
+
+fn scrape(registry: &Registry, mut stream: TcpStream) -> std::io::Result<()> {
+    // The request itself doesn't matter, read it up to the blank line.
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder.encode(&registry.gather(), &mut body).unwrap();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+        encoder.format_type(),
+        body.len()
+    )?;
+    stream.write_all(&body)
+}
+
+fn main() {
+    let h = Box::new(HealthService {}) as Box<dyn health_ttrpc::Health + Send + Sync>;
+    let hservice = health_ttrpc::create_health(Arc::new(h));
+
+    let registry = Registry::new();
+    let sink = PrometheusSink::new(&registry).unwrap();
+
+    utils::remove_if_sock_exist(utils::SOCK_ADDR).unwrap();
+    let mut server = Server::new()
+        .bind(utils::SOCK_ADDR)
+        .unwrap()
+        .register_service(hservice)
+        .set_metrics_sink(sink);
+    server.start().unwrap();
+
+    thread::spawn(move || serve_metrics(registry));
+    println!(
+        "Server is running, metrics at http://{}/metrics, press Ctrl + C to exit",
+        METRICS_ADDR
+    );
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    ctrlc::set_handler(move || {
+        tx.send(()).unwrap();
+    })
+    .expect("Error setting Ctrl-C handler");
+    rx.recv().unwrap();
+}
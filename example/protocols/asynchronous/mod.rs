@@ -12,4 +12,8 @@ pub mod health_ttrpc;
 mod oci;
 pub mod streaming;
 pub mod streaming_ttrpc;
+pub mod task;
+pub mod task_events;
+pub mod task_events_ttrpc;
+pub mod task_ttrpc;
 pub mod types;
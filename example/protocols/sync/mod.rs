@@ -10,4 +10,6 @@ mod gogo;
 pub mod health;
 pub mod health_ttrpc;
 mod oci;
+pub mod task;
+pub mod task_ttrpc;
 pub mod types;
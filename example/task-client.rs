@@ -0,0 +1,105 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Runs a task through its life cycle on `task-server`, checking every step
+//! on the way. Exits with a panic if one goes wrong.
+
+mod protocols;
+mod tasks;
+
+use std::time::Duration;
+
+use protocols::sync::{task, task_ttrpc};
+use ttrpc::context::{self, Context};
+use ttrpc::proto::Code;
+use ttrpc::{Client, Error};
+
+fn ctx(ns: &str, timeout_ms: i64) -> Context {
+    context::with_duration(Duration::from_millis(timeout_ms as u64))
+        .with_value(tasks::NAMESPACE_KEY, ns)
+}
+
+fn assert_code<T: std::fmt::Debug>(r: ttrpc::Result<T>, code: Code) {
+    match r {
+        Err(Error::RpcStatus(s)) if s.code() == code => {}
+        r => panic!("expected {:?}, got {:?}", code, r),
+    }
+}
+
+fn main() {
+    let c = Client::connect(tasks::SOCK_ADDR).unwrap();
+    let tc = task_ttrpc::TaskClient::new(c);
+
+    let id = "demo".to_string();
+    let created = tc
+        .create(
+            ctx("example", 1000),
+            &task::CreateRequest {
+                id: id.clone(),
+                run_ms: 500,
+                exit_status: 3,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    println!("created {} with pid {}", id, created.pid);
+
+    // Tasks are only visible in their namespace.
+    assert_code(
+        tc.start(
+            ctx("other", 1000),
+            &task::StartRequest {
+                id: id.clone(),
+                ..Default::default()
+            },
+        ),
+        Code::NOT_FOUND,
+    );
+
+    let started = tc
+        .start(
+            ctx("example", 1000),
+            &task::StartRequest {
+                id: id.clone(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(started.pid, created.pid);
+
+    let wait = task::WaitRequest {
+        id: id.clone(),
+        ..Default::default()
+    };
+    // The task runs for longer than this call may wait.
+    let r = tc.wait(ctx("example", 100), &wait);
+    println!("short wait -> {:?}", r);
+    assert!(r.is_err());
+    assert_code(
+        tc.delete(
+            ctx("example", 1000),
+            &task::DeleteRequest {
+                id: id.clone(),
+                ..Default::default()
+            },
+        ),
+        Code::FAILED_PRECONDITION,
+    );
+
+    let waited = tc.wait(ctx("example", 5000), &wait).unwrap();
+    assert_eq!(waited.exit_status, 3);
+
+    let deleted = tc
+        .delete(
+            ctx("example", 1000),
+            &task::DeleteRequest {
+                id: id.clone(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!((deleted.pid, deleted.exit_status), (created.pid, 3));
+    println!("deleted {}, it exited with {}", id, deleted.exit_status);
+}
@@ -0,0 +1,118 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! A miniature task service shim on the sync runtime, see `task-client`.
+
+mod protocols;
+mod tasks;
+mod utils;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{info, LevelFilter};
+
+use protocols::sync::{task, task_ttrpc};
+use tasks::{namespace, Tasks};
+use ttrpc::{Server, TtrpcContext};
+
+#[derive(Clone, Default)]
+struct TaskService {
+    tasks: Arc<Tasks>,
+}
+
+impl task_ttrpc::Task for TaskService {
+    fn create(
+        &self,
+        ctx: &TtrpcContext,
+        req: task::CreateRequest,
+    ) -> ttrpc::Result<task::CreateResponse> {
+        let ns = namespace(&ctx.metadata);
+        let run = Duration::from_millis(req.run_ms.into());
+        let pid = self.tasks.create(&ns, &req.id, run, req.exit_status)?;
+        info!("created task {}/{} with pid {}", ns, req.id, pid);
+        Ok(task::CreateResponse {
+            pid,
+            ..Default::default()
+        })
+    }
+
+    fn start(
+        &self,
+        ctx: &TtrpcContext,
+        req: task::StartRequest,
+    ) -> ttrpc::Result<task::StartResponse> {
+        let ns = namespace(&ctx.metadata);
+        let (pid, run) = self.tasks.start(&ns, &req.id)?;
+        info!("started task {}/{}", ns, req.id);
+
+        let tasks = self.tasks.clone();
+        thread::spawn(move || {
+            thread::sleep(run);
+            if let Some(status) = tasks.exit(&ns, &req.id) {
+                info!("task {}/{} exited with {}", ns, req.id, status);
+            }
+        });
+
+        Ok(task::StartResponse {
+            pid,
+            ..Default::default()
+        })
+    }
+
+    fn wait(
+        &self,
+        ctx: &TtrpcContext,
+        req: task::WaitRequest,
+    ) -> ttrpc::Result<task::WaitResponse> {
+        let ns = namespace(&ctx.metadata);
+        let exit_status = self.tasks.wait(&ns, &req.id, ctx.deadline)?;
+        Ok(task::WaitResponse {
+            exit_status,
+            ..Default::default()
+        })
+    }
+
+    fn delete(
+        &self,
+        ctx: &TtrpcContext,
+        req: task::DeleteRequest,
+    ) -> ttrpc::Result<task::DeleteResponse> {
+        let ns = namespace(&ctx.metadata);
+        let (pid, exit_status) = self.tasks.delete(&ns, &req.id)?;
+        info!("deleted task {}/{}", ns, req.id);
+        Ok(task::DeleteResponse {
+            pid,
+            exit_status,
+            ..Default::default()
+        })
+    }
+}
+
+fn main() {
+    simple_logging::log_to_stderr(LevelFilter::Info);
+
+    let t = Box::new(TaskService::default()) as Box<dyn task_ttrpc::Task + Send + Sync>;
+    let service = task_ttrpc::create_task(Arc::new(t));
+
+    utils::remove_if_sock_exist(tasks::SOCK_ADDR).unwrap();
+    let mut server = Server::new()
+        .bind(tasks::SOCK_ADDR)
+        .unwrap()
+        .register_service(service);
+    server.start().unwrap();
+
+    // Hold the main thread until receiving signal SIGTERM
+    let (tx, rx) = std::sync::mpsc::channel();
+    ctrlc::set_handler(move || {
+        tx.send(()).unwrap();
+    })
+    .expect("Error setting Ctrl-C handler");
+    println!("Server is running, press Ctrl + C to exit");
+    rx.recv().unwrap();
+
+    server.shutdown();
+}
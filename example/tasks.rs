@@ -0,0 +1,169 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Helpers shared by the task service examples, what a shim keeps whatever
+//! runtime serves it: the tasks by namespace and their life cycle.
+
+#![allow(dead_code)]
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use ttrpc::error::{get_rpc_status, Result};
+use ttrpc::proto::Code;
+
+pub const SOCK_ADDR: &str = "unix:///tmp/ttrpc-task-test";
+
+/// Metadata key of the namespace of a request.
+pub const NAMESPACE_KEY: &str = "namespace";
+
+/// Namespace of the requests without one.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// Returns the namespace of a request.
+pub fn namespace(metadata: &HashMap<String, Vec<String>>) -> String {
+    metadata
+        .get(NAMESPACE_KEY)
+        .and_then(|v| v.first())
+        .cloned()
+        .unwrap_or_else(|| DEFAULT_NAMESPACE.to_string())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Created,
+    Running,
+    Stopped(u32),
+}
+
+struct Task {
+    pid: u32,
+    state: State,
+    run: Duration,
+    exit_status: u32,
+}
+
+/// The tasks of all namespaces.
+#[derive(Default)]
+pub struct Tasks {
+    tasks: Mutex<HashMap<(String, String), Task>>,
+    exited: Condvar,
+    last_pid: AtomicU32,
+}
+
+impl Tasks {
+    /// Creates a task exiting with `exit_status` after running for `run`, and
+    /// returns its pid.
+    pub fn create(&self, ns: &str, id: &str, run: Duration, exit_status: u32) -> Result<u32> {
+        if id.is_empty() {
+            return Err(get_rpc_status(Code::INVALID_ARGUMENT, "empty task id"));
+        }
+        let mut tasks = self.tasks.lock().unwrap();
+        let key = (ns.to_string(), id.to_string());
+        if tasks.contains_key(&key) {
+            return Err(get_rpc_status(
+                Code::ALREADY_EXISTS,
+                format!("task {} already exists", id),
+            ));
+        }
+        let pid = self.last_pid.fetch_add(1, Ordering::Relaxed) + 1;
+        tasks.insert(
+            key,
+            Task {
+                pid,
+                state: State::Created,
+                run,
+                exit_status,
+            },
+        );
+        Ok(pid)
+    }
+
+    /// Marks a created task as running and returns its pid and how long it
+    /// runs. The caller calls [`Tasks::exit`] once that time has passed.
+    pub fn start(&self, ns: &str, id: &str) -> Result<(u32, Duration)> {
+        let mut tasks = self.tasks.lock().unwrap();
+        let task = get_mut(&mut tasks, ns, id)?;
+        if task.state != State::Created {
+            return Err(get_rpc_status(
+                Code::FAILED_PRECONDITION,
+                format!("task {} is {:?}", id, task.state),
+            ));
+        }
+        task.state = State::Running;
+        Ok((task.pid, task.run))
+    }
+
+    /// Marks a running task as stopped and returns its exit status.
+    pub fn exit(&self, ns: &str, id: &str) -> Option<u32> {
+        let mut tasks = self.tasks.lock().unwrap();
+        let task = get_mut(&mut tasks, ns, id).ok()?;
+        if task.state != State::Running {
+            return None;
+        }
+        task.state = State::Stopped(task.exit_status);
+        self.exited.notify_all();
+        Some(task.exit_status)
+    }
+
+    pub fn state(&self, ns: &str, id: &str) -> Result<State> {
+        let mut tasks = self.tasks.lock().unwrap();
+        Ok(get_mut(&mut tasks, ns, id)?.state)
+    }
+
+    /// Blocks until the task exits or the deadline passes, and returns its
+    /// exit status.
+    pub fn wait(&self, ns: &str, id: &str, deadline: Option<Instant>) -> Result<u32> {
+        let mut tasks = self.tasks.lock().unwrap();
+        loop {
+            if let State::Stopped(status) = get_mut(&mut tasks, ns, id)?.state {
+                return Ok(status);
+            }
+            tasks = match deadline {
+                None => self.exited.wait(tasks).unwrap(),
+                Some(d) => {
+                    let timeout = d.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        return Err(get_rpc_status(
+                            Code::DEADLINE_EXCEEDED,
+                            format!("task {} is still running", id),
+                        ));
+                    }
+                    self.exited.wait_timeout(tasks, timeout).unwrap().0
+                }
+            };
+        }
+    }
+
+    /// Deletes a task which isn't running, and returns its pid and exit status.
+    pub fn delete(&self, ns: &str, id: &str) -> Result<(u32, u32)> {
+        let mut tasks = self.tasks.lock().unwrap();
+        let task = get_mut(&mut tasks, ns, id)?;
+        let exit_status = match task.state {
+            State::Running => {
+                return Err(get_rpc_status(
+                    Code::FAILED_PRECONDITION,
+                    format!("task {} is running", id),
+                ))
+            }
+            State::Created => 0,
+            State::Stopped(status) => status,
+        };
+        let pid = task.pid;
+        tasks.remove(&(ns.to_string(), id.to_string()));
+        Ok((pid, exit_status))
+    }
+}
+
+fn get_mut<'a>(
+    tasks: &'a mut HashMap<(String, String), Task>,
+    ns: &str,
+    id: &str,
+) -> Result<&'a mut Task> {
+    tasks
+        .get_mut(&(ns.to_string(), id.to_string()))
+        .ok_or_else(|| get_rpc_status(Code::NOT_FOUND, format!("task {} not found", id)))
+}
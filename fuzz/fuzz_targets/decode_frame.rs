@@ -0,0 +1,33 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Feeds arbitrary bytes to the frame decoder, as a peer could.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ttrpc::codec::{decode_frame, validate_header, HeaderLimits};
+use ttrpc::proto::{Codec, MESSAGE_TYPE_REQUEST, MESSAGE_TYPE_RESPONSE};
+use ttrpc::{Request, Response};
+
+fuzz_target!(|data: &[u8]| {
+    let limits = HeaderLimits::new();
+    let mut buf = data;
+    while let Ok(Some((msg, used))) = decode_frame(buf) {
+        buf = &buf[used..];
+        if validate_header(&msg.header, &limits).is_err() {
+            break;
+        }
+        match msg.header.type_ {
+            MESSAGE_TYPE_REQUEST => {
+                let _ = Request::decode(&msg.payload);
+            }
+            MESSAGE_TYPE_RESPONSE => {
+                let _ = Response::decode(&msg.payload);
+            }
+            _ => {}
+        }
+    }
+});
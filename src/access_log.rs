@@ -0,0 +1,195 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Access logging of the calls a server handles.
+//!
+//! An [`AccessLog`] is an interceptor, installed with `add_interceptor()` of
+//! either server. It makes an [`AccessLogEntry`] of every call once done,
+//! and hands it to a sink, by default the `log` crate:
+//!
+//! ```
+//! use ttrpc::access_log::AccessLog;
+//!
+//! // Logged at info level with the target "ttrpc::access".
+//! let log = AccessLog::default();
+//! // Or anywhere else.
+//! let log = AccessLog::new(|entry| eprintln!("{}", entry));
+//! ```
+//!
+//! Put it first, so the time other interceptors take is counted and the
+//! calls they fail are logged.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::common::PeerCredentials;
+use crate::proto::Code;
+
+/// What is logged of a call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessLogEntry {
+    /// The method called, such as `grpc.Containerd/Checkpoint`.
+    pub method: String,
+    /// Id of the connection the call came in on, see
+    /// [`ConnectionInfo`](crate::ConnectionInfo).
+    pub connection: u64,
+    /// Credentials of the caller, if the transport provides them.
+    pub peer: Option<PeerCredentials>,
+    /// Size of the request payload.
+    pub request_size: usize,
+    /// Size of the response, none if the call got no response.
+    pub response_size: Option<usize>,
+    pub code: Code,
+    /// Time from the call reaching the access log to its end.
+    pub latency: Duration,
+}
+
+/// Formats the entry as `key=value` pairs.
+impl fmt::Display for AccessLogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "method={} conn={}", self.method, self.connection)?;
+        if let Some(peer) = self.peer {
+            write!(f, " uid={} gid={}", peer.uid, peer.gid)?;
+            if let Some(pid) = peer.pid {
+                write!(f, " pid={}", pid)?;
+            }
+        }
+        write!(f, " req_bytes={}", self.request_size)?;
+        if let Some(size) = self.response_size {
+            write!(f, " res_bytes={}", size)?;
+        }
+        write!(
+            f,
+            " code={:?} latency_us={}",
+            self.code,
+            self.latency.as_micros()
+        )
+    }
+}
+
+type Sink = Box<dyn Fn(&AccessLogEntry) + Send + Sync>;
+
+/// Access logging interceptor, see the [module docs](self).
+pub struct AccessLog {
+    sink: Sink,
+}
+
+impl AccessLog {
+    /// Hands the entry of every call to `sink`.
+    pub fn new(sink: impl Fn(&AccessLogEntry) + Send + Sync + 'static) -> Self {
+        Self {
+            sink: Box::new(sink),
+        }
+    }
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self::new(|entry| info!(target: "ttrpc::access", "{}", entry))
+    }
+}
+
+cfg_sync! {
+    impl crate::sync::Interceptor for AccessLog {
+        fn intercept(
+            &self,
+            ctx: crate::sync::TtrpcContext,
+            req: crate::proto::Request,
+            next: crate::sync::Next<'_>,
+        ) -> crate::Result<()> {
+            let started = std::time::Instant::now();
+            let mut entry = AccessLogEntry {
+                method: format!("{}/{}", req.service, req.method),
+                connection: ctx.connection.id,
+                peer: ctx.peer_cred,
+                request_size: req.payload.len(),
+                response_size: None,
+                code: Code::OK,
+                latency: Duration::ZERO,
+            };
+
+            // Handlers send the responses themselves, look at them on the way.
+            let (res, response) = crate::sync::interceptor::run_watched(ctx, req, next);
+            entry.response_size = response.map(|(size, _)| size);
+            entry.code = crate::sync::interceptor::call_code(&res, response);
+            entry.latency = started.elapsed();
+            (self.sink)(&entry);
+            res
+        }
+    }
+}
+
+cfg_async! {
+    #[async_trait::async_trait]
+    impl crate::r#async::Interceptor for AccessLog {
+        async fn intercept(
+            &self,
+            ctx: crate::r#async::TtrpcContext,
+            req: crate::proto::Request,
+            next: crate::r#async::Next<'_>,
+        ) -> crate::Result<Option<crate::proto::Response>> {
+            use protobuf::Message;
+
+            let started = tokio::time::Instant::now();
+            let mut entry = AccessLogEntry {
+                method: format!("{}/{}", req.service, req.method),
+                connection: ctx.connection.id,
+                peer: ctx.peer_cred,
+                request_size: req.payload.len(),
+                response_size: None,
+                code: Code::OK,
+                latency: Duration::ZERO,
+            };
+
+            let res = next.run(ctx, req).await;
+            match &res {
+                Ok(Some(response)) => {
+                    entry.response_size = Some(response.compute_size() as usize);
+                    entry.code = response.status().code();
+                }
+                Ok(None) => {}
+                Err(_) => entry.code = Code::UNKNOWN,
+            }
+            entry.latency = started.elapsed();
+            (self.sink)(&entry);
+            res
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_log_entry_display() {
+        let mut entry = AccessLogEntry {
+            method: "grpc.Containerd/Checkpoint".to_string(),
+            connection: 3,
+            peer: Some(PeerCredentials {
+                pid: Some(42),
+                uid: 0,
+                gid: 0,
+            }),
+            request_size: 10,
+            response_size: Some(20),
+            code: Code::OK,
+            latency: Duration::from_micros(1500),
+        };
+        assert_eq!(
+            entry.to_string(),
+            "method=grpc.Containerd/Checkpoint conn=3 uid=0 gid=0 pid=42 req_bytes=10 \
+             res_bytes=20 code=OK latency_us=1500"
+        );
+
+        entry.peer = None;
+        entry.response_size = None;
+        entry.code = Code::NOT_FOUND;
+        assert_eq!(
+            entry.to_string(),
+            "method=grpc.Containerd/Checkpoint conn=3 req_bytes=10 code=NOT_FOUND latency_us=1500"
+        );
+    }
+}
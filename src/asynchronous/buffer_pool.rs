@@ -0,0 +1,175 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Reuse of the buffers frames are read into.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+
+/// Buffers that frames are read into, taken back for reuse once the
+/// messages read into them are dropped.
+///
+/// A pool can be shared by all connections of a server, see
+/// [`Server::set_buffer_pool()`](crate::r#async::Server::set_buffer_pool).
+#[derive(Debug)]
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    max_buffers: usize,
+    max_buffer_size: usize,
+    reused: AtomicU64,
+    allocated: AtomicU64,
+    discarded: AtomicU64,
+}
+
+/// Counters of a [`BufferPool`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// Buffers taken from the pool.
+    pub reused: u64,
+    /// Buffers allocated because the pool had none to give.
+    pub allocated: u64,
+    /// Buffers freed rather than kept, the pool being full or them too large.
+    pub discarded: u64,
+    /// Buffers in the pool now.
+    pub pooled: usize,
+}
+
+impl BufferPool {
+    /// Creates a pool keeping up to `max_buffers` buffers of at most
+    /// `max_buffer_size` bytes. Larger frames are read into buffers of
+    /// their own.
+    pub fn new(max_buffers: usize, max_buffer_size: usize) -> Self {
+        Self {
+            free: Mutex::new(Vec::with_capacity(max_buffers)),
+            max_buffers,
+            max_buffer_size,
+            reused: AtomicU64::new(0),
+            allocated: AtomicU64::new(0),
+            discarded: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            reused: self.reused.load(Ordering::Relaxed),
+            allocated: self.allocated.load(Ordering::Relaxed),
+            discarded: self.discarded.load(Ordering::Relaxed),
+            pooled: self.free.lock().unwrap().len(),
+        }
+    }
+
+    /// Returns a zeroed buffer of `len` bytes, which goes back to the pool
+    /// when dropped.
+    pub(crate) fn get(self: &Arc<Self>, len: usize) -> PooledBuffer {
+        let free = if len <= self.max_buffer_size {
+            self.free.lock().unwrap().pop()
+        } else {
+            None
+        };
+        let mut buf = match free {
+            Some(buf) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            None => {
+                self.allocated.fetch_add(1, Ordering::Relaxed);
+                Vec::new()
+            }
+        };
+        buf.resize(len, 0);
+        PooledBuffer {
+            buf,
+            pool: self.clone(),
+        }
+    }
+
+    fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() <= self.max_buffer_size {
+            let mut free = self.free.lock().unwrap();
+            if free.len() < self.max_buffers {
+                buf.clear();
+                free.push(buf);
+                return;
+            }
+        }
+        self.discarded.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A buffer of a [`BufferPool`], given back to it on drop.
+#[derive(Debug)]
+pub(crate) struct PooledBuffer {
+    buf: Vec<u8>,
+    pool: Arc<BufferPool>,
+}
+
+impl PooledBuffer {
+    /// Turns the buffer into a payload, returned to the pool once all its
+    /// clones are dropped.
+    pub(crate) fn freeze(self) -> Bytes {
+        Bytes::from_owner(self)
+    }
+}
+
+impl AsRef<[u8]> for PooledBuffer {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl AsMut<[u8]> for PooledBuffer {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buf));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reuse() {
+        let pool = Arc::new(BufferPool::new(1, 16));
+
+        let mut buf = pool.get(8);
+        buf.as_mut().copy_from_slice(b"abcdefgh");
+        let payload = buf.freeze();
+        let part = payload.slice(2..4);
+        drop(payload);
+        // Still held by the slice.
+        assert_eq!(pool.stats().pooled, 0);
+        assert_eq!(&part[..], b"cd");
+        drop(part);
+        assert_eq!(pool.stats().pooled, 1);
+
+        // Comes back zeroed.
+        assert_eq!(pool.get(4).as_ref(), &[0; 4]);
+        let held = pool.get(4);
+        let other = pool.get(4);
+        drop(held);
+        // The pool is full.
+        drop(other);
+        // Too large to take from the pool, or to keep.
+        drop(pool.get(32));
+
+        assert_eq!(
+            pool.stats(),
+            BufferPoolStats {
+                reused: 2,
+                allocated: 3,
+                discarded: 2,
+                pooled: 1,
+            }
+        );
+    }
+}
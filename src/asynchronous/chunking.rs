@@ -0,0 +1,185 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Splitting of large payloads into continuation frames.
+//!
+//! All frames of a chunked message but the last carry [`FLAG_CONTINUED`],
+//! along with the stream id, type and flags of the message. A connection
+//! writes the frames of a message back to back, so there is at most one
+//! message being reassembled per stream.
+
+use std::collections::{HashMap, HashSet};
+
+use bytes::BytesMut;
+
+use crate::error::get_status;
+use crate::proto::{Code, GenMessage, MessageHeader, Status, FLAG_CONTINUED};
+
+/// Splits `msg` into frames of at most `chunk_size` payload bytes.
+pub(crate) fn split(msg: GenMessage, chunk_size: usize) -> Vec<GenMessage> {
+    if chunk_size == 0 || msg.payload.len() <= chunk_size {
+        return vec![msg];
+    }
+
+    let len = msg.payload.len();
+    let count = len.div_ceil(chunk_size);
+    (0..count)
+        .map(|i| {
+            // Shares the payload of `msg` rather than copying it.
+            let payload = msg
+                .payload
+                .slice(i * chunk_size..len.min((i + 1) * chunk_size));
+            let mut header = msg.header;
+            header.length = payload.len() as u32;
+            if i + 1 < count {
+                header.add_flags(FLAG_CONTINUED);
+            }
+            GenMessage { header, payload }
+        })
+        .collect()
+}
+
+/// Puts the frames of chunked messages back together.
+#[derive(Debug, Default)]
+pub(crate) struct Reassembler {
+    partial: HashMap<u32, (MessageHeader, BytesMut)>,
+    // Streams whose message was refused, their remaining frames are dropped.
+    skipping: HashSet<u32>,
+}
+
+impl Reassembler {
+    /// Takes a frame off the connection, returns the message once complete.
+    ///
+    /// A message over `max_len` bytes is refused with the header of its first
+    /// frame, a `max_len` of 0 refuses all chunked messages.
+    pub(crate) fn push(
+        &mut self,
+        frame: GenMessage,
+        max_len: usize,
+    ) -> Result<Option<GenMessage>, (MessageHeader, Status)> {
+        let stream_id = frame.header.stream_id;
+        let last = frame.header.flags & FLAG_CONTINUED == 0;
+
+        if self.skipping.contains(&stream_id) {
+            if last {
+                self.skipping.remove(&stream_id);
+            }
+            return Ok(None);
+        }
+
+        let (mut header, payload) = match self.partial.remove(&stream_id) {
+            None if last => return Ok(Some(frame)),
+            None => (frame.header, BytesMut::from(&frame.payload[..])),
+            Some((header, mut payload)) => {
+                if header.type_ != frame.header.type_ {
+                    return Err(self.refuse(
+                        header,
+                        last,
+                        get_status(Code::INVALID_ARGUMENT, "chunked message changed type"),
+                    ));
+                }
+                payload.extend_from_slice(&frame.payload);
+                (header, payload)
+            }
+        };
+
+        if payload.len() > max_len {
+            let status = get_status(
+                Code::RESOURCE_EXHAUSTED,
+                format!(
+                    "chunked message exceeds maximum size of {} at {} bytes",
+                    max_len,
+                    payload.len()
+                ),
+            );
+            return Err(self.refuse(header, last, status));
+        }
+
+        if last {
+            header.length = payload.len() as u32;
+            header.flags &= !FLAG_CONTINUED;
+            return Ok(Some(GenMessage {
+                header,
+                payload: payload.freeze(),
+            }));
+        }
+        self.partial.insert(stream_id, (header, payload));
+        Ok(None)
+    }
+
+    fn refuse(
+        &mut self,
+        mut header: MessageHeader,
+        last: bool,
+        status: Status,
+    ) -> (MessageHeader, Status) {
+        if !last {
+            self.skipping.insert(header.stream_id);
+        }
+        header.flags &= !FLAG_CONTINUED;
+        (header, status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(stream_id: u32, payload: Vec<u8>) -> GenMessage {
+        GenMessage {
+            header: MessageHeader::new_response(stream_id, payload.len() as u32),
+            payload: payload.into(),
+        }
+    }
+
+    #[test]
+    fn test_split_and_reassemble() {
+        let payload: Vec<u8> = (0..100u8).collect();
+        let msg = response(1, payload.clone());
+        let start = msg.payload.as_ptr();
+        let frames = split(msg, 30);
+        assert_eq!(frames.len(), 4);
+        // The frames share the payload of the message.
+        assert_eq!(frames[1].payload.as_ptr(), start.wrapping_add(30));
+        assert!(frames[..3]
+            .iter()
+            .all(|f| f.header.flags & FLAG_CONTINUED != 0));
+        assert_eq!(frames[3].header.flags, 0);
+        assert_eq!(frames[3].header.length, 10);
+
+        // Frames of another stream may come in between.
+        let mut reassembler = Reassembler::default();
+        let mut frames = frames.into_iter();
+        assert_eq!(reassembler.push(frames.next().unwrap(), 100), Ok(None));
+        let other = response(3, vec![1, 2, 3]);
+        assert_eq!(reassembler.push(other.clone(), 100), Ok(Some(other)));
+        let mut done = None;
+        for frame in frames {
+            done = reassembler.push(frame, 100).unwrap();
+        }
+        assert_eq!(done, Some(response(1, payload)));
+    }
+
+    #[test]
+    fn test_reassemble_too_large() {
+        let mut reassembler = Reassembler::default();
+        let mut frames = split(response(1, vec![0; 100]), 30).into_iter();
+        assert_eq!(reassembler.push(frames.next().unwrap(), 50), Ok(None));
+        let (header, status) = reassembler.push(frames.next().unwrap(), 50).unwrap_err();
+        assert_eq!(header.stream_id, 1);
+        assert_eq!(status.code(), Code::RESOURCE_EXHAUSTED);
+
+        // The rest of the message is dropped, the stream can be used again.
+        for frame in frames {
+            assert_eq!(reassembler.push(frame, 50), Ok(None));
+        }
+        let msg = response(1, vec![1]);
+        assert_eq!(reassembler.push(msg.clone(), 50), Ok(Some(msg)));
+
+        // Chunking disabled.
+        let frame = split(response(5, vec![0; 100]), 30).remove(0);
+        assert!(reassembler.push(frame, 0).is_err());
+    }
+}
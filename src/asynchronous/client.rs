@@ -4,35 +4,183 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::os::unix::io::RawFd;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 
 use async_trait::async_trait;
+use bytes::Bytes;
+use nix::sys::socket::{self, Shutdown};
 use nix::unistd::close;
-use tokio::{self, sync::mpsc, task};
+use tokio::{
+    self,
+    net::UnixStream,
+    select,
+    sync::{mpsc, oneshot, Mutex as AsyncMutex, Notify},
+    task,
+};
 
-use crate::common::client_connect;
-use crate::error::{Error, Result};
+use crate::common::{
+    self, client_connect, client_connect_timeout, is_transport_not_ready, CloseReason, Closing,
+    ConnectRetry, ConnectionInfo, ConnectionState, Jitter, RetryPolicy, StateWatch,
+};
+use crate::compression::{self, CompressionConfig, CompressionOverride};
+use crate::context::Context;
+use crate::error::{get_shutdown_status, Error, Result, ShutdownReason};
 use crate::proto::{
-    Code, Codec, GenMessage, Message, Request, Response, FLAG_REMOTE_CLOSED, FLAG_REMOTE_OPEN,
-    MESSAGE_TYPE_DATA, MESSAGE_TYPE_RESPONSE,
+    Code, Codec, GenMessage, GoAway, Message, MessageHeader, Notification, Request, Response,
+    Status, Subscription, FLAG_REMOTE_CLOSED, FLAG_REMOTE_OPEN, MESSAGE_LENGTH_MAX,
+    MESSAGE_TYPE_DATA, MESSAGE_TYPE_GOAWAY, MESSAGE_TYPE_NOTIFICATION, MESSAGE_TYPE_REQUEST,
+    MESSAGE_TYPE_RESPONSE, MESSAGE_TYPE_WINDOW_UPDATE,
 };
 use crate::r#async::connection::*;
+use crate::r#async::flow_control::{self, Windows};
+use crate::r#async::interceptor::{ClientInterceptor, ClientNext};
+use crate::r#async::peer::Callbacks;
 use crate::r#async::shutdown;
 use crate::r#async::stream::{
     Kind, MessageReceiver, MessageSender, ResultReceiver, ResultSender, StreamInner,
 };
 use crate::r#async::utils;
+use crate::r#async::Service;
+use crate::spans;
+
+const DEFAULT_NOTIFICATION_BUFFER: usize = 16;
+
+type NotificationSenders = Arc<Mutex<HashMap<String, mpsc::Sender<Vec<u8>>>>>;
+// Told when the server acknowledged the close of the connection.
+type CloseAck = Arc<Mutex<Option<oneshot::Sender<()>>>>;
+
+// The task running the connection, shared by the clones of a client.
+#[derive(Debug, Default)]
+struct ConnectionTask {
+    handle: Mutex<Option<task::JoinHandle<std::io::Result<()>>>>,
+    // Notified to close the connection right away.
+    close: Arc<Notify>,
+}
+
+impl Drop for ConnectionTask {
+    fn drop(&mut self) {
+        if self.handle.get_mut().unwrap().is_some() {
+            warn!("Client dropped without close(), its connection lingers until its streams end");
+        }
+    }
+}
+
+/// Options of a single call, on top of those of the client.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CallOptions {
+    /// Overrides how the request is compressed, the client's
+    /// [`set_compression()`](Client::set_compression) applies when none.
+    pub compression: Option<CompressionOverride>,
+}
 
 /// A ttrpc Client (async).
 #[derive(Clone)]
 pub struct Client {
     req_tx: MessageSender,
+    // Shared by the connections of a reconnecting client, so that the ids
+    // of its calls are never reused: a late response to a call made on a
+    // dropped connection can then never be taken for one to a later call.
     next_stream_id: Arc<AtomicU32>,
     streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
+    notifications: NotificationSenders,
+    compression: Option<CompressionConfig>,
+    retry: Option<Arc<RetryPolicy>>,
+    max_message_size: Arc<AtomicUsize>,
+    max_chunked_message_size: Arc<AtomicUsize>,
+    going_away: Arc<AtomicBool>,
+    // Set once the server of the connection agreed to calls being
+    // cancelled, see CANCEL_KEY.
+    server_cancels: Arc<AtomicBool>,
+    closing: Arc<AtomicBool>,
+    close_ack: CloseAck,
+    task: Arc<ConnectionTask>,
+    windows: Windows,
+    stream_window: Option<u32>,
+    io_timeouts: Arc<Mutex<IoTimeouts>>,
+    // Of the first connection, none for a lazy client.
+    info: Option<Arc<ConnectionInfo>>,
+    callbacks: CallbackSlot,
+    // The queue of messages to send until a connection takes it.
+    rx: RxSlot,
+    // How a lazy client connects, until it did.
+    lazy: Option<Arc<AsyncMutex<Option<Dialer>>>>,
+    interceptors: Arc<Vec<Arc<dyn ClientInterceptor>>>,
+    state: StateWatch,
+}
+
+// The services the client serves to the server, once registered.
+type CallbackSlot = Arc<Mutex<Option<Arc<Callbacks>>>>;
+// The queue of messages to send, handed back by the writer of a connection
+// for the next one.
+type RxSlot = Arc<Mutex<Option<MessageReceiver>>>;
+
+// The ids of the streams with messages written on a connection, whose calls
+// fail should it drop. The ones of finished calls are pruned once they pile
+// up.
+#[derive(Clone, Default)]
+struct Written(Arc<Mutex<(HashSet<u32>, usize)>>);
+
+const WRITTEN_PRUNE_MIN: usize = 256;
+
+impl Written {
+    fn insert(&self, stream_id: u32, streams: &Mutex<HashMap<u32, ResultSender>>) {
+        let mut written = self.0.lock().unwrap();
+        let (ids, prune_at) = &mut *written;
+        ids.insert(stream_id);
+        if ids.len() > *prune_at {
+            let streams = streams.lock().unwrap();
+            ids.retain(|id| streams.contains_key(id));
+            *prune_at = (ids.len() * 2).max(WRITTEN_PRUNE_MIN);
+        }
+    }
+
+    fn take(&self) -> HashSet<u32> {
+        std::mem::take(&mut self.0.lock().unwrap().0)
+    }
+}
+
+// The error of a call the server won't handle as it's going away.
+fn going_away_error() -> Error {
+    Error::RpcStatus(get_shutdown_status(
+        ShutdownReason::Drain,
+        "the server is going away",
+    ))
+}
+
+// Connects to `sockaddr`, retrying while nothing listens on it yet as set
+// out by `retry`. An attempt is given up on once the timeout of `retry`
+// passes, so an unanswered vsock connect doesn't block past it.
+async fn connect_retrying(sockaddr: &str, retry: &ConnectRetry) -> Result<RawFd> {
+    // Timed on the tokio clock, as the waits are.
+    let started = utils::now();
+    let mut jitter = Jitter::new(retry.jitter_seed);
+    let mut attempt = 0;
+    loop {
+        let left = retry
+            .timeout
+            .saturating_sub(utils::now().saturating_duration_since(started));
+        match unsafe { client_connect_timeout(sockaddr, left) } {
+            Ok(fd) => return Ok(fd),
+            Err(e) if is_transport_not_ready(&e) => match retry.next_backoff(
+                utils::now().saturating_duration_since(started),
+                attempt,
+                &mut jitter,
+            ) {
+                Some(backoff) => {
+                    trace!("{} is not ready, retry in {:?}: {}", sockaddr, backoff, e);
+                    tokio::time::sleep(backoff).await;
+                }
+                None => return Err(e),
+            },
+            Err(e) => return Err(e),
+        }
+        attempt += 1;
+    }
 }
 
 impl Client {
@@ -41,64 +189,515 @@ impl Client {
         Ok(Self::new(fd))
     }
 
+    /// Connects to `sockaddr`, failing with ETIMEDOUT unless connected
+    /// within `timeout`, as a vsock connect may not be answered for long.
+    pub fn connect_timeout(sockaddr: &str, timeout: Duration) -> Result<Client> {
+        let fd = unsafe { client_connect_timeout(sockaddr, timeout)? };
+        Ok(Self::new(fd))
+    }
+
+    /// Connects to `sockaddr`, retrying while nothing listens on it yet as
+    /// set out by `retry`: this waits for a server that isn't ready, such
+    /// as one yet to bind its address. Connecting fails once the timeout of
+    /// `retry` passes.
+    pub async fn connect_with_retry(sockaddr: &str, retry: ConnectRetry) -> Result<Client> {
+        Ok(Self::new(connect_retrying(sockaddr, &retry).await?))
+    }
+
+    /// Connects to `sockaddr` as [`Client::connect_with_retry()`] does, and
+    /// connects again the same way whenever the connection drops.
+    ///
+    /// The calls pending on the dropped connection fail, those still queued
+    /// to it and the calls made meanwhile are made on the new one. Should
+    /// connecting again fail within the timeout of `retry`, the client stays
+    /// closed and its calls fail. A server going away is only connected to
+    /// again once it closed the connection, and [`Client::close()`] stops
+    /// reconnecting.
+    pub async fn connect_with_reconnect(sockaddr: &str, retry: ConnectRetry) -> Result<Client> {
+        let fd = connect_retrying(sockaddr, &retry).await?;
+        let dialer = Dialer {
+            sockaddr: sockaddr.to_string(),
+            retry,
+        };
+        Ok(Self::start(fd, Some(dialer)))
+    }
+
+    /// Connects to `sockaddr` and checks a ttrpc server answers on it within
+    /// `timeout`, see [`Client::verify()`].
+    pub async fn connect_verified(sockaddr: &str, timeout: Duration) -> Result<Client> {
+        let client = Self::connect(sockaddr)?;
+        client.verify(timeout).await?;
+        Ok(client)
+    }
+
+    /// Checks a ttrpc server answers on the connection within `timeout`,
+    /// with a call the servers of this crate answer before any handler sees
+    /// it, and other servers fail as a method they don't have.
+    ///
+    /// Some transports accept connections no server will serve, a vsock
+    /// port in a guest still booting for instance, and the first call then
+    /// fails with a write error. This fails early instead, or with an
+    /// UNAVAILABLE status if the server won't take calls.
+    pub async fn verify(&self, timeout: Duration) -> Result<()> {
+        common::probe_result(self.request(common::probe_request(timeout)).await)
+    }
+
     /// Initialize a new [`Client`].
     pub fn new(fd: RawFd) -> Client {
-        let stream = utils::new_unix_stream_from_raw_fd(fd);
+        Self::start(fd, None)
+    }
 
-        let (req_tx, rx): (MessageSender, MessageReceiver) = mpsc::channel(100);
+    fn start(fd: RawFd, reconnect: Option<Dialer>) -> Client {
+        let mut client = Self::unconnected();
+        client.info = Some(client.spawn(fd, reconnect));
+        client
+    }
 
-        let req_map = Arc::new(Mutex::new(HashMap::new()));
-        let delegate = ClientBuilder {
-            rx: Some(rx),
-            streams: req_map.clone(),
+    /// Makes a client connecting to `sockaddr` on its first call, as
+    /// [`Client::connect_with_retry()`] does, rather than right away.
+    ///
+    /// Making clients for many servers is then cheap, and doesn't fail for
+    /// the ones not up yet. The calls made while connecting wait for it, a
+    /// call fails with the error of connecting if it fails and the next
+    /// call tries again. The connection isn't made again once it dropped.
+    pub fn connect_lazy(sockaddr: &str, retry: ConnectRetry) -> Client {
+        let dialer = Dialer {
+            sockaddr: sockaddr.to_string(),
+            retry,
         };
+        let mut client = Self::unconnected();
+        client.lazy = Some(Arc::new(AsyncMutex::new(Some(dialer))));
+        client
+    }
 
-        let conn = Connection::new(stream, delegate);
-        tokio::spawn(async move { conn.run().await });
-
+    fn unconnected() -> Client {
+        let (req_tx, rx): (MessageSender, MessageReceiver) = mpsc::channel(100);
         Client {
             req_tx,
+            rx: Arc::new(Mutex::new(Some(rx))),
             next_stream_id: Arc::new(AtomicU32::new(1)),
-            streams: req_map,
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            notifications: Arc::new(Mutex::new(HashMap::new())),
+            compression: None,
+            retry: None,
+            max_message_size: Arc::new(AtomicUsize::new(MESSAGE_LENGTH_MAX)),
+            max_chunked_message_size: Arc::new(AtomicUsize::new(0)),
+            going_away: Arc::new(AtomicBool::new(false)),
+            server_cancels: Arc::new(AtomicBool::new(false)),
+            closing: Arc::new(AtomicBool::new(false)),
+            close_ack: CloseAck::default(),
+            task: Arc::new(ConnectionTask::default()),
+            windows: Windows::default(),
+            stream_window: None,
+            io_timeouts: Arc::new(Mutex::new(IoTimeouts::default())),
+            info: None,
+            callbacks: CallbackSlot::default(),
+            lazy: None,
+            interceptors: Arc::default(),
+            state: StateWatch::new(ConnectionState::Disconnected),
+        }
+    }
+
+    // Runs the connection over `fd`, connecting again once it drops if
+    // `reconnect` says where.
+    fn spawn(&self, fd: RawFd, reconnect: Option<Dialer>) -> Arc<ConnectionInfo> {
+        let info = Arc::new(ConnectionInfo::new(fd));
+        spans::connection_opened(spans::Kind::Client, &info);
+        let delegate = ClientBuilder {
+            fd,
+            // Left to the connection, so that the calls made once it's gone
+            // fail rather than wait.
+            rx: Arc::new(Mutex::new(self.rx.lock().unwrap().take())),
+            streams: self.streams.clone(),
+            notifications: self.notifications.clone(),
+            max_message_size: self.max_message_size.clone(),
+            max_chunked_message_size: self.max_chunked_message_size.clone(),
+            going_away: self.going_away.clone(),
+            server_cancels: self.server_cancels.clone(),
+            close_ack: self.close_ack.clone(),
+            close: self.task.close.clone(),
+            windows: self.windows.clone(),
+            io_timeouts: self.io_timeouts.clone(),
+            info: info.clone(),
+            callbacks: self.callbacks.clone(),
+            state: self.state.clone(),
+            reconnects: reconnect.is_some(),
+        };
+        self.state.set(ConnectionState::Ready);
+
+        let stream = utils::new_unix_stream_from_raw_fd(fd);
+        let conn = Connection::new(stream, delegate.clone());
+        let handle = match reconnect {
+            Some(reconnect) => {
+                let (closing, task) = (self.closing.clone(), Arc::downgrade(&self.task));
+                tokio::spawn(async move { reconnect.run(conn, delegate, closing, task).await })
+            }
+            None => tokio::spawn(async move { conn.run().await }),
+        };
+        *self.task.handle.lock().unwrap() = Some(handle);
+        info
+    }
+
+    // Connects a lazy client, on its first call.
+    async fn connect_pending(&self) -> Result<()> {
+        let lazy = match self.lazy.as_ref() {
+            Some(lazy) => lazy,
+            None => return Ok(()),
+        };
+        let mut dialer = lazy.lock().await;
+        if let Some(d) = dialer.as_ref() {
+            self.state.set(ConnectionState::Connecting);
+            let fd = match connect_retrying(&d.sockaddr, &d.retry).await {
+                Ok(fd) => fd,
+                Err(e) => {
+                    self.state.set(ConnectionState::Disconnected);
+                    return Err(e);
+                }
+            };
+            self.spawn(fd, None);
+            *dialer = None;
+        }
+        Ok(())
+    }
+
+    /// Returns true once the server said it's going away, new calls then
+    /// fail right away with an UNAVAILABLE status and
+    /// [`ShutdownReason::Drain`]. The caller should connect again, possibly
+    /// elsewhere.
+    pub fn is_going_away(&self) -> bool {
+        self.going_away.load(Ordering::Relaxed)
+    }
+
+    /// Returns the state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        self.state.get()
+    }
+
+    /// Calls `f` with the new state each time the state of the connection
+    /// changes. A dropped connection is
+    /// [`Disconnected`](ConnectionState::Disconnected), then
+    /// [`Reconnecting`](ConnectionState::Reconnecting) if the client
+    /// connects again.
+    ///
+    /// `f` is called from the task changing the state and mustn't block.
+    /// The setting is shared with the clones of the client.
+    pub fn on_state_change<F>(self, f: F) -> Client
+    where
+        F: Fn(ConnectionState) + Send + Sync + 'static,
+    {
+        self.state.add_hook(Arc::new(f));
+        self
+    }
+
+    /// Closes the connection gracefully, waiting up to `timeout` for the
+    /// server to acknowledge it.
+    ///
+    /// No more calls are made, they fail with [`Error::LocalClosed`]. The
+    /// server answers once it sent the responses to the calls made before,
+    /// so they have all arrived when this returns and the process can exit
+    /// without losing any. Streams still open keep the server from
+    /// answering, as do servers without support for this.
+    ///
+    /// The connection is then closed and its task waited for, whether or
+    /// not the server answered, failing the calls still pending. Without
+    /// this, the connection is only closed once the last clone of the client
+    /// and its streams are dropped. Closing again does nothing.
+    pub async fn close(&self, timeout: Duration) -> Result<()> {
+        if self.closing.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+        // A lazy client that never connected has nothing to close.
+        if let Some(lazy) = self.lazy.as_ref() {
+            if lazy.lock().await.take().is_some() {
+                return Ok(());
+            }
+        }
+        let res = self.close_handshake(timeout).await;
+
+        self.task.close.notify_one();
+        let handle = self.task.handle.lock().unwrap().take();
+        if let Some(handle) = handle {
+            if let Err(e) = handle.await {
+                debug!("Client connection task failed: {}", e);
+            }
+        }
+        res
+    }
+
+    async fn close_handshake(&self, timeout: Duration) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        *self.close_ack.lock().unwrap() = Some(tx);
+
+        let goaway = GoAway {
+            last_stream_id: self
+                .next_stream_id
+                .load(Ordering::Relaxed)
+                .saturating_sub(2),
+            ..Default::default()
+        };
+        let payload = goaway
+            .encode()
+            .map_err(err_to_others_err!(e, "Encode GoAway failed."))?;
+        let msg = GenMessage {
+            header: MessageHeader::new_goaway(payload.len() as u32),
+            payload: payload.into(),
+        };
+        self.req_tx
+            .send(msg)
+            .await
+            .map_err(|e| Error::Others(format!("Send packet to sender error {:?}", e)))?;
+
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err(Error::RemoteClosed),
+            Err(e) => Err(Error::Others(format!(
+                "Wait for close acknowledgement timeout {:?}",
+                e
+            ))),
+        }
+    }
+
+    // Fails calls made once the connection is closing or going away, and
+    // connects a lazy client.
+    async fn check_open(&self) -> Result<()> {
+        if self.closing.load(Ordering::Relaxed) {
+            return Err(Error::LocalClosed);
+        }
+        if self.is_going_away() {
+            return Err(going_away_error());
         }
+        self.connect_pending().await
+    }
+
+    /// Serves `services` to the server, which calls them with the
+    /// [`Peer`](crate::r#async::peer::Peer) in the context of its handlers.
+    ///
+    /// The connection then stays open until [`Client::close()`], even once
+    /// the clients are dropped. Calls of a server to a client serving
+    /// nothing are left unanswered, up to their timeout.
+    pub fn register_service(self, services: HashMap<String, Service>) -> Client {
+        *self.callbacks.lock().unwrap() = Some(Arc::new(Callbacks {
+            services,
+            tx: self.req_tx.clone(),
+        }));
+        self
+    }
+
+    /// Compress unary requests with `config`.
+    ///
+    /// The server must support the algorithm, requests are not compressed
+    /// otherwise. Compressed responses are always accepted, for the
+    /// algorithms built in. A call can override this with [`CallOptions`].
+    pub fn set_compression(mut self, config: CompressionConfig) -> Self {
+        self.compression = Some(config);
+        self
+    }
+
+    /// Retries the unary calls made with this client as set out by
+    /// `policy`.
+    ///
+    /// Only calls to methods safe to call more than once should be retried:
+    /// the setting isn't shared with the clones of the client, set it on a
+    /// clone kept for those. Streams and one-way requests aren't retried.
+    pub fn set_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(Arc::new(policy));
+        self
+    }
+
+    /// Adds an interceptor wrapping the unary calls made with this client.
+    /// Interceptors run in the order they are added, the first one seeing
+    /// the calls first.
+    ///
+    /// The setting isn't shared with the clones made before.
+    pub fn add_interceptor(mut self, interceptor: impl ClientInterceptor + 'static) -> Self {
+        Arc::make_mut(&mut self.interceptors).push(Arc::new(interceptor));
+        self
+    }
+
+    /// Sets the largest response payload the client accepts, defaults to
+    /// [`MESSAGE_LENGTH_MAX`]. A request whose response is larger fails with
+    /// RESOURCE_EXHAUSTED.
+    ///
+    /// The setting is shared with the clones of the client.
+    pub fn set_max_message_size(self, size: usize) -> Self {
+        self.max_message_size.store(size, Ordering::Relaxed);
+        self
+    }
+
+    /// Allows messages of up to `size` bytes to be exchanged in continuation
+    /// frames, each within the maximum message size.
+    ///
+    /// Requests larger than the maximum message size are then split, the
+    /// server must have chunking enabled too. Off by default, the setting is
+    /// shared with the clones of the client.
+    pub fn set_max_chunked_message_size(self, size: usize) -> Self {
+        self.max_chunked_message_size.store(size, Ordering::Relaxed);
+        self
+    }
+
+    /// Puts streams under flow control, the server may send up to `window`
+    /// messages on a stream ahead of the ones taken with `recv()`.
+    ///
+    /// The client then also waits for room before sending data if the
+    /// server set a window of its own. Servers without flow control ignore
+    /// it. Off by default.
+    pub fn set_stream_window(mut self, window: u32) -> Self {
+        self.stream_window = (window > 0).then_some(window);
+        self
+    }
+
+    /// Closes the connection if a response, once it started arriving,
+    /// didn't arrive in whole within `timeout`. Waiting for responses isn't
+    /// limited by this, the setting is shared with the clones of the client.
+    pub fn set_read_timeout(self, timeout: Duration) -> Self {
+        self.io_timeouts.lock().unwrap().read = Some(timeout);
+        self
+    }
+
+    /// Closes the connection if writing a request took longer than
+    /// `timeout`, as when the server stopped reading. The calls pending
+    /// then fail. The setting is shared with the clones of the client.
+    pub fn set_write_timeout(self, timeout: Duration) -> Self {
+        self.io_timeouts.lock().unwrap().write = Some(timeout);
+        self
     }
 
     /// Requsts a unary request and returns with response.
+    ///
+    /// If the returned future is dropped, or times out, before the response
+    /// arrives, the request is cancelled on the server, if the server agreed
+    /// to calls being cancelled in an earlier response (see
+    /// [`CANCEL_KEY`](crate::proto::CANCEL_KEY)). Else the server only
+    /// stops handling it at its deadline or once the connection drops.
     pub async fn request(&self, req: Request) -> Result<Response> {
-        let timeout_nano = req.timeout_nano;
+        self.request_with_options(req, CallOptions::default()).await
+    }
+
+    /// Makes a unary call as [`Client::request()`] does, with `opts`
+    /// applying to this call only.
+    pub async fn request_with_options(&self, req: Request, opts: CallOptions) -> Result<Response> {
+        if self.interceptors.is_empty() {
+            return self.request_retrying(req, opts).await;
+        }
+        ClientNext::new(&self.interceptors, move |req| {
+            Box::pin(self.request_retrying(req, opts))
+        })
+        .run(req)
+        .await
+    }
+
+    /// Calls `method` of `service`, such as `grpc.Containerd` and `Version`,
+    /// with `payload` as the encoded request message, and returns the
+    /// encoded response message. The timeout and metadata of the call are
+    /// taken from `ctx`.
+    ///
+    /// This makes calls without generated code, for tools like CLIs,
+    /// fuzzers or gateways. The call goes through the interceptors and is
+    /// retried like any other.
+    pub async fn call_raw(
+        &self,
+        service: &str,
+        method: &str,
+        payload: Vec<u8>,
+        ctx: Context,
+    ) -> Result<Vec<u8>> {
+        let req = common::raw_request(service, method, payload, ctx);
+        Ok(self.request(req).await?.payload)
+    }
+
+    async fn request_retrying(&self, mut req: Request, opts: CallOptions) -> Result<Response> {
+        let policy = match self.retry.as_ref() {
+            Some(policy) => policy,
+            None => return self.request_once(req, opts).await,
+        };
+        // Timed on the tokio clock, as the waits are.
+        let deadline = common::get_deadline(utils::now(), req.timeout_nano);
+        let mut jitter = Jitter::new(policy.jitter_seed);
+        let mut attempt = 1;
+        loop {
+            let e = match self.request_once(req.clone(), opts).await {
+                Ok(res) => return Ok(res),
+                Err(e) => e,
+            };
+            let backoff =
+                match policy.next_backoff(attempt, &e, deadline, utils::now(), &mut jitter) {
+                    Some(backoff) => backoff,
+                    None => return Err(e),
+                };
+            debug!("Retrying {} in {:?}: {:?}", req.method, backoff, e);
+            tokio::time::sleep(backoff).await;
+            if let Some(deadline) = deadline {
+                req.timeout_nano = common::remaining_nano(deadline, utils::now());
+            }
+            attempt += 1;
+        }
+    }
+
+    async fn request_once(&self, req: Request, opts: CallOptions) -> Result<Response> {
+        self.check_open().await?;
         let stream_id = self.next_stream_id.fetch_add(2, Ordering::Relaxed);
+        let span = spans::call(
+            spans::Kind::Client,
+            &req.service,
+            &req.method,
+            Some(stream_id),
+            self.info.as_deref(),
+        );
+        spans::instrument(span, self.send_request(stream_id, req, opts)).await
+    }
 
-        let msg: GenMessage = Message::new_request(stream_id, req)
+    async fn send_request(
+        &self,
+        stream_id: u32,
+        mut req: Request,
+        opts: CallOptions,
+    ) -> Result<Response> {
+        let timeout_nano = req.timeout_nano;
+
+        compression::add_accept_encoding(&mut req);
+        common::add_cancel_key(&mut req.metadata);
+        let mut msg: GenMessage = Message::new_request(stream_id, req)
             .try_into()
             .map_err(|e: protobuf::Error| Error::Others(e.to_string()))?;
+        let config = match opts.compression {
+            Some(CompressionOverride::Off) => None,
+            Some(CompressionOverride::On(config)) => Some(config),
+            None => self.compression,
+        };
+        if let Some(config) = config.as_ref() {
+            compression::compress_message(&mut msg, config, config.algorithm.accept_bit())?;
+        }
 
         let (tx, mut rx): (ResultSender, ResultReceiver) = mpsc::channel(100);
 
         // TODO: check return.
         self.streams.lock().unwrap().insert(stream_id, tx);
+        let mut cancel = CancelGuard::new(stream_id, self);
 
         self.req_tx
             .send(msg)
             .await
             .map_err(|e| Error::Others(format!("Send packet to sender error {:?}", e)))?;
+        cancel.sent = true;
 
         let result = if timeout_nano == 0 {
             rx.recv()
                 .await
                 .ok_or_else(|| Error::Others("Receive packet from receiver error".to_string()))?
         } else {
-            tokio::time::timeout(
-                std::time::Duration::from_nanos(timeout_nano as u64),
-                rx.recv(),
-            )
-            .await
-            .map_err(|e| Error::Others(format!("Receive packet timeout {:?}", e)))?
-            .ok_or_else(|| Error::Others("Receive packet from receiver error".to_string()))?
+            tokio::time::timeout(Duration::from_nanos(timeout_nano as u64), rx.recv())
+                .await
+                .map_err(|e| Error::Others(format!("Receive packet timeout {:?}", e)))?
+                .ok_or_else(|| Error::Others("Receive packet from receiver error".to_string()))?
         };
+        cancel.armed = false;
 
         let msg = result?;
         let res = Response::decode(msg.payload)
             .map_err(err_to_others_err!(e, "Unpack response error "))?;
+        if common::has_cancel_key(&res.metadata) {
+            self.server_cancels.store(true, Ordering::Relaxed);
+        }
 
         let status = res.status();
         if status.code() != Code::OK {
@@ -108,15 +707,42 @@ impl Client {
         Ok(res)
     }
 
+    /// Sends a one-way request to a unary method, which the server handles
+    /// without responding.
+    ///
+    /// Returns once the request is queued for sending: whether it succeeds
+    /// is only known to the server. A server without support for one-way
+    /// requests, such as a Go one, responds all the same, and the response
+    /// is dropped.
+    pub async fn notify(&self, mut req: Request) -> Result<()> {
+        self.check_open().await?;
+        let stream_id = self.next_stream_id.fetch_add(2, Ordering::Relaxed);
+
+        common::add_no_response_key(&mut req);
+        let mut msg: GenMessage = Message::new_request(stream_id, req)
+            .try_into()
+            .map_err(|e: protobuf::Error| Error::Others(e.to_string()))?;
+        if let Some(config) = self.compression.as_ref() {
+            compression::compress_message(&mut msg, config, config.algorithm.accept_bit())?;
+        }
+
+        self.req_tx
+            .send(msg)
+            .await
+            .map_err(|e| Error::Others(format!("Send packet to sender error {:?}", e)))
+    }
+
     /// Creates a StreamInner instance.
     pub async fn new_stream(
         &self,
-        req: Request,
+        mut req: Request,
         streaming_client: bool,
         streaming_server: bool,
     ) -> Result<StreamInner> {
+        self.check_open().await?;
         let stream_id = self.next_stream_id.fetch_add(2, Ordering::Relaxed);
 
+        common::add_cancel_key(&mut req.metadata);
         let mut msg: GenMessage = Message::new_request(stream_id, req)
             .try_into()
             .map_err(|e: protobuf::Error| Error::Others(e.to_string()))?;
@@ -130,12 +756,15 @@ impl Client {
         let (tx, rx): (ResultSender, ResultReceiver) = mpsc::channel(100);
         // TODO: check return
         self.streams.lock().unwrap().insert(stream_id, tx);
+        let mut cancel = CancelGuard::new(stream_id, self);
         self.req_tx
             .send(msg)
             .await
             .map_err(|e| Error::Others(format!("Send packet to sender error {:?}", e)))?;
+        // The stream takes over from here.
+        cancel.armed = false;
 
-        Ok(StreamInner::new(
+        let mut inner = StreamInner::new(
             stream_id,
             self.req_tx.clone(),
             rx,
@@ -143,7 +772,181 @@ impl Client {
             streaming_server,
             Kind::Client,
             self.streams.clone(),
-        ))
+        )
+        .with_server_cancels(&self.server_cancels);
+        if self.stream_window.is_some() {
+            inner = inner.with_flow_control(&self.windows, self.stream_window);
+            inner.open_window().await?;
+        }
+        Ok(inner)
+    }
+
+    /// Subscribes to the notifications the server publishes under `topic`.
+    ///
+    /// Notifications are dropped if the returned receiver is not drained in time.
+    /// Subscribing to the same topic again replaces the previous receiver.
+    pub async fn subscribe(&self, topic: &str) -> Result<mpsc::Receiver<Vec<u8>>> {
+        let (tx, rx) = mpsc::channel(DEFAULT_NOTIFICATION_BUFFER);
+        self.notifications
+            .lock()
+            .unwrap()
+            .insert(topic.to_string(), tx);
+        self.send_subscription(topic, false).await?;
+        Ok(rx)
+    }
+
+    /// Stops receiving the notifications published under `topic`.
+    pub async fn unsubscribe(&self, topic: &str) -> Result<()> {
+        self.notifications.lock().unwrap().remove(topic);
+        self.send_subscription(topic, true).await
+    }
+
+    async fn send_subscription(&self, topic: &str, unsubscribe: bool) -> Result<()> {
+        self.connect_pending().await?;
+        let sub = Subscription {
+            topic: topic.to_string(),
+            unsubscribe,
+            ..Default::default()
+        };
+        let payload = sub
+            .encode()
+            .map_err(err_to_others_err!(e, "Encode Subscription failed."))?;
+        let msg = GenMessage {
+            header: MessageHeader::new_subscribe(payload.len() as u32),
+            payload: payload.into(),
+        };
+        self.req_tx
+            .send(msg)
+            .await
+            .map_err(|e| Error::Others(format!("Send packet to sender error {:?}", e)))
+    }
+}
+
+/// Clients calls are spread over round-robin, connected to one server or
+/// several serving the same services.
+///
+/// The messages of a connection are written and read one after the other,
+/// so a large one holds up the calls behind it: spreading the calls over
+/// several connections avoids that. A call made on a client whose
+/// connection dropped fails, make the pool of clients that reconnect to
+/// avoid it. The clones of a pool share the clients.
+#[derive(Clone)]
+pub struct ClientPool {
+    clients: Arc<Vec<Client>>,
+    next: Arc<AtomicUsize>,
+}
+
+impl ClientPool {
+    /// Makes a pool of `clients`, failing if there are none.
+    pub fn new(clients: Vec<Client>) -> Result<ClientPool> {
+        if clients.is_empty() {
+            return Err(Error::Others("A client pool needs clients".to_string()));
+        }
+        Ok(ClientPool {
+            clients: Arc::new(clients),
+            next: Arc::default(),
+        })
+    }
+
+    /// Makes a pool of `size` connections to `sockaddr`.
+    pub fn connect(sockaddr: &str, size: usize) -> Result<ClientPool> {
+        Self::connect_all(&[sockaddr], size)
+    }
+
+    /// Makes a pool of `size` connections to each of `sockaddrs`.
+    pub fn connect_all(sockaddrs: &[&str], size: usize) -> Result<ClientPool> {
+        let mut clients = Vec::with_capacity(sockaddrs.len() * size);
+        for sockaddr in sockaddrs {
+            for _ in 0..size {
+                clients.push(Client::connect(sockaddr)?);
+            }
+        }
+        Self::new(clients)
+    }
+
+    /// Returns the next client in turn, to make a call or open a stream
+    /// with.
+    pub fn client(&self) -> Client {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        self.clients[next % self.clients.len()].clone()
+    }
+
+    pub fn clients(&self) -> &[Client] {
+        &self.clients
+    }
+
+    /// Makes the call with the next client in turn.
+    pub async fn request(&self, req: Request) -> Result<Response> {
+        self.client().request(req).await
+    }
+
+    /// Makes the call with the next client in turn, as
+    /// [`Client::request_with_options()`] does.
+    pub async fn request_with_options(&self, req: Request, opts: CallOptions) -> Result<Response> {
+        self.client().request_with_options(req, opts).await
+    }
+
+    /// Closes the clients as [`Client::close()`] does, each waiting up to
+    /// `timeout`, and returns the first error.
+    pub async fn close(&self, timeout: Duration) -> Result<()> {
+        let closed = futures::future::join_all(self.clients.iter().map(|c| c.close(timeout))).await;
+        closed.into_iter().collect()
+    }
+}
+
+// Cancels an in-flight request when dropped while still armed: forgets it,
+// and tells the server once the request was queued to it, if the server
+// agreed to calls being cancelled.
+struct CancelGuard<'a> {
+    stream_id: u32,
+    req_tx: &'a MessageSender,
+    streams: &'a Mutex<HashMap<u32, ResultSender>>,
+    server_cancels: &'a AtomicBool,
+    armed: bool,
+    sent: bool,
+}
+
+impl<'a> CancelGuard<'a> {
+    fn new(stream_id: u32, client: &'a Client) -> Self {
+        CancelGuard {
+            stream_id,
+            req_tx: &client.req_tx,
+            streams: &client.streams,
+            server_cancels: &client.server_cancels,
+            armed: true,
+            sent: false,
+        }
+    }
+}
+
+impl Drop for CancelGuard<'_> {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        self.streams.lock().unwrap().remove(&self.stream_id);
+        if !self.sent || !self.server_cancels.load(Ordering::Relaxed) {
+            return;
+        }
+        let msg = GenMessage {
+            header: MessageHeader::new_cancel(self.stream_id),
+            payload: Bytes::new(),
+        };
+        match self.req_tx.try_send(msg) {
+            Ok(()) => {}
+            // Queued behind the requests, so it can't overtake the one it
+            // cancels.
+            Err(mpsc::error::TrySendError::Full(msg)) => {
+                match tokio::runtime::Handle::try_current() {
+                    Ok(handle) => {
+                        let req_tx = self.req_tx.clone();
+                        handle.spawn(async move { req_tx.send(msg).await.ok() });
+                    }
+                    Err(e) => debug!("Failed to cancel stream id {}: {}", self.stream_id, e),
+                }
+            }
+            Err(e) => debug!("Failed to cancel stream id {}: {}", self.stream_id, e),
+        }
     }
 }
 
@@ -160,10 +963,26 @@ impl Drop for ClientClose {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone)]
 struct ClientBuilder {
-    rx: Option<MessageReceiver>,
+    fd: RawFd,
+    rx: RxSlot,
     streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
+    notifications: NotificationSenders,
+    max_message_size: Arc<AtomicUsize>,
+    max_chunked_message_size: Arc<AtomicUsize>,
+    going_away: Arc<AtomicBool>,
+    server_cancels: Arc<AtomicBool>,
+    close_ack: CloseAck,
+    close: Arc<Notify>,
+    windows: Windows,
+    io_timeouts: Arc<Mutex<IoTimeouts>>,
+    info: Arc<ConnectionInfo>,
+    callbacks: CallbackSlot,
+    state: StateWatch,
+    // Whether the calls still queued once the connection drops are sent on
+    // the next one.
+    reconnects: bool,
 }
 
 impl Builder for ClientBuilder {
@@ -172,14 +991,37 @@ impl Builder for ClientBuilder {
 
     fn build(&mut self) -> (Self::Reader, Self::Writer) {
         let (notifier, waiter) = shutdown::new();
+        let written = Written::default();
         (
             ClientReader {
+                fd: self.fd,
                 shutdown_waiter: waiter,
                 streams: self.streams.clone(),
+                notifications: self.notifications.clone(),
+                max_message_size: self.max_message_size.clone(),
+                max_chunked_message_size: self.max_chunked_message_size.clone(),
+                going_away: self.going_away.clone(),
+                close_ack: self.close_ack.clone(),
+                close: self.close.clone(),
+                windows: self.windows.clone(),
+                io_timeouts: self.io_timeouts.clone(),
+                info: self.info.clone(),
+                closing: Closing::default(),
+                callbacks: self.callbacks.clone(),
+                state: self.state.clone(),
+                written: written.clone(),
+                reconnects: self.reconnects,
             },
             ClientWriter {
-                rx: self.rx.take().unwrap(),
+                fd: self.fd,
+                rx: self.rx.lock().unwrap().take(),
+                rx_slot: self.rx.clone(),
                 shutdown_notifier: notifier,
+                max_message_size: self.max_message_size.clone(),
+                max_chunked_message_size: self.max_chunked_message_size.clone(),
+                io_timeouts: self.io_timeouts.clone(),
+                written,
+                broken: AtomicBool::new(false),
 
                 streams: self.streams.clone(),
             },
@@ -188,8 +1030,16 @@ impl Builder for ClientBuilder {
 }
 
 struct ClientWriter {
-    rx: MessageReceiver,
+    fd: RawFd,
+    rx: Option<MessageReceiver>,
+    rx_slot: RxSlot,
     shutdown_notifier: shutdown::Notifier,
+    max_message_size: Arc<AtomicUsize>,
+    max_chunked_message_size: Arc<AtomicUsize>,
+    io_timeouts: Arc<Mutex<IoTimeouts>>,
+    written: Written,
+    // Set once a write failed.
+    broken: AtomicBool,
 
     streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
 }
@@ -197,10 +1047,21 @@ struct ClientWriter {
 #[async_trait]
 impl WriterDelegate for ClientWriter {
     async fn recv(&mut self) -> Option<GenMessage> {
-        self.rx.recv().await
+        if self.broken.load(Ordering::Relaxed) {
+            // The messages left stay queued for the next connection, the
+            // reader sees this one is gone and stops the writer.
+            return futures::future::pending().await;
+        }
+        let msg = self.rx.as_mut()?.recv().await?;
+        self.written.insert(msg.header.stream_id, &self.streams);
+        Some(msg)
     }
 
     async fn disconnect(&self, msg: &GenMessage, e: Error) {
+        self.broken.store(true, Ordering::Relaxed);
+        // Nothing can follow a message partly written, make sure the reader
+        // sees the connection is gone.
+        socket::shutdown(self.fd, Shutdown::Both).unwrap_or(());
         // TODO:
         // At this point, a new request may have been received.
         let resp_tx = {
@@ -221,11 +1082,177 @@ impl WriterDelegate for ClientWriter {
     async fn exit(&self) {
         self.shutdown_notifier.shutdown();
     }
+
+    fn chunk_size(&self) -> Option<usize> {
+        if self.max_chunked_message_size.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        Some(self.max_message_size.load(Ordering::Relaxed))
+    }
+
+    fn io_timeouts(&self) -> IoTimeouts {
+        *self.io_timeouts.lock().unwrap()
+    }
+}
+
+impl Drop for ClientWriter {
+    fn drop(&mut self) {
+        *self.rx_slot.lock().unwrap() = self.rx.take();
+    }
+}
+
+// Where a client connects, on its first call or again once its connection
+// dropped.
+struct Dialer {
+    sockaddr: String,
+    retry: ConnectRetry,
+}
+
+impl Dialer {
+    // Runs the connection, and connects again whenever it drops until the
+    // client is closed or dropped.
+    async fn run(
+        self,
+        mut conn: Connection<UnixStream, ClientBuilder>,
+        builder: ClientBuilder,
+        closing: Arc<AtomicBool>,
+        task: Weak<ConnectionTask>,
+    ) -> std::io::Result<()> {
+        loop {
+            conn.run().await?;
+            if closing.load(Ordering::Relaxed) || task.strong_count() == 0 {
+                builder.give_up(Error::LocalClosed).await;
+                return Ok(());
+            }
+            builder.state.set(ConnectionState::Reconnecting);
+            let res = select! {
+                res = connect_retrying(&self.sockaddr, &self.retry) => res,
+                _ = builder.close.notified() => {
+                    builder.state.set(ConnectionState::Disconnected);
+                    builder.give_up(Error::LocalClosed).await;
+                    return Ok(());
+                }
+            };
+            let fd = match res {
+                Ok(fd) => fd,
+                Err(e) => {
+                    warn!("Failed to reconnect to {}: {}", self.sockaddr, e);
+                    builder.state.set(ConnectionState::Disconnected);
+                    builder.give_up(e).await;
+                    return Ok(());
+                }
+            };
+            debug!("Reconnected to {}", self.sockaddr);
+            builder.going_away.store(false, Ordering::Relaxed);
+            builder.server_cancels.store(false, Ordering::Relaxed);
+            let info = Arc::new(ConnectionInfo::new(fd));
+            spans::connection_opened(spans::Kind::Client, &info);
+            let next = ClientBuilder {
+                fd,
+                info,
+                ..builder.clone()
+            };
+            builder.state.set(ConnectionState::Ready);
+            conn = Connection::new(utils::new_unix_stream_from_raw_fd(fd), next);
+        }
+    }
+}
+
+impl ClientBuilder {
+    // Fails the calls made while reconnecting, and the ones made later.
+    async fn give_up(&self, e: Error) {
+        self.rx.lock().unwrap().take();
+        let map = std::mem::take(&mut *self.streams.lock().unwrap());
+        for (_stream_id, resp_tx) in map {
+            resp_tx.send(Err(e.clone())).await.ok();
+        }
+    }
 }
 
 struct ClientReader {
+    fd: RawFd,
     streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
+    notifications: NotificationSenders,
     shutdown_waiter: shutdown::Waiter,
+    max_message_size: Arc<AtomicUsize>,
+    max_chunked_message_size: Arc<AtomicUsize>,
+    going_away: Arc<AtomicBool>,
+    close_ack: CloseAck,
+    close: Arc<Notify>,
+    windows: Windows,
+    io_timeouts: Arc<Mutex<IoTimeouts>>,
+    info: Arc<ConnectionInfo>,
+    // Why the connection closed, for tracing.
+    closing: Closing,
+    callbacks: CallbackSlot,
+    state: StateWatch,
+    written: Written,
+    reconnects: bool,
+}
+
+impl ClientReader {
+    // Fails the calls the server won't handle.
+    async fn handle_goaway(&self, msg: GenMessage) {
+        let goaway = match GoAway::decode(&msg.payload) {
+            Ok(g) => g,
+            Err(e) => {
+                debug!("Receiver got malformed go away {:?}: {}", msg, e);
+                return;
+            }
+        };
+        debug!(
+            "Server is going away after stream id {}",
+            goaway.last_stream_id
+        );
+        self.going_away.store(true, Ordering::Relaxed);
+        if goaway.ack {
+            if let Some(tx) = self.close_ack.lock().unwrap().take() {
+                tx.send(()).ok();
+            }
+        }
+
+        let abandoned: Vec<ResultSender> = {
+            let mut map = self.streams.lock().unwrap();
+            let ids: Vec<u32> = map
+                .keys()
+                .filter(|&&id| id > goaway.last_stream_id)
+                .copied()
+                .collect();
+            ids.iter().filter_map(|id| map.remove(id)).collect()
+        };
+        for tx in abandoned {
+            tx.send(Err(going_away_error()))
+                .await
+                .unwrap_or_else(|_e| error!("The request has returned"));
+        }
+    }
+
+    fn handle_notification(&self, msg: GenMessage) {
+        let notification = match Notification::decode(&msg.payload) {
+            Ok(n) => n,
+            Err(e) => {
+                debug!("Receiver got malformed notification {:?}: {}", msg, e);
+                return;
+            }
+        };
+        let tx = self
+            .notifications
+            .lock()
+            .unwrap()
+            .get(&notification.topic)
+            .cloned();
+        match tx {
+            Some(tx) => {
+                if let Err(e) = tx.try_send(notification.payload) {
+                    debug!("Drop notification for topic {}: {}", notification.topic, e);
+                }
+            }
+            None => debug!(
+                "Receiver got notification for unsubscribed topic {}",
+                notification.topic
+            ),
+        }
+    }
 }
 
 #[async_trait]
@@ -234,14 +1261,34 @@ impl ReaderDelegate for ClientReader {
         self.shutdown_waiter.wait_shutdown().await
     }
 
+    async fn wait_close(&self) -> Error {
+        self.close.notified().await;
+        Error::LocalClosed
+    }
+
     async fn disconnect(&self, e: Error, sender: &mut task::JoinHandle<()>) {
         // Abort the request sender task to prevent incoming RPC requests
         // from being processed.
         sender.abort();
         let _ = sender.await;
 
-        // Take all items out of `req_map`.
-        let mut map = std::mem::take(&mut *self.streams.lock().unwrap());
+        self.closing.set(CloseReason::from_error(&e));
+        // A close waiting for the server won't be acknowledged any more.
+        self.close_ack.lock().unwrap().take();
+
+        // Take the calls made on this connection out of `req_map`. When
+        // reconnecting, the ones whose messages are still queued are made on
+        // the next connection instead.
+        let mut map = if self.reconnects {
+            let written = self.written.take();
+            let mut streams = self.streams.lock().unwrap();
+            written
+                .iter()
+                .filter_map(|id| streams.remove_entry(id))
+                .collect()
+        } else {
+            std::mem::take(&mut *self.streams.lock().unwrap())
+        };
         // Terminate undone RPC requests with the error.
         for (_stream_id, resp_tx) in map.drain() {
             if let Err(_e) = resp_tx.send(Err(e.clone())).await {
@@ -250,10 +1297,41 @@ impl ReaderDelegate for ClientReader {
         }
     }
 
-    async fn exit(&self) {}
+    async fn exit(&self) {
+        spans::connection_closed(spans::Kind::Client, &self.info, &self.closing.take());
+        self.state.set(ConnectionState::Disconnected);
+    }
 
     async fn handle_msg(&self, msg: GenMessage) {
+        if msg.header.type_ == MESSAGE_TYPE_NOTIFICATION {
+            self.handle_notification(msg);
+            return;
+        }
+        if msg.header.type_ == MESSAGE_TYPE_GOAWAY {
+            self.handle_goaway(msg).await;
+            return;
+        }
+        if msg.header.type_ == MESSAGE_TYPE_WINDOW_UPDATE {
+            flow_control::handle_update(&self.windows, &msg, false);
+            return;
+        }
+        if msg.header.type_ == MESSAGE_TYPE_REQUEST {
+            let callbacks = self.callbacks.lock().unwrap().clone();
+            match callbacks {
+                Some(callbacks) => {
+                    let (fd, info) = (self.fd, self.info.clone());
+                    tokio::spawn(async move { callbacks.handle(fd, info, msg).await });
+                }
+                None => debug!(
+                    "Receiver got a request, but serves nothing {:?}",
+                    msg.header
+                ),
+            }
+            return;
+        }
+
         let req_map = self.streams.clone();
+        let max_len = self.max_message_size().max(self.max_chunked_message_size());
         tokio::spawn(async move {
             let resp_tx = match msg.header.type_ {
                 MESSAGE_TYPE_RESPONSE => {
@@ -302,10 +1380,421 @@ impl ReaderDelegate for ClientReader {
                     return;
                 }
             };
+            let mut msg = msg;
+            let res = compression::decompress_message(&mut msg, max_len).map(|_| msg);
             resp_tx
-                .send(Ok(msg))
+                .send(res)
                 .await
                 .unwrap_or_else(|_e| error!("The request has returned"));
         });
     }
+
+    async fn handle_oversized(&self, header: MessageHeader, status: Status) {
+        // Whatever was skipped, the stream can't go on without it.
+        let resp_tx = match self.streams.lock().unwrap().remove(&header.stream_id) {
+            Some(tx) => tx,
+            None => {
+                debug!("Receiver got unknown oversized packet {:?}", header);
+                return;
+            }
+        };
+        resp_tx
+            .send(Err(Error::RpcStatus(status)))
+            .await
+            .unwrap_or_else(|_e| error!("The request has returned"));
+    }
+
+    fn max_message_size(&self) -> usize {
+        self.max_message_size.load(Ordering::Relaxed)
+    }
+
+    fn max_chunked_message_size(&self) -> usize {
+        self.max_chunked_message_size.load(Ordering::Relaxed)
+    }
+
+    fn io_timeouts(&self) -> IoTimeouts {
+        *self.io_timeouts.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::get_status;
+    use crate::r#async::Server;
+    use crate::service_fn::{self, Raw};
+    use std::os::unix::io::IntoRawFd;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::UnixListener;
+
+    // An address to listen on, unique to the test.
+    fn sockaddr(name: &str) -> (String, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("ttrpc-{}-{}.sock", name, std::process::id()));
+        std::fs::remove_file(&path).ok();
+        (format!("unix://{}", path.display()), path)
+    }
+
+    fn request(method: &str, payload: Vec<u8>) -> Request {
+        Request {
+            service: "svc".to_string(),
+            method: method.to_string(),
+            payload,
+            ..Default::default()
+        }
+    }
+
+    // Reads the next request off `conn`.
+    async fn next_request(conn: &mut UnixStream) -> (u32, Request) {
+        loop {
+            let msg = GenMessage::read_from(&mut *conn).await.unwrap();
+            if msg.header.type_ == MESSAGE_TYPE_REQUEST {
+                return (msg.header.stream_id, Request::decode(&msg.payload).unwrap());
+            }
+        }
+    }
+
+    async fn respond(conn: &mut UnixStream, stream_id: u32, payload: &[u8]) {
+        let mut res = Response::new();
+        res.set_status(get_status(Code::OK, ""));
+        res.payload = payload.to_vec();
+        let payload = res.encode().unwrap();
+        let msg = GenMessage {
+            header: MessageHeader::new_response(stream_id, payload.len() as u32),
+            payload: payload.into(),
+        };
+        msg.write_to(&mut *conn).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_late_response_of_timed_out_call() {
+        let (addr, path) = sockaddr("late-timed-out");
+        let listener = UnixListener::bind(&path).unwrap();
+        let client = Client::connect(&addr).unwrap();
+        let (mut conn, _) = listener.accept().await.unwrap();
+
+        let mut req = request("Slow", Vec::new());
+        req.timeout_nano = Duration::from_millis(50).as_nanos() as i64;
+        let slow = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(req).await }
+        });
+        let (slow_id, _) = next_request(&mut conn).await;
+        assert!(slow.await.unwrap().is_err());
+
+        // The server answers the timed-out call once the next one is made,
+        // before answering that one.
+        let next = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("Next", Vec::new())).await }
+        });
+        let (next_id, req) = next_request(&mut conn).await;
+        assert_eq!(req.method, "Next");
+        assert_ne!(next_id, slow_id);
+        respond(&mut conn, slow_id, b"late").await;
+        respond(&mut conn, next_id, b"next").await;
+        assert_eq!(next.await.unwrap().unwrap().payload, b"next");
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_repeated_response_of_finished_call() {
+        let (addr, path) = sockaddr("late-finished");
+        let listener = UnixListener::bind(&path).unwrap();
+        let client = Client::connect(&addr).unwrap();
+        let (mut conn, _) = listener.accept().await.unwrap();
+
+        let first = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("First", Vec::new())).await }
+        });
+        let (first_id, _) = next_request(&mut conn).await;
+        respond(&mut conn, first_id, b"first").await;
+        assert_eq!(first.await.unwrap().unwrap().payload, b"first");
+
+        // The server answers the finished call again before answering the
+        // next one.
+        let next = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("Next", Vec::new())).await }
+        });
+        let (next_id, _) = next_request(&mut conn).await;
+        respond(&mut conn, first_id, b"again").await;
+        respond(&mut conn, next_id, b"next").await;
+        assert_eq!(next.await.unwrap().unwrap().payload, b"next");
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_sends_queued_calls() {
+        let (addr, path) = sockaddr("reconnect-queued");
+        let listener = UnixListener::bind(&path).unwrap();
+        let client = Client::connect_with_reconnect(&addr, ConnectRetry::default())
+            .await
+            .unwrap();
+        let (mut first, _) = listener.accept().await.unwrap();
+
+        // A request larger than the socket buffers keeps the writer busy
+        // once the server stops reading, the next ones stay queued.
+        let big = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("Big", vec![0; 3 << 20])).await }
+        });
+        let mut header = [0; crate::proto::MESSAGE_HEADER_LENGTH];
+        first.read_exact(&mut header).await.unwrap();
+        let queued: Vec<_> = (0..2)
+            .map(|_| {
+                let client = client.clone();
+                tokio::spawn(async move { client.request(request("Small", Vec::new())).await })
+            })
+            .collect();
+        while client.streams.lock().unwrap().len() < 3 {
+            tokio::task::yield_now().await;
+        }
+        drop(first);
+
+        // Only the request written on the dropped connection fails.
+        assert!(big.await.unwrap().is_err());
+        let (mut second, _) = listener.accept().await.unwrap();
+        for _ in 0..2 {
+            let (stream_id, req) = next_request(&mut second).await;
+            assert_eq!(req.method, "Small");
+            respond(&mut second, stream_id, b"ok").await;
+        }
+        for call in queued {
+            assert_eq!(call.await.unwrap().unwrap().payload, b"ok");
+        }
+
+        client.close(Duration::from_millis(10)).await.ok();
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_drops_stale_responses() {
+        let (addr, path) = sockaddr("reconnect-stale");
+        let listener = UnixListener::bind(&path).unwrap();
+        let client = Client::connect_with_reconnect(&addr, ConnectRetry::default())
+            .await
+            .unwrap();
+        let (mut first, _) = listener.accept().await.unwrap();
+
+        let old = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("Old", Vec::new())).await }
+        });
+        let (old_id, _) = next_request(&mut first).await;
+        drop(first);
+        assert!(old.await.unwrap().is_err());
+
+        // The server answers the call of the dropped connection late, on
+        // the new one, before answering the call made since.
+        let (mut second, _) = listener.accept().await.unwrap();
+        let new = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("New", Vec::new())).await }
+        });
+        let (new_id, req) = next_request(&mut second).await;
+        assert_eq!(req.method, "New");
+        assert_ne!(
+            new_id, old_id,
+            "stream ids are not reused across connections"
+        );
+        respond(&mut second, old_id, b"stale").await;
+        respond(&mut second, new_id, b"fresh").await;
+        assert_eq!(new.await.unwrap().unwrap().payload, b"fresh");
+
+        client.close(Duration::from_millis(10)).await.ok();
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_call_dropped_before_queued() {
+        let (addr, path) = sockaddr("dropped-before-queued");
+        let listener = UnixListener::bind(&path).unwrap();
+        let client = Client::connect(&addr).unwrap();
+        let (mut conn, _) = listener.accept().await.unwrap();
+
+        // A request larger than the socket buffers keeps the writer busy
+        // once the server stops reading, the next ones fill the queue.
+        let big = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("Big", vec![0; 3 << 20])).await }
+        });
+        let mut header = [0; crate::proto::MESSAGE_HEADER_LENGTH];
+        conn.read_exact(&mut header).await.unwrap();
+        let queued: Vec<_> = (0..110)
+            .map(|_| {
+                let client = client.clone();
+                tokio::spawn(async move { client.request(request("Small", Vec::new())).await })
+            })
+            .collect();
+        while client.streams.lock().unwrap().len() < 111 {
+            tokio::task::yield_now().await;
+        }
+
+        let call = client.request(request("Dropped", Vec::new()));
+        assert!(tokio::time::timeout(Duration::from_millis(50), call)
+            .await
+            .is_err());
+        assert_eq!(client.streams.lock().unwrap().len(), 111);
+
+        drop(conn);
+        assert!(big.await.unwrap().is_err());
+        for call in queued {
+            assert!(call.await.unwrap().is_err());
+        }
+        assert!(client.streams.lock().unwrap().is_empty());
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_client_pool() {
+        let (addr, path) = sockaddr("client-pool");
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let mut server = Server::new()
+            .add_listener(listener.into_raw_fd())
+            .unwrap()
+            .set_domain_unix()
+            .register_method(
+                "svc",
+                "Id",
+                service_fn::async_method(Raw, |ctx, _req: Vec<u8>| {
+                    let id = ctx.connection.id;
+                    async move { Ok(id.to_be_bytes().to_vec()) }
+                }),
+            )
+            .register_method(
+                "svc",
+                "Break",
+                service_fn::async_method(Raw, |ctx, req: Vec<u8>| {
+                    socket::shutdown(ctx.fd, Shutdown::Both).unwrap();
+                    async { Ok(req) }
+                }),
+            );
+        server.start().await.unwrap();
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            clients.push(
+                Client::connect_with_reconnect(&addr, ConnectRetry::default())
+                    .await
+                    .unwrap(),
+            );
+        }
+        let pool = ClientPool::new(clients).unwrap();
+        let ids = || async {
+            let mut ids = Vec::new();
+            for _ in 0..3 {
+                ids.push(
+                    pool.request(request("Id", Vec::new()))
+                        .await
+                        .unwrap()
+                        .payload,
+                );
+            }
+            ids
+        };
+
+        // The calls go round the connections.
+        let first = ids().await;
+        assert_eq!(ids().await, first);
+        assert!(first[0] != first[1] && first[1] != first[2] && first[0] != first[2]);
+
+        // A client whose connection broke connects again.
+        let broken = &pool.clients()[0];
+        assert!(broken.request(request("Break", Vec::new())).await.is_err());
+        let next = ids().await;
+        assert_ne!(next[0], first[0]);
+        assert_eq!(next[1..], first[1..]);
+
+        pool.close(Duration::from_millis(10)).await.ok();
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_lazy() {
+        let (addr, path) = sockaddr("connect-lazy");
+        let retry = ConnectRetry {
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let client = Client::connect_lazy(&addr, retry);
+
+        // Nothing listens, each call tries to connect and fails.
+        for _ in 0..2 {
+            assert!(client.request(request("Ping", Vec::new())).await.is_err());
+        }
+
+        let listener = UnixListener::bind(&path).unwrap();
+        let accept = tokio::time::timeout(Duration::from_millis(50), listener.accept());
+        assert!(accept.await.is_err(), "connected before a call");
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("Ping", Vec::new())).await }
+        });
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let (stream_id, _) = next_request(&mut conn).await;
+        respond(&mut conn, stream_id, b"pong").await;
+        assert_eq!(call.await.unwrap().unwrap().payload, b"pong");
+
+        client.close(Duration::from_millis(10)).await.ok();
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[cfg(feature = "gzip")]
+    #[tokio::test]
+    async fn test_call_compression_override() {
+        use crate::compression::Compression;
+        use crate::proto::FLAG_COMPRESSED_GZIP;
+
+        // Reads the flags of the next request off `conn`, and answers it.
+        async fn request_flags(conn: &mut UnixStream) -> u8 {
+            loop {
+                let msg = GenMessage::read_from(&mut *conn).await.unwrap();
+                if msg.header.type_ == MESSAGE_TYPE_REQUEST {
+                    respond(conn, msg.header.stream_id, b"ok").await;
+                    return msg.header.flags;
+                }
+            }
+        }
+
+        let (addr, path) = sockaddr("call-compression");
+        let listener = UnixListener::bind(&path).unwrap();
+        let gzip = CompressionConfig {
+            algorithm: Compression::Gzip,
+            threshold: 0,
+        };
+        let compressing = Client::connect(&addr).unwrap().set_compression(gzip);
+        let (mut first, _) = listener.accept().await.unwrap();
+        let plain = Client::connect(&addr).unwrap();
+        let (mut second, _) = listener.accept().await.unwrap();
+
+        let off = CallOptions {
+            compression: Some(CompressionOverride::Off),
+        };
+        let on = CallOptions {
+            compression: Some(CompressionOverride::On(gzip)),
+        };
+        for (compressing_client, opts, compressed) in [
+            (true, CallOptions::default(), true),
+            (true, off, false),
+            (false, CallOptions::default(), false),
+            (false, on, true),
+        ] {
+            let (client, conn) = if compressing_client {
+                (&compressing, &mut first)
+            } else {
+                (&plain, &mut second)
+            };
+            let req = request("Call", vec![b'a'; 4096]);
+            let (res, flags) =
+                tokio::join!(client.request_with_options(req, opts), request_flags(conn));
+            assert_eq!(res.unwrap().payload, b"ok");
+            assert_eq!(flags & FLAG_COMPRESSED_GZIP != 0, compressed, "{:?}", opts);
+        }
+
+        compressing.close(Duration::from_millis(10)).await.ok();
+        plain.close(Duration::from_millis(10)).await.ok();
+        std::fs::remove_file(&path).ok();
+    }
 }
@@ -5,16 +5,32 @@
 //
 
 use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use log::{error, trace};
+use nix::sys::socket::{self, Shutdown};
 use tokio::{
-    io::{split, AsyncRead, AsyncWrite, ReadHalf},
+    io::{split, AsyncBufReadExt as _, AsyncRead, AsyncWrite, BufReader, ReadHalf},
     select, task,
 };
 
-use crate::error::Error;
-use crate::proto::GenMessage;
+use crate::codec::HeaderLimits;
+use crate::error::{sock_error_msg, Error, Result};
+use crate::proto::{GenMessage, MessageHeader, Status};
+use crate::r#async::buffer_pool::BufferPool;
+use crate::r#async::chunking::{self, Reassembler};
+
+pub(crate) use crate::config::DEFAULT_READ_BUFFER_SIZE;
+
+/// How long reading a frame, once it started arriving, and writing one may
+/// take before the connection is closed. No limits by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IoTimeouts {
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+}
 
 pub trait Builder {
     type Reader;
@@ -28,14 +44,48 @@ pub trait WriterDelegate {
     async fn recv(&mut self) -> Option<GenMessage>;
     async fn disconnect(&self, msg: &GenMessage, e: Error);
     async fn exit(&self);
+    /// Payloads larger than this are split into continuation frames.
+    fn chunk_size(&self) -> Option<usize>;
+    fn io_timeouts(&self) -> IoTimeouts {
+        IoTimeouts::default()
+    }
 }
 
 #[async_trait]
 pub trait ReaderDelegate {
     async fn wait_shutdown(&self);
+    /// Resolves once the connection is to be closed right away, with the
+    /// error the calls still pending fail with.
+    async fn wait_close(&self) -> Error {
+        futures::future::pending().await
+    }
     async fn disconnect(&self, e: Error, task: &mut task::JoinHandle<()>);
     async fn exit(&self);
     async fn handle_msg(&self, msg: GenMessage);
+    /// Called for a message that was skipped for its size.
+    async fn handle_oversized(&self, header: MessageHeader, status: Status);
+    fn max_message_size(&self) -> usize;
+    /// The largest payload a message in continuation frames may reassemble
+    /// to, 0 to refuse them.
+    fn max_chunked_message_size(&self) -> usize;
+    /// Limits the headers of frames from the peer are checked against, the
+    /// connection is closed on the first one failing them.
+    fn header_limits(&self) -> HeaderLimits {
+        HeaderLimits::default()
+    }
+    /// Reads from the peer are buffered by up to this many bytes, 0 reads
+    /// frames straight from the connection.
+    fn read_buffer_size(&self) -> usize {
+        DEFAULT_READ_BUFFER_SIZE
+    }
+    /// Payloads are read into buffers of this pool, if any, rather than
+    /// newly allocated ones.
+    fn buffer_pool(&self) -> Option<Arc<BufferPool>> {
+        None
+    }
+    fn io_timeouts(&self) -> IoTimeouts {
+        IoTimeouts::default()
+    }
 }
 
 pub struct Connection<S, B: Builder> {
@@ -52,6 +102,7 @@ where
     B::Writer: WriterDelegate + Send + Sync + 'static,
 {
     pub fn new(conn: S, mut builder: B) -> Self {
+        let fd = conn.as_raw_fd();
         let (reader, mut writer) = split(conn);
 
         let (reader_delegate, mut writer_delegate) = builder.build();
@@ -59,9 +110,33 @@ where
         let writer_task = tokio::spawn(async move {
             while let Some(msg) = writer_delegate.recv().await {
                 trace!("write message: {:?}", msg);
-                if let Err(e) = msg.write_to(&mut writer).await {
-                    error!("write_message got error: {:?}", e);
-                    writer_delegate.disconnect(&msg, e).await;
+                let frames = match writer_delegate.chunk_size() {
+                    Some(size) => chunking::split(msg, size),
+                    None => vec![msg],
+                };
+                for frame in frames {
+                    let res = match writer_delegate.io_timeouts().write {
+                        Some(timeout) => {
+                            match tokio::time::timeout(timeout, frame.write_to(&mut writer)).await {
+                                Ok(res) => res,
+                                Err(_) => {
+                                    // Part of the frame may be out, so nothing
+                                    // can follow it. The reader sees the close too.
+                                    socket::shutdown(fd, Shutdown::Both).unwrap_or(());
+                                    Err(Error::Socket(format!(
+                                        "timed out after {:?} writing a message",
+                                        timeout
+                                    )))
+                                }
+                            }
+                        }
+                        None => frame.write_to(&mut writer).await,
+                    };
+                    if let Err(e) = res {
+                        error!("write_message got error: {:?}", e);
+                        writer_delegate.disconnect(&frame, e).await;
+                        break;
+                    }
                 }
             }
             writer_delegate.exit().await;
@@ -77,17 +152,33 @@ where
 
     pub async fn run(self) -> std::io::Result<()> {
         let Connection {
-            mut reader,
+            reader,
             mut writer_task,
             reader_delegate,
         } = self;
+        // Frames of small messages then usually take a single read each, or
+        // fewer, instead of one for the header and another for the payload.
+        let mut reader = BufReader::with_capacity(reader_delegate.read_buffer_size(), reader);
+        let mut reassembler = Reassembler::default();
+        let header_limits = reader_delegate.header_limits();
+        let buffer_pool = reader_delegate.buffer_pool();
         loop {
             select! {
-                res = GenMessage::read_from(&mut reader) => {
+                res = read_frame(&mut reader, &reader_delegate, &header_limits, buffer_pool.as_ref()) => {
                     match res {
-                        Ok(msg) => {
-                            trace!("Got Message {:?}", msg);
-                            reader_delegate.handle_msg(msg).await;
+                        Ok(Ok(frame)) => {
+                            trace!("Got Message {:?}", frame);
+                            match reassembler.push(frame, reader_delegate.max_chunked_message_size()) {
+                                Ok(Some(msg)) => reader_delegate.handle_msg(msg).await,
+                                Ok(None) => {}
+                                Err((header, status)) => {
+                                    reader_delegate.handle_oversized(header, status).await;
+                                }
+                            }
+                        }
+                        Ok(Err((header, status))) => {
+                            trace!("Skipped oversized message {:?}", header);
+                            reader_delegate.handle_oversized(header, status).await;
                         }
                         Err(e) => {
                             trace!("Read msg err: {:?}", e);
@@ -100,6 +191,11 @@ where
                     trace!("Receive shutdown.");
                     break;
                 }
+                e = reader_delegate.wait_close() => {
+                    trace!("Close connection.");
+                    reader_delegate.disconnect(e, &mut writer_task).await;
+                    break;
+                }
             }
         }
         reader_delegate.exit().await;
@@ -108,3 +204,45 @@ where
         Ok(())
     }
 }
+
+// Reads the next frame, which must arrive in whole within the read timeout
+// once it started. Waiting for it to start isn't limited. The peer closing
+// the connection between frames is told apart when reads are buffered.
+async fn read_frame<R, D>(
+    reader: &mut BufReader<R>,
+    delegate: &D,
+    limits: &HeaderLimits,
+    pool: Option<&Arc<BufferPool>>,
+) -> Result<std::result::Result<GenMessage, (MessageHeader, Status)>>
+where
+    R: AsyncRead + Unpin,
+    D: ReaderDelegate,
+{
+    let max_len = delegate.max_message_size();
+    let timeout = delegate.io_timeouts().read;
+    if timeout.is_some() || delegate.read_buffer_size() > 0 {
+        // An unbuffered reader fills nothing, without waiting.
+        let buf = reader
+            .fill_buf()
+            .await
+            .map_err(|e| Error::Socket(e.to_string()))?;
+        if buf.is_empty() && delegate.read_buffer_size() > 0 {
+            return Err(sock_error_msg(0, String::new()));
+        }
+    }
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return GenMessage::read_from_pooled(reader, max_len, limits, pool).await,
+    };
+    tokio::time::timeout(
+        timeout,
+        GenMessage::read_from_pooled(reader, max_len, limits, pool),
+    )
+    .await
+    .unwrap_or_else(|_| {
+        Err(Error::Socket(format!(
+            "timed out after {:?} reading a message",
+            timeout
+        )))
+    })
+}
@@ -0,0 +1,88 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Events a server publishes about its connections and calls.
+
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+
+use crate::common::PeerCredentials;
+use crate::proto::Code;
+
+const DEFAULT_EVENT_CAPACITY: usize = 1024;
+
+/// Something that happened on a [`Server`](crate::r#async::Server), see
+/// [`Server::events()`](crate::r#async::Server::events).
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerEvent {
+    ConnectionOpened {
+        fd: RawFd,
+        peer_cred: Option<PeerCredentials>,
+    },
+    ConnectionClosed {
+        fd: RawFd,
+    },
+    RequestStarted {
+        fd: RawFd,
+        stream_id: u32,
+        path: String,
+    },
+    RequestFinished {
+        fd: RawFd,
+        stream_id: u32,
+        path: String,
+        code: Code,
+        /// Time since the request was read off the connection.
+        elapsed: Duration,
+    },
+}
+
+/// Publishes server events to whoever subscribed.
+#[derive(Debug, Clone)]
+pub(crate) struct EventSender(broadcast::Sender<ServerEvent>);
+
+impl Default for EventSender {
+    fn default() -> Self {
+        EventSender(broadcast::channel(DEFAULT_EVENT_CAPACITY).0)
+    }
+}
+
+impl EventSender {
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.0.subscribe()
+    }
+
+    /// Returns true if anyone listens, so building an event is worth it.
+    pub(crate) fn is_active(&self) -> bool {
+        self.0.receiver_count() > 0
+    }
+
+    pub(crate) fn send(&self, event: ServerEvent) {
+        // Nobody listening is fine.
+        self.0.send(event).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_event_sender() {
+        let events = EventSender::default();
+        assert!(!events.is_active());
+        events.send(ServerEvent::ConnectionClosed { fd: 1 });
+
+        let mut rx = events.subscribe();
+        assert!(events.is_active());
+        events.send(ServerEvent::ConnectionClosed { fd: 2 });
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            ServerEvent::ConnectionClosed { fd: 2 }
+        );
+    }
+}
@@ -0,0 +1,237 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Per-stream flow control.
+//!
+//! The receiving end of a stream grants its peer room for a number of data
+//! messages with [`WindowUpdate`]s, and grants more as its consumer takes
+//! them. A sender that got a grant waits for room before sending, so a slow
+//! consumer holds it back instead of messages piling up in between.
+//!
+//! Grants are limits on the data messages sent in all, so they can't race
+//! with the data in flight. Data flows freely until the first grant, and
+//! grants are only sent to peers that sent one first, peers that don't know
+//! about them are left alone.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::select;
+use tokio::sync::Notify;
+
+use crate::error::{Error, Result};
+use crate::proto::{Codec, GenMessage, MessageHeader, WindowUpdate};
+use crate::r#async::stream::MessageSender;
+
+/// The send windows of the streams of a connection, by stream id.
+pub(crate) type Windows = Arc<Mutex<HashMap<u32, Arc<SendWindow>>>>;
+
+// Counts wrap around, `a` is ahead of `b` if less than half the range apart.
+fn is_ahead(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// Room granted by the peer to send data on a stream.
+#[derive(Debug, Default)]
+pub(crate) struct SendWindow {
+    state: Mutex<SendState>,
+    notify: Notify,
+}
+
+#[derive(Debug, Default)]
+struct SendState {
+    sent: u32,
+    // None until the peer sent a grant.
+    limit: Option<u32>,
+}
+
+impl SendWindow {
+    /// Waits for room to send a data message and takes it. Fails once the
+    /// connection is closed.
+    pub(crate) async fn acquire(&self, tx: &MessageSender) -> Result<()> {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.limit.is_none_or(|limit| is_ahead(limit, state.sent)) {
+                    state.sent = state.sent.wrapping_add(1);
+                    return Ok(());
+                }
+            }
+            select! {
+                _ = notified => {}
+                _ = tx.closed() => {
+                    return Err(Error::Others(
+                        "connection closed while waiting for the stream window".to_string(),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Takes a grant from the peer.
+    pub(crate) fn update(&self, limit: u32) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.limit.is_none_or(|old| is_ahead(limit, old)) {
+                state.limit = Some(limit);
+            }
+        }
+        self.notify.notify_waiters();
+    }
+
+    /// Returns true once the peer sent a grant, it then takes them too.
+    pub(crate) fn peer_aware(&self) -> bool {
+        self.state.lock().unwrap().limit.is_some()
+    }
+}
+
+/// Room granted to the peer to send data on a stream.
+#[derive(Debug)]
+pub(crate) struct RecvWindow {
+    window: u32,
+    consumed: u32,
+    granted: Option<u32>,
+}
+
+impl RecvWindow {
+    pub(crate) fn new(window: u32) -> Self {
+        RecvWindow {
+            window,
+            consumed: 0,
+            granted: None,
+        }
+    }
+
+    /// Counts a data message taken by the consumer.
+    pub(crate) fn consume(&mut self) {
+        self.consumed = self.consumed.wrapping_add(1);
+    }
+
+    /// Gets the limit to grant the peer if it's time to, that is at first and
+    /// then whenever half of the window is used up.
+    pub(crate) fn grant(&mut self) -> Option<u32> {
+        if let Some(granted) = self.granted {
+            if granted.wrapping_sub(self.consumed) > self.window / 2 {
+                return None;
+            }
+        }
+        let limit = self.consumed.wrapping_add(self.window);
+        self.granted = Some(limit);
+        Some(limit)
+    }
+}
+
+/// Creates the message granting room up to `limit` on a stream.
+pub(crate) fn window_update(stream_id: u32, limit: u32) -> Result<GenMessage> {
+    let update = WindowUpdate {
+        limit,
+        ..Default::default()
+    };
+    let payload = update
+        .encode()
+        .map_err(err_to_others_err!(e, "Encode WindowUpdate failed."))?;
+    Ok(GenMessage {
+        header: MessageHeader::new_window_update(stream_id, payload.len() as u32),
+        payload: payload.into(),
+    })
+}
+
+/// Hands a grant from the peer to the stream it's for. `create` adds the
+/// stream's window if it isn't there yet, a grant may arrive before the
+/// handler of the stream is started.
+pub(crate) fn handle_update(windows: &Windows, msg: &GenMessage, create: bool) {
+    let update = match WindowUpdate::decode(&msg.payload) {
+        Ok(update) => update,
+        Err(e) => {
+            debug!("Got malformed window update {:?}: {}", msg.header, e);
+            return;
+        }
+    };
+    let window = {
+        let mut windows = windows.lock().unwrap();
+        if create {
+            Some(windows.entry(msg.header.stream_id).or_default().clone())
+        } else {
+            windows.get(&msg.header.stream_id).cloned()
+        }
+    };
+    match window {
+        Some(window) => window.update(update.limit),
+        None => debug!("Got window update for unknown stream {:?}", msg.header),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_recv_window() {
+        let mut window = RecvWindow::new(4);
+        assert_eq!(window.grant(), Some(4));
+        window.consume();
+        assert_eq!(window.grant(), None);
+        window.consume();
+        assert_eq!(window.grant(), Some(6));
+
+        let mut window = RecvWindow::new(4);
+        window.consumed = u32::MAX;
+        assert_eq!(window.grant(), Some(3));
+        window.consume();
+        assert_eq!(window.grant(), None);
+        window.consume();
+        assert_eq!(window.grant(), Some(5));
+    }
+
+    #[tokio::test]
+    async fn test_send_window() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let window = Arc::new(SendWindow::default());
+
+        // Free until the first grant.
+        window.acquire(&tx).await.unwrap();
+        assert!(!window.peer_aware());
+        window.update(2);
+        assert!(window.peer_aware());
+        window.acquire(&tx).await.unwrap();
+
+        let waiting = {
+            let window = window.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move { window.acquire(&tx).await })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiting.is_finished());
+        // Grants only grow.
+        window.update(1);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiting.is_finished());
+        window.update(3);
+        waiting.await.unwrap().unwrap();
+
+        let waiting = {
+            let window = window.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move { window.acquire(&tx).await })
+        };
+        rx.close();
+        drop(tx);
+        assert!(waiting.await.unwrap().is_err());
+    }
+
+    #[test]
+    fn test_handle_update() {
+        let windows = Windows::default();
+        let msg = window_update(1, 5).unwrap();
+
+        handle_update(&windows, &msg, false);
+        assert!(windows.lock().unwrap().is_empty());
+        handle_update(&windows, &msg, true);
+        assert!(windows.lock().unwrap()[&1].peer_aware());
+    }
+}
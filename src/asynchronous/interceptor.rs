@@ -0,0 +1,399 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Interceptors, wrapping the calls a server handles or a client makes.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+
+use crate::common::{self, PeerCredentials};
+use crate::error::{get_status, Result};
+use crate::proto::{Code, Request, Response};
+use crate::r#async::TtrpcContext;
+
+/// Wraps every call of a [`Server`](crate::r#async::Server), see
+/// [`Server::add_interceptor()`](crate::r#async::Server::add_interceptor).
+///
+/// An interceptor sees the method, metadata and payload of a call before
+/// the handler does. It may change them, fail the call, or pass it on with
+/// [`Next::run()`] and look at the result. This makes room for
+/// authorization, logging or metrics without touching generated code.
+///
+/// For streams, the payload is the first message of the client, if it came
+/// along with the request, and the result comes once the handler is done.
+#[async_trait]
+pub trait Interceptor: Send + Sync {
+    async fn intercept(
+        &self,
+        ctx: TtrpcContext,
+        req: Request,
+        next: Next<'_>,
+    ) -> Result<Option<Response>>;
+}
+
+type Handler<'a> =
+    Box<dyn FnOnce(TtrpcContext, Request) -> BoxFuture<'a, Result<Option<Response>>> + Send + 'a>;
+
+/// The rest of the interceptors of a call, then its handler.
+pub struct Next<'a> {
+    interceptors: &'a [Arc<dyn Interceptor>],
+    handler: Handler<'a>,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        interceptors: &'a [Arc<dyn Interceptor>],
+        handler: impl FnOnce(TtrpcContext, Request) -> BoxFuture<'a, Result<Option<Response>>>
+            + Send
+            + 'a,
+    ) -> Self {
+        Self {
+            interceptors,
+            handler: Box::new(handler),
+        }
+    }
+
+    /// Passes the call on to the next interceptor, or to the handler.
+    pub async fn run(self, ctx: TtrpcContext, req: Request) -> Result<Option<Response>> {
+        match self.interceptors.split_first() {
+            Some((interceptor, interceptors)) => {
+                let next = Next {
+                    interceptors,
+                    handler: self.handler,
+                };
+                interceptor.intercept(ctx, req, next).await
+            }
+            None => (self.handler)(ctx, req).await,
+        }
+    }
+}
+
+/// Wraps every unary call made with a [`Client`](crate::r#async::Client),
+/// see [`Client::add_interceptor()`](crate::r#async::Client::add_interceptor).
+///
+/// An interceptor sees the method, metadata and payload of a call before it
+/// is sent. It may change them, to add an auth token for instance, fail the
+/// call, or pass it on with [`ClientNext::run()`] and look at the response
+/// or error. Retries happen within the chain, streams and one-way requests
+/// don't go through it.
+#[async_trait]
+pub trait ClientInterceptor: Send + Sync {
+    async fn intercept(&self, req: Request, next: ClientNext<'_>) -> Result<Response>;
+}
+
+type Call<'a> = Box<dyn FnOnce(Request) -> BoxFuture<'a, Result<Response>> + Send + 'a>;
+
+/// The rest of the interceptors of a call, then the call itself.
+pub struct ClientNext<'a> {
+    interceptors: &'a [Arc<dyn ClientInterceptor>],
+    call: Call<'a>,
+}
+
+impl<'a> ClientNext<'a> {
+    pub(crate) fn new(
+        interceptors: &'a [Arc<dyn ClientInterceptor>],
+        call: impl FnOnce(Request) -> BoxFuture<'a, Result<Response>> + Send + 'a,
+    ) -> Self {
+        Self {
+            interceptors,
+            call: Box::new(call),
+        }
+    }
+
+    /// Passes the call on to the next interceptor, or makes it.
+    pub async fn run(self, req: Request) -> Result<Response> {
+        match self.interceptors.split_first() {
+            Some((interceptor, interceptors)) => {
+                let next = ClientNext {
+                    interceptors,
+                    call: self.call,
+                };
+                interceptor.intercept(req, next).await
+            }
+            None => (self.call)(req).await,
+        }
+    }
+}
+
+// Caps the calls of a service or method running at once, see
+// Server::set_concurrency_limit().
+pub(crate) struct ConcurrencyLimit {
+    name: String,
+    max: usize,
+    queue_timeout: Duration,
+    permits: Semaphore,
+}
+
+impl ConcurrencyLimit {
+    pub(crate) fn new(name: &str, max: usize, queue_timeout: Duration) -> Self {
+        Self {
+            name: name.to_string(),
+            max,
+            queue_timeout,
+            permits: Semaphore::new(max),
+        }
+    }
+}
+
+#[async_trait]
+impl Interceptor for ConcurrencyLimit {
+    async fn intercept(
+        &self,
+        ctx: TtrpcContext,
+        req: Request,
+        next: Next<'_>,
+    ) -> Result<Option<Response>> {
+        if !common::covers_call(&self.name, &req) {
+            return next.run(ctx, req).await;
+        }
+        // Held until the call is done, even if its handler panics.
+        let _permit = match timeout(self.queue_timeout, self.permits.acquire()).await {
+            Ok(Ok(permit)) => permit,
+            _ => {
+                let mut res = Response::new();
+                res.set_status(get_status(
+                    Code::RESOURCE_EXHAUSTED,
+                    format!(
+                        "too many calls to {} at once, the limit is {}",
+                        self.name, self.max
+                    ),
+                ));
+                return Ok(Some(res));
+            }
+        };
+        next.run(ctx, req).await
+    }
+}
+
+// The authorizer of a server, see Server::set_authorizer().
+pub(crate) struct Authorize<F>(pub(crate) F);
+
+#[async_trait]
+impl<F> Interceptor for Authorize<F>
+where
+    F: Fn(Option<PeerCredentials>, &str) -> bool + Send + Sync,
+{
+    async fn intercept(
+        &self,
+        ctx: TtrpcContext,
+        req: Request,
+        next: Next<'_>,
+    ) -> Result<Option<Response>> {
+        let path = format!("{}/{}", req.service, req.method);
+        if !(self.0)(ctx.peer_cred, &path) {
+            debug!("{} denied to peer {:?}", path, ctx.peer_cred);
+            let mut res = Response::new();
+            res.set_status(get_status(
+                Code::PERMISSION_DENIED,
+                format!("{} is not allowed", path),
+            ));
+            return Ok(Some(res));
+        }
+        next.run(ctx, req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::SystemTime;
+
+    use futures::FutureExt as _;
+
+    use super::*;
+    use crate::common::ConnectionInfo;
+    use crate::error::get_rpc_status;
+    use crate::proto::{Code, MessageHeader};
+
+    struct Record {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Interceptor for Record {
+        async fn intercept(
+            &self,
+            ctx: TtrpcContext,
+            mut req: Request,
+            next: Next<'_>,
+        ) -> Result<Option<Response>> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", self.name, req.method));
+            if req.method == "Denied" {
+                return Err(get_rpc_status(Code::PERMISSION_DENIED, self.name));
+            }
+            req.payload.push(self.name.as_bytes()[0]);
+            next.run(ctx, req).await
+        }
+    }
+
+    fn context() -> TtrpcContext {
+        TtrpcContext {
+            fd: -1,
+            mh: MessageHeader::new_request(1, 0),
+            metadata: Default::default(),
+            timeout_nano: 0,
+            peer_cred: None,
+            connection: Arc::new(ConnectionInfo::new(-1)),
+            received: SystemTime::now(),
+            response_metadata: Default::default(),
+            response_status: Default::default(),
+            deadline: None,
+            cancel: Default::default(),
+            peer: None,
+        }
+    }
+
+    async fn call(interceptors: &[Arc<dyn Interceptor>], method: &str) -> Result<Vec<u8>> {
+        let req = Request {
+            method: method.to_string(),
+            ..Default::default()
+        };
+        let handler = |_ctx, req: Request| {
+            async move {
+                let mut res = Response::new();
+                res.payload = req.payload;
+                Ok(Some(res))
+            }
+            .boxed()
+        };
+        let res = Next::new(interceptors, handler).run(context(), req).await?;
+        Ok(res.unwrap().payload)
+    }
+
+    #[tokio::test]
+    async fn test_chain() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let interceptors: Vec<Arc<dyn Interceptor>> = vec![
+            Arc::new(Record {
+                name: "a",
+                calls: calls.clone(),
+            }),
+            Arc::new(Record {
+                name: "b",
+                calls: calls.clone(),
+            }),
+        ];
+
+        assert_eq!(call(&[], "Allowed").await.unwrap(), b"");
+        assert_eq!(call(&interceptors, "Allowed").await.unwrap(), b"ab");
+        match call(&interceptors, "Denied").await {
+            Err(crate::Error::RpcStatus(s)) => {
+                assert_eq!(s.code(), Code::PERMISSION_DENIED);
+                assert_eq!(s.message, "a");
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["a Allowed", "b Allowed", "a Denied"]
+        );
+    }
+
+    struct Stamp(&'static str);
+
+    #[async_trait]
+    impl ClientInterceptor for Stamp {
+        async fn intercept(&self, mut req: Request, next: ClientNext<'_>) -> Result<Response> {
+            req.payload.push(self.0.as_bytes()[0]);
+            let mut res = next.run(req).await?;
+            res.payload.push(self.0.as_bytes()[0]);
+            Ok(res)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_chain() {
+        let interceptors: Vec<Arc<dyn ClientInterceptor>> =
+            vec![Arc::new(Stamp("a")), Arc::new(Stamp("b"))];
+        let call = |req: Request| {
+            async move {
+                let mut res = Response::new();
+                res.payload = req.payload;
+                res.payload.push(b'-');
+                Ok(res)
+            }
+            .boxed()
+        };
+        let res = ClientNext::new(&interceptors, call)
+            .run(Request::default())
+            .await
+            .unwrap();
+        assert_eq!(res.payload, b"ab-ba");
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit() {
+        tokio::time::pause();
+
+        let limit: Arc<dyn Interceptor> = Arc::new(ConcurrencyLimit::new(
+            "svc/Slow",
+            1,
+            Duration::from_millis(10),
+        ));
+        let release = Arc::new(tokio::sync::Notify::new());
+        let run = |method: &str| {
+            let req = Request {
+                service: "svc".to_string(),
+                method: method.to_string(),
+                ..Default::default()
+            };
+            let interceptors = [limit.clone()];
+            let release = release.clone();
+            async move {
+                let handler = |_ctx, req: Request| {
+                    async move {
+                        if req.method == "Slow" {
+                            release.notified().await;
+                        }
+                        Ok(Some(Response::new()))
+                    }
+                    .boxed()
+                };
+                let res = Next::new(&interceptors, handler).run(context(), req).await;
+                res.unwrap().unwrap().status().code()
+            }
+        };
+
+        let slow = tokio::spawn(run("Slow"));
+        tokio::task::yield_now().await;
+        // Over the limit once the queue timeout passes, other methods go on.
+        assert_eq!(run("Slow").await, Code::RESOURCE_EXHAUSTED);
+        assert_eq!(run("Fast").await, Code::OK);
+
+        release.notify_one();
+        assert_eq!(slow.await.unwrap(), Code::OK);
+    }
+
+    #[tokio::test]
+    async fn test_authorize() {
+        let authorize: Arc<dyn Interceptor> =
+            Arc::new(Authorize(|cred: Option<PeerCredentials>, path: &str| {
+                cred.is_some() || path == "/Public"
+            }));
+        let interceptors = [authorize];
+
+        assert_eq!(call(&interceptors, "Public").await.unwrap(), b"");
+        let req = Request {
+            method: "Private".to_string(),
+            ..Default::default()
+        };
+        let handler = |_ctx, _req| async { Ok(Some(Response::new())) }.boxed();
+        let res = Next::new(&interceptors, handler)
+            .run(context(), req)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.status().code(), Code::PERMISSION_DENIED);
+    }
+}
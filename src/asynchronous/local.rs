@@ -0,0 +1,214 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Services whose handlers aren't `Send`.
+//!
+//! The methods of a [`LocalService`] run on a thread of their own per
+//! connection, on a current-thread runtime and a [`LocalSet`], so they may
+//! hold state that can't move between threads. The service is made anew
+//! on that thread for every connection calling it, see
+//! [`Server::register_local_service()`](crate::r#async::Server::register_local_service).
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::LocalSet;
+
+use crate::error::{get_rpc_status, Error, Result};
+use crate::proto::{Code, Request, Response};
+use crate::r#async::{MethodHandler, TtrpcContext};
+
+/// Handler of a method of a [`LocalService`], it need not be `Send`.
+#[async_trait(?Send)]
+pub trait LocalMethodHandler {
+    async fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<Response>;
+}
+
+/// The methods of a service whose handlers aren't `Send`.
+#[derive(Default)]
+pub struct LocalService {
+    methods: HashMap<String, Box<dyn LocalMethodHandler>>,
+}
+
+impl LocalService {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the handler of `method`.
+    pub fn add_method(mut self, method: &str, handler: impl LocalMethodHandler + 'static) -> Self {
+        self.methods.insert(method.to_string(), Box::new(handler));
+        self
+    }
+}
+
+type Factory = Arc<dyn Fn() -> LocalService + Send + Sync>;
+
+/// The local services of a server by name, and how to make each of them.
+pub(crate) type LocalServices = Arc<HashMap<String, Factory>>;
+
+type LocalCall = (TtrpcContext, Request, oneshot::Sender<Result<Response>>);
+
+/// Runs the local services of a connection, on a thread started by the
+/// first call to one of them, which ends with the connection.
+pub(crate) struct LocalExecutor {
+    services: LocalServices,
+    tx: Mutex<Option<mpsc::UnboundedSender<LocalCall>>>,
+}
+
+impl LocalExecutor {
+    pub(crate) fn new(services: LocalServices) -> Self {
+        Self {
+            services,
+            tx: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn has_service(&self, name: &str) -> bool {
+        self.services.contains_key(name)
+    }
+
+    fn sender(&self) -> Result<mpsc::UnboundedSender<LocalCall>> {
+        let mut tx = self.tx.lock().unwrap();
+        if let Some(tx) = tx.as_ref() {
+            return Ok(tx.clone());
+        }
+
+        let (sender, rx) = mpsc::unbounded_channel();
+        let services = self.services.clone();
+        std::thread::Builder::new()
+            .name("ttrpc-local".to_string())
+            .spawn(move || run(services, rx))
+            .map_err(err_to_others_err!(
+                e,
+                "failed to start local handler thread: "
+            ))?;
+        *tx = Some(sender.clone());
+        Ok(sender)
+    }
+}
+
+#[async_trait]
+impl MethodHandler for LocalExecutor {
+    async fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<Response> {
+        let (tx, rx) = oneshot::channel();
+        self.sender()?
+            .send((ctx, req, tx))
+            .map_err(|_| Error::Others("local handler thread is gone".to_string()))?;
+        rx.await
+            .map_err(|_| Error::Others("local handler dropped the call".to_string()))?
+    }
+}
+
+fn run(services: LocalServices, mut rx: mpsc::UnboundedReceiver<LocalCall>) {
+    let rt = match tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+    {
+        Ok(rt) => rt,
+        Err(e) => {
+            error!("failed to build local handler runtime: {}", e);
+            return;
+        }
+    };
+    let services: HashMap<String, Rc<LocalService>> = services
+        .iter()
+        .map(|(name, factory)| (name.clone(), Rc::new(factory())))
+        .collect();
+
+    LocalSet::new().block_on(&rt, async move {
+        while let Some((ctx, req, tx)) = rx.recv().await {
+            let service = services.get(&req.service).cloned();
+            tokio::task::spawn_local(async move {
+                let res = match service.as_ref().and_then(|s| s.methods.get(&req.method)) {
+                    Some(method) => method.handler(ctx, req).await,
+                    None => Err(get_rpc_status(
+                        Code::UNIMPLEMENTED,
+                        format!("{} method", &req.method),
+                    )),
+                };
+                tx.send(res).ok();
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::time::SystemTime;
+
+    use crate::common::ConnectionInfo;
+    use crate::proto::MessageHeader;
+
+    // Counts its calls in a Cell, which isn't Sync.
+    struct Counter(Cell<u32>);
+
+    #[async_trait(?Send)]
+    impl LocalMethodHandler for Counter {
+        async fn handler(&self, _ctx: TtrpcContext, _req: Request) -> Result<Response> {
+            self.0.set(self.0.get() + 1);
+            tokio::task::yield_now().await;
+            Ok(Response {
+                payload: self.0.get().to_be_bytes().to_vec(),
+                ..Default::default()
+            })
+        }
+    }
+
+    fn context() -> TtrpcContext {
+        TtrpcContext {
+            fd: -1,
+            mh: MessageHeader::new_request(1, 0),
+            metadata: Default::default(),
+            timeout_nano: 0,
+            peer_cred: None,
+            connection: Arc::new(ConnectionInfo::new(-1)),
+            received: SystemTime::now(),
+            response_metadata: Default::default(),
+            response_status: Default::default(),
+            deadline: None,
+            cancel: Default::default(),
+            peer: None,
+        }
+    }
+
+    fn request(method: &str) -> Request {
+        Request {
+            service: "local".to_string(),
+            method: method.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_local_executor() {
+        let mut services: HashMap<String, Factory> = HashMap::new();
+        services.insert(
+            "local".to_string(),
+            Arc::new(|| LocalService::new().add_method("Count", Counter(Cell::new(0)))),
+        );
+        let services = Arc::new(services);
+
+        let executor = LocalExecutor::new(services.clone());
+        assert!(executor.has_service("local"));
+        assert!(!executor.has_service("remote"));
+        for n in 1..=2u32 {
+            let res = executor.handler(context(), request("Count")).await.unwrap();
+            assert_eq!(res.payload, n.to_be_bytes());
+        }
+        let res = executor.handler(context(), request("Missing")).await;
+        assert!(matches!(res, Err(Error::RpcStatus(s)) if s.code() == Code::UNIMPLEMENTED));
+
+        // Another connection gets a service of its own.
+        let executor = LocalExecutor::new(services);
+        let res = executor.handler(context(), request("Count")).await.unwrap();
+        assert_eq!(res.payload, 1u32.to_be_bytes());
+    }
+}
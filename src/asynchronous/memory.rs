@@ -0,0 +1,185 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Accounting of the memory held by buffered messages.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+
+/// A limit on the bytes held by messages buffered across a whole server.
+///
+/// Going over the limit is allowed, so that a single message larger than the
+/// limit can still be served; readers are expected to stop reading new messages
+/// with [`MemoryBudget::wait_available()`] until enough memory is released.
+#[derive(Debug)]
+pub(crate) struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+    released: Notify,
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+            released: Notify::new(),
+        }
+    }
+
+    /// Return the number of bytes currently charged.
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Return true if the charged bytes have reached the limit.
+    pub(crate) fn is_exhausted(&self) -> bool {
+        self.used() >= self.limit
+    }
+
+    /// Charge `size` bytes, which must be given back with [`MemoryBudget::release()`].
+    pub(crate) fn add(&self, size: usize) {
+        self.used.fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// Give back `size` bytes previously charged with [`MemoryBudget::add()`].
+    pub(crate) fn release(&self, size: usize) {
+        self.used.fetch_sub(size, Ordering::Relaxed);
+        self.released.notify_waiters();
+    }
+
+    /// Charge `size` bytes, which are released when the returned guard is dropped.
+    pub(crate) fn charge(self: &Arc<Self>, size: usize) -> MemoryCharge {
+        self.add(size);
+        MemoryCharge {
+            budget: self.clone(),
+            size,
+        }
+    }
+
+    /// Charge `size` bytes like [`MemoryBudget::charge()`], unless that goes
+    /// over the limit.
+    pub(crate) fn try_charge(self: &Arc<Self>, size: usize) -> Option<MemoryCharge> {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(size).filter(|&used| used <= self.limit)
+            })
+            .ok()?;
+        Some(MemoryCharge {
+            budget: self.clone(),
+            size,
+        })
+    }
+
+    /// Wait until the charged bytes drop below the limit.
+    pub(crate) async fn wait_available(&self) {
+        while self.is_exhausted() {
+            let released = self.released.notified();
+            if !self.is_exhausted() {
+                return;
+            }
+            released.await;
+        }
+    }
+}
+
+/// Bytes charged to a [`MemoryBudget`], released on drop.
+#[derive(Debug)]
+pub(crate) struct MemoryCharge {
+    budget: Arc<MemoryBudget>,
+    size: usize,
+}
+
+impl Drop for MemoryCharge {
+    fn drop(&mut self) {
+        self.budget.release(self.size);
+    }
+}
+
+/// Limits on the bytes of the requests a connection has in flight, of the
+/// connection and across the server. Unlike [`MemoryBudget`], requests over
+/// a limit are refused rather than waited for.
+#[derive(Debug)]
+pub(crate) struct RequestBudget {
+    server: Arc<MemoryBudget>,
+    connection: Arc<MemoryBudget>,
+}
+
+impl RequestBudget {
+    pub(crate) fn new(server: Arc<MemoryBudget>, connection_limit: usize) -> Self {
+        Self {
+            server,
+            connection: Arc::new(MemoryBudget::new(connection_limit)),
+        }
+    }
+
+    /// Charge a request of `size` bytes until its handler is done with it,
+    /// unless that goes over either limit.
+    pub(crate) fn try_charge(&self, size: usize) -> Option<(MemoryCharge, MemoryCharge)> {
+        let connection = self.connection.try_charge(size)?;
+        let server = self.server.try_charge(size)?;
+        Some((connection, server))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::time::Duration;
+
+    #[tokio::test]
+    async fn charge_and_release() {
+        let budget = Arc::new(MemoryBudget::new(100));
+        let charge = budget.charge(60);
+        budget.add(30);
+        assert_eq!(budget.used(), 90);
+        budget.wait_available().await;
+
+        budget.add(20);
+        assert!(budget.is_exhausted());
+        drop(charge);
+        assert_eq!(budget.used(), 50);
+        budget.release(50);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[tokio::test]
+    async fn wait_available() {
+        let budget = Arc::new(MemoryBudget::new(10));
+        let charge = budget.charge(20);
+
+        let waiter = budget.clone();
+        let mut task = tokio::spawn(async move {
+            waiter.wait_available().await;
+        });
+
+        // Still over the limit.
+        let res = tokio::time::timeout(Duration::from_millis(50), &mut task).await;
+        assert!(res.is_err());
+        drop(charge);
+        task.await.unwrap();
+    }
+
+    #[test]
+    fn request_budget() {
+        let server = Arc::new(MemoryBudget::new(100));
+        let first = RequestBudget::new(server.clone(), 60);
+        let second = RequestBudget::new(server.clone(), 60);
+
+        let charge = first.try_charge(50).unwrap();
+        // Over the limit of the connection.
+        assert!(first.try_charge(20).is_none());
+        // Over the limit of the server, the connection isn't charged.
+        let _other = second.try_charge(40).unwrap();
+        assert!(second.try_charge(20).is_none());
+        assert_eq!(second.connection.used(), 40);
+
+        drop(charge);
+        assert_eq!(server.used(), 40);
+        assert!(first.try_charge(60).is_some());
+    }
+}
@@ -11,7 +11,17 @@ mod stream;
 #[macro_use]
 #[doc(hidden)]
 mod utils;
+pub(crate) mod buffer_pool;
+mod chunking;
 mod connection;
+mod events;
+mod flow_control;
+mod interceptor;
+pub mod local;
+mod memory;
+pub mod paging;
+pub mod peer;
+mod raw;
 pub mod shutdown;
 mod unix_incoming;
 
@@ -20,8 +30,16 @@ pub use self::stream::{
     ServerStreamReceiver, ServerStreamSender, StreamInner,
 };
 #[doc(inline)]
-pub use crate::r#async::client::Client;
+pub use crate::r#async::buffer_pool::{BufferPool, BufferPoolStats};
+#[doc(inline)]
+pub use crate::r#async::client::{CallOptions, Client, ClientPool};
+#[doc(inline)]
+pub use crate::r#async::events::ServerEvent;
+#[doc(inline)]
+pub use crate::r#async::interceptor::{ClientInterceptor, ClientNext, Interceptor, Next};
+#[doc(inline)]
+pub use crate::r#async::raw::RawService;
 #[doc(inline)]
 pub use crate::r#async::server::{Server, Service};
 #[doc(inline)]
-pub use utils::{MethodHandler, StreamHandler, TtrpcContext};
+pub use utils::{CancellationToken, MethodHandler, StreamHandler, TtrpcContext};
@@ -0,0 +1,225 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Sending large collections as pages on a server stream.
+//!
+//! A handler listing many items, thousands of containers say, can't return
+//! them in one response without hitting the message size limit. The helpers
+//! here group items into pages of bounded size, wrap each page in a response
+//! and send it. Every send waits for room on the connection, so a slow client
+//! holds back the producer instead of the pages piling up in memory.
+//!
+//! ```ignore
+//! paging::send_iter(&stream, containers, Pager::default(), |containers| {
+//!     ListContainersResponse {
+//!         containers,
+//!         ..Default::default()
+//!     }
+//! })
+//! .await?;
+//! ```
+
+use futures::{Stream, StreamExt};
+
+use crate::error::{Error, Result};
+use crate::proto::{Codec, MESSAGE_LENGTH_MAX};
+use crate::r#async::ServerStreamSender;
+
+/// Default payload size of a page, leaving room for the fields of the
+/// response around the items.
+pub const DEFAULT_PAGE_BYTES: usize = MESSAGE_LENGTH_MAX / 4;
+
+/// Groups items into pages.
+///
+/// A page is full once its items take up `max_bytes`, counted as they are
+/// encoded in a repeated field, or once it holds `max_items`. An item larger
+/// than `max_bytes` is put on a page of its own.
+#[derive(Debug)]
+pub struct Pager<T> {
+    max_bytes: usize,
+    max_items: usize,
+    items: Vec<T>,
+    bytes: usize,
+}
+
+impl<T: Codec> Default for Pager<T> {
+    fn default() -> Self {
+        Pager::new(DEFAULT_PAGE_BYTES)
+    }
+}
+
+impl<T: Codec> Pager<T> {
+    /// Pages of at most `max_bytes` of items.
+    pub fn new(max_bytes: usize) -> Self {
+        Pager {
+            max_bytes,
+            max_items: usize::MAX,
+            items: Vec::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Limits the number of items of a page, 0 means no limit.
+    pub fn set_max_items(mut self, max_items: usize) -> Self {
+        self.max_items = if max_items == 0 {
+            usize::MAX
+        } else {
+            max_items
+        };
+        self
+    }
+
+    /// Adds an item, returns the previous page if the item didn't fit on it.
+    pub fn push(&mut self, item: T) -> Option<Vec<T>> {
+        let size = encoded_len(item.size() as usize);
+        let full = !self.items.is_empty()
+            && (self.bytes + size > self.max_bytes || self.items.len() >= self.max_items);
+        let page = if full { self.take() } else { None };
+
+        self.items.push(item);
+        self.bytes += size;
+        page
+    }
+
+    /// Takes the items added since the last page, if any.
+    pub fn take(&mut self) -> Option<Vec<T>> {
+        if self.items.is_empty() {
+            return None;
+        }
+        self.bytes = 0;
+        Some(std::mem::take(&mut self.items))
+    }
+}
+
+/// Size of an embedded message of `len` bytes, with its tag and length.
+fn encoded_len(len: usize) -> usize {
+    let mut varint = 1;
+    let mut rest = len >> 7;
+    while rest != 0 {
+        varint += 1;
+        rest >>= 7;
+    }
+    // Field numbers up to 15 take one byte of tag.
+    1 + varint + len
+}
+
+/// Sends `items` on `stream` in pages, `build` turns a page into a response.
+///
+/// Stops at the first failed send, for instance when the client went away.
+pub async fn send_iter<P, T, I, F>(
+    stream: &ServerStreamSender<P>,
+    items: I,
+    mut pager: Pager<T>,
+    mut build: F,
+) -> Result<()>
+where
+    P: Codec,
+    <P as Codec>::E: std::fmt::Display,
+    T: Codec,
+    I: IntoIterator<Item = T>,
+    F: FnMut(Vec<T>) -> P,
+{
+    for item in items {
+        if let Some(page) = pager.push(item) {
+            send_page(stream, build(page)).await?;
+        }
+    }
+    if let Some(page) = pager.take() {
+        send_page(stream, build(page)).await?;
+    }
+    Ok(())
+}
+
+/// Like [`send_iter()`], taking the items from a stream.
+///
+/// An error from `items` is returned once the items before it were sent.
+pub async fn send_stream<P, T, S, F>(
+    stream: &ServerStreamSender<P>,
+    items: S,
+    mut pager: Pager<T>,
+    mut build: F,
+) -> Result<()>
+where
+    P: Codec,
+    <P as Codec>::E: std::fmt::Display,
+    T: Codec,
+    S: Stream<Item = Result<T>>,
+    F: FnMut(Vec<T>) -> P,
+{
+    futures::pin_mut!(items);
+    while let Some(item) = items.next().await {
+        let item = match item {
+            Ok(item) => item,
+            Err(e) => {
+                if let Some(page) = pager.take() {
+                    send_page(stream, build(page)).await?;
+                }
+                return Err(e);
+            }
+        };
+        if let Some(page) = pager.push(item) {
+            send_page(stream, build(page)).await?;
+        }
+    }
+    if let Some(page) = pager.take() {
+        send_page(stream, build(page)).await?;
+    }
+    Ok(())
+}
+
+async fn send_page<P>(stream: &ServerStreamSender<P>, resp: P) -> Result<()>
+where
+    P: Codec,
+    <P as Codec>::E: std::fmt::Display,
+{
+    let size = resp.size() as usize;
+    if size > MESSAGE_LENGTH_MAX {
+        return Err(Error::Others(format!(
+            "page of {} bytes exceeds maximum message size of {}",
+            size, MESSAGE_LENGTH_MAX
+        )));
+    }
+    stream.send(&resp).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::Status;
+
+    fn status(len: usize) -> Status {
+        let mut s = Status::new();
+        s.set_message("x".repeat(len));
+        s
+    }
+
+    #[test]
+    fn test_encoded_len() {
+        assert_eq!(encoded_len(0), 2);
+        assert_eq!(encoded_len(127), 129);
+        assert_eq!(encoded_len(128), 131);
+        assert_eq!(encoded_len(status(10).size() as usize), 14);
+    }
+
+    #[test]
+    fn test_pager() {
+        // Each item takes 14 bytes.
+        let mut pager = Pager::new(30);
+        assert!(pager.push(status(10)).is_none());
+        assert!(pager.push(status(10)).is_none());
+        assert_eq!(pager.push(status(10)).unwrap().len(), 2);
+        // Too large for a page, it gets one of its own.
+        assert_eq!(pager.push(status(100)).unwrap().len(), 1);
+        assert_eq!(pager.push(status(10)).unwrap().len(), 1);
+        assert_eq!(pager.take().unwrap().len(), 1);
+        assert!(pager.take().is_none());
+
+        let mut pager = Pager::new(1000).set_max_items(2);
+        let pages: Vec<_> = (0..5).filter_map(|_| pager.push(status(10))).collect();
+        assert_eq!(pages.len(), 2);
+        assert!(pages.iter().all(|p| p.len() == 2));
+        assert_eq!(pager.take().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Calls both ways over one connection.
+//!
+//! A server handler calls the client back with the [`Peer`] in its
+//! context, and a client serves those calls with the services it registers
+//! with [`Client::register_service()`](crate::r#async::Client::register_service),
+//! so a shim can deliver events to containerd over the connection it's
+//! called on rather than a second socket. Clients make calls on odd stream
+//! ids and servers on even ones, so they can't clash. Calls back are unary,
+//! streams only go from client to server.
+
+use std::collections::HashMap;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::oneshot;
+
+use crate::common::{self, ConnectionInfo};
+use crate::context;
+use crate::error::{get_rpc_status, get_status, Error, Result};
+use crate::proto::{Code, Codec, GenMessage, MessageHeader, Request, Response};
+use crate::r#async::stream::MessageSender;
+use crate::r#async::utils;
+use crate::r#async::{CancellationToken, Service, TtrpcContext};
+
+type Calls = Arc<Mutex<HashMap<u32, oneshot::Sender<GenMessage>>>>;
+
+/// Calls the client on the other end of the connection a request came in
+/// on, see the [module docs](self).
+#[derive(Clone, Debug)]
+pub struct Peer {
+    tx: MessageSender,
+    next_stream_id: Arc<AtomicU32>,
+    calls: Calls,
+}
+
+// Forgets a call once it's answered, or given up on.
+struct Pending<'a> {
+    calls: &'a Calls,
+    stream_id: u32,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        self.calls.lock().unwrap().remove(&self.stream_id);
+    }
+}
+
+impl Peer {
+    pub(crate) fn new(tx: MessageSender) -> Self {
+        Self {
+            tx,
+            next_stream_id: Arc::new(AtomicU32::new(2)),
+            calls: Calls::default(),
+        }
+    }
+
+    /// Calls the client, which fails the call with UNIMPLEMENTED if it
+    /// serves no such method. The call fails once it's over the timeout of
+    /// `req`, or once the connection closes.
+    pub async fn request(&self, req: Request) -> Result<Response> {
+        let timeout_nano = req.timeout_nano;
+        let stream_id = self.next_stream_id.fetch_add(2, Ordering::Relaxed);
+        let payload = req
+            .encode()
+            .map_err(err_to_others_err!(e, "Encode Request failed."))?;
+        let msg = GenMessage {
+            header: MessageHeader::new_request(stream_id, payload.len() as u32),
+            payload: payload.into(),
+        };
+
+        let (tx, rx) = oneshot::channel();
+        self.calls.lock().unwrap().insert(stream_id, tx);
+        let _pending = Pending {
+            calls: &self.calls,
+            stream_id,
+        };
+        self.tx
+            .send(msg)
+            .await
+            .map_err(|e| Error::Others(format!("Send packet to sender error {:?}", e)))?;
+
+        let msg = if timeout_nano > 0 {
+            tokio::time::timeout(Duration::from_nanos(timeout_nano as u64), rx)
+                .await
+                .map_err(|_| get_rpc_status(Code::DEADLINE_EXCEEDED, "the peer didn't answer"))?
+        } else {
+            rx.await
+        };
+        let msg = msg.map_err(|_| Error::Others("the connection closed".to_string()))?;
+        let res = Response::decode(msg.payload)
+            .map_err(err_to_others_err!(e, "Unpack response error "))?;
+
+        let status = res.status();
+        if status.code() != Code::OK {
+            return Err(Error::RpcStatus((*status).clone()));
+        }
+        Ok(res)
+    }
+
+    /// Hands the response `msg` to the call waiting for it.
+    pub(crate) fn answer(&self, msg: GenMessage) {
+        let tx = self.calls.lock().unwrap().remove(&msg.header.stream_id);
+        match tx {
+            Some(tx) => tx.send(msg).unwrap_or(()),
+            None => debug!("Receiver got unknown response {:?}", msg.header),
+        }
+    }
+
+    /// Fails the calls still waiting, the connection being closed.
+    pub(crate) fn close(&self) {
+        self.calls.lock().unwrap().clear();
+    }
+}
+
+/// The services a client serves to the server it's connected to, and how
+/// to answer it.
+pub(crate) struct Callbacks {
+    pub(crate) services: HashMap<String, Service>,
+    pub(crate) tx: MessageSender,
+}
+
+impl Callbacks {
+    /// Handles the request `msg` of the server, and answers it.
+    pub(crate) async fn handle(&self, fd: RawFd, info: Arc<ConnectionInfo>, msg: GenMessage) {
+        let stream_id = msg.header.stream_id;
+        let res = match Request::decode(&msg.payload) {
+            Ok(req) => self.call(fd, info, msg.header, req).await,
+            Err(e) => status_response(get_status(
+                Code::INVALID_ARGUMENT,
+                format!("failed to decode request: {}", e),
+            )),
+        };
+        let payload = match res.encode() {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Encode Response failed: {:?}", e);
+                return;
+            }
+        };
+        let msg = GenMessage {
+            header: MessageHeader::new_response(stream_id, payload.len() as u32),
+            payload: payload.into(),
+        };
+        self.tx
+            .send(msg)
+            .await
+            .unwrap_or_else(|e| debug!("failed to answer the server: {}", e));
+    }
+
+    async fn call(
+        &self,
+        fd: RawFd,
+        info: Arc<ConnectionInfo>,
+        mh: MessageHeader,
+        req: Request,
+    ) -> Response {
+        let method = self
+            .services
+            .get(&req.service)
+            .and_then(|service| service.get_method(&req.method));
+        let method = match method {
+            Some(method) => method,
+            None => {
+                let path = utils::get_path(&req.service, &req.method);
+                return status_response(get_status(Code::UNIMPLEMENTED, path));
+            }
+        };
+
+        let deadline = common::get_deadline(utils::now(), req.timeout_nano);
+        let ctx = TtrpcContext {
+            fd,
+            mh,
+            metadata: context::from_pb(&req.metadata),
+            timeout_nano: req.timeout_nano,
+            peer_cred: info.peer_cred,
+            connection: info,
+            received: SystemTime::now(),
+            response_metadata: Default::default(),
+            response_status: Default::default(),
+            deadline,
+            cancel: CancellationToken::default().with_deadline(deadline),
+            peer: None,
+        };
+        match method.handler(ctx, req).await {
+            Ok(res) => res,
+            Err(Error::RpcStatus(status)) => status_response(status),
+            Err(e) => status_response(get_status(Code::UNKNOWN, format!("{:?}", e))),
+        }
+    }
+}
+
+fn status_response(status: crate::proto::Status) -> Response {
+    let mut res = Response::new();
+    res.set_status(status);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_peer_request() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let peer = Peer::new(tx);
+
+        let call = tokio::spawn({
+            let peer = peer.clone();
+            async move { peer.request(Request::new()).await }
+        });
+        let msg = rx.recv().await.unwrap();
+        assert_eq!(msg.header.stream_id, 2, "servers call on even ids");
+
+        let payload = status_response(get_status(Code::OK, "")).encode().unwrap();
+        peer.answer(GenMessage {
+            header: MessageHeader::new_response(2, payload.len() as u32),
+            payload: payload.into(),
+        });
+        assert!(call.await.unwrap().is_ok());
+        assert!(peer.calls.lock().unwrap().is_empty());
+
+        // Calls still waiting fail with the connection.
+        let call = tokio::spawn({
+            let peer = peer.clone();
+            async move { peer.request(Request::new()).await }
+        });
+        assert_eq!(rx.recv().await.unwrap().header.stream_id, 4);
+        peer.close();
+        assert!(call.await.unwrap().is_err());
+    }
+}
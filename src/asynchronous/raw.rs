@@ -0,0 +1,71 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Services handling the calls to any method under a prefix, as bytes.
+
+use std::cmp::Reverse;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::error::Result;
+use crate::proto::{Request, Response};
+use crate::r#async::{MethodHandler, TtrpcContext};
+use crate::service_fn::{self, Raw};
+
+/// Handles the calls to the methods of the services under a prefix, see
+/// [`Server::register_raw_service()`](crate::r#async::Server::register_raw_service).
+///
+/// It gets the service, method and payload of a call as they are, and
+/// answers with the payload of the response, so proxies and protocol
+/// translators need not know the methods they serve. Failing with
+/// [`Error::RpcStatus`](crate::Error::RpcStatus) answers the client with
+/// that status.
+#[async_trait]
+pub trait RawService: Send + Sync {
+    async fn call(
+        &self,
+        ctx: &TtrpcContext,
+        service: &str,
+        method: &str,
+        payload: Vec<u8>,
+    ) -> Result<Vec<u8>>;
+}
+
+struct RawHandler<S>(S);
+
+#[async_trait]
+impl<S: RawService> MethodHandler for RawHandler<S> {
+    async fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<Response> {
+        let rep = self
+            .0
+            .call(&ctx, &req.service, &req.method, req.payload)
+            .await;
+        let mut res = service_fn::response(&Raw, rep);
+        res.set_metadata(crate::context::to_pb(ctx.response_metadata.take()));
+        Ok(res)
+    }
+}
+
+/// The raw services of a server, by prefix.
+#[derive(Clone, Default)]
+pub(crate) struct RawServices(Arc<Vec<(String, Arc<dyn MethodHandler + Send + Sync>)>>);
+
+impl RawServices {
+    pub(crate) fn add(&mut self, prefix: &str, service: impl RawService + 'static) {
+        let services = Arc::get_mut(&mut self.0).unwrap();
+        services.push((prefix.to_string(), Arc::new(RawHandler(service))));
+        // The longest prefix matching a service wins.
+        services.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+    }
+
+    /// Returns the handler of the calls to `service`, if under a prefix.
+    pub(crate) fn get(&self, service: &str) -> Option<&(dyn MethodHandler + Send + Sync)> {
+        self.0
+            .iter()
+            .find(|(prefix, _)| service.starts_with(prefix.as_str()))
+            .map(|(_, handler)| handler.as_ref())
+    }
+}
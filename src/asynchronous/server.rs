@@ -4,19 +4,23 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use std::collections::HashMap;
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::marker::Unpin;
 use std::os::unix::io::RawFd;
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::os::unix::net::UnixListener as SysUnixListener;
+use std::panic::AssertUnwindSafe;
 use std::result::Result as StdResult;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, Instant, SystemTime};
 
 use async_trait::async_trait;
+use bytes::Bytes;
 use futures::stream::Stream;
-use futures::StreamExt as _;
+use futures::{FutureExt as _, StreamExt as _};
 use nix::unistd;
 use tokio::{
     self,
@@ -24,31 +28,59 @@ use tokio::{
     net::UnixListener,
     select, spawn,
     sync::mpsc::{channel, Sender},
+    sync::{broadcast, oneshot, Notify},
     task,
-    time::timeout,
+    time::timeout_at,
 };
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use tokio_vsock::VsockListener;
 
 use crate::asynchronous::unix_incoming::UnixIncoming;
-use crate::common::{self, Domain};
+#[cfg(feature = "audit")]
+use crate::audit::{AuditEvent, AuditLog};
+use crate::channelz::{Channelz, InFlightCalls};
+use crate::codec::HeaderLimits;
+use crate::common::{
+    self, CloseReason, Closing, ConnectionHooks, ConnectionInfo, ConnectionLimit, Domain,
+    PeerCredentials,
+};
+use crate::compression::{self, CompressionConfig};
+use crate::config::{ServerConfig, ServerKind, DEFAULT_SHUTDOWN_TIMEOUT};
 use crate::context;
-use crate::error::{get_status, Error, Result};
+use crate::error::{get_shutdown_status, get_status, Error, Result, ShutdownReason};
+use crate::frame_limit::{FrameCounter, FrameLimit};
+use crate::metrics::{CallMetrics, Metrics, MetricsSink};
 use crate::proto::{
-    Code, Codec, GenMessage, Message, MessageHeader, Request, Response, Status, FLAG_NO_DATA,
-    FLAG_REMOTE_CLOSED, FLAG_REMOTE_OPEN, MESSAGE_TYPE_DATA, MESSAGE_TYPE_REQUEST,
+    Code, Codec, GenMessage, GoAway, Message, MessageHeader, Notification, Request, Response,
+    Status, Subscription, FLAG_NO_DATA, FLAG_REMOTE_CLOSED, FLAG_REMOTE_OPEN,
+    MESSAGE_HEADER_LENGTH, MESSAGE_TYPE_CANCEL, MESSAGE_TYPE_DATA, MESSAGE_TYPE_GOAWAY,
+    MESSAGE_TYPE_REQUEST, MESSAGE_TYPE_RESPONSE, MESSAGE_TYPE_SUBSCRIBE,
+    MESSAGE_TYPE_WINDOW_UPDATE,
 };
+use crate::r#async::buffer_pool::BufferPool;
 use crate::r#async::connection::*;
+use crate::r#async::events::{EventSender, ServerEvent};
+use crate::r#async::flow_control::{self, Windows};
+use crate::r#async::interceptor::{Authorize, ConcurrencyLimit, Interceptor, Next};
+use crate::r#async::local::{LocalExecutor, LocalService, LocalServices};
+use crate::r#async::memory::{MemoryBudget, RequestBudget};
+use crate::r#async::peer::Peer;
+use crate::r#async::raw::{RawService, RawServices};
 use crate::r#async::shutdown;
 use crate::r#async::stream::{
     Kind, MessageReceiver, MessageSender, ResultReceiver, ResultSender, StreamInner,
 };
 use crate::r#async::utils;
-use crate::r#async::{MethodHandler, StreamHandler, TtrpcContext};
+use crate::r#async::{CancellationToken, MethodHandler, StreamHandler, TtrpcContext};
+use crate::reflection;
+use crate::restart::ListenerState;
+use crate::spans;
+use crate::stats::{self, Counter};
 
-const DEFAULT_CONN_SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(5000);
-const DEFAULT_SERVER_SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(10000);
+// How long connections get to close once their calls are cut short.
+const FORCE_CLOSE_TIMEOUT: Duration = Duration::from_millis(5000);
 
+#[derive(Default)]
 pub struct Service {
     pub methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>>,
     pub streams: HashMap<String, Arc<dyn StreamHandler + Send + Sync>>,
@@ -64,24 +96,182 @@ impl Service {
     }
 }
 
+// The end of the last request of a serial connection, which the next one
+// waits for.
+type SerialQueue = Arc<Mutex<Option<oneshot::Receiver<()>>>>;
+
+// The services of a server, which may change while it's running. Calls hold
+// on to the service they were dispatched to.
+type Services = Arc<RwLock<HashMap<String, Arc<Service>>>>;
+
+// Answers reflection::LIST_SERVICES with the services of the server.
+struct ListServices(Weak<RwLock<HashMap<String, Arc<Service>>>>);
+
+#[async_trait]
+impl MethodHandler for ListServices {
+    async fn handler(&self, _ctx: TtrpcContext, _req: Request) -> Result<Response> {
+        let services = self
+            .0
+            .upgrade()
+            .ok_or_else(|| Error::Others("the server is gone".to_string()))?;
+        let list = {
+            let services = services.read().unwrap();
+            reflection::list_services(services.iter().flat_map(|(name, srv)| {
+                let methods = srv
+                    .methods
+                    .keys()
+                    .map(move |m| (name.as_str(), m.as_str(), false));
+                let streams = srv
+                    .streams
+                    .keys()
+                    .map(move |m| (name.as_str(), m.as_str(), true));
+                methods.chain(streams)
+            }))
+        };
+        let mut res = Response::new();
+        res.set_status(get_status(Code::OK, ""));
+        res.payload = list
+            .encode()
+            .map_err(err_to_others_err!(e, "Encode ListServicesResponse failed."))?;
+        Ok(res)
+    }
+}
+
+/// The connections open on a server.
+#[derive(Default)]
+struct OpenConnections {
+    count: AtomicUsize,
+    closed: Notify,
+}
+
+/// Counts a connection as open until dropped.
+struct ConnectionSlot(Arc<OpenConnections>);
+
+impl ConnectionSlot {
+    fn new(open: Arc<OpenConnections>) -> Self {
+        open.count.fetch_add(1, Ordering::Relaxed);
+        Self(open)
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.count.fetch_sub(1, Ordering::Relaxed);
+        self.0.closed.notify_one();
+    }
+}
+
+// Where to send the notifications of a connection, and the topics it's
+// subscribed to.
+type Subscriber = (MessageSender, HashSet<String>);
+
+/// Connections subscribed to server notifications, keyed by connection fd.
+#[derive(Clone, Default)]
+struct Subscribers {
+    inner: Arc<Mutex<HashMap<RawFd, Subscriber>>>,
+}
+
+impl Subscribers {
+    fn update(&self, fd: RawFd, tx: &MessageSender, sub: Subscription) {
+        let mut subs = self.inner.lock().unwrap();
+        if sub.unsubscribe {
+            if let Some((_, topics)) = subs.get_mut(&fd) {
+                topics.remove(&sub.topic);
+                if topics.is_empty() {
+                    subs.remove(&fd);
+                }
+            }
+        } else {
+            subs.entry(fd)
+                .or_insert_with(|| (tx.clone(), HashSet::new()))
+                .1
+                .insert(sub.topic);
+        }
+    }
+
+    fn remove(&self, fd: RawFd) {
+        self.inner.lock().unwrap().remove(&fd);
+    }
+
+    fn senders(&self, topic: &str) -> Vec<MessageSender> {
+        self.inner
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|(_, topics)| topics.contains(topic))
+            .map(|(tx, _)| tx.clone())
+            .collect()
+    }
+}
+
+#[cfg(feature = "audit")]
+type ServerAudit = Option<Arc<AuditLog>>;
+#[cfg(not(feature = "audit"))]
+type ServerAudit = ();
+
 /// A ttrpc Server (async).
 pub struct Server {
     listeners: Vec<RawFd>,
-    services: Arc<HashMap<String, Service>>,
+    services: Services,
+    interceptors: Arc<Vec<Arc<dyn Interceptor>>>,
+    fallback: Option<Arc<dyn MethodHandler + Send + Sync>>,
+    local_services: LocalServices,
+    raw_services: RawServices,
     domain: Option<Domain>,
+    subscribers: Subscribers,
+    config: ServerConfig,
+    // Of `config`, shared by the connections.
+    memory: Arc<MemoryBudget>,
+    request_budget: Arc<MemoryBudget>,
+    compression: Option<CompressionConfig>,
+    max_chunked_message_size: usize,
+    stream_window: Option<u32>,
+    header_limits: HeaderLimits,
+    buffer_pool: Option<Arc<BufferPool>>,
+    audit: ServerAudit,
+    events: EventSender,
+    hooks: ConnectionHooks,
+    connections: Arc<OpenConnections>,
 
     shutdown: shutdown::Notifier,
-    stop_listen_tx: Option<Sender<Sender<RawFd>>>,
+    stop_listen_tx: Option<Sender<StopListen>>,
+    // Started, but not accepting connections for now.
+    accept_paused: bool,
+}
+
+// Asks the accept loop to stop and hand back its listener.
+struct StopListen {
+    fd_tx: Sender<RawFd>,
+    // Accept the connections already waiting first.
+    drain: bool,
 }
 
 impl Default for Server {
     fn default() -> Self {
         Server {
             listeners: Vec::with_capacity(1),
-            services: Arc::new(HashMap::new()),
+            services: Services::default(),
+            interceptors: Arc::new(Vec::new()),
+            fallback: None,
+            local_services: LocalServices::default(),
+            raw_services: RawServices::default(),
             domain: None,
-            shutdown: shutdown::with_timeout(DEFAULT_SERVER_SHUTDOWN_TIMEOUT).0,
+            subscribers: Subscribers::default(),
+            config: ServerConfig::default(),
+            memory: Arc::new(MemoryBudget::new(usize::MAX)),
+            request_budget: Arc::new(MemoryBudget::new(usize::MAX)),
+            compression: None,
+            max_chunked_message_size: 0,
+            stream_window: None,
+            header_limits: HeaderLimits::default(),
+            buffer_pool: None,
+            audit: Default::default(),
+            events: EventSender::default(),
+            hooks: ConnectionHooks::default(),
+            connections: Arc::default(),
+            shutdown: shutdown::with_timeout(DEFAULT_SHUTDOWN_TIMEOUT + FORCE_CLOSE_TIMEOUT).0,
             stop_listen_tx: None,
+            accept_paused: false,
         }
     }
 }
@@ -123,12 +313,411 @@ impl Server {
         Ok(self)
     }
 
-    pub fn register_service(mut self, new: HashMap<String, Service>) -> Server {
-        let services = Arc::get_mut(&mut self.services).unwrap();
-        services.extend(new);
+    /// Limit the bytes held by buffered messages across the whole server.
+    ///
+    /// This covers requests and stream data from the time they are read until
+    /// their handlers are done with them, and responses queued for writing. Once
+    /// the limit is reached, the server stops reading from its connections until
+    /// memory is released. There is no limit by default.
+    pub fn set_memory_limit(mut self, limit: usize) -> Self {
+        self.config.memory_limit = Some(limit);
+        self.memory = Arc::new(MemoryBudget::new(limit));
+        self
+    }
+
+    /// Limit the bytes of the requests being handled, from the time they are
+    /// read until their handlers are done with them, across the whole server.
+    ///
+    /// Unlike [`set_memory_limit()`](Server::set_memory_limit), requests over
+    /// the limit are answered with RESOURCE_EXHAUSTED, so slow handlers can't
+    /// pile them up. There is no limit by default.
+    pub fn set_request_budget(mut self, limit: usize) -> Self {
+        self.config.request_budget = Some(limit);
+        self.request_budget = Arc::new(MemoryBudget::new(limit));
+        self
+    }
+
+    /// Limit the bytes of the requests of each connection being handled,
+    /// like [`set_request_budget()`](Server::set_request_budget) does
+    /// across the server. There is no limit by default.
+    pub fn set_connection_request_budget(mut self, limit: usize) -> Self {
+        self.config.connection_request_budget = Some(limit);
+        self
+    }
+
+    /// Limit the frames a connection may send per `window` that carry neither a
+    /// request nor data for an active stream, such as cancellations,
+    /// subscriptions and frames of unknown types.
+    ///
+    /// Such frames are cheap to send but still cost the server work, a
+    /// connection going over the limit is disconnected. Defaults to 1000 per
+    /// second.
+    pub fn set_control_frame_limit(mut self, max: u32, window: Duration) -> Self {
+        self.config.control_frame_limit = FrameLimit { max, window };
+        self
+    }
+
+    /// Compress the responses to clients that accept the algorithm.
+    pub fn set_compression(mut self, config: CompressionConfig) -> Self {
+        self.compression = Some(config);
+        self
+    }
+
+    /// Sets the largest message payload the server accepts, defaults to
+    /// [`MESSAGE_LENGTH_MAX`](crate::proto::MESSAGE_LENGTH_MAX). Larger
+    /// requests are answered with RESOURCE_EXHAUSTED, the connection stays up.
+    pub fn set_max_message_size(mut self, size: usize) -> Self {
+        self.config.max_message_size = size;
+        self
+    }
+
+    /// Allows messages of up to `size` bytes to be exchanged in continuation
+    /// frames, each within the maximum message size.
+    ///
+    /// Responses larger than the maximum message size are then split, the
+    /// client must have chunking enabled too. Off by default.
+    pub fn set_max_chunked_message_size(mut self, size: usize) -> Self {
+        self.max_chunked_message_size = size;
+        self
+    }
+
+    /// Limits the calls a connection may have open at once, unary calls and
+    /// streams alike. Requests over the limit are answered with
+    /// RESOURCE_EXHAUSTED, the connection stays up. There is no limit by
+    /// default.
+    pub fn set_max_concurrent_streams(mut self, max: usize) -> Self {
+        self.config.max_concurrent_streams = max;
+        self
+    }
+
+    /// Handles the requests of a connection one at a time, in the order
+    /// they arrive, for services whose correctness depends on it. A stream
+    /// holds the requests after it until it ends. Off by default.
+    pub fn set_serial_requests(mut self, serial: bool) -> Self {
+        self.config.serial_requests = serial;
+        self
+    }
+
+    /// Puts streams from clients with flow control under it, a client may
+    /// send up to `window` messages on a stream ahead of the ones taken with
+    /// `recv()`.
+    ///
+    /// Streams to clients with flow control are always under it, sending
+    /// then waits for the room the client grants. Off by default.
+    pub fn set_stream_window(mut self, window: u32) -> Self {
+        self.stream_window = (window > 0).then_some(window);
+        self
+    }
+
+    /// Checks the headers of frames from clients against `limits`, strictly
+    /// by default. A connection sending a header failing them is closed.
+    pub fn set_header_limits(mut self, limits: HeaderLimits) -> Self {
+        self.header_limits = limits;
+        self
+    }
+
+    /// Buffers reads from connections by up to `size` bytes, so that small
+    /// frames are read a few at a time rather than in two reads each.
+    /// 0 reads straight from the connections.
+    pub fn set_read_buffer_size(mut self, size: usize) -> Self {
+        self.config.read_buffer_size = size;
+        self
+    }
+
+    /// Caps the connections open at once to `max`, `over` telling what
+    /// becomes of the ones coming beyond it. Unlimited by default.
+    pub fn set_max_connections(mut self, max: usize, over: ConnectionLimit) -> Self {
+        self.config.max_connections = Some((max, over));
+        self
+    }
+
+    /// Closes the connections which have sent nothing for `timeout` while
+    /// no call of theirs was being handled, so that abandoned clients don't
+    /// hold on to them. Off by default.
+    pub fn set_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Closes the connections from which a message, once it started
+    /// arriving, didn't arrive in whole within `timeout`. Waiting for the
+    /// next message isn't limited, see [`Server::set_idle_timeout()`].
+    pub fn set_read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Closes the connections on which writing a message took longer than
+    /// `timeout`, so that clients not reading their responses don't keep
+    /// the connection tasks forever.
+    pub fn set_write_timeout(mut self, timeout: Duration) -> Self {
+        self.config.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how long [`Server::shutdown()`] waits for the calls being
+    /// handled to complete, defaults to 5 seconds. The calls still running
+    /// then are cut short, their clients told the server is going away.
+    pub fn set_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.config.shutdown_timeout = timeout;
+        self.shutdown = shutdown::with_timeout(timeout + FORCE_CLOSE_TIMEOUT).0;
+        self
+    }
+
+    /// Applies the knobs of `config` once [validated](ServerConfig::validate_for),
+    /// failing if it sets any only sync servers have.
+    pub fn set_config(mut self, config: &ServerConfig) -> Result<Self> {
+        config.validate_for(ServerKind::Async)?;
+        self.config = config.clone();
+        self.request_budget = Arc::new(MemoryBudget::new(
+            config.request_budget.unwrap_or(usize::MAX),
+        ));
+        self.memory = Arc::new(MemoryBudget::new(config.memory_limit.unwrap_or(usize::MAX)));
+        Ok(self.set_shutdown_timeout(config.shutdown_timeout))
+    }
+
+    /// Reads the messages of all connections into buffers of `pool`, which
+    /// are reused once the messages are done with. Keep a clone of `pool`
+    /// to follow its [`stats()`](BufferPool::stats).
+    pub fn set_buffer_pool(mut self, pool: Arc<BufferPool>) -> Self {
+        self.buffer_pool = Some(pool);
+        self
+    }
+
+    /// Records connections and administrative actions to `audit`.
+    #[cfg(feature = "audit")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "audit")))]
+    pub fn set_audit_log(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Subscribes to the events of the server's connections and calls.
+    ///
+    /// Events are only built while someone is subscribed. A receiver that
+    /// falls too far behind skips the oldest events, see
+    /// [`RecvError::Lagged`](broadcast::error::RecvError::Lagged).
+    pub fn events(&self) -> broadcast::Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    #[cfg(feature = "audit")]
+    fn audit_admin(&self, action: &str) {
+        if let Some(audit) = self.audit.as_ref() {
+            audit.record(AuditEvent::Admin {
+                action: action.to_string(),
+            });
+        }
+    }
+
+    /// Exports the listener so that a new process can take it over after exec.
+    ///
+    /// If `inheritable` is true, FD_CLOEXEC is cleared on the listener fd.
+    pub fn export_listener(&self, inheritable: bool) -> Result<ListenerState> {
+        let fd = self.get_listenfd()?;
+        #[cfg(feature = "audit")]
+        self.audit_admin("export_listener");
+        ListenerState::export(fd, inheritable)
+    }
+
+    /// Creates a Server from a listener exported by the previous process.
+    pub fn from_listener_state(state: &ListenerState) -> Result<Server> {
+        let fd = state.import()?;
+        let mut server = Server::new().add_listener(fd)?;
+        server.domain = Some(state.domain());
+        Ok(server)
+    }
+
+    pub fn register_service(self, new: HashMap<String, Service>) -> Server {
+        self.add_services(new);
+        self
+    }
+
+    /// Registers `handler` as the handler of `method` of `service`, added to
+    /// the methods the service has, see [`service_fn`](crate::service_fn).
+    pub fn register_method(
+        self,
+        service: &str,
+        method: &str,
+        handler: impl MethodHandler + Send + Sync + 'static,
+    ) -> Server {
+        {
+            let mut services = self.services.write().unwrap();
+            let srv = services.entry(service.to_string()).or_default();
+            Arc::get_mut(srv)
+                .unwrap()
+                .methods
+                .insert(method.to_string(), Box::new(handler));
+        }
+        self
+    }
+
+    /// Adds services to the server, which may be running, replacing the
+    /// ones with the same names. New calls are dispatched to them.
+    pub fn add_services(&self, new: HashMap<String, Service>) {
+        let new = new.into_iter().map(|(name, srv)| (name, Arc::new(srv)));
+        self.services.write().unwrap().extend(new);
+    }
+
+    /// Removes the service `name` from the server, which may be running,
+    /// returning whether it had it. Calls already dispatched to the service
+    /// complete, new ones are handled as calls to an unknown service.
+    pub fn remove_service(&self, name: &str) -> bool {
+        self.services.write().unwrap().remove(name).is_some()
+    }
+
+    /// Adds the [`reflection`] service, listing the services of the server.
+    pub fn enable_reflection(self) -> Server {
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert(
+            reflection::LIST_SERVICES.to_string(),
+            Box::new(ListServices(Arc::downgrade(&self.services))),
+        );
+        let service = Service {
+            methods,
+            streams: HashMap::new(),
+        };
+        self.register_service(HashMap::from([(reflection::SERVICE.to_string(), service)]))
+    }
+
+    /// Adds an interceptor wrapping the calls to all services. Interceptors
+    /// run in the order they are added, the first one seeing the calls first.
+    pub fn add_interceptor(mut self, interceptor: impl Interceptor + 'static) -> Server {
+        let interceptors = Arc::get_mut(&mut self.interceptors).unwrap();
+        interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Sets the hook deciding, for every call, whether the peer may call
+    /// the method. It gets the credentials of the peer, none over vsock,
+    /// and the method such as `grpc.Containerd/Checkpoint`. Denied calls
+    /// fail with PERMISSION_DENIED before any interceptor sees them.
+    pub fn set_authorizer<F>(mut self, authorizer: F) -> Server
+    where
+        F: Fn(Option<PeerCredentials>, &str) -> bool + Send + Sync + 'static,
+    {
+        let interceptors = Arc::get_mut(&mut self.interceptors).unwrap();
+        interceptors.insert(0, Arc::new(Authorize(authorizer)));
+        self
+    }
+
+    /// Calls `hook` with every connection accepted, before handling any
+    /// of its calls. It runs on the accepting task, so it should be quick.
+    pub fn on_connection_opened(
+        mut self,
+        hook: impl Fn(&ConnectionInfo) + Send + Sync + 'static,
+    ) -> Server {
+        self.hooks.opened = Some(Arc::new(hook));
+        self
+    }
+
+    /// Calls `hook` with every connection closed and why, once its calls
+    /// are done. Per-client state can be cleaned up there.
+    pub fn on_connection_closed(
+        mut self,
+        hook: impl Fn(&ConnectionInfo, &CloseReason) + Send + Sync + 'static,
+    ) -> Server {
+        self.hooks.closed = Some(Arc::new(hook));
+        self
+    }
+
+    /// Reports the calls, bytes and connections of the server to `sink`.
+    /// Calls are timed from before any interceptor sees them.
+    pub fn set_metrics_sink(mut self, sink: impl MetricsSink + 'static) -> Server {
+        let sink: Arc<dyn MetricsSink> = Arc::new(sink);
+        self.hooks.metrics = Metrics::new(sink.clone());
+        let interceptors = Arc::get_mut(&mut self.interceptors).unwrap();
+        interceptors.insert(0, Arc::new(CallMetrics(sink)));
+        self
+    }
+
+    /// Keeps track of the connections and calls of the server in
+    /// `channelz`, see [`channelz`](crate::channelz).
+    pub fn set_channelz(mut self, channelz: Channelz) -> Server {
+        self.hooks.channelz = Some(channelz.clone());
+        let interceptors = Arc::get_mut(&mut self.interceptors).unwrap();
+        interceptors.insert(0, Arc::new(InFlightCalls(channelz)));
+        self
+    }
+
+    /// Caps the calls of `name` running at once to `max`. `name` is a
+    /// service such as `grpc.Containerd`, or a method such as
+    /// `grpc.Containerd/Checkpoint`. Calls beyond the cap wait up to
+    /// `queue_timeout` for one to finish, then fail with RESOURCE_EXHAUSTED.
+    ///
+    /// The cap is an interceptor, running in order with the others added.
+    pub fn set_concurrency_limit(self, name: &str, max: usize, queue_timeout: Duration) -> Server {
+        self.add_interceptor(ConcurrencyLimit::new(name, max, queue_timeout))
+    }
+
+    /// Sets the handler of the calls to methods no registered service has,
+    /// which otherwise fail. It gets the request as is, service, method and
+    /// payload, so it can forward or translate the call, and answers it like
+    /// the handler of a unary method.
+    pub fn set_fallback_handler(
+        mut self,
+        handler: impl MethodHandler + Send + Sync + 'static,
+    ) -> Server {
+        self.fallback = Some(Arc::new(handler));
         self
     }
 
+    /// Registers `service` as the handler of the calls to methods no
+    /// registered service has, of the services whose names start with
+    /// `prefix`. The longest matching prefix wins, then the fallback handler
+    /// gets the calls no raw service takes.
+    pub fn register_raw_service(
+        mut self,
+        prefix: &str,
+        service: impl RawService + 'static,
+    ) -> Server {
+        self.raw_services.add(prefix, service);
+        self
+    }
+
+    /// Registers a service whose handlers aren't `Send`, made by `factory`
+    /// for every connection calling it. They run on a thread of the
+    /// connection with a current-thread runtime, see [`local`](crate::r#async::local).
+    /// Services registered with [`register_service()`](Server::register_service)
+    /// take precedence.
+    pub fn register_local_service(
+        mut self,
+        name: &str,
+        factory: impl Fn() -> LocalService + Send + Sync + 'static,
+    ) -> Server {
+        Arc::get_mut(&mut self.local_services)
+            .unwrap()
+            .insert(name.to_string(), Arc::new(factory));
+        self
+    }
+
+    /// Pushes a notification to every connection subscribed to `topic`.
+    ///
+    /// Returns the number of connections the notification was queued to.
+    pub async fn notify(&self, topic: &str, payload: Vec<u8>) -> Result<usize> {
+        let notification = Notification {
+            topic: topic.to_string(),
+            payload,
+            ..Default::default()
+        };
+        let payload: Bytes = notification
+            .encode()
+            .map_err(err_to_others_err!(e, "Encode Notification failed."))?
+            .into();
+
+        let mut sent = 0;
+        for tx in self.subscribers.senders(topic) {
+            let msg = GenMessage {
+                header: MessageHeader::new_notification(payload.len() as u32),
+                payload: payload.clone(),
+            };
+            if tx.send(msg).await.is_ok() {
+                sent += 1;
+            }
+        }
+        Ok(sent)
+    }
+
     fn get_listenfd(&self) -> Result<RawFd> {
         if self.listeners.is_empty() {
             return Err(Error::Others("ttrpc-rust not bind".to_string()));
@@ -173,7 +762,29 @@ impl Server {
         I: Stream<Item = std::io::Result<S>> + Unpin + Send + 'static + AsRawFd,
         S: AsyncRead + AsyncWrite + AsRawFd + Send + 'static,
     {
+        if let Some(channelz) = self.hooks.channelz.as_ref() {
+            channelz.started();
+        }
         let services = self.services.clone();
+        let interceptors = self.interceptors.clone();
+        let fallback = self.fallback.clone();
+        let local_services = self.local_services.clone();
+        let raw_services = self.raw_services.clone();
+        let subscribers = self.subscribers.clone();
+        let config = Arc::new(self.config.clone());
+        let memory = self.memory.clone();
+        let request_budget = self.request_budget.clone();
+        let frame_limit = self.config.control_frame_limit;
+        let compression = self.compression;
+        let max_chunked_message_size = self.max_chunked_message_size;
+        let stream_window = self.stream_window;
+        let header_limits = self.header_limits;
+        let buffer_pool = self.buffer_pool.clone();
+        let audit = self.audit.clone();
+        let events = self.events.clone();
+        let hooks = self.hooks.clone();
+        let domain = self.domain;
+        let connections = self.connections.clone();
 
         let shutdown_waiter = self.shutdown.subscribe();
 
@@ -181,24 +792,82 @@ impl Server {
         self.stop_listen_tx = Some(stop_listen_tx);
 
         spawn(async move {
+            let count = |counter| {
+                if let Some(domain) = domain {
+                    stats::record(domain, counter);
+                }
+            };
+            let full = |over| {
+                config.max_connections.is_some_and(|(max, o)| {
+                    o == over && connections.count.load(Ordering::Relaxed) >= max
+                })
+            };
+            // Closes a connection over the limit, returns whether it was.
+            let reject = |conn: &S| {
+                if !full(ConnectionLimit::Reject) {
+                    return false;
+                }
+                count(Counter::Accepted);
+                count(Counter::Rejected);
+                let fd = conn.as_raw_fd();
+                warn!(
+                    "closing connection fd {}, the server has {} already",
+                    fd,
+                    connections.count.load(Ordering::Relaxed)
+                );
+                #[cfg(feature = "audit")]
+                if let Some(audit) = audit.as_ref() {
+                    audit.record(AuditEvent::Connection {
+                        peer_cred: common::get_peer_credentials(fd).ok(),
+                        accepted: false,
+                        reason: "too many connections".to_string(),
+                    });
+                }
+                true
+            };
+            let accept = |conn: S| {
+                count(Counter::Accepted);
+                let fd = conn.as_raw_fd();
+                // spawn a connection handler, would not block
+                spawn_connection_handler(
+                    fd,
+                    conn,
+                    ConnectionSlot::new(connections.clone()),
+                    services.clone(),
+                    interceptors.clone(),
+                    fallback.clone(),
+                    local_services.clone(),
+                    raw_services.clone(),
+                    subscribers.clone(),
+                    config.clone(),
+                    memory.clone(),
+                    request_budget.clone(),
+                    frame_limit,
+                    compression,
+                    max_chunked_message_size,
+                    stream_window,
+                    header_limits,
+                    buffer_pool.clone(),
+                    audit.clone(),
+                    events.clone(),
+                    hooks.clone(),
+                    shutdown_waiter.clone(),
+                )
+            };
             loop {
+                // Over the limit, clients are left waiting until a
+                // connection closes.
+                let waiting = full(ConnectionLimit::Wait);
                 select! {
-                    conn = incoming.next() => {
+                    conn = incoming.next(), if !waiting => {
                         if let Some(conn) = conn {
                             // Accept a new connection
                             match conn {
-                                Ok(conn) => {
-                                    let fd = conn.as_raw_fd();
-                                    // spawn a connection handler, would not block
-                                    spawn_connection_handler(
-                                        fd,
-                                        conn,
-                                        services.clone(),
-                                        shutdown_waiter.clone(),
-                                    ).await;
-                                }
+                                Ok(conn) if reject(&conn) => {}
+                                Ok(conn) => accept(conn).await,
                                 Err(e) => {
-                                    error!("{:?}", e)
+                                    error!("{:?}", e);
+                                    count(Counter::AcceptErrors);
                                 }
                             }
 
@@ -206,17 +875,34 @@ impl Server {
                             break;
                         }
                     }
-                    fd_tx = stop_listen_rx.recv() => {
-                        if let Some(fd_tx) = fd_tx {
-                            // dup fd to keep the listener open
-                            // or the listener will be closed when the incoming was dropped.
-                            let dup_fd = unistd::dup(incoming.as_raw_fd()).unwrap();
-                            common::set_fd_close_exec(dup_fd).unwrap();
-                            drop(incoming);
-
-                            fd_tx.send(dup_fd).await.unwrap();
-                            break;
+                    _ = connections.closed.notified(), if waiting => {}
+                    stop = stop_listen_rx.recv() => {
+                        let StopListen { fd_tx, drain } = match stop {
+                            Some(stop) => stop,
+                            // The server was dropped, the listener closes
+                            // along with `incoming`.
+                            None => break,
+                        };
+                        if drain {
+                            while let Some(Some(conn)) = incoming.next().now_or_never() {
+                                match conn {
+                                    Ok(conn) if reject(&conn) => {}
+                                    Ok(conn) => accept(conn).await,
+                                    Err(e) => {
+                                        error!("{:?}", e);
+                                        count(Counter::AcceptErrors);
+                                    }
+                                }
+                            }
                         }
+                        // dup fd to keep the listener open
+                        // or the listener will be closed when the incoming was dropped.
+                        let dup_fd = unistd::dup(incoming.as_raw_fd()).unwrap();
+                        common::set_fd_close_exec(dup_fd).unwrap();
+                        drop(incoming);
+
+                        fd_tx.send(dup_fd).await.unwrap();
+                        break;
                     }
                 }
             }
@@ -224,13 +910,40 @@ impl Server {
         Ok(())
     }
 
+    /// Stops accepting connections and closes the ones open, telling their
+    /// clients the server is going away.
+    ///
+    /// The calls being handled get up to the timeout set with
+    /// [`Server::set_shutdown_timeout()`] to complete before they're cut
+    /// short. Returns once the connections are closed, their handlers,
+    /// stream handlers included, are done and the responses they left are
+    /// written, so the process may exit right after. Stream handlers not
+    /// heeding their cancellation are given up on a little later.
     pub async fn shutdown(&mut self) -> Result<()> {
+        #[cfg(feature = "audit")]
+        self.audit_admin("shutdown");
+        self.accept_paused = false;
         self.stop_listen().await;
         self.disconnect().await;
 
         Ok(())
     }
 
+    /// Shuts the server down like [`Server::shutdown()`] and closes its
+    /// listeners, removing the files of unix sockets.
+    ///
+    /// Dropping a running server only starts to shut it down, its
+    /// connections are then closed in the background.
+    pub async fn close(mut self) -> Result<()> {
+        self.shutdown().await?;
+        for fd in self.listeners.drain(..) {
+            let path = common::get_socket_path(fd);
+            unistd::close(fd).unwrap_or_else(|e| warn!("failed to close listener {}: {}", fd, e));
+            common::remove_socket_path(path);
+        }
+        Ok(())
+    }
+
     pub async fn disconnect(&mut self) {
         self.shutdown.shutdown();
 
@@ -238,35 +951,147 @@ impl Server {
             .wait_all_exit()
             .await
             .map_err(|e| {
-                trace!("wait connection exit error: {}", e);
+                warn!("connections still closing after shutdown: {}", e);
             })
             .ok();
         trace!("wait connection exit.");
     }
 
+    /// Stops accepting connections for now, keeping the listener and the
+    /// connections open. Clients connecting meanwhile wait to be accepted
+    /// until [`Server::resume_accept()`].
+    pub async fn pause_accept(&mut self) {
+        if self.stop_listen_tx.is_none() {
+            return;
+        }
+        #[cfg(feature = "audit")]
+        self.audit_admin("pause_accept");
+        self.stop_listener(false).await;
+        self.accept_paused = true;
+        info!("server paused accepting connections");
+    }
+
+    /// Accepts connections again after [`Server::pause_accept()`].
+    pub async fn resume_accept(&mut self) -> Result<()> {
+        if !self.accept_paused {
+            return Ok(());
+        }
+        #[cfg(feature = "audit")]
+        self.audit_admin("resume_accept");
+        self.start().await?;
+        self.accept_paused = false;
+        info!("server resumed accepting connections");
+        Ok(())
+    }
+
     pub async fn stop_listen(&mut self) {
+        self.stop_listener(false).await;
+    }
+
+    async fn stop_listener(&mut self, drain: bool) {
         if let Some(tx) = self.stop_listen_tx.take() {
             let (fd_tx, mut fd_rx) = channel(1);
-            tx.send(fd_tx).await.unwrap();
+            tx.send(StopListen { fd_tx, drain }).await.unwrap();
 
             let fd = fd_rx.recv().await.unwrap();
             self.listeners.clear();
             self.listeners.push(fd);
         }
     }
+
+    /// Moves the server to `sockaddr` without dropping its connections.
+    ///
+    /// Once listening on the new address, the connections already waiting
+    /// on the old one are accepted, then it's closed and the file of a unix
+    /// socket is removed. A server that isn't started yet only swaps its
+    /// listener.
+    pub async fn rebind(&mut self, sockaddr: &str) -> Result<()> {
+        let (fd, domain) = common::do_bind(sockaddr)?;
+        if let Err(e) = common::do_listen(fd) {
+            unistd::close(fd).unwrap_or(());
+            return Err(e);
+        }
+
+        let running = self.stop_listen_tx.is_some();
+        self.stop_listener(true).await;
+        for old in self.listeners.drain(..) {
+            let path = common::get_socket_path(old);
+            unistd::close(old).unwrap_or_else(|e| warn!("failed to close listener {}: {}", old, e));
+            common::remove_socket_path(path);
+        }
+        self.listeners.push(fd);
+        self.domain = Some(domain);
+        #[cfg(feature = "audit")]
+        self.audit_admin("rebind");
+        info!("server rebound to {}", sockaddr);
+
+        if running {
+            self.start().await?;
+        }
+        Ok(())
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
+#[cfg_attr(not(feature = "audit"), allow(unused_variables))]
 async fn spawn_connection_handler<C>(
     fd: RawFd,
     conn: C,
-    services: Arc<HashMap<String, Service>>,
+    slot: ConnectionSlot,
+    services: Services,
+    interceptors: Arc<Vec<Arc<dyn Interceptor>>>,
+    fallback: Option<Arc<dyn MethodHandler + Send + Sync>>,
+    local_services: LocalServices,
+    raw_services: RawServices,
+    subscribers: Subscribers,
+    config: Arc<ServerConfig>,
+    memory: Arc<MemoryBudget>,
+    request_budget: Arc<MemoryBudget>,
+    frame_limit: FrameLimit,
+    compression: Option<CompressionConfig>,
+    max_chunked_message_size: usize,
+    stream_window: Option<u32>,
+    header_limits: HeaderLimits,
+    buffer_pool: Option<Arc<BufferPool>>,
+    audit: ServerAudit,
+    events: EventSender,
+    hooks: ConnectionHooks,
     shutdown_waiter: shutdown::Waiter,
 ) where
     C: AsyncRead + AsyncWrite + AsRawFd + Send + 'static,
 {
+    let info = Arc::new(ConnectionInfo::new(fd));
+    let peer_cred = info.peer_cred;
+    #[cfg(feature = "audit")]
+    if let Some(audit) = audit {
+        audit.record(AuditEvent::Connection {
+            peer_cred,
+            accepted: true,
+            reason: String::new(),
+        });
+    }
+    events.send(ServerEvent::ConnectionOpened { fd, peer_cred });
+    hooks.opened(&info);
     let delegate = ServerBuilder {
         fd,
+        info,
         services,
+        interceptors,
+        fallback,
+        local_services,
+        raw_services,
+        subscribers,
+        config,
+        memory,
+        request_budget,
+        frame_limit,
+        compression,
+        max_chunked_message_size,
+        stream_window,
+        header_limits,
+        buffer_pool,
+        events,
+        hooks,
         streams: Arc::new(Mutex::new(HashMap::new())),
         shutdown_waiter,
     };
@@ -278,6 +1103,7 @@ async fn spawn_connection_handler<C>(
                 trace!("connection run error. {}", e);
             })
             .ok();
+        drop(slot);
     });
 }
 
@@ -287,6 +1113,14 @@ impl FromRawFd for Server {
     }
 }
 
+impl Drop for Server {
+    fn drop(&mut self) {
+        if self.stop_listen_tx.is_some() || self.accept_paused {
+            warn!("Server dropped while running without close(), its connections close in the background");
+        }
+    }
+}
+
 impl AsRawFd for Server {
     fn as_raw_fd(&self) -> RawFd {
         self.listeners[0]
@@ -295,7 +1129,24 @@ impl AsRawFd for Server {
 
 struct ServerBuilder {
     fd: RawFd,
-    services: Arc<HashMap<String, Service>>,
+    info: Arc<ConnectionInfo>,
+    services: Services,
+    interceptors: Arc<Vec<Arc<dyn Interceptor>>>,
+    fallback: Option<Arc<dyn MethodHandler + Send + Sync>>,
+    local_services: LocalServices,
+    raw_services: RawServices,
+    subscribers: Subscribers,
+    config: Arc<ServerConfig>,
+    memory: Arc<MemoryBudget>,
+    request_budget: Arc<MemoryBudget>,
+    frame_limit: FrameLimit,
+    compression: Option<CompressionConfig>,
+    max_chunked_message_size: usize,
+    stream_window: Option<u32>,
+    header_limits: HeaderLimits,
+    buffer_pool: Option<Arc<BufferPool>>,
+    events: EventSender,
+    hooks: ConnectionHooks,
     streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
     shutdown_waiter: shutdown::Waiter,
 }
@@ -307,40 +1158,144 @@ impl Builder for ServerBuilder {
     fn build(&mut self) -> (Self::Reader, Self::Writer) {
         let (tx, rx): (MessageSender, MessageReceiver) = channel(100);
         let (disconnect_notifier, _disconnect_waiter) =
-            shutdown::with_timeout(DEFAULT_CONN_SHUTDOWN_TIMEOUT);
+            shutdown::with_timeout(self.config.shutdown_timeout);
 
         (
             ServerReader {
                 fd: self.fd,
+                info: self.info.clone(),
+                peer: Peer::new(tx.clone()),
                 tx,
                 services: self.services.clone(),
+                interceptors: self.interceptors.clone(),
+                fallback: self.fallback.clone(),
+                local: Arc::new(LocalExecutor::new(self.local_services.clone())),
+                raw_services: self.raw_services.clone(),
+                subscribers: self.subscribers.clone(),
+                config: self.config.clone(),
+                memory: self.memory.clone(),
+                requests: RequestBudget::new(
+                    self.request_budget.clone(),
+                    self.config.connection_request_budget.unwrap_or(usize::MAX),
+                ),
+                compression: self.compression,
+                max_chunked_message_size: self.max_chunked_message_size,
+                serial: self.config.serial_requests.then(SerialQueue::default),
+                stream_window: self.stream_window,
+                header_limits: self.header_limits,
+                buffer_pool: self.buffer_pool.clone(),
+                events: self.events.clone(),
+                hooks: self.hooks.clone(),
+                close_reason: Closing::default(),
                 streams: self.streams.clone(),
+                windows: Windows::default(),
+                cancels: Arc::new(Mutex::new(HashMap::new())),
+                frames: Mutex::new(FrameCounter::new(self.frame_limit)),
+                kicked: Notify::new(),
+                last_stream_id: Arc::new(AtomicU32::new(0)),
+                closing: Arc::new(AtomicBool::new(false)),
+                last_active: Mutex::new(utils::now()),
                 server_shutdown: self.shutdown_waiter.clone(),
                 handler_shutdown: disconnect_notifier,
             },
-            ServerWriter { rx },
+            ServerWriter {
+                rx,
+                memory: self.memory.clone(),
+                info: self.info.clone(),
+                hooks: self.hooks.clone(),
+                chunk_size: (self.max_chunked_message_size > 0)
+                    .then_some(self.config.max_message_size),
+                io_timeouts: self.config.io_timeouts(),
+                _server_shutdown: self.shutdown_waiter.clone(),
+            },
         )
     }
 }
 
+impl ServerConfig {
+    fn io_timeouts(&self) -> IoTimeouts {
+        IoTimeouts {
+            read: self.read_timeout,
+            write: self.write_timeout,
+        }
+    }
+}
+
 struct ServerWriter {
     rx: MessageReceiver,
+    memory: Arc<MemoryBudget>,
+    info: Arc<ConnectionInfo>,
+    hooks: ConnectionHooks,
+    chunk_size: Option<usize>,
+    io_timeouts: IoTimeouts,
+    // Keeps the server shutting down until the responses are written.
+    _server_shutdown: shutdown::Waiter,
 }
 
 #[async_trait]
 impl WriterDelegate for ServerWriter {
     async fn recv(&mut self) -> Option<GenMessage> {
-        self.rx.recv().await
+        let msg = self.rx.recv().await;
+        if let Some(msg) = msg.as_ref() {
+            // Responses are charged by HandlerContext::respond() when queued.
+            if msg.header.type_ == MESSAGE_TYPE_RESPONSE {
+                self.memory.release(msg.payload.len());
+                self.hooks.metrics.response_dequeued();
+            }
+            self.hooks
+                .sent(&self.info, MESSAGE_HEADER_LENGTH + msg.payload.len());
+        }
+        msg
     }
     async fn disconnect(&self, _msg: &GenMessage, _: Error) {}
     async fn exit(&self) {}
+    fn chunk_size(&self) -> Option<usize> {
+        self.chunk_size
+    }
+    fn io_timeouts(&self) -> IoTimeouts {
+        self.io_timeouts
+    }
 }
 
 struct ServerReader {
     fd: RawFd,
+    info: Arc<ConnectionInfo>,
     tx: MessageSender,
-    services: Arc<HashMap<String, Service>>,
+    // Calls the client back, on even stream ids.
+    peer: Peer,
+    services: Services,
+    interceptors: Arc<Vec<Arc<dyn Interceptor>>>,
+    fallback: Option<Arc<dyn MethodHandler + Send + Sync>>,
+    local: Arc<LocalExecutor>,
+    raw_services: RawServices,
+    subscribers: Subscribers,
+    config: Arc<ServerConfig>,
+    memory: Arc<MemoryBudget>,
+    requests: RequestBudget,
+    compression: Option<CompressionConfig>,
+    max_chunked_message_size: usize,
+    serial: Option<SerialQueue>,
+    stream_window: Option<u32>,
+    header_limits: HeaderLimits,
+    buffer_pool: Option<Arc<BufferPool>>,
+    events: EventSender,
+    hooks: ConnectionHooks,
+    // Why the connection is closing, told to the hooks.
+    close_reason: Closing,
     streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
+    windows: Windows,
+    // Of the calls being handled, also counts them against the stream limit.
+    cancels: Arc<Mutex<HashMap<u32, oneshot::Sender<()>>>>,
+    frames: Mutex<FrameCounter>,
+    // Notified to drop a connection that misbehaves.
+    kicked: Notify,
+    // Of the latest request, told to the client when going away.
+    last_stream_id: Arc<AtomicU32>,
+    // Set once the client is closing the connection, under the lock of
+    // `cancels`.
+    closing: Arc<AtomicBool>,
+    // When the latest message was read.
+    last_active: Mutex<Instant>,
     server_shutdown: shutdown::Waiter,
     handler_shutdown: shutdown::Notifier,
 }
@@ -348,55 +1303,446 @@ struct ServerReader {
 #[async_trait]
 impl ReaderDelegate for ServerReader {
     async fn wait_shutdown(&self) {
-        self.server_shutdown.wait_shutdown().await
+        select! {
+            _ = self.server_shutdown.wait_shutdown() => {
+                self.close_reason.set(CloseReason::Shutdown);
+                self.send_goaway();
+            }
+            _ = self.kicked.notified() => {
+                self.close_reason.set(CloseReason::Error("too many control frames".to_string()));
+            }
+        }
+    }
+
+    async fn wait_close(&self) -> Error {
+        let timeout = match self.config.idle_timeout {
+            Some(timeout) => timeout,
+            None => return futures::future::pending().await,
+        };
+        loop {
+            let deadline = *self.last_active.lock().unwrap() + timeout;
+            tokio::time::sleep_until(deadline.into()).await;
+            if *self.last_active.lock().unwrap() + timeout > utils::now() {
+                continue;
+            }
+            if self.cancels.lock().unwrap().is_empty() {
+                debug!("fd {} idle for {:?}, closing it", self.fd, timeout);
+                self.close_reason.set(CloseReason::Idle);
+                return Error::Others("connection idle".to_string());
+            }
+            // Waiting on calls isn't idling, look again later.
+            *self.last_active.lock().unwrap() = utils::now();
+        }
     }
 
-    async fn disconnect(&self, _: Error, _: &mut task::JoinHandle<()>) {
+    async fn disconnect(&self, e: Error, _: &mut task::JoinHandle<()>) {
+        self.close_reason.set(CloseReason::from_error(&e));
         self.handler_shutdown.shutdown();
         // TODO: Don't wait for all requests to complete? when the connection is disconnected.
     }
 
     async fn exit(&self) {
+        self.subscribers.remove(self.fd);
+        self.peer.close();
         // TODO: Don't self.conn_shutdown.shutdown();
         // Wait pedding request/stream to exit.
-        self.handler_shutdown
-            .wait_all_exit()
-            .await
-            .map_err(|e| {
-                trace!("wait handler exit error: {}", e);
-            })
-            .ok();
+        if self.handler_shutdown.wait_all_exit().await.is_err() {
+            // Cut the calls still running short.
+            debug!("fd {} calls still running, cancelling them", self.fd);
+            self.handler_shutdown.shutdown();
+            self.handler_shutdown
+                .wait_all_exit()
+                .await
+                .map_err(|e| {
+                    trace!("wait handler exit error: {}", e);
+                })
+                .ok();
+        }
+        self.events
+            .send(ServerEvent::ConnectionClosed { fd: self.fd });
+        self.hooks.closed(&self.info, &self.close_reason.take());
     }
 
-    async fn handle_msg(&self, msg: GenMessage) {
+    async fn handle_msg(&self, mut msg: GenMessage) {
+        *self.last_active.lock().unwrap() = utils::now();
+        self.hooks
+            .received(&self.info, MESSAGE_HEADER_LENGTH + msg.payload.len());
+        if self.is_control_frame(&msg) && !self.frames.lock().unwrap().hit(utils::now()) {
+            warn!(
+                "fd {} sent too many control frames, disconnecting it",
+                self.fd
+            );
+            self.kicked.notify_one();
+            return;
+        }
+
+        if msg.header.type_ == MESSAGE_TYPE_SUBSCRIBE {
+            match Subscription::decode(&msg.payload) {
+                Ok(sub) => self.subscribers.update(self.fd, &self.tx, sub),
+                Err(e) => debug!("Got malformed subscription {:?}: {}", msg, e),
+            }
+            return;
+        }
+        if msg.header.type_ == MESSAGE_TYPE_CANCEL {
+            self.cancel(msg.header.stream_id).await;
+            return;
+        }
+        if msg.header.type_ == MESSAGE_TYPE_GOAWAY {
+            self.handle_close().await;
+            return;
+        }
+        if msg.header.type_ == MESSAGE_TYPE_WINDOW_UPDATE {
+            let active = self
+                .cancels
+                .lock()
+                .unwrap()
+                .contains_key(&msg.header.stream_id);
+            flow_control::handle_update(&self.windows, &msg, active);
+            return;
+        }
+        if msg.header.type_ == MESSAGE_TYPE_RESPONSE {
+            // The client answering a call of the server back.
+            let max_len = self
+                .config
+                .max_message_size
+                .max(self.max_chunked_message_size);
+            if let Err(e) = compression::decompress_message(&mut msg, max_len) {
+                debug!("fd {} sent a malformed response: {}", self.fd, e);
+                return;
+            }
+            self.peer.answer(msg);
+            return;
+        }
+
         let handler_shutdown_waiter = self.handler_shutdown.subscribe();
         let context = self.context();
+        let stream_id = msg.header.stream_id;
+        let is_request = msg.header.type_ == MESSAGE_TYPE_REQUEST;
+        if is_request {
+            self.last_stream_id.fetch_max(stream_id, Ordering::Relaxed);
+        }
+        let max_len = self
+            .config
+            .max_message_size
+            .max(self.max_chunked_message_size);
+        if let Err(e) = compression::decompress_message(&mut msg, max_len) {
+            context
+                .respond_with_status(stream_id, get_status(Code::INVALID_ARGUMENT, e))
+                .await;
+            return;
+        }
+        // Held until the handler is done with the message.
+        let _charge = self.memory.charge(msg.payload.len());
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        let mut request_charge = None;
+        if is_request {
+            let refused = {
+                let mut cancels = self.cancels.lock().unwrap();
+                if self.closing.load(Ordering::Relaxed) {
+                    Some(get_shutdown_status(
+                        ShutdownReason::Drain,
+                        "the client is closing the connection",
+                    ))
+                } else if cancels.len() >= self.config.max_concurrent_streams {
+                    debug!(
+                        "fd {} is over the limit of {} concurrent streams",
+                        self.fd, self.config.max_concurrent_streams
+                    );
+                    Some(get_status(
+                        Code::RESOURCE_EXHAUSTED,
+                        format!(
+                            "too many concurrent streams, the limit is {}",
+                            self.config.max_concurrent_streams
+                        ),
+                    ))
+                } else {
+                    request_charge = self.requests.try_charge(msg.payload.len());
+                    if request_charge.is_some() {
+                        cancels.insert(stream_id, cancel_tx);
+                        None
+                    } else {
+                        debug!("fd {} is over its request budget", self.fd);
+                        Some(get_status(
+                            Code::RESOURCE_EXHAUSTED,
+                            "too many request bytes being handled",
+                        ))
+                    }
+                }
+            };
+            if let Some(status) = refused {
+                context.respond_with_status(stream_id, status).await;
+                return;
+            }
+        }
+        // On a serial connection, a request waits for the one before it.
+        let (previous, done) = match self.serial.as_ref().filter(|_| is_request) {
+            Some(serial) => {
+                let (done_tx, done_rx) = oneshot::channel::<()>();
+                (serial.lock().unwrap().replace(done_rx), Some(done_tx))
+            }
+            None => (None, None),
+        };
+        let cancels = self.cancels.clone();
+        let windows = self.windows.clone();
+        let closing = self.closing.clone();
+        let last_stream_id = self.last_stream_id.clone();
+        let tx = self.tx.clone();
+        let cancel = context.cancel.clone();
         spawn(async move {
+            let _charge = _charge;
+            let _request_charge = request_charge;
+            let _done = done;
+            let handle = async {
+                if let Some(previous) = previous {
+                    previous.await.ok();
+                }
+                context.handle_msg(msg).await
+            };
             select! {
-                _ = context.handle_msg(msg) => {}
-                _ = handler_shutdown_waiter.wait_shutdown() => {}
+                _ = handle => {}
+                Ok(()) = cancel_rx => {
+                    debug!("Stream id {}: cancelled by the client", stream_id);
+                }
+                _ = handler_shutdown_waiter.wait_shutdown() => {
+                    // The call is being cut short; if it is because the server is
+                    // going away, tell the client so it doesn't have to guess.
+                    if is_request && context.server_shutdown.is_shutdown() {
+                        context
+                            .respond_with_status(
+                                stream_id,
+                                get_shutdown_status(
+                                    ShutdownReason::ForceClose,
+                                    "server shutdown timed out before the call completed",
+                                ),
+                            )
+                            .await;
+                    }
+                }
+            }
+            // A stream handler runs on, tell it to stop.
+            cancel.cancel();
+            if is_request {
+                let closed = {
+                    let mut cancels = cancels.lock().unwrap();
+                    cancels.remove(&stream_id);
+                    closing.load(Ordering::Relaxed) && cancels.is_empty()
+                };
+                windows.lock().unwrap().remove(&stream_id);
+                if closed {
+                    send_close_ack(&tx, last_stream_id.load(Ordering::Relaxed)).await;
+                }
             }
         });
+
+        // Stop reading from this connection while the server is over its memory limit.
+        if self.memory.is_exhausted() {
+            trace!("memory limit reached, pausing reads on fd {}", self.fd);
+            select! {
+                _ = self.memory.wait_available() => {}
+                _ = self.server_shutdown.wait_shutdown() => {}
+            }
+        }
+    }
+
+    async fn handle_oversized(&self, header: MessageHeader, status: Status) {
+        *self.last_active.lock().unwrap() = utils::now();
+        match header.type_ {
+            MESSAGE_TYPE_REQUEST => {
+                self.context()
+                    .respond_with_status(header.stream_id, status)
+                    .await;
+            }
+            MESSAGE_TYPE_DATA => {
+                // The stream handler can't go on without the skipped data.
+                let stream_tx = self.streams.lock().unwrap().remove(&header.stream_id);
+                if let Some(stream_tx) = stream_tx {
+                    stream_tx.send(Err(Error::RpcStatus(status))).await.ok();
+                }
+            }
+            _ => debug!("Skipped oversized message {:?}", header),
+        }
+    }
+
+    fn max_message_size(&self) -> usize {
+        self.config.max_message_size
+    }
+
+    fn max_chunked_message_size(&self) -> usize {
+        self.max_chunked_message_size
+    }
+
+    fn header_limits(&self) -> HeaderLimits {
+        self.header_limits
+    }
+
+    fn read_buffer_size(&self) -> usize {
+        self.config.read_buffer_size
+    }
+
+    fn buffer_pool(&self) -> Option<Arc<BufferPool>> {
+        self.buffer_pool.clone()
+    }
+
+    fn io_timeouts(&self) -> IoTimeouts {
+        self.config.io_timeouts()
     }
 }
 
 impl ServerReader {
+    // Tells the client no more requests will be handled, best effort as the
+    // connection is about to close anyway.
+    fn send_goaway(&self) {
+        let msg = match goaway(self.last_stream_id.load(Ordering::Relaxed), false) {
+            Some(msg) => msg,
+            None => return,
+        };
+        if let Err(e) = self.tx.try_send(msg) {
+            debug!("Failed to send go away on fd {}: {}", self.fd, e);
+        }
+    }
+
+    // The client is closing the connection. No more requests are taken, and
+    // the client is told once the responses to the ones before are sent.
+    async fn handle_close(&self) {
+        debug!("Client on fd {} is closing the connection", self.fd);
+        let closed = {
+            let cancels = self.cancels.lock().unwrap();
+            self.closing.store(true, Ordering::Relaxed);
+            cancels.is_empty()
+        };
+        if closed {
+            send_close_ack(&self.tx, self.last_stream_id.load(Ordering::Relaxed)).await;
+        }
+    }
+
+    // Frames that don't carry a request or data for an active stream.
+    fn is_control_frame(&self, msg: &GenMessage) -> bool {
+        match msg.header.type_ {
+            MESSAGE_TYPE_REQUEST => false,
+            MESSAGE_TYPE_WINDOW_UPDATE => !self
+                .cancels
+                .lock()
+                .unwrap()
+                .contains_key(&msg.header.stream_id),
+            MESSAGE_TYPE_DATA => !self
+                .streams
+                .lock()
+                .unwrap()
+                .contains_key(&msg.header.stream_id),
+            _ => true,
+        }
+    }
+
+    // Stop the handling of a request, the client is no longer waiting for it.
+    async fn cancel(&self, stream_id: u32) {
+        if let Some(cancel_tx) = self.cancels.lock().unwrap().remove(&stream_id) {
+            cancel_tx.send(()).ok();
+        }
+        // A stream handler runs on its own task, fail its pending receive.
+        let stream_tx = self.streams.lock().unwrap().remove(&stream_id);
+        if let Some(stream_tx) = stream_tx {
+            stream_tx
+                .send(Err(Error::RpcStatus(get_status(
+                    Code::CANCELLED,
+                    "cancelled by the client",
+                ))))
+                .await
+                .ok();
+        }
+    }
+
     fn context(&self) -> HandlerContext {
         HandlerContext {
             fd: self.fd,
+            info: self.info.clone(),
             tx: self.tx.clone(),
+            peer: self.peer.clone(),
             services: self.services.clone(),
+            interceptors: self.interceptors.clone(),
+            fallback: self.fallback.clone(),
+            local: self.local.clone(),
+            raw_services: self.raw_services.clone(),
+            memory: self.memory.clone(),
+            metrics: self.hooks.metrics.clone(),
+            compression: self.compression,
+            accept: AtomicU8::new(0),
+            cancel_key: AtomicBool::new(false),
+            one_way: AtomicBool::new(false),
+            events: self.events.clone(),
             streams: self.streams.clone(),
+            stream_window: self.stream_window,
+            windows: self.windows.clone(),
+            server_shutdown: self.server_shutdown.clone(),
+            received: utils::now(),
+            cancel: CancellationToken::default(),
             _handler_shutdown_waiter: self.handler_shutdown.subscribe(),
         }
     }
 }
 
+// The response of a call whose handler panicked.
+fn panic_response(path: &str, panic: Box<dyn Any + Send>) -> Response {
+    let mut res = Response::new();
+    res.set_status(common::panic_status(path, panic));
+    res
+}
+
+fn goaway(last_stream_id: u32, ack: bool) -> Option<GenMessage> {
+    let goaway = GoAway {
+        last_stream_id,
+        ack,
+        ..Default::default()
+    };
+    let payload = match goaway.encode() {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!("Encode GoAway failed: {:?}", e);
+            return None;
+        }
+    };
+    Some(GenMessage {
+        header: MessageHeader::new_goaway(payload.len() as u32),
+        payload: payload.into(),
+    })
+}
+
+// Acknowledges the close of the client, queued after the last responses.
+async fn send_close_ack(tx: &MessageSender, last_stream_id: u32) {
+    if let Some(msg) = goaway(last_stream_id, true) {
+        tx.send(msg).await.ok();
+    }
+}
+
 struct HandlerContext {
     fd: RawFd,
+    info: Arc<ConnectionInfo>,
     tx: MessageSender,
-    services: Arc<HashMap<String, Service>>,
+    peer: Peer,
+    services: Services,
+    interceptors: Arc<Vec<Arc<dyn Interceptor>>>,
+    fallback: Option<Arc<dyn MethodHandler + Send + Sync>>,
+    local: Arc<LocalExecutor>,
+    raw_services: RawServices,
+    memory: Arc<MemoryBudget>,
+    metrics: Metrics,
+    compression: Option<CompressionConfig>,
+    // Compression algorithms the client accepts for the response, known
+    // once the request is decoded.
+    accept: AtomicU8,
+    // The client offered to cancel its calls, the response agrees to.
+    cancel_key: AtomicBool,
+    // The client wants no response, whatever comes of the request, known
+    // once the request is decoded.
+    one_way: AtomicBool,
+    events: EventSender,
     streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
+    stream_window: Option<u32>,
+    windows: Windows,
+    server_shutdown: shutdown::Waiter,
+    // When the message was read off the connection, deadlines count from here.
+    received: Instant,
+    // Fired once the call is over, handed to the handler.
+    cancel: CancellationToken,
     // Used for waiting handler exit.
     _handler_shutdown_waiter: shutdown::Waiter,
 }
@@ -406,8 +1752,7 @@ impl HandlerContext {
         let stream_id = msg.header.stream_id;
 
         if (stream_id % 2) != 1 {
-            Self::respond_with_status(
-                self.tx.clone(),
+            self.respond_with_status(
                 stream_id,
                 get_status(Code::INVALID_ARGUMENT, "stream id must be odd"),
             )
@@ -419,7 +1764,7 @@ impl HandlerContext {
             MESSAGE_TYPE_REQUEST => match self.handle_request(msg).await {
                 Ok(opt_msg) => match opt_msg {
                     Some(msg) => {
-                        Self::respond(self.tx.clone(), stream_id, msg)
+                        self.respond(stream_id, msg)
                             .await
                             .map_err(|e| {
                                 error!("respond got error {:?}", e);
@@ -431,7 +1776,7 @@ impl HandlerContext {
                         header.set_flags(FLAG_REMOTE_CLOSED | FLAG_NO_DATA);
                         let msg = GenMessage {
                             header,
-                            payload: Vec::new(),
+                            payload: Bytes::new(),
                         };
 
                         self.tx
@@ -441,15 +1786,14 @@ impl HandlerContext {
                             .ok();
                     }
                 },
-                Err(status) => Self::respond_with_status(self.tx.clone(), stream_id, status).await,
+                Err(status) => self.respond_with_status(stream_id, status).await,
             },
             MESSAGE_TYPE_DATA => {
                 // TODO(wllenyj): Compatible with golang behavior.
                 if (msg.header.flags & FLAG_REMOTE_CLOSED) == FLAG_REMOTE_CLOSED
                     && !msg.payload.is_empty()
                 {
-                    Self::respond_with_status(
-                        self.tx.clone(),
+                    self.respond_with_status(
                         stream_id,
                         get_status(
                             Code::INVALID_ARGUMENT,
@@ -465,8 +1809,7 @@ impl HandlerContext {
                 let stream_tx = self.streams.lock().unwrap().get(&stream_id).cloned();
                 if let Some(stream_tx) = stream_tx {
                     if let Err(e) = stream_tx.send(Ok(msg)).await {
-                        Self::respond_with_status(
-                            self.tx.clone(),
+                        self.respond_with_status(
                             stream_id,
                             get_status(
                                 Code::INVALID_ARGUMENT,
@@ -476,8 +1819,7 @@ impl HandlerContext {
                         .await;
                     }
                 } else {
-                    Self::respond_with_status(
-                        self.tx.clone(),
+                    self.respond_with_status(
                         stream_id,
                         get_status(Code::INVALID_ARGUMENT, "Stream is no longer active"),
                     )
@@ -499,25 +1841,97 @@ impl HandlerContext {
         //}
         // self.last_stream_id = header.stream_id;
 
+        if self.server_shutdown.is_shutdown() {
+            return Err(get_shutdown_status(
+                ShutdownReason::Drain,
+                "server is shutting down and accepts no new calls",
+            ));
+        }
+
         let req_msg = Message::<Request>::try_from(msg)
             .map_err(|e| get_status(Code::INVALID_ARGUMENT, e.to_string()))?;
 
         let req = &req_msg.payload;
         trace!("Got Message request {} {}", req.service, req.method);
+        self.accept
+            .store(compression::accepted(req), Ordering::Relaxed);
+        self.cancel_key
+            .store(common::has_cancel_key(&req.metadata), Ordering::Relaxed);
+        self.one_way
+            .store(common::is_one_way(&req_msg.header, req), Ordering::Relaxed);
+        if common::is_probe(req) {
+            return Ok(Some(Response::new()));
+        }
 
-        let srv = self.services.get(&req.service).ok_or_else(|| {
-            get_status(
-                Code::INVALID_ARGUMENT,
-                format!("{} service does not exist", &req.service),
-            )
-        })?;
+        let stream_id = req_msg.header.stream_id;
+        let path = self.events.is_active().then(|| {
+            let path = utils::get_path(&req.service, &req.method);
+            self.events.send(ServerEvent::RequestStarted {
+                fd: self.fd,
+                stream_id,
+                path: path.clone(),
+            });
+            path
+        });
+        let span = spans::call(
+            spans::Kind::Server,
+            &req.service,
+            &req.method,
+            Some(stream_id),
+            Some(self.info.as_ref()),
+        );
+        let res = spans::instrument(span, self.dispatch(req_msg)).await;
+        if let Some(path) = path {
+            let code = match &res {
+                Ok(Some(res)) => res.status().code(),
+                Ok(None) => Code::OK,
+                Err(status) => status.code(),
+            };
+            self.events.send(ServerEvent::RequestFinished {
+                fd: self.fd,
+                stream_id,
+                path,
+                code,
+                elapsed: utils::now().saturating_duration_since(self.received),
+            });
+        }
+        res
+    }
 
-        if let Some(method) = srv.get_method(&req.method) {
+    async fn dispatch(&self, req_msg: Message<Request>) -> StdResult<Option<Response>, Status> {
+        let req = &req_msg.payload;
+        let srv = self.services.read().unwrap().get(&req.service).cloned();
+
+        if let Some(method) = srv.as_ref().and_then(|srv| srv.get_method(&req.method)) {
             return self.handle_method(method, req_msg).await;
         }
-        if let Some(stream) = srv.get_stream(&req.method) {
+        if let Some(stream) = srv.as_ref().and_then(|srv| srv.get_stream(&req.method)) {
+            if self.one_way.load(Ordering::Relaxed) {
+                return Err(get_status(
+                    Code::INVALID_ARGUMENT,
+                    format!(
+                        "{} method is a stream, it can't be called one-way",
+                        &req.method
+                    ),
+                ));
+            }
             return self.handle_stream(stream, req_msg).await;
         }
+        if srv.is_none() && self.local.has_service(&req.service) {
+            return self.handle_method(self.local.as_ref(), req_msg).await;
+        }
+        if let Some(raw) = self.raw_services.get(&req.service) {
+            return self.handle_method(raw, req_msg).await;
+        }
+        if let Some(fallback) = self.fallback.as_deref() {
+            return self.handle_method(fallback, req_msg).await;
+        }
+        if srv.is_none() {
+            return Err(get_status(
+                Code::INVALID_ARGUMENT,
+                format!("{} service does not exist", &req.service),
+            ));
+        }
         Err(get_status(
             Code::UNIMPLEMENTED,
             format!("{} method", &req.method),
@@ -532,42 +1946,76 @@ impl HandlerContext {
         let req = req_msg.payload;
         let path = utils::get_path(&req.service, &req.method);
 
+        let deadline = common::get_deadline(self.received, req.timeout_nano);
         let ctx = TtrpcContext {
             fd: self.fd,
             mh: req_msg.header,
             metadata: context::from_pb(&req.metadata),
             timeout_nano: req.timeout_nano,
+            peer_cred: self.info.peer_cred,
+            connection: self.info.clone(),
+            received: SystemTime::now(),
+            response_metadata: Default::default(),
+            response_status: Default::default(),
+            deadline,
+            cancel: self.cancel.with_deadline(deadline),
+            peer: Some(self.peer.clone()),
         };
 
         let get_unknown_status_and_log_err = |e| {
             error!("method handle {} got error {:?}", path, &e);
             get_status(Code::UNKNOWN, e)
         };
-        if req.timeout_nano == 0 {
-            method
-                .handler(ctx, req)
+        if let Some(deadline) = deadline {
+            if ctx.deadline_exceeded() {
+                // The client has already given up, don't bother calling the handler.
+                debug!("method handle {} skipped, deadline exceeded", path);
+                return Err(get_status(Code::DEADLINE_EXCEEDED, "timeout"));
+            }
+            timeout_at(deadline.into(), self.intercept(ctx, req, method))
                 .await
-                .map_err(get_unknown_status_and_log_err)
-                .map(Some)
+                .map_err(|_| {
+                    // Timed out
+                    error!("method handle {} got error timed out", path);
+                    get_status(Code::DEADLINE_EXCEEDED, "timeout")
+                })
+                .and_then(|r| {
+                    // Handler finished
+                    r.map_err(get_unknown_status_and_log_err)
+                })
         } else {
-            timeout(
-                Duration::from_nanos(req.timeout_nano as u64),
-                method.handler(ctx, req),
-            )
-            .await
-            .map_err(|_| {
-                // Timed out
-                error!("method handle {} got error timed out", path);
-                get_status(Code::DEADLINE_EXCEEDED, "timeout")
-            })
-            .and_then(|r| {
-                // Handler finished
-                r.map_err(get_unknown_status_and_log_err)
-            })
-            .map(Some)
+            self.intercept(ctx, req, method)
+                .await
+                .map_err(get_unknown_status_and_log_err)
         }
     }
 
+    // Runs the interceptors, then the handler of a method.
+    async fn intercept(
+        &self,
+        ctx: TtrpcContext,
+        req: Request,
+        method: &(dyn MethodHandler + Send + Sync),
+    ) -> Result<Option<Response>> {
+        let handler = move |ctx, req: Request| {
+            async move {
+                let path = utils::get_path(&req.service, &req.method);
+                // A panicking handler only fails its call.
+                match AssertUnwindSafe(method.handler(ctx, req))
+                    .catch_unwind()
+                    .await
+                {
+                    Ok(res) => res.map(Some),
+                    Err(panic) => Ok(Some(panic_response(&path, panic))),
+                }
+            }
+            .boxed()
+        };
+        Next::new(&self.interceptors[..], handler)
+            .run(ctx, req)
+            .await
+    }
+
     async fn handle_stream(
         &self,
         stream: Arc<dyn StreamHandler + Send + Sync>,
@@ -581,7 +2029,7 @@ impl HandlerContext {
         let stream_tx = tx.clone();
         self.streams.lock().unwrap().insert(stream_id, tx);
 
-        let _remote_close = (req_msg.header.flags & FLAG_REMOTE_CLOSED) == FLAG_REMOTE_CLOSED;
+        let remote_close = (req_msg.header.flags & FLAG_REMOTE_CLOSED) == FLAG_REMOTE_CLOSED;
         let _remote_open = (req_msg.header.flags & FLAG_REMOTE_OPEN) == FLAG_REMOTE_OPEN;
         let si = StreamInner::new(
             stream_id,
@@ -591,55 +2039,108 @@ impl HandlerContext {
             true,
             Kind::Server,
             self.streams.clone(),
-        );
+        )
+        .with_flow_control(&self.windows, self.stream_window);
 
+        let deadline = common::get_deadline(self.received, req.timeout_nano);
         let ctx = TtrpcContext {
             fd: self.fd,
             mh: req_msg.header,
             metadata: context::from_pb(&req.metadata),
             timeout_nano: req.timeout_nano,
+            peer_cred: self.info.peer_cred,
+            connection: self.info.clone(),
+            received: SystemTime::now(),
+            response_metadata: Default::default(),
+            response_status: Default::default(),
+            deadline,
+            cancel: self.cancel.with_deadline(deadline),
+            peer: Some(self.peer.clone()),
         };
 
-        let task = spawn(async move { stream.handler(ctx, si).await });
-
-        if !req.payload.is_empty() {
-            // Fake the first data message.
-            let msg = GenMessage {
-                header: MessageHeader::new_data(stream_id, req.payload.len() as u32),
-                payload: req.payload,
-            };
-            stream_tx.send(Ok(msg)).await.map_err(|e| {
-                error!("send stream data {} got error {:?}", path, &e);
-                get_status(Code::UNKNOWN, e)
-            })?;
-        }
-        task.await
-            .unwrap_or_else(|e| {
-                Err(Error::Others(format!(
-                    "stream {} task got error {:?}",
-                    path, e
-                )))
-            })
+        // The handler runs on its own task, which keeps the server shutting
+        // down until it's done. Once the call is cut short, it's told to
+        // stop through the cancellation token of its context.
+        let server_shutdown = self.server_shutdown.clone();
+        let handler = move |ctx, req: Request| {
+            async move {
+                let task = spawn(async move {
+                    let _server_shutdown = server_shutdown;
+                    stream.handler(ctx, si).await
+                });
+
+                // Fake the first data message. When the client doesn't stream, the
+                // request is the only one and comes even if it's encoded as nothing,
+                // as an empty message is.
+                if !req.payload.is_empty() || remote_close {
+                    let msg = GenMessage {
+                        header: MessageHeader::new_data(stream_id, req.payload.len() as u32),
+                        payload: req.payload.into(),
+                    };
+                    stream_tx.send(Ok(msg)).await.map_err(|e| {
+                        error!("send stream data {} got error {:?}", path, &e);
+                        Error::Others(e.to_string())
+                    })?;
+                }
+                match task.await {
+                    Ok(res) => res,
+                    Err(e) if e.is_panic() => Ok(Some(panic_response(&path, e.into_panic()))),
+                    Err(e) => Err(Error::Others(format!(
+                        "stream {} task got error {:?}",
+                        path, e
+                    ))),
+                }
+            }
+            .boxed()
+        };
+        Next::new(&self.interceptors[..], handler)
+            .run(ctx, req)
+            .await
             .map_err(|e| get_status(Code::UNKNOWN, e))
     }
 
-    async fn respond(tx: MessageSender, stream_id: u32, resp: Response) -> Result<()> {
+    async fn respond(&self, stream_id: u32, mut resp: Response) -> Result<()> {
+        if self.one_way.load(Ordering::Relaxed) {
+            if resp.status().code() != Code::OK {
+                debug!(
+                    "Stream id {}: one-way request failed: {:?}",
+                    stream_id,
+                    resp.status()
+                );
+            }
+            return Ok(());
+        }
+        if self.cancel_key.load(Ordering::Relaxed) {
+            common::add_cancel_key(&mut resp.metadata);
+        }
         let payload = resp
             .encode()
             .map_err(err_to_others_err!(e, "Encode Response failed."))?;
-        let msg = GenMessage {
+        let mut msg = GenMessage {
             header: MessageHeader::new_response(stream_id, payload.len() as u32),
-            payload,
+            payload: payload.into(),
         };
-        tx.send(msg)
-            .await
-            .map_err(err_to_others_err!(e, "Send packet to sender error "))
+        if let Some(config) = self.compression.as_ref() {
+            let accept = self.accept.load(Ordering::Relaxed);
+            if let Err(e) = compression::compress_message(&mut msg, config, accept) {
+                debug!("send response uncompressed: {:?}", e);
+            }
+        }
+        let len = msg.payload.len();
+        // Released by ServerWriter once the response is dequeued for writing.
+        self.memory.add(len);
+        self.metrics.response_queued();
+        self.tx.send(msg).await.map_err(|e| {
+            self.memory.release(len);
+            self.metrics.response_dequeued();
+            Error::Others(format!("Send packet to sender error {}", e))
+        })
     }
 
-    async fn respond_with_status(tx: MessageSender, stream_id: u32, status: Status) {
+    async fn respond_with_status(&self, stream_id: u32, status: Status) {
         let mut resp = Response::new();
         resp.set_status(status);
-        Self::respond(tx, stream_id, resp)
+        self.respond(stream_id, resp)
             .await
             .map_err(|e| {
                 error!("respond with status got error {:?}", e);
@@ -647,3 +2148,601 @@ impl HandlerContext {
             .ok();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::r#async::{Client, StreamInner};
+    use crate::service_fn::{self, Raw};
+    use async_trait::async_trait;
+    use std::os::unix::io::IntoRawFd;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::UnixStream;
+    use tokio::sync::mpsc;
+
+    // A server listening on a socket unique to the test, and the address
+    // to connect to.
+    fn server(name: &str) -> (Server, String) {
+        let path =
+            std::env::temp_dir().join(format!("ttrpc-async-{}-{}.sock", name, std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let listener = SysUnixListener::bind(&path).unwrap();
+        let server = Server::new()
+            .add_listener(listener.into_raw_fd())
+            .unwrap()
+            .set_domain_unix();
+        (server, format!("unix://{}", path.display()))
+    }
+
+    fn request(method: &str) -> Request {
+        Request {
+            service: "test.Svc".to_string(),
+            method: method.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // Tells the test once the handler holding it is over.
+    struct Ended(mpsc::UnboundedSender<()>);
+
+    impl Drop for Ended {
+        fn drop(&mut self) {
+            self.0.send(()).ok();
+        }
+    }
+
+    // A stream handler that runs until its call is cancelled.
+    struct Endless(mpsc::UnboundedSender<()>);
+
+    #[async_trait]
+    impl StreamHandler for Endless {
+        async fn handler(
+            &self,
+            ctx: TtrpcContext,
+            _stream: StreamInner,
+        ) -> Result<Option<Response>> {
+            let _ended = Ended(self.0.clone());
+            ctx.cancel.cancelled().await;
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancel_ends_handler() {
+        let (ended_tx, mut ended) = mpsc::unbounded_channel();
+        let endless = ended_tx.clone();
+        let mut streams: HashMap<String, Arc<dyn StreamHandler + Send + Sync>> = HashMap::new();
+        streams.insert("Watch".to_string(), Arc::new(Endless(ended_tx.clone())));
+        let mut services = HashMap::new();
+        services.insert(
+            "test.Svc".to_string(),
+            Service {
+                methods: HashMap::new(),
+                streams,
+            },
+        );
+        let (server, addr) = server("cancel");
+        let mut server = server
+            .register_service(services)
+            .register_method(
+                "test.Svc",
+                "Ping",
+                service_fn::async_method(Raw, |_ctx, req: Vec<u8>| async { Ok(req) }),
+            )
+            .register_method(
+                "test.Svc",
+                "Block",
+                service_fn::async_method(Raw, move |_ctx, _req: Vec<u8>| {
+                    let ended = Ended(endless.clone());
+                    async move {
+                        let _ended = ended;
+                        futures::future::pending::<Result<Vec<u8>>>().await
+                    }
+                }),
+            );
+        server.start().await.unwrap();
+        let client = Client::connect(&addr).unwrap();
+        // The server agrees to calls being cancelled in its first response.
+        client.request(request("Ping")).await.unwrap();
+
+        let call =
+            tokio::time::timeout(Duration::from_millis(50), client.request(request("Block")));
+        assert!(call.await.is_err());
+        tokio::time::timeout(Duration::from_secs(5), ended.recv())
+            .await
+            .expect("the unary handler runs on");
+
+        let stream = client
+            .new_stream(request("Watch"), false, true)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(stream);
+        tokio::time::timeout(Duration::from_secs(5), ended.recv())
+            .await
+            .expect("the stream handler runs on");
+
+        client.close(Duration::from_millis(10)).await.ok();
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_close_drains_calls() {
+        let (started_tx, mut started) = mpsc::unbounded_channel();
+        let release = Arc::new(Notify::new());
+        let slow_release = release.clone();
+        let (server, addr) = server("close-drains");
+        let mut server = server.register_method(
+            "test.Svc",
+            "Slow",
+            service_fn::async_method(Raw, move |_ctx, req: Vec<u8>| {
+                started_tx.send(()).ok();
+                let release = slow_release.clone();
+                async move {
+                    release.notified().await;
+                    Ok(req)
+                }
+            }),
+        );
+        server.start().await.unwrap();
+        let client = Client::connect(&addr).unwrap();
+
+        let caller = client.clone();
+        let call = tokio::spawn(async move { caller.request(request("Slow")).await });
+        started.recv().await.unwrap();
+        let closer = client.clone();
+        let closing = tokio::spawn(async move { closer.close(Duration::from_secs(5)).await });
+        // The server only acknowledges the go away once the call is done.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!closing.is_finished());
+        assert!(matches!(
+            client.request(request("Slow")).await,
+            Err(Error::LocalClosed)
+        ));
+
+        release.notify_one();
+        call.await.unwrap().unwrap();
+        closing.await.unwrap().unwrap();
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let fallback_calls = Arc::new(AtomicUsize::new(0));
+        let calls = fallback_calls.clone();
+        let (server, addr) = server("probe");
+        let mut server = server.set_fallback_handler(service_fn::async_method(
+            Raw,
+            move |_ctx, req: Vec<u8>| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                async { Ok(req) }
+            },
+        ));
+        server.start().await.unwrap();
+        let client = Client::connect(&addr).unwrap();
+
+        // Answered by the server, the fallback handler doesn't see it.
+        client.verify(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(fallback_calls.load(Ordering::SeqCst), 0);
+        client.request(request("Other")).await.unwrap();
+        assert_eq!(fallback_calls.load(Ordering::SeqCst), 1);
+
+        client.close(Duration::from_millis(10)).await.ok();
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_notifications() {
+        let (mut server, addr) = server("notifications");
+        server.start().await.unwrap();
+        let subscribed = Client::connect(&addr).unwrap();
+        let other = Client::connect(&addr).unwrap();
+
+        let mut rx = subscribed.subscribe("config").await.unwrap();
+        let mut other_rx = other.subscribe("other").await.unwrap();
+        // The subscriptions reach the server in the background.
+        while server.notify("config", Vec::new()).await.unwrap() == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(rx.recv().await.unwrap(), b"");
+
+        assert_eq!(
+            server.notify("config", b"refresh".to_vec()).await.unwrap(),
+            1
+        );
+        assert_eq!(rx.recv().await.unwrap(), b"refresh");
+        // Only the topics subscribed to are sent.
+        while server.notify("other", b"other".to_vec()).await.unwrap() == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(other_rx.recv().await.unwrap(), b"other");
+        assert!(rx.try_recv().is_err());
+
+        subscribed.unsubscribe("config").await.unwrap();
+        while server.notify("config", b"gone".to_vec()).await.unwrap() != 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        subscribed.close(Duration::from_millis(10)).await.ok();
+        other.close(Duration::from_millis(10)).await.ok();
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_one_way() {
+        let (handled_tx, mut handled) = mpsc::unbounded_channel();
+        let (server, addr) = server("one-way");
+        let mut server = server.register_method(
+            "test.Svc",
+            "Ping",
+            service_fn::async_method(Raw, move |ctx, req: Vec<u8>| {
+                handled_tx.send(ctx.mh.stream_id).ok();
+                async { Ok(req) }
+            }),
+        );
+        server.start().await.unwrap();
+        let mut conn = UnixStream::connect(addr.trim_start_matches("unix://"))
+            .await
+            .unwrap();
+
+        let mut one_way = request("Ping");
+        common::add_no_response_key(&mut one_way);
+        for (stream_id, req) in [(1, one_way), (3, request("Ping"))] {
+            let msg = GenMessage::try_from(Message::new_request(stream_id, req)).unwrap();
+            msg.write_to(&mut conn).await.unwrap();
+        }
+        // The server acknowledges the go away after the last response.
+        goaway(3, false).unwrap().write_to(&mut conn).await.unwrap();
+        let mut responses = Vec::new();
+        loop {
+            let msg = GenMessage::read_from(&mut conn).await.unwrap();
+            if msg.header.type_ == MESSAGE_TYPE_GOAWAY {
+                break;
+            }
+            responses.push(msg.header.stream_id);
+        }
+        assert_eq!(responses, vec![3]);
+        assert_eq!(
+            handled.recv().await.unwrap() + handled.recv().await.unwrap(),
+            4
+        );
+
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_timeout() {
+        let (started_tx, mut started) = mpsc::unbounded_channel();
+        let (server, addr) = server("shutdown-timeout");
+        let mut server = server
+            .set_shutdown_timeout(Duration::from_millis(50))
+            .register_method(
+                "test.Svc",
+                "Slow",
+                service_fn::async_method(Raw, move |_ctx, _req: Vec<u8>| {
+                    started_tx.send(()).ok();
+                    futures::future::pending::<Result<Vec<u8>>>()
+                }),
+            );
+        server.start().await.unwrap();
+        let client = Client::connect(&addr).unwrap();
+
+        let caller = client.clone();
+        let call = tokio::spawn(async move { caller.request(request("Slow")).await });
+        started.recv().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server.shutdown())
+            .await
+            .expect("the slow handler is cut off")
+            .unwrap();
+        let err = call.await.unwrap().unwrap_err();
+        assert_eq!(err.shutdown_reason(), Some(ShutdownReason::ForceClose));
+
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pause_accept() {
+        let (server, addr) = server("pause-accept");
+        let mut server = server.register_method(
+            "test.Svc",
+            "Ping",
+            service_fn::async_method(Raw, |_ctx, req: Vec<u8>| async { Ok(req) }),
+        );
+        server.start().await.unwrap();
+        let client = Client::connect(&addr).unwrap();
+        client.request(request("Ping")).await.unwrap();
+
+        server.pause_accept().await;
+        // The client connects, but its connection waits to be accepted.
+        let mut conn = UnixStream::connect(addr.trim_start_matches("unix://"))
+            .await
+            .unwrap();
+        let msg = GenMessage::try_from(Message::new_request(1, request("Ping"))).unwrap();
+        msg.write_to(&mut conn).await.unwrap();
+        let read =
+            tokio::time::timeout(Duration::from_millis(100), GenMessage::read_from(&mut conn));
+        assert!(read.await.is_err());
+        // The connections open are served on.
+        client.request(request("Ping")).await.unwrap();
+
+        server.resume_accept().await.unwrap();
+        let msg = tokio::time::timeout(Duration::from_secs(5), GenMessage::read_from(&mut conn))
+            .await
+            .expect("the connection is accepted")
+            .unwrap();
+        assert_eq!(msg.header.stream_id, 1);
+
+        client.close(Duration::from_millis(10)).await.ok();
+        server.close().await.unwrap();
+    }
+
+    async fn max_connections_server(name: &str, over: ConnectionLimit) -> (Server, String) {
+        let (server, addr) = server(name);
+        let mut server = server.set_max_connections(1, over).register_method(
+            "test.Svc",
+            "Ping",
+            service_fn::async_method(Raw, |_ctx, req: Vec<u8>| async { Ok(req) }),
+        );
+        server.start().await.unwrap();
+        (server, addr.trim_start_matches("unix://").to_string())
+    }
+
+    async fn write_request(conn: &mut UnixStream, stream_id: u32) {
+        let msg = GenMessage::try_from(Message::new_request(stream_id, request("Ping"))).unwrap();
+        msg.write_to(conn).await.unwrap();
+    }
+
+    async fn read_response(conn: &mut UnixStream) -> Result<GenMessage> {
+        tokio::time::timeout(Duration::from_secs(5), GenMessage::read_from(conn))
+            .await
+            .expect("the response is written")
+    }
+
+    // Connects and makes a call, so the connection is known to be accepted.
+    async fn ping(path: &str) -> UnixStream {
+        let mut conn = UnixStream::connect(path).await.unwrap();
+        write_request(&mut conn, 1).await;
+        assert_eq!(read_response(&mut conn).await.unwrap().header.stream_id, 1);
+        conn
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_wait() {
+        let (server, path) = max_connections_server("max-wait", ConnectionLimit::Wait).await;
+        let first = ping(&path).await;
+
+        let mut waiting = UnixStream::connect(&path).await.unwrap();
+        write_request(&mut waiting, 1).await;
+        let read = tokio::time::timeout(
+            Duration::from_millis(100),
+            GenMessage::read_from(&mut waiting),
+        );
+        assert!(read.await.is_err());
+
+        // There's room once the first connection closes.
+        drop(first);
+        let msg = read_response(&mut waiting).await.unwrap();
+        assert_eq!(msg.header.stream_id, 1);
+
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_reject() {
+        let (server, path) = max_connections_server("max-reject", ConnectionLimit::Reject).await;
+        let mut first = ping(&path).await;
+
+        let mut rejected = UnixStream::connect(&path).await.unwrap();
+        assert!(read_response(&mut rejected).await.is_err());
+        // The connection open is served on.
+        write_request(&mut first, 3).await;
+        assert_eq!(read_response(&mut first).await.unwrap().header.stream_id, 3);
+
+        drop(first);
+        server.close().await.unwrap();
+    }
+
+    struct Named(&'static str);
+
+    #[async_trait]
+    impl RawService for Named {
+        async fn call(&self, _: &TtrpcContext, _: &str, _: &str, _: Vec<u8>) -> Result<Vec<u8>> {
+            Ok(self.0.as_bytes().to_vec())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_order() {
+        let (server, addr) = server("dispatch-order");
+        let mut server = server
+            .register_method(
+                "test.Svc",
+                "Ping",
+                service_fn::async_method(Raw, |_ctx, _req: Vec<u8>| async {
+                    Ok(b"method".to_vec())
+                }),
+            )
+            .register_raw_service("test.", Named("raw"))
+            .set_fallback_handler(service_fn::async_method(Raw, |_ctx, _req: Vec<u8>| async {
+                Ok(b"fallback".to_vec())
+            }));
+        server.start().await.unwrap();
+        let client = Client::connect(&addr).unwrap();
+
+        // Registered methods first, then raw services, then the fallback.
+        for (service, method, handler) in [
+            ("test.Svc", "Ping", "method"),
+            ("test.Svc", "Other", "raw"),
+            ("other.Svc", "Ping", "fallback"),
+        ] {
+            let req = Request {
+                service: service.to_string(),
+                method: method.to_string(),
+                ..Default::default()
+            };
+            let res = client.request(req).await.unwrap();
+            assert_eq!(res.payload, handler.as_bytes(), "{}/{}", service, method);
+        }
+
+        client.close(Duration::from_millis(10)).await.ok();
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_add_services() {
+        let (mut server, addr) = server("add-services");
+        server.start().await.unwrap();
+        let client = Client::connect(&addr).unwrap();
+        assert!(client.request(request("Ping")).await.is_err());
+
+        // Calls on the connections open already see the new service.
+        let method: Box<dyn MethodHandler + Send + Sync> =
+            Box::new(service_fn::async_method(Raw, |_ctx, req: Vec<u8>| async {
+                Ok(req)
+            }));
+        let service = Service {
+            methods: HashMap::from([("Ping".to_string(), method)]),
+            streams: HashMap::new(),
+        };
+        server.add_services(HashMap::from([("test.Svc".to_string(), service)]));
+        client.request(request("Ping")).await.unwrap();
+
+        assert!(server.remove_service("test.Svc"));
+        assert!(client.request(request("Ping")).await.is_err());
+
+        client.close(Duration::from_millis(10)).await.ok();
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_close_reasons() {
+        let (opened_tx, mut opened) = mpsc::unbounded_channel();
+        let (closed_tx, mut closed) = mpsc::unbounded_channel();
+        let (server, addr) = server("close-reasons");
+        let path = addr.trim_start_matches("unix://").to_string();
+        let mut server = server
+            .set_idle_timeout(Duration::from_millis(200))
+            .on_connection_opened(move |_info| opened_tx.send(()).unwrap())
+            .on_connection_closed(move |_info, reason| closed_tx.send(reason.clone()).unwrap());
+        server.start().await.unwrap();
+        let timeout = Duration::from_secs(5);
+
+        drop(UnixStream::connect(&path).await.unwrap());
+        let reason = tokio::time::timeout(timeout, closed.recv()).await.unwrap();
+        assert_eq!(reason, Some(CloseReason::Client));
+
+        // A header cut short.
+        let mut conn = UnixStream::connect(&path).await.unwrap();
+        conn.write_all(&[0; 3]).await.unwrap();
+        conn.shutdown().await.unwrap();
+        let reason = tokio::time::timeout(timeout, closed.recv()).await.unwrap();
+        assert!(
+            matches!(reason, Some(CloseReason::Error(_))),
+            "{:?}",
+            reason
+        );
+
+        let _idle = UnixStream::connect(&path).await.unwrap();
+        let reason = tokio::time::timeout(timeout, closed.recv()).await.unwrap();
+        assert_eq!(reason, Some(CloseReason::Idle));
+
+        let _open = UnixStream::connect(&path).await.unwrap();
+        for _ in 0..4 {
+            opened.recv().await.unwrap();
+        }
+        server.shutdown().await.unwrap();
+        let reason = tokio::time::timeout(timeout, closed.recv()).await.unwrap();
+        assert_eq!(reason, Some(CloseReason::Shutdown));
+
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_serial_requests() {
+        let (server, addr) = server("serial");
+        let mut server = server.set_serial_requests(true).register_method(
+            "test.Svc",
+            "Ping",
+            service_fn::async_method(Raw, |_ctx, req: Vec<u8>| async move {
+                tokio::time::sleep(Duration::from_millis(req[0].into())).await;
+                Ok(req)
+            }),
+        );
+        server.start().await.unwrap();
+
+        let mut conn = UnixStream::connect(addr.trim_start_matches("unix://"))
+            .await
+            .unwrap();
+        // The first call is the slowest, it'd be answered last if the calls
+        // were handled at once.
+        for (stream_id, sleep_ms) in [(1, 50), (3, 0), (5, 0)] {
+            let mut req = request("Ping");
+            req.payload = vec![sleep_ms];
+            let msg = GenMessage::try_from(Message::new_request(stream_id, req)).unwrap();
+            msg.write_to(&mut conn).await.unwrap();
+        }
+        let mut stream_ids = Vec::new();
+        for _ in 0..3 {
+            stream_ids.push(read_response(&mut conn).await.unwrap().header.stream_id);
+        }
+        assert_eq!(stream_ids, [1, 3, 5]);
+
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_writers() {
+        let (started_tx, mut started) = mpsc::unbounded_channel();
+        let release = Arc::new(Notify::new());
+        let slow_release = release.clone();
+        let (server, addr) = server("shutdown-writers");
+        let mut server = server.register_method(
+            "test.Svc",
+            "Slow",
+            service_fn::async_method(Raw, move |_ctx, _req: Vec<u8>| {
+                started_tx.send(()).ok();
+                let release = slow_release.clone();
+                async move {
+                    release.notified().await;
+                    // More than the socket buffers, so it's only written
+                    // once the client reads it.
+                    Ok(vec![0; 1 << 20])
+                }
+            }),
+        );
+        server.start().await.unwrap();
+        let mut conn = UnixStream::connect(addr.trim_start_matches("unix://"))
+            .await
+            .unwrap();
+        let msg = GenMessage::try_from(Message::new_request(1, request("Slow"))).unwrap();
+        msg.write_to(&mut conn).await.unwrap();
+        started.recv().await.unwrap();
+
+        let msg = {
+            let shutdown = server.shutdown();
+            tokio::pin!(shutdown);
+            let pending = Duration::from_millis(50);
+            assert!(tokio::time::timeout(pending, &mut shutdown).await.is_err());
+            // The handler is done, its response is being written.
+            release.notify_one();
+            assert!(tokio::time::timeout(pending, &mut shutdown).await.is_err());
+
+            let read = async {
+                loop {
+                    let msg = read_response(&mut conn).await.unwrap();
+                    // The server tells the client it's going away first.
+                    if msg.header.type_ == MESSAGE_TYPE_RESPONSE {
+                        return msg;
+                    }
+                }
+            };
+            let (shutdown, msg) = tokio::join!(shutdown, read);
+            shutdown.unwrap();
+            msg
+        };
+        let res = Response::decode(&msg.payload).unwrap();
+        assert_eq!(res.payload.len(), 1 << 20);
+
+        server.close().await.unwrap();
+    }
+}
@@ -9,13 +9,17 @@ use std::marker::PhantomData;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
+use bytes::Bytes;
 use tokio::sync::mpsc;
 
+use crate::common;
+use crate::context;
 use crate::error::{Error, Result};
 use crate::proto::{
-    Code, Codec, GenMessage, MessageHeader, Response, FLAG_NO_DATA, FLAG_REMOTE_CLOSED,
+    Code, Codec, GenMessage, MessageHeader, Response, Status, FLAG_NO_DATA, FLAG_REMOTE_CLOSED,
     MESSAGE_TYPE_DATA, MESSAGE_TYPE_RESPONSE,
 };
+use crate::r#async::flow_control::{self, RecvWindow, SendWindow, Windows};
 
 pub type MessageSender = mpsc::Sender<GenMessage>;
 pub type MessageReceiver = mpsc::Receiver<GenMessage>;
@@ -65,6 +69,21 @@ where
     pub async fn recv(&mut self) -> Result<P> {
         self.rx.recv().await
     }
+
+    /// Final status sent by the server, once the stream ended.
+    pub fn status(&self) -> Option<&Status> {
+        self.rx.status()
+    }
+
+    /// Trailing metadata sent by the server with its final status.
+    pub fn trailers(&self) -> &HashMap<String, Vec<String>> {
+        self.rx.trailers()
+    }
+
+    /// Ends the stream, see [`CSReceiver::close()`].
+    pub async fn close(self) -> Result<()> {
+        self.rx.close().await
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -105,6 +124,25 @@ where
         let msg_buf = self.rx.recv().await?;
         P::decode(msg_buf).map_err(err_to_others_err!(e, "Decode message failed."))
     }
+
+    /// Final status sent by the server, once the stream ended.
+    pub fn status(&self) -> Option<&Status> {
+        self.rx.status.as_ref()
+    }
+
+    /// Trailing metadata sent by the server with its final status.
+    pub fn trailers(&self) -> &HashMap<String, Vec<String>> {
+        &self.rx.trailers
+    }
+
+    /// Ends the stream, cancelling it on the server if it isn't over yet.
+    ///
+    /// Dropping the receiver does the same on a best effort basis, the
+    /// cancellation is lost if the connection is busy. This waits for room
+    /// to send it instead.
+    pub async fn close(mut self) -> Result<()> {
+        self.rx.close().await
+    }
 }
 
 #[derive(Debug)]
@@ -218,11 +256,37 @@ where
         self.inner.send(msg_buf).await
     }
 
-    pub async fn close_and_recv(&mut self) -> Result<P> {
-        self.inner.close_send().await?;
+    /// Tells the server no more requests follow, the response can then be
+    /// taken with [`recv()`](Self::recv). Sending afterwards fails with
+    /// [`Error::LocalClosed`].
+    pub async fn close_send(&self) -> Result<()> {
+        self.inner.close_send().await
+    }
+
+    /// Waits for the response, once [`close_send()`](Self::close_send)
+    /// was called.
+    pub async fn recv(&mut self) -> Result<P> {
         let msg_buf = self.inner.recv().await?;
         P::decode(msg_buf).map_err(err_to_others_err!(e, "Decode message failed."))
     }
+
+    pub async fn close_and_recv(&mut self) -> Result<P> {
+        if !self.inner.sender.local_closed.load(Ordering::Relaxed) {
+            self.close_send().await?;
+        }
+        self.recv().await
+    }
+
+    /// Trailing metadata sent by the server with its response.
+    pub fn trailers(&self) -> &HashMap<String, Vec<String>> {
+        &self.inner.receiver.trailers
+    }
+
+    /// Ends the stream without waiting for the response, see
+    /// [`CSReceiver::close()`].
+    pub async fn close(mut self) -> Result<()> {
+        self.inner.receiver.close().await
+    }
 }
 
 pub struct ServerStreamSender<P> {
@@ -277,6 +341,24 @@ where
             .map_err(err_to_others_err!(e, "Decode message failed."))
             .map(Some)
     }
+
+    /// Final status sent by the server, once the stream ended.
+    ///
+    /// Servers only send one when the handler set trailers or a status,
+    /// otherwise the stream ends without it.
+    pub fn status(&self) -> Option<&Status> {
+        self.inner.status.as_ref()
+    }
+
+    /// Trailing metadata sent by the server with its final status.
+    pub fn trailers(&self) -> &HashMap<String, Vec<String>> {
+        &self.inner.trailers
+    }
+
+    /// Ends the stream, see [`CSReceiver::close()`].
+    pub async fn close(mut self) -> Result<()> {
+        self.inner.close().await
+    }
 }
 
 pub struct ServerStreamReceiver<Q> {
@@ -345,6 +427,7 @@ impl StreamInner {
         kind: Kind,
         streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
     ) -> Self {
+        let sender_tx = tx.clone();
         Self {
             sender: StreamSender {
                 tx,
@@ -352,18 +435,55 @@ impl StreamInner {
                 sendable,
                 local_closed: Arc::new(AtomicBool::new(false)),
                 kind,
+                window: None,
             },
             receiver: StreamReceiver {
+                tx: sender_tx,
                 rx,
                 stream_id,
                 recveivable,
                 remote_closed: false,
                 kind,
                 streams,
+                status: None,
+                trailers: HashMap::new(),
+                recv_window: None,
+                send_window: None,
+                windows: None,
+                server_cancels: None,
             },
         }
     }
 
+    /// Puts the stream under flow control, `recv_window` is the room granted
+    /// to the peer, in data messages.
+    pub(crate) fn with_flow_control(mut self, windows: &Windows, recv_window: Option<u32>) -> Self {
+        let send_window = windows
+            .lock()
+            .unwrap()
+            .entry(self.sender.stream_id)
+            .or_default()
+            .clone();
+        self.sender.window = Some(send_window.clone());
+        self.receiver.send_window = Some(send_window);
+        self.receiver.windows = Some(windows.clone());
+        self.receiver.recv_window = recv_window.filter(|w| *w > 0).map(RecvWindow::new);
+        self
+    }
+
+    /// Lets the stream of a client be cancelled once `server_cancels` is
+    /// set, see [`CANCEL_KEY`](crate::proto::CANCEL_KEY).
+    pub(crate) fn with_server_cancels(mut self, server_cancels: &Arc<AtomicBool>) -> Self {
+        self.receiver.server_cancels = Some(server_cancels.clone());
+        self
+    }
+
+    /// Sends the first grant to the peer, which shows it this end takes
+    /// grants too.
+    pub(crate) async fn open_window(&mut self) -> Result<()> {
+        self.receiver.grant().await
+    }
+
     fn split(self) -> (StreamSender, StreamReceiver) {
         (self.sender, self.receiver)
     }
@@ -376,7 +496,7 @@ impl StreamInner {
         self.sender.close_send().await
     }
 
-    pub async fn recv(&mut self) -> Result<Vec<u8>> {
+    pub async fn recv(&mut self) -> Result<Bytes> {
         self.receiver.recv().await
     }
 }
@@ -388,21 +508,49 @@ pub struct StreamSender {
     sendable: bool,
     local_closed: Arc<AtomicBool>,
     kind: Kind,
+    window: Option<Arc<SendWindow>>,
 }
 
 #[derive(Debug)]
 pub struct StreamReceiver {
+    tx: MessageSender,
     rx: ResultReceiver,
     stream_id: u32,
     recveivable: bool,
     remote_closed: bool,
     kind: Kind,
     streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
+    // Set from the response ending the stream.
+    status: Option<Status>,
+    trailers: HashMap<String, Vec<String>>,
+    recv_window: Option<RecvWindow>,
+    send_window: Option<Arc<SendWindow>>,
+    windows: Option<Windows>,
+    // Of a client, set once its server agreed to calls being cancelled.
+    server_cancels: Option<Arc<AtomicBool>>,
 }
 
 impl Drop for StreamReceiver {
     fn drop(&mut self) {
         self.streams.lock().unwrap().remove(&self.stream_id);
+        // The server drops the window once the call is done, its sender
+        // may outlive the receiver.
+        if let (Kind::Client, Some(windows)) = (self.kind, self.windows.as_ref()) {
+            windows.lock().unwrap().remove(&self.stream_id);
+        }
+        // The client went away before the server finished, tell it to stop.
+        if self.kind == Kind::Client && !self.remote_closed && self.can_cancel() {
+            let msg = GenMessage {
+                header: MessageHeader::new_cancel(self.stream_id),
+                payload: Bytes::new(),
+            };
+            if let Err(e) = self.tx.try_send(msg) {
+                warn!(
+                    "Stream id {} dropped without close(), failed to cancel it: {}",
+                    self.stream_id, e
+                );
+            }
+        }
     }
 }
 
@@ -413,10 +561,13 @@ impl StreamSender {
             debug_assert_eq!(self.kind, Kind::Client);
             return Err(Error::LocalClosed);
         }
+        if let Some(window) = self.window.as_ref() {
+            window.acquire(&self.tx).await?;
+        }
         let header = MessageHeader::new_data(self.stream_id, buf.len() as u32);
         let msg = GenMessage {
             header,
-            payload: buf,
+            payload: buf.into(),
         };
         _send(&self.tx, msg).await?;
 
@@ -433,7 +584,7 @@ impl StreamSender {
         header.set_flags(FLAG_REMOTE_CLOSED | FLAG_NO_DATA);
         let msg = GenMessage {
             header,
-            payload: Vec::new(),
+            payload: Bytes::new(),
         };
         _send(&self.tx, msg).await?;
         self.local_closed.store(true, Ordering::Relaxed);
@@ -442,10 +593,60 @@ impl StreamSender {
 }
 
 impl StreamReceiver {
-    pub async fn recv(&mut self) -> Result<Vec<u8>> {
+    // Cancels the stream if the server isn't done with it, waiting for room
+    // to send the cancellation. Dropping the receiver has nothing left to do.
+    async fn close(&mut self) -> Result<()> {
+        self.streams.lock().unwrap().remove(&self.stream_id);
+        if self.kind != Kind::Client || self.remote_closed {
+            return Ok(());
+        }
+        self.remote_closed = true;
+        if !self.can_cancel() {
+            return Ok(());
+        }
+        let msg = GenMessage {
+            header: MessageHeader::new_cancel(self.stream_id),
+            payload: Bytes::new(),
+        };
+        _send(&self.tx, msg).await
+    }
+
+    fn can_cancel(&self) -> bool {
+        self.server_cancels
+            .as_ref()
+            .is_some_and(|c| c.load(Ordering::Relaxed))
+    }
+
+    // Grants the peer more room if it's time to. A server only grants to
+    // clients that sent a grant first, a client only has a window if it
+    // was set up with one.
+    async fn grant(&mut self) -> Result<()> {
+        let peer_aware =
+            self.kind == Kind::Client || self.send_window.as_ref().is_some_and(|w| w.peer_aware());
+        let limit = match self.recv_window.as_mut() {
+            Some(window) if peer_aware => window.grant(),
+            _ => None,
+        };
+        if let Some(limit) = limit {
+            _send(
+                &self.tx,
+                flow_control::window_update(self.stream_id, limit)?,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn recv(&mut self) -> Result<Bytes> {
         if self.remote_closed {
             return Err(Error::RemoteClosed);
         }
+        if let Err(e) = self.grant().await {
+            debug!(
+                "Failed to grant window on stream id {}: {}",
+                self.stream_id, e
+            );
+        }
         let msg = _recv(&mut self.rx).await?;
         let payload = match msg.header.type_ {
             MESSAGE_TYPE_RESPONSE => {
@@ -453,12 +654,23 @@ impl StreamReceiver {
                 self.remote_closed = true;
                 let resp = Response::decode(&msg.payload)
                     .map_err(err_to_others_err!(e, "Decode message failed."))?;
+                if common::has_cancel_key(&resp.metadata) {
+                    if let Some(server_cancels) = self.server_cancels.as_ref() {
+                        server_cancels.store(true, Ordering::Relaxed);
+                    }
+                }
+                self.trailers = context::from_pb(&resp.metadata);
+                self.status = resp.status.as_ref().cloned();
                 if let Some(status) = resp.status.as_ref() {
                     if status.code() != Code::OK {
                         return Err(Error::RpcStatus((*status).clone()));
                     }
                 }
-                resp.payload
+                if self.recveivable {
+                    // The server sent trailers, the stream is over.
+                    return Err(Error::Eof);
+                }
+                resp.payload.into()
             }
             MESSAGE_TYPE_DATA => {
                 if !self.recveivable {
@@ -473,6 +685,9 @@ impl StreamReceiver {
                         return Err(Error::Eof);
                     }
                 }
+                if let Some(window) = self.recv_window.as_mut() {
+                    window.consume();
+                }
                 msg.payload
             }
             _ => {
@@ -482,3 +697,113 @@ impl StreamReceiver {
         Ok(payload)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::get_status;
+    use crate::proto::{KeyValue, MESSAGE_TYPE_CANCEL};
+
+    fn response(code: Code) -> GenMessage {
+        let mut resp = Response::new();
+        resp.set_status(get_status(code, "done"));
+        let mut kv = KeyValue::new();
+        kv.key = "count".to_string();
+        kv.value = "2".to_string();
+        resp.metadata.push(kv);
+        let payload = resp.encode().unwrap();
+        GenMessage {
+            header: MessageHeader::new_response(1, payload.len() as u32),
+            payload: payload.into(),
+        }
+    }
+
+    fn receiver(code: Code) -> ClientStreamReceiver<Status> {
+        let (tx, _rx) = mpsc::channel(1);
+        let (res_tx, res_rx) = mpsc::channel(1);
+        res_tx.try_send(Ok(response(code))).unwrap();
+        let inner = StreamInner::new(1, tx, res_rx, false, true, Kind::Client, Default::default());
+        ClientStreamReceiver::new(inner)
+    }
+
+    #[tokio::test]
+    async fn test_trailers() {
+        let mut stream = receiver(Code::OK);
+        assert!(stream.status().is_none());
+        assert!(stream.recv().await.unwrap().is_none());
+        assert_eq!(stream.status().unwrap().message(), "done");
+        assert_eq!(stream.trailers()["count"], vec!["2".to_string()]);
+
+        let mut stream = receiver(Code::ABORTED);
+        assert!(matches!(
+            stream.recv().await,
+            Err(Error::RpcStatus(s)) if s.code() == Code::ABORTED
+        ));
+        assert_eq!(stream.status().unwrap().code(), Code::ABORTED);
+        assert_eq!(stream.trailers()["count"], vec!["2".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_close_send() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let (res_tx, res_rx) = mpsc::channel(1);
+        let inner = StreamInner::new(1, tx, res_rx, true, false, Kind::Client, Default::default());
+        let mut stream = ClientStreamSender::<Status, Status>::new(inner);
+
+        stream.close_send().await.unwrap();
+        let msg = rx.recv().await.unwrap();
+        assert_eq!(msg.header.type_, MESSAGE_TYPE_DATA);
+        assert_eq!(msg.header.flags, FLAG_REMOTE_CLOSED | FLAG_NO_DATA);
+        assert!(matches!(
+            stream.send(&Status::new()).await,
+            Err(Error::LocalClosed)
+        ));
+
+        res_tx.send(Ok(response(Code::OK))).await.unwrap();
+        assert_eq!(stream.close_and_recv().await.unwrap(), Status::new());
+        assert_eq!(stream.trailers()["count"], vec!["2".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let (_res_tx, res_rx) = mpsc::channel(1);
+        let inner = StreamInner::new(
+            1,
+            tx.clone(),
+            res_rx,
+            false,
+            true,
+            Kind::Client,
+            Default::default(),
+        )
+        .with_server_cancels(&Arc::new(AtomicBool::new(true)));
+        let stream = ClientStreamReceiver::<Status>::new(inner);
+
+        // The connection is busy, dropping the stream couldn't cancel it.
+        tx.try_send(response(Code::OK)).unwrap();
+        drop(tx);
+        let closing = tokio::spawn(stream.close());
+        assert_eq!(rx.recv().await.unwrap().header.type_, MESSAGE_TYPE_RESPONSE);
+        closing.await.unwrap().unwrap();
+
+        let msg = rx.recv().await.unwrap();
+        assert_eq!(msg.header.type_, MESSAGE_TYPE_CANCEL);
+        assert_eq!(msg.header.stream_id, 1);
+        // Nothing more once dropped.
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_close_without_server_cancels() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let (_res_tx, res_rx) = mpsc::channel(1);
+        let inner = StreamInner::new(1, tx, res_rx, false, true, Kind::Client, Default::default())
+            .with_server_cancels(&Arc::default());
+        let stream = ClientStreamReceiver::<Status>::new(inner);
+
+        // The server never agreed to calls being cancelled.
+        stream.close().await.unwrap();
+        assert!(rx.recv().await.is_none());
+    }
+}
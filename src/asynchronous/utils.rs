@@ -6,12 +6,19 @@
 
 use std::collections::HashMap;
 use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use async_trait::async_trait;
 use tokio::net::UnixStream;
+use tokio::sync::Notify;
 
+use crate::common::{ConnectionInfo, PeerCredentials};
+use crate::context::{ResponseMetadata, ResponseStatus};
 use crate::error::Result;
 use crate::proto::{MessageHeader, Request, Response};
+use crate::r#async::peer::Peer;
 
 /// Handle request in async mode.
 #[macro_export]
@@ -46,6 +53,7 @@ macro_rules! async_request_handler {
                 }
             },
         }
+        res.set_metadata(::ttrpc::context::to_pb($ctx.response_metadata.take()));
 
         return Ok(res);
     };
@@ -78,6 +86,7 @@ macro_rules! async_client_streamimg_handler {
                 }
             },
         }
+        res.set_metadata(::ttrpc::context::to_pb($ctx.response_metadata.take()));
         return Ok(Some(res));
     };
 }
@@ -92,7 +101,19 @@ macro_rules! async_server_streamimg_handler {
         let stream = ::ttrpc::r#async::ServerStreamSender::new($inner);
         match $class.service.$req_fn(&$ctx, req, stream).await {
             Ok(_) => {
-                return Ok(None);
+                let md = $ctx.response_metadata.take();
+                let status = $ctx.response_status.take();
+                if md.is_empty() && status.is_none() {
+                    return Ok(None);
+                }
+                // Trailers go with a final response instead of the closing data frame.
+                let mut res = ::ttrpc::Response::new();
+                res.set_status(
+                    status
+                        .unwrap_or_else(|| ::ttrpc::get_status(::ttrpc::Code::OK, "".to_string())),
+                );
+                res.set_metadata(::ttrpc::context::to_pb(md));
+                return Ok(Some(res));
             }
             Err(x) => {
                 let mut res = ::ttrpc::Response::new();
@@ -107,6 +128,7 @@ macro_rules! async_server_streamimg_handler {
                         ));
                     }
                 }
+                res.set_metadata(::ttrpc::context::to_pb($ctx.response_metadata.take()));
                 return Ok(Some(res));
             }
         }
@@ -120,7 +142,19 @@ macro_rules! async_duplex_streamimg_handler {
         let stream = ::ttrpc::r#async::ServerStream::new($inner);
         match $class.service.$req_fn(&$ctx, stream).await {
             Ok(_) => {
-                return Ok(None);
+                let md = $ctx.response_metadata.take();
+                let status = $ctx.response_status.take();
+                if md.is_empty() && status.is_none() {
+                    return Ok(None);
+                }
+                // Trailers go with a final response instead of the closing data frame.
+                let mut res = ::ttrpc::Response::new();
+                res.set_status(
+                    status
+                        .unwrap_or_else(|| ::ttrpc::get_status(::ttrpc::Code::OK, "".to_string())),
+                );
+                res.set_metadata(::ttrpc::context::to_pb(md));
+                return Ok(Some(res));
             }
             Err(x) => {
                 let mut res = ::ttrpc::Response::new();
@@ -135,6 +169,7 @@ macro_rules! async_duplex_streamimg_handler {
                         ));
                     }
                 }
+                res.set_metadata(::ttrpc::context::to_pb($ctx.response_metadata.take()));
                 return Ok(Some(res));
             }
         }
@@ -255,6 +290,102 @@ pub struct TtrpcContext {
     pub mh: MessageHeader,
     pub metadata: HashMap<String, Vec<String>>,
     pub timeout_nano: i64,
+    /// Credentials of the connected peer, if the transport provides them.
+    pub peer_cred: Option<PeerCredentials>,
+    /// The connection the request came in on.
+    pub connection: Arc<ConnectionInfo>,
+    /// When the request was received.
+    pub received: SystemTime,
+    /// Metadata to send back to the client with the response.
+    pub response_metadata: ResponseMetadata,
+    /// Final status of a streaming response, sent with `response_metadata`
+    /// as trailers after the last message.
+    pub response_status: ResponseStatus,
+    /// When the call times out, derived from `timeout_nano` on receipt of the request.
+    pub deadline: Option<Instant>,
+    /// Fires once the call is over, so long-running handlers can stop early.
+    pub cancel: CancellationToken,
+    /// Calls the client back over the connection, none for the calls a
+    /// client serves.
+    pub peer: Option<Peer>,
+}
+
+/// Tells a handler its call is over: the client cancelled it or went away,
+/// or its deadline passed.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    inner: Arc<CancelInner>,
+    deadline: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct CancelInner {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancellationToken {
+    /// Returns true once the call is cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst) || self.deadline.is_some_and(|d| d <= now())
+    }
+
+    /// Waits for the call to be cancelled, to `select!` against the work
+    /// of a handler.
+    pub async fn cancelled(&self) {
+        let notified = self.inner.notify.notified();
+        if self.is_cancelled() {
+            return;
+        }
+        match self.deadline {
+            Some(deadline) => {
+                tokio::select! {
+                    _ = notified => {}
+                    _ = tokio::time::sleep_until(deadline.into()) => {}
+                }
+            }
+            None => notified.await,
+        }
+    }
+
+    pub(crate) fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    // The same token, also fired by `deadline`.
+    pub(crate) fn with_deadline(&self, deadline: Option<Instant>) -> CancellationToken {
+        CancellationToken {
+            inner: self.inner.clone(),
+            deadline,
+        }
+    }
+}
+
+impl TtrpcContext {
+    /// Returns true if the call has a deadline and it has passed. Handlers
+    /// doing long work can check this to give up early.
+    ///
+    /// Time is read from the tokio clock, so this follows `tokio::time::pause()`.
+    pub fn deadline_exceeded(&self) -> bool {
+        self.deadline.is_some_and(|d| d <= now())
+    }
+
+    /// Returns how long is left until the deadline, zero once it has passed,
+    /// or none if the call has no deadline. Handlers can pass it on to the
+    /// calls they make.
+    pub fn time_remaining(&self) -> Option<Duration> {
+        self.deadline.map(|d| d.saturating_duration_since(now()))
+    }
+}
+
+/// Current time on the tokio clock.
+///
+/// The async runtime takes all its timestamps from here rather than from
+/// `std::time::Instant::now()`, so that tests running with paused time see
+/// timeouts and deadlines expire as virtual time advances.
+pub(crate) fn now() -> Instant {
+    tokio::time::Instant::now().into_std()
 }
 
 pub(crate) fn new_unix_stream_from_raw_fd(fd: RawFd) -> UnixStream {
@@ -271,3 +402,58 @@ pub(crate) fn new_unix_stream_from_raw_fd(fd: RawFd) -> UnixStream {
 pub(crate) fn get_path(service: &str, method: &str) -> String {
     format!("/{}/{}", service, method)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_deadline_follows_paused_time() {
+        tokio::time::pause();
+
+        let ctx = TtrpcContext {
+            fd: -1,
+            mh: MessageHeader::default(),
+            metadata: HashMap::new(),
+            timeout_nano: 1_000_000_000,
+            peer_cred: None,
+            connection: Arc::new(ConnectionInfo::new(-1)),
+            received: SystemTime::now(),
+            response_metadata: ResponseMetadata::default(),
+            response_status: ResponseStatus::default(),
+            deadline: Some(now() + Duration::from_secs(1)),
+            cancel: CancellationToken::default(),
+            peer: None,
+        };
+        assert!(!ctx.deadline_exceeded());
+        assert_eq!(ctx.time_remaining(), Some(Duration::from_secs(1)));
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert!(ctx.deadline_exceeded());
+        assert_eq!(ctx.time_remaining(), Some(Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn test_cancellation_token() {
+        tokio::time::pause();
+
+        let token = CancellationToken::default();
+        let timed = token.with_deadline(Some(now() + Duration::from_secs(1)));
+        assert!(!timed.is_cancelled());
+        // Fired by the deadline alone.
+        timed.cancelled().await;
+        assert!(timed.is_cancelled());
+        assert!(!token.is_cancelled());
+
+        let waiter = tokio::spawn({
+            let token = token.clone();
+            async move { token.cancelled().await }
+        });
+        tokio::task::yield_now().await;
+        token.cancel();
+        waiter.await.unwrap();
+        assert!(token.is_cancelled());
+        assert!(timed.is_cancelled());
+    }
+}
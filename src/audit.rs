@@ -0,0 +1,238 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Audit log of security relevant server events.
+//!
+//! Every [`AuditRecord`] carries the SHA-256 hash of the record before it, so
+//! removing, reordering or editing records breaks the chain, which
+//! [`verify_chain()`] detects. Where the records are kept is up to the
+//! [`AuditSink`].
+
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use crate::common::PeerCredentials;
+
+/// Length of the hashes chaining audit records.
+pub const AUDIT_HASH_LEN: usize = 32;
+
+/// A security relevant event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// A connection was accepted or refused.
+    Connection {
+        peer_cred: Option<PeerCredentials>,
+        accepted: bool,
+        reason: String,
+    },
+    /// A call was refused before it reached its handler.
+    CallDenied {
+        path: String,
+        peer_cred: Option<PeerCredentials>,
+        reason: String,
+    },
+    /// An administrative action on the server, e.g. a shutdown.
+    Admin { action: String },
+}
+
+fn fmt_peer(f: &mut fmt::Formatter, peer_cred: &Option<PeerCredentials>) -> fmt::Result {
+    match peer_cred {
+        Some(c) => match c.pid {
+            Some(pid) => write!(f, "pid={} uid={} gid={}", pid, c.uid, c.gid),
+            None => write!(f, "uid={} gid={}", c.uid, c.gid),
+        },
+        None => write!(f, "peer=unknown"),
+    }
+}
+
+// The text form is what gets hashed, keep it stable.
+impl fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuditEvent::Connection {
+                peer_cred,
+                accepted,
+                reason,
+            } => {
+                write!(f, "connection accepted={} ", accepted)?;
+                fmt_peer(f, peer_cred)?;
+                write!(f, " reason={:?}", reason)
+            }
+            AuditEvent::CallDenied {
+                path,
+                peer_cred,
+                reason,
+            } => {
+                write!(f, "call_denied path={:?} ", path)?;
+                fmt_peer(f, peer_cred)?;
+                write!(f, " reason={:?}", reason)
+            }
+            AuditEvent::Admin { action } => write!(f, "admin action={:?}", action),
+        }
+    }
+}
+
+/// An entry of the audit log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// Position in the log, starting from 0.
+    pub seq: u64,
+    /// Nanoseconds since the unix epoch.
+    pub timestamp_nano: u128,
+    pub event: AuditEvent,
+    /// Hash of the previous record, all zeros for the first one.
+    pub prev_hash: [u8; AUDIT_HASH_LEN],
+    /// Hash of this record, covering all the fields above.
+    pub hash: [u8; AUDIT_HASH_LEN],
+}
+
+impl AuditRecord {
+    fn digest(&self) -> [u8; AUDIT_HASH_LEN] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.seq.to_be_bytes());
+        hasher.update(self.timestamp_nano.to_be_bytes());
+        hasher.update(self.prev_hash);
+        hasher.update(self.event.to_string().as_bytes());
+        hasher.finalize().into()
+    }
+}
+
+/// Where audit records are written to.
+pub trait AuditSink: Send + Sync {
+    fn write(&self, record: &AuditRecord);
+}
+
+/// Records audit events into a hash chain.
+pub struct AuditLog {
+    sink: Box<dyn AuditSink>,
+    // Sequence number and hash of the last record.
+    last: Mutex<(u64, [u8; AUDIT_HASH_LEN])>,
+}
+
+impl AuditLog {
+    pub fn new(sink: Box<dyn AuditSink>) -> Self {
+        AuditLog {
+            sink,
+            last: Mutex::new((0, [0; AUDIT_HASH_LEN])),
+        }
+    }
+
+    /// Continues the chain from the last record of an existing log, e.g. one
+    /// written before a restart.
+    pub fn resume(sink: Box<dyn AuditSink>, last: &AuditRecord) -> Self {
+        AuditLog {
+            sink,
+            last: Mutex::new((last.seq + 1, last.hash)),
+        }
+    }
+
+    /// Appends `event` to the log.
+    pub fn record(&self, event: AuditEvent) {
+        let timestamp_nano = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+
+        // Writing under the lock keeps the sink in chain order.
+        let mut last = self.last.lock().unwrap();
+        let mut record = AuditRecord {
+            seq: last.0,
+            timestamp_nano,
+            event,
+            prev_hash: last.1,
+            hash: [0; AUDIT_HASH_LEN],
+        };
+        record.hash = record.digest();
+        self.sink.write(&record);
+        *last = (record.seq + 1, record.hash);
+    }
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("last", &*self.last.lock().unwrap())
+            .finish()
+    }
+}
+
+/// Checks that `records` form an unbroken chain.
+///
+/// Returns the sequence number of the first record that doesn't fit in.
+pub fn verify_chain(records: &[AuditRecord]) -> Result<(), u64> {
+    let mut prev: Option<&AuditRecord> = None;
+    for record in records {
+        if let Some(prev) = prev {
+            if record.seq != prev.seq + 1 || record.prev_hash != prev.hash {
+                return Err(record.seq);
+            }
+        }
+        if record.hash != record.digest() {
+            return Err(record.seq);
+        }
+        prev = Some(record);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[derive(Default)]
+    struct MemorySink(Arc<Mutex<Vec<AuditRecord>>>);
+
+    impl AuditSink for MemorySink {
+        fn write(&self, record: &AuditRecord) {
+            self.0.lock().unwrap().push(record.clone());
+        }
+    }
+
+    #[test]
+    fn test_audit_chain() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let log = AuditLog::new(Box::new(MemorySink(records.clone())));
+        log.record(AuditEvent::Connection {
+            peer_cred: Some(PeerCredentials {
+                pid: Some(1),
+                uid: 0,
+                gid: 0,
+            }),
+            accepted: true,
+            reason: String::new(),
+        });
+        log.record(AuditEvent::Admin {
+            action: "shutdown".to_string(),
+        });
+
+        let mut records = records.lock().unwrap().clone();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].prev_hash, [0; AUDIT_HASH_LEN]);
+        assert_eq!(records[1].prev_hash, records[0].hash);
+        assert_eq!(verify_chain(&records), Ok(()));
+
+        let resumed = Arc::new(Mutex::new(Vec::new()));
+        let log = AuditLog::resume(Box::new(MemorySink(resumed.clone())), &records[1]);
+        log.record(AuditEvent::Admin {
+            action: "start".to_string(),
+        });
+        records.extend(resumed.lock().unwrap().iter().cloned());
+        assert_eq!(verify_chain(&records), Ok(()));
+
+        // Tampering with an event is detected.
+        records[1].event = AuditEvent::Admin {
+            action: "noop".to_string(),
+        };
+        assert_eq!(verify_chain(&records), Err(1));
+
+        // So is dropping a record.
+        records.remove(1);
+        assert_eq!(verify_chain(&records), Err(2));
+    }
+}
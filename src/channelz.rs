@@ -0,0 +1,334 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Introspection of a running server, after grpc's channelz.
+//!
+//! A [`Channelz`] given to `set_channelz()` of either server keeps track of
+//! its connections, the peers on them, the bytes they carried and the calls
+//! in flight on each, so a stuck server can be looked into while it runs.
+//! Clones share what they track, keep one to take a
+//! [`snapshot()`](Channelz::snapshot) of it:
+//!
+//! ```
+//! use ttrpc::channelz::Channelz;
+//!
+//! let channelz = Channelz::new();
+//! # #[cfg(feature = "sync")]
+//! let server = ttrpc::Server::new().set_channelz(channelz.clone());
+//! assert!(channelz.snapshot().connections.is_empty());
+//! ```
+//!
+//! The snapshot can also be served, as a [`ServerStats`] answering the
+//! [`GET_SERVER`] method of the [`SERVICE`] service, whose request payload
+//! is empty: register the handler given by
+//! [`sync_handler()`](Channelz::sync_handler) or
+//! [`async_handler()`](Channelz::async_handler) with `register_method()`.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use crate::common::ConnectionInfo;
+use crate::proto::Request;
+use crate::service_fn::{self, Codec, Protobuf, Raw};
+
+pub use crate::proto::{CallStats, ConnectionStats, ServerStats};
+
+/// The name of the channelz service.
+pub const SERVICE: &str = "ttrpc.channelz.Channelz";
+/// The method of the channelz service getting the state of the server.
+pub const GET_SERVER: &str = "GetServer";
+
+/// What a server is doing, see the [module docs](self).
+#[derive(Clone, Default)]
+pub struct Channelz(Arc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    started: Mutex<Option<Instant>>,
+    connections: Mutex<BTreeMap<u64, Arc<Connection>>>,
+}
+
+struct Connection {
+    info: ConnectionInfo,
+    received: AtomicU64,
+    sent: AtomicU64,
+    // The calls being handled, by stream id.
+    calls: Mutex<BTreeMap<u32, Call>>,
+}
+
+struct Call {
+    service: String,
+    method: String,
+    started: Instant,
+}
+
+/// The state of a server at some point.
+#[derive(Debug, Clone)]
+pub struct ServerSnapshot {
+    /// How long the server has been running, none before it starts.
+    pub uptime: Option<Duration>,
+    /// The open connections, oldest first.
+    pub connections: Vec<ConnectionSnapshot>,
+}
+
+/// The state of a connection at some point.
+#[derive(Debug, Clone)]
+pub struct ConnectionSnapshot {
+    pub info: ConnectionInfo,
+    /// Bytes read off the connection, headers included.
+    pub bytes_received: u64,
+    /// Bytes written to the connection, headers included.
+    pub bytes_sent: u64,
+    /// The calls being handled, by stream id.
+    pub calls: Vec<CallSnapshot>,
+}
+
+/// A call being handled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallSnapshot {
+    pub stream_id: u32,
+    pub service: String,
+    pub method: String,
+    /// How long the call has been running.
+    pub elapsed: Duration,
+}
+
+impl Channelz {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes a snapshot of the server.
+    pub fn snapshot(&self) -> ServerSnapshot {
+        let now = Instant::now();
+        let connections = self.0.connections.lock().unwrap();
+        ServerSnapshot {
+            uptime: self.0.started.lock().unwrap().map(|t| now - t),
+            connections: connections
+                .values()
+                .map(|conn| ConnectionSnapshot {
+                    info: conn.info.clone(),
+                    bytes_received: conn.received.load(Ordering::Relaxed),
+                    bytes_sent: conn.sent.load(Ordering::Relaxed),
+                    calls: conn
+                        .calls
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .map(|(stream_id, call)| CallSnapshot {
+                            stream_id: *stream_id,
+                            service: call.service.clone(),
+                            method: call.method.clone(),
+                            elapsed: now - call.started,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn started(&self) {
+        *self.0.started.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub(crate) fn opened(&self, info: &ConnectionInfo) {
+        let conn = Connection {
+            info: info.clone(),
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            calls: Mutex::new(BTreeMap::new()),
+        };
+        let mut connections = self.0.connections.lock().unwrap();
+        connections.insert(info.id, Arc::new(conn));
+    }
+
+    pub(crate) fn closed(&self, info: &ConnectionInfo) {
+        self.0.connections.lock().unwrap().remove(&info.id);
+    }
+
+    fn connection(&self, info: &ConnectionInfo) -> Option<Arc<Connection>> {
+        self.0.connections.lock().unwrap().get(&info.id).cloned()
+    }
+
+    pub(crate) fn received(&self, info: &ConnectionInfo, size: usize) {
+        if let Some(conn) = self.connection(info) {
+            conn.received.fetch_add(size as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn sent(&self, info: &ConnectionInfo, size: usize) {
+        if let Some(conn) = self.connection(info) {
+            conn.sent.fetch_add(size as u64, Ordering::Relaxed);
+        }
+    }
+
+    // Counts the call as in flight until the guard is dropped.
+    fn call(&self, info: &ConnectionInfo, stream_id: u32, req: &Request) -> InFlight {
+        let conn = self.connection(info);
+        if let Some(conn) = conn.as_ref() {
+            let call = Call {
+                service: req.service.clone(),
+                method: req.method.clone(),
+                started: Instant::now(),
+            };
+            conn.calls.lock().unwrap().insert(stream_id, call);
+        }
+        InFlight { conn, stream_id }
+    }
+
+    // The snapshot as served by the channelz service.
+    fn server_stats(&self) -> ServerStats {
+        let snapshot = self.snapshot();
+        ServerStats {
+            uptime_ms: snapshot.uptime.unwrap_or_default().as_millis() as u64,
+            connections: snapshot
+                .connections
+                .into_iter()
+                .map(|conn| ConnectionStats {
+                    id: conn.info.id,
+                    local_addr: conn.info.local_addr.unwrap_or_default(),
+                    remote_addr: conn.info.remote_addr.unwrap_or_default(),
+                    established_ms: conn
+                        .info
+                        .established
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis() as u64,
+                    pid: conn.info.peer_cred.and_then(|c| c.pid).unwrap_or_default(),
+                    bytes_received: conn.bytes_received,
+                    bytes_sent: conn.bytes_sent,
+                    calls: conn
+                        .calls
+                        .into_iter()
+                        .map(|call| CallStats {
+                            stream_id: call.stream_id,
+                            service: call.service,
+                            method: call.method,
+                            elapsed_ms: call.elapsed.as_millis() as u64,
+                            ..Default::default()
+                        })
+                        .collect(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+struct InFlight {
+    conn: Option<Arc<Connection>>,
+    stream_id: u32,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.as_ref() {
+            conn.calls.lock().unwrap().remove(&self.stream_id);
+        }
+    }
+}
+
+// Keeps track of the calls in flight, ahead of the other interceptors.
+pub(crate) struct InFlightCalls(pub(crate) Channelz);
+
+cfg_sync! {
+    impl Channelz {
+        /// The handler of [`GET_SERVER`] for a sync server.
+        pub fn sync_handler(&self) -> impl crate::sync::MethodHandler + Send + Sync {
+            let channelz = self.clone();
+            service_fn::sync_method(Raw, move |_: &crate::sync::TtrpcContext, _: Vec<u8>| {
+                Protobuf.encode(&channelz.server_stats())
+            })
+        }
+    }
+
+    impl crate::sync::Interceptor for InFlightCalls {
+        fn intercept(
+            &self,
+            ctx: crate::sync::TtrpcContext,
+            req: Request,
+            next: crate::sync::Next<'_>,
+        ) -> crate::Result<()> {
+            let _call = self.0.call(&ctx.connection, ctx.mh.stream_id, &req);
+            next.run(ctx, req)
+        }
+    }
+}
+
+cfg_async! {
+    impl Channelz {
+        /// The handler of [`GET_SERVER`] for an async server.
+        pub fn async_handler(&self) -> impl crate::r#async::MethodHandler + Send + Sync {
+            let channelz = self.clone();
+            service_fn::async_method(Raw, move |_, _: Vec<u8>| {
+                let stats = channelz.server_stats();
+                async move { Protobuf.encode(&stats) }
+            })
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl crate::r#async::Interceptor for InFlightCalls {
+        async fn intercept(
+            &self,
+            ctx: crate::r#async::TtrpcContext,
+            req: Request,
+            next: crate::r#async::Next<'_>,
+        ) -> crate::Result<Option<crate::proto::Response>> {
+            let _call = self.0.call(&ctx.connection, ctx.mh.stream_id, &req);
+            next.run(ctx, req).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str) -> Request {
+        Request {
+            service: "grpc.Containerd".to_string(),
+            method: method.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_channelz_snapshot() {
+        let channelz = Channelz::new();
+        assert!(channelz.snapshot().uptime.is_none());
+        channelz.started();
+
+        let info = ConnectionInfo::new(-1);
+        channelz.opened(&info);
+        channelz.received(&info, 30);
+        channelz.sent(&info, 12);
+        let call = channelz.call(&info, 3, &request("Checkpoint"));
+
+        let snapshot = channelz.snapshot();
+        assert!(snapshot.uptime.is_some());
+        assert_eq!(snapshot.connections.len(), 1);
+        let conn = &snapshot.connections[0];
+        assert_eq!(conn.info.id, info.id);
+        assert_eq!((conn.bytes_received, conn.bytes_sent), (30, 12));
+        let calls: Vec<_> = conn
+            .calls
+            .iter()
+            .map(|c| (c.stream_id, c.method.as_str()))
+            .collect();
+        assert_eq!(calls, [(3, "Checkpoint")]);
+
+        let stats = channelz.server_stats();
+        assert_eq!(stats.connections[0].calls[0].service, "grpc.Containerd");
+
+        drop(call);
+        assert!(channelz.snapshot().connections[0].calls.is_empty());
+        channelz.closed(&info);
+        assert!(channelz.snapshot().connections.is_empty());
+    }
+}
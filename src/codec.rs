@@ -0,0 +1,340 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Encoding and decoding of the ttrpc wire format.
+//!
+//! A ttrpc connection carries a sequence of frames. Each is a
+//! [`MessageHeader`] of [`MESSAGE_HEADER_LENGTH`] bytes, holding the payload
+//! length and stream id as big endian `u32`s followed by the message type and
+//! flags, and then `length` bytes of payload. The payload of requests and
+//! responses is a protobuf encoded [`Request`] or [`Response`]. The flags in
+//! [`FLAGS_APP`] are left to applications, for hints of their own.
+//!
+//! This module lets tools like proxies, fuzzers or wire analyzers work with
+//! ttrpc traffic. With the `async` feature, [`GenMessage::read_from()`] and
+//! [`GenMessage::write_to()`] do the same on tokio streams.
+//!
+//! [`Request`]: crate::Request
+//! [`Response`]: crate::Response
+
+use std::io::{Read, Write};
+
+use bytes::Bytes;
+
+use crate::error::{get_rpc_status, Error, Result};
+use crate::proto::Code;
+pub use crate::proto::{
+    GenMessage, MessageHeader, FLAGS_APP, FLAG_COMPRESSED_GZIP, FLAG_COMPRESSED_ZSTD,
+    FLAG_CONTINUED, FLAG_NO_DATA, FLAG_REMOTE_CLOSED, FLAG_REMOTE_OPEN, MESSAGE_HEADER_LENGTH,
+    MESSAGE_LENGTH_MAX, MESSAGE_TYPE_CANCEL, MESSAGE_TYPE_DATA, MESSAGE_TYPE_GOAWAY,
+    MESSAGE_TYPE_NOTIFICATION, MESSAGE_TYPE_REQUEST, MESSAGE_TYPE_RESPONSE, MESSAGE_TYPE_SUBSCRIBE,
+    MESSAGE_TYPE_WINDOW_UPDATE,
+};
+
+/// Encodes a message header.
+pub fn encode_header(mh: &MessageHeader) -> [u8; MESSAGE_HEADER_LENGTH] {
+    let mut buf = [0; MESSAGE_HEADER_LENGTH];
+    mh.into_buf(&mut buf[..]);
+    buf
+}
+
+/// Decodes the message header at the start of `buf`.
+pub fn decode_header(buf: &[u8]) -> Result<MessageHeader> {
+    if buf.len() < MESSAGE_HEADER_LENGTH {
+        return Err(Error::Others(format!(
+            "message header length {} is too small",
+            buf.len()
+        )));
+    }
+
+    Ok(MessageHeader::from(&buf[..MESSAGE_HEADER_LENGTH]))
+}
+
+/// Encodes a message into a frame.
+pub fn encode_frame(msg: &GenMessage) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(MESSAGE_HEADER_LENGTH + msg.payload.len());
+    buf.extend_from_slice(&encode_header(&msg.header));
+    buf.extend_from_slice(&msg.payload);
+    buf
+}
+
+/// Decodes the frame at the start of `buf`, returns the message and the
+/// number of bytes it took up, or `None` if `buf` doesn't hold all of it yet.
+///
+/// The header's length isn't trusted, no more than `buf` is allocated.
+pub fn decode_frame(buf: &[u8]) -> Result<Option<(GenMessage, usize)>> {
+    if buf.len() < MESSAGE_HEADER_LENGTH {
+        return Ok(None);
+    }
+    let header = decode_header(buf)?;
+    let end = MESSAGE_HEADER_LENGTH + header.length as usize;
+    if buf.len() < end {
+        return Ok(None);
+    }
+
+    let msg = GenMessage {
+        header,
+        payload: Bytes::copy_from_slice(&buf[MESSAGE_HEADER_LENGTH..end]),
+    };
+
+    Ok(Some((msg, end)))
+}
+
+/// Limits the headers of incoming frames are checked against, see
+/// [`validate_header()`].
+///
+/// By default the length of a frame isn't limited here, frames over the
+/// maximum message size of a connection are skipped without being read into
+/// memory. Setting `max_length` closes the connection instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeaderLimits {
+    max_length: u32,
+    strict: bool,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> Self {
+        HeaderLimits {
+            max_length: u32::MAX,
+            strict: true,
+        }
+    }
+}
+
+impl HeaderLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Refuses frames with a payload over `max_length` bytes.
+    pub fn set_max_length(mut self, max_length: u32) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Checks the type, stream id and flags of frames, on by default. Turning
+    /// it off only checks the length, for peers using extensions of the
+    /// protocol.
+    pub fn set_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+}
+
+/// Checks a header from the peer against `limits`.
+///
+/// In strict mode the type must be known, requests must be on odd stream
+/// ids as the client opens them, other messages of a stream on a non-zero one
+/// and messages of the whole connection on stream 0. The flags must not
+/// contradict each other. A header failing this fails with INVALID_ARGUMENT,
+/// the connection can't be trusted to be at a frame boundary afterwards.
+pub fn validate_header(mh: &MessageHeader, limits: &HeaderLimits) -> Result<()> {
+    let invalid = |reason: &str| {
+        Err(get_rpc_status(
+            Code::INVALID_ARGUMENT,
+            format!("invalid message header {:?}: {}", mh, reason),
+        ))
+    };
+
+    if mh.length > limits.max_length {
+        return invalid(&format!("length over {}", limits.max_length));
+    }
+    if !limits.strict {
+        return Ok(());
+    }
+
+    match mh.type_ {
+        MESSAGE_TYPE_REQUEST if mh.stream_id.is_multiple_of(2) => {
+            return invalid("requests must be on odd stream ids");
+        }
+        MESSAGE_TYPE_RESPONSE
+        | MESSAGE_TYPE_DATA
+        | MESSAGE_TYPE_CANCEL
+        | MESSAGE_TYPE_WINDOW_UPDATE
+            if mh.stream_id == 0 =>
+        {
+            return invalid("stream id 0");
+        }
+        MESSAGE_TYPE_SUBSCRIBE | MESSAGE_TYPE_NOTIFICATION | MESSAGE_TYPE_GOAWAY
+            if mh.stream_id != 0 =>
+        {
+            return invalid("not on stream id 0");
+        }
+        MESSAGE_TYPE_REQUEST..=MESSAGE_TYPE_WINDOW_UPDATE => {}
+        _ => return invalid("unknown type"),
+    }
+
+    let both = |a: u8, b: u8| mh.flags & (a | b) == a | b;
+    if both(FLAG_COMPRESSED_GZIP, FLAG_COMPRESSED_ZSTD) {
+        return invalid("compressed twice");
+    }
+    if both(FLAG_REMOTE_CLOSED, FLAG_REMOTE_OPEN) {
+        return invalid("remote both open and closed");
+    }
+    if mh.type_ == MESSAGE_TYPE_DATA && mh.flags & FLAG_NO_DATA != 0 && mh.length != 0 {
+        return invalid("payload flagged as no data");
+    }
+
+    Ok(())
+}
+
+/// Reads a frame whose payload is at most `max_len` bytes long.
+///
+/// A larger one fails with INVALID_ARGUMENT, and the reader is left in the
+/// middle of it.
+pub fn read_frame(mut reader: impl Read, max_len: usize) -> Result<GenMessage> {
+    let mut buf = [0; MESSAGE_HEADER_LENGTH];
+    reader
+        .read_exact(&mut buf)
+        .map_err(|e| Error::Socket(e.to_string()))?;
+    let header = MessageHeader::from(&buf);
+
+    if header.length as usize > max_len {
+        return Err(get_rpc_status(
+            Code::INVALID_ARGUMENT,
+            format!(
+                "message length {} exceed maximum message size of {}",
+                header.length, max_len
+            ),
+        ));
+    }
+
+    let mut payload = vec![0; header.length as usize];
+    reader
+        .read_exact(&mut payload)
+        .map_err(|e| Error::Socket(e.to_string()))?;
+
+    Ok(GenMessage {
+        header,
+        payload: payload.into(),
+    })
+}
+
+/// Writes a frame.
+pub fn write_frame(mut writer: impl Write, msg: &GenMessage) -> Result<()> {
+    writer
+        .write_all(&encode_header(&msg.header))
+        .and_then(|_| writer.write_all(&msg.payload))
+        .and_then(|_| writer.flush())
+        .map_err(|e| Error::Socket(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> GenMessage {
+        GenMessage {
+            header: MessageHeader {
+                length: 3,
+                stream_id: 0x1020_3040,
+                type_: MESSAGE_TYPE_RESPONSE,
+                flags: FLAG_NO_DATA,
+            },
+            payload: Bytes::from_static(b"abc"),
+        }
+    }
+
+    #[test]
+    fn test_header() {
+        let mh = message().header;
+        let buf = encode_header(&mh);
+        assert_eq!(buf, [0, 0, 0, 3, 0x10, 0x20, 0x30, 0x40, 2, 4]);
+        assert_eq!(decode_header(&buf).unwrap(), mh);
+        assert!(decode_header(&buf[..9]).is_err());
+    }
+
+    #[test]
+    fn test_frame() {
+        let msg = message();
+        let mut buf = encode_frame(&msg);
+        buf.push(0xff);
+
+        for len in 0..MESSAGE_HEADER_LENGTH + 3 {
+            assert!(decode_frame(&buf[..len]).unwrap().is_none());
+        }
+        let (got, used) = decode_frame(&buf).unwrap().unwrap();
+        assert_eq!(got, msg);
+        assert_eq!(used, buf.len() - 1);
+    }
+
+    #[test]
+    fn test_read_write_frame() {
+        let msg = message();
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &msg).unwrap();
+        write_frame(&mut buf, &msg).unwrap();
+
+        let mut reader = &buf[..];
+        assert_eq!(read_frame(&mut reader, 3).unwrap(), msg);
+        assert!(matches!(
+            read_frame(&mut reader, 2),
+            Err(Error::RpcStatus(s)) if s.code() == Code::INVALID_ARGUMENT
+        ));
+
+        let mut reader = &buf[..12];
+        assert!(matches!(read_frame(&mut reader, 3), Err(Error::Socket(_))));
+    }
+
+    #[test]
+    fn test_validate_header() {
+        let limits = HeaderLimits::default();
+        let valid = [
+            MessageHeader::new_request(1, 10),
+            MessageHeader::new_response(2, 10),
+            MessageHeader::new_data(3, 0),
+            MessageHeader::new_subscribe(10),
+            MessageHeader::new_notification(10),
+            MessageHeader::new_cancel(5),
+            MessageHeader::new_goaway(0),
+            MessageHeader::new_window_update(4, 2),
+        ];
+        for mh in valid.iter() {
+            validate_header(mh, &limits).unwrap();
+        }
+
+        let mut no_data = MessageHeader::new_data(3, 0);
+        no_data.set_flags(FLAG_REMOTE_CLOSED | FLAG_NO_DATA);
+        validate_header(&no_data, &limits).unwrap();
+
+        let mut unknown = MessageHeader::new_request(1, 0);
+        unknown.type_ = 0x9;
+        let mut compressed = MessageHeader::new_request(1, 10);
+        compressed.set_flags(FLAG_COMPRESSED_GZIP | FLAG_COMPRESSED_ZSTD);
+        let mut open_closed = MessageHeader::new_data(1, 10);
+        open_closed.set_flags(FLAG_REMOTE_CLOSED | FLAG_REMOTE_OPEN);
+        let mut payload = MessageHeader::new_data(1, 10);
+        payload.set_flags(FLAG_NO_DATA);
+        let invalid = [
+            unknown,
+            MessageHeader::new_request(2, 10),
+            MessageHeader::new_response(0, 10),
+            MessageHeader::new_cancel(0),
+            MessageHeader {
+                stream_id: 1,
+                ..MessageHeader::new_goaway(0)
+            },
+            compressed,
+            open_closed,
+            payload,
+        ];
+        for mh in invalid.iter() {
+            assert!(matches!(
+                validate_header(mh, &limits),
+                Err(Error::RpcStatus(s)) if s.code() == Code::INVALID_ARGUMENT
+            ));
+            validate_header(mh, &limits.set_strict(false)).unwrap();
+        }
+
+        let limits = limits.set_max_length(10);
+        validate_header(&MessageHeader::new_request(1, 10), &limits).unwrap();
+        assert!(validate_header(&MessageHeader::new_request(1, 11), &limits).is_err());
+        assert!(validate_header(
+            &MessageHeader::new_request(1, 11),
+            &limits.set_strict(false)
+        )
+        .is_err());
+    }
+}
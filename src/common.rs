@@ -5,17 +5,178 @@
 
 //! Common functions and macros.
 
-use crate::error::{Error, Result};
-#[cfg(any(
-    feature = "async",
-    not(any(target_os = "linux", target_os = "android"))
-))]
-use nix::fcntl::FdFlag;
-use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use crate::channelz::Channelz;
+use crate::context::{self, Context};
+use crate::error::{get_status, Error, Result, SOCK_DICONNECTED};
+use crate::metrics::Metrics;
+use crate::proto::{
+    Code, KeyValue, MessageHeader, Request, Status, CANCEL_KEY, FLAG_REMOTE_OPEN, NO_RESPONSE_KEY,
+};
+use crate::spans::{self, Kind};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
 use nix::sys::socket::*;
+use std::any::Any;
 use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Credentials of the process on the other end of a unix socket connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    /// Process id of the peer, only available on Linux and Android.
+    pub pid: Option<i32>,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// What is known about a connection, shared by the calls it carries.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// Tells the connection apart from the others of the process.
+    pub id: u64,
+    /// Address of this end, such as `unix:///run/a.sock` or `vsock://3:1024`.
+    pub local_addr: Option<String>,
+    /// Address of the other end, none for an unnamed unix socket.
+    pub remote_addr: Option<String>,
+    /// When the connection was accepted.
+    pub established: SystemTime,
+    /// Credentials of the connected peer, if the transport provides them.
+    pub peer_cred: Option<PeerCredentials>,
+}
+
+impl ConnectionInfo {
+    /// Gathers the information of the connection `fd`, just accepted or
+    /// connected.
+    pub(crate) fn new(fd: RawFd) -> ConnectionInfo {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+        ConnectionInfo {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            local_addr: getsockname(fd).ok().and_then(format_addr),
+            remote_addr: getpeername(fd).ok().and_then(format_addr),
+            established: SystemTime::now(),
+            peer_cred: get_peer_credentials(fd)
+                .map_err(|e| {
+                    debug!("failed to get peer credentials: {:?}", e);
+                })
+                .ok(),
+        }
+    }
+}
+
+/// Why a server closed a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The client closed it, or went away.
+    Client,
+    /// Nothing was sent on it for longer than the idle timeout.
+    Idle,
+    /// The server is shutting down.
+    Shutdown,
+    /// Reading or writing failed, or the client broke the protocol.
+    Error(String),
+}
+
+impl CloseReason {
+    pub(crate) fn from_error(e: &Error) -> CloseReason {
+        match e {
+            Error::Socket(msg) if msg == SOCK_DICONNECTED => CloseReason::Client,
+            Error::LocalClosed => CloseReason::Client,
+            e => CloseReason::Error(e.to_string()),
+        }
+    }
+}
+
+type OpenedHook = Arc<dyn Fn(&ConnectionInfo) + Send + Sync>;
+type ClosedHook = Arc<dyn Fn(&ConnectionInfo, &CloseReason) + Send + Sync>;
+
+/// Callbacks of a server on the connections opening and closing, and on
+/// the work they carry.
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionHooks {
+    pub(crate) opened: Option<OpenedHook>,
+    pub(crate) closed: Option<ClosedHook>,
+    pub(crate) metrics: Metrics,
+    pub(crate) channelz: Option<Channelz>,
+}
+
+impl ConnectionHooks {
+    pub(crate) fn opened(&self, info: &ConnectionInfo) {
+        self.metrics.connection_opened();
+        spans::connection_opened(Kind::Server, info);
+        if let Some(channelz) = self.channelz.as_ref() {
+            channelz.opened(info);
+        }
+        if let Some(opened) = self.opened.as_ref() {
+            opened(info);
+        }
+    }
+
+    pub(crate) fn closed(&self, info: &ConnectionInfo, reason: &CloseReason) {
+        self.metrics.connection_closed();
+        spans::connection_closed(Kind::Server, info, reason);
+        if let Some(channelz) = self.channelz.as_ref() {
+            channelz.closed(info);
+        }
+        if let Some(closed) = self.closed.as_ref() {
+            closed(info, reason);
+        }
+    }
+
+    pub(crate) fn received(&self, info: &ConnectionInfo, size: usize) {
+        self.metrics.received(size);
+        if let Some(channelz) = self.channelz.as_ref() {
+            channelz.received(info, size);
+        }
+    }
+
+    pub(crate) fn sent(&self, info: &ConnectionInfo, size: usize) {
+        self.metrics.sent(size);
+        if let Some(channelz) = self.channelz.as_ref() {
+            channelz.sent(info, size);
+        }
+    }
+}
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// The reason a connection is closing for, the first one given winning.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Closing(Arc<Mutex<Option<CloseReason>>>);
+
+impl Closing {
+    pub(crate) fn set(&self, reason: CloseReason) {
+        self.0.lock().unwrap().get_or_insert(reason);
+    }
+
+    /// Takes the reason, assuming the client closed the connection if none
+    /// was given.
+    pub(crate) fn take(&self) -> CloseReason {
+        self.0.lock().unwrap().take().unwrap_or(CloseReason::Client)
+    }
+}
+
+// Formats a socket address the way it is given to bind or connect, none for
+// an unnamed unix socket.
+fn format_addr(addr: SockAddr) -> Option<String> {
+    match addr {
+        SockAddr::Unix(addr) => {
+            if let Some(path) = addr.path() {
+                return Some(format!("unix://{}", path.display()));
+            }
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            let name = addr.as_abstract();
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            let name: Option<&[u8]> = None;
+            name.map(|name| format!("unix://@{}", String::from_utf8_lossy(name)))
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        SockAddr::Vsock(addr) => Some(format!("vsock://{}:{}", addr.cid(), addr.port())),
+        addr => Some(addr.to_string()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum Domain {
     Unix,
     #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -74,6 +235,427 @@ pub(crate) fn set_fd_close_exec(fd: RawFd) -> Result<RawFd> {
     Ok(fd)
 }
 
+/// Sets or clears FD_CLOEXEC, deciding whether the fd survives an exec.
+pub(crate) fn set_fd_inheritable(fd: RawFd, inheritable: bool) -> Result<()> {
+    let flags = fcntl(fd, FcntlArg::F_GETFD)
+        .map_err(|e| Error::Others(format!("failed to get flags of fd: {}: {}", fd, e)))?;
+    let mut flags = FdFlag::from_bits_truncate(flags);
+    flags.set(FdFlag::FD_CLOEXEC, !inheritable);
+    fcntl(fd, FcntlArg::F_SETFD(flags))
+        .map_err(|e| Error::Others(format!("failed to set flags of fd: {}: {}", fd, e)))?;
+    Ok(())
+}
+
+/// Gets the domain of a bound socket.
+pub(crate) fn get_domain(fd: RawFd) -> Result<Domain> {
+    match getsockname(fd)? {
+        SockAddr::Unix(_) => Ok(Domain::Unix),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        SockAddr::Vsock(_) => Ok(Domain::Vsock),
+        addr => Err(Error::Others(format!(
+            "socket address {} is not supported",
+            addr
+        ))),
+    }
+}
+
+/// Gets the path of the file a listening unix socket is bound to, none for
+/// an abstract or vsock socket.
+pub(crate) fn get_socket_path(fd: RawFd) -> Option<PathBuf> {
+    match getsockname(fd) {
+        Ok(SockAddr::Unix(addr)) => addr.path().map(|p| p.to_path_buf()),
+        _ => None,
+    }
+}
+
+/// Removes the file of a unix socket that was listened on.
+pub(crate) fn remove_socket_path(path: Option<PathBuf>) {
+    if let Some(path) = path {
+        std::fs::remove_file(&path)
+            .unwrap_or_else(|e| warn!("failed to remove socket {:?}: {}", path, e));
+    }
+}
+
+/// Gets the credentials of the peer connected to a unix socket.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn get_peer_credentials(fd: RawFd) -> Result<PeerCredentials> {
+    let cred = getsockopt(fd, sockopt::PeerCredentials)?;
+    Ok(PeerCredentials {
+        pid: Some(cred.pid()),
+        uid: cred.uid(),
+        gid: cred.gid(),
+    })
+}
+
+/// Gets the credentials of the peer connected to a unix socket.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn get_peer_credentials(fd: RawFd) -> Result<PeerCredentials> {
+    let mut uid = 0;
+    let mut gid = 0;
+    if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } != 0 {
+        return Err(Error::Socket(std::io::Error::last_os_error().to_string()));
+    }
+    Ok(PeerCredentials {
+        pid: None,
+        uid,
+        gid,
+    })
+}
+
+/// Gets the deadline of a request received at `received`, if it has a timeout.
+pub(crate) fn get_deadline(received: Instant, timeout_nano: i64) -> Option<Instant> {
+    if timeout_nano <= 0 {
+        return None;
+    }
+    received.checked_add(Duration::from_nanos(timeout_nano as u64))
+}
+
+/// Lists [`CANCEL_KEY`] in `metadata`, of a request offering to cancel
+/// calls or of a response agreeing to.
+pub(crate) fn add_cancel_key(metadata: &mut Vec<KeyValue>) {
+    metadata.push(KeyValue {
+        key: CANCEL_KEY.to_string(),
+        value: "1".to_string(),
+        ..Default::default()
+    });
+}
+
+pub(crate) fn has_cancel_key(metadata: &[KeyValue]) -> bool {
+    metadata.iter().any(|kv| kv.key == CANCEL_KEY)
+}
+
+/// Makes `req` one-way, see [`NO_RESPONSE_KEY`].
+pub(crate) fn add_no_response_key(req: &mut Request) {
+    req.metadata.push(KeyValue {
+        key: NO_RESPONSE_KEY.to_string(),
+        value: "1".to_string(),
+        ..Default::default()
+    });
+}
+
+/// Tells if the client of `req`, sent with `mh`, wants no response. A
+/// request opening a stream from the client always gets one.
+pub(crate) fn is_one_way(mh: &MessageHeader, req: &Request) -> bool {
+    mh.flags & FLAG_REMOTE_OPEN == 0 && req.metadata.iter().any(|kv| kv.key == NO_RESPONSE_KEY)
+}
+
+/// Tells if `name`, a service such as `grpc.Containerd` or a method such as
+/// `grpc.Containerd/Checkpoint`, covers the call `req`.
+pub(crate) fn covers_call(name: &str, req: &Request) -> bool {
+    match name.strip_prefix(req.service.as_str()) {
+        Some("") => true,
+        Some(rest) => rest.strip_prefix('/') == Some(req.method.as_str()),
+        None => false,
+    }
+}
+
+/// Logs the panic of the handler of `path`, returning the INTERNAL status
+/// the client gets for it.
+pub(crate) fn panic_status(path: &str, panic: Box<dyn Any + Send>) -> Status {
+    let msg = panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>");
+    error!("method handle {} panicked: {}", path, msg);
+    get_status(Code::INTERNAL, format!("{} panicked", path))
+}
+
+/// What a server does with new connections once it has as many as it
+/// takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionLimit {
+    /// Leaves them waiting to be accepted until a connection closes.
+    Wait,
+    /// Accepts and closes them right away.
+    Reject,
+}
+
+/// The state of the connection of a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Connecting for the first time.
+    Connecting,
+    /// Connected, calls can be made.
+    Ready,
+    /// Not connected: not yet for a lazy client, or the connection was lost
+    /// and isn't reconnected, or connecting failed.
+    Disconnected,
+    /// Connecting again after the connection was lost.
+    Reconnecting,
+}
+
+type StateHook = Arc<dyn Fn(ConnectionState) + Send + Sync>;
+
+/// Tracks the state of the connection of a client, calling the hooks on
+/// each change.
+#[derive(Clone)]
+pub(crate) struct StateWatch {
+    inner: Arc<Mutex<(ConnectionState, Vec<StateHook>)>>,
+}
+
+impl StateWatch {
+    pub(crate) fn new(state: ConnectionState) -> StateWatch {
+        StateWatch {
+            inner: Arc::new(Mutex::new((state, Vec::new()))),
+        }
+    }
+
+    pub(crate) fn get(&self) -> ConnectionState {
+        self.inner.lock().unwrap().0
+    }
+
+    pub(crate) fn set(&self, state: ConnectionState) {
+        let hooks = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.0 == state {
+                return;
+            }
+            inner.0 = state;
+            inner.1.clone()
+        };
+        // Called unlocked so they may look at the state.
+        for hook in hooks {
+            hook(state);
+        }
+    }
+
+    pub(crate) fn add_hook(&self, hook: StateHook) {
+        self.inner.lock().unwrap().1.push(hook);
+    }
+}
+
+/// How a client retries connecting to a server that may not be listening
+/// yet, typically an agent in a guest that is still booting.
+///
+/// Only failures meaning nothing listens on the address yet are retried,
+/// others are returned right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectRetry {
+    /// How long to keep trying, counted from the first attempt.
+    pub timeout: Duration,
+    /// Wait after the first failed attempt, doubled after every further one.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Seeds the jitter of the waits, so that a test sees the same ones on
+    /// every run. Seeded from the clock if none.
+    pub jitter_seed: Option<u64>,
+}
+
+impl Default for ConnectRetry {
+    fn default() -> Self {
+        ConnectRetry {
+            timeout: Duration::from_secs(30),
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(500),
+            jitter_seed: None,
+        }
+    }
+}
+
+impl ConnectRetry {
+    /// Gets the wait before the next attempt, `elapsed` after the first one,
+    /// none once the timeout has passed. The async client reads the time off
+    /// the tokio clock, so that retrying follows `tokio::time::pause()`.
+    pub(crate) fn next_backoff(
+        &self,
+        elapsed: Duration,
+        attempt: u32,
+        jitter: &mut Jitter,
+    ) -> Option<Duration> {
+        let remaining = self.timeout.checked_sub(elapsed)?;
+        if remaining == Duration::from_secs(0) {
+            return None;
+        }
+        Some(self.backoff(attempt, jitter.sample()).min(remaining))
+    }
+
+    fn backoff(&self, attempt: u32, jitter: f64) -> Duration {
+        backoff(self.initial_backoff, self.max_backoff, attempt, jitter)
+    }
+}
+
+/// How a client retries calls failing with a status that may not last,
+/// set with `set_retry_policy()` of either client for methods that are
+/// safe to call more than once.
+///
+/// A failed call is retried while it has attempts left, it failed with one
+/// of `codes`, and its timeout, if any, leaves time for the wait. Socket
+/// errors count as UNAVAILABLE. Each attempt gets what's left of the
+/// timeout of the call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The most attempts at a call, the first one included.
+    pub max_attempts: u32,
+    /// Wait after the first failed attempt, doubled after every further one.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// The status codes calls are retried on.
+    pub codes: Vec<Code>,
+    /// Seeds the jitter of the waits, as `ConnectRetry::jitter_seed` does.
+    pub jitter_seed: Option<u64>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+            codes: vec![Code::UNAVAILABLE],
+            jitter_seed: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Gets the wait before the next attempt at a call whose `attempt`th
+    /// attempt, counting from 1, failed with `e`, none if it's not retried.
+    /// The call has to be over by `deadline`, if any, on the clock `now`
+    /// is read from: the tokio one for the async client.
+    pub(crate) fn next_backoff(
+        &self,
+        attempt: u32,
+        e: &Error,
+        deadline: Option<Instant>,
+        now: Instant,
+        jitter: &mut Jitter,
+    ) -> Option<Duration> {
+        let code = match e {
+            Error::RpcStatus(status) => status.code(),
+            Error::Socket(_) => Code::UNAVAILABLE,
+            _ => return None,
+        };
+        if attempt >= self.max_attempts || !self.codes.contains(&code) {
+            return None;
+        }
+        let backoff = backoff(
+            self.initial_backoff,
+            self.max_backoff,
+            attempt - 1,
+            jitter.sample(),
+        );
+        match deadline {
+            Some(deadline) if now + backoff >= deadline => None,
+            _ => Some(backoff),
+        }
+    }
+}
+
+// Gets the wait after `attempt` failed attempts, from 0, doubling
+// `initial` each time up to `max`.
+fn backoff(initial: Duration, max: Duration, attempt: u32, jitter: f64) -> Duration {
+    let base = 1u32
+        .checked_shl(attempt)
+        .and_then(|n| initial.checked_mul(n))
+        .map_or(max, |d| d.min(max));
+    // Keep clients started together from retrying in lockstep.
+    base / 2 + base.mul_f64(jitter) / 2
+}
+
+/// Gets the timeout, in nanoseconds, left to a call from `now` until
+/// `deadline`.
+pub(crate) fn remaining_nano(deadline: Instant, now: Instant) -> i64 {
+    // A timeout of 0 is none at all.
+    (deadline.saturating_duration_since(now).as_nanos() as i64).max(1)
+}
+
+/// Draws the jitter of the waits of a retry loop, the same ones for the same
+/// seed.
+pub(crate) struct Jitter(u64);
+
+impl Jitter {
+    pub(crate) fn new(seed: Option<u64>) -> Jitter {
+        Jitter(seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64)
+        }))
+    }
+
+    /// Gets the next value, in [0, 1).
+    pub(crate) fn sample(&mut self) -> f64 {
+        // splitmix64.
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Returns true if a connect failed because nothing listens on the address
+/// yet, rather than because the address is wrong or access is denied.
+pub(crate) fn is_transport_not_ready(e: &Error) -> bool {
+    use nix::errno::Errno;
+
+    match e {
+        Error::Nix(errno) => matches!(
+            errno,
+            // No listener yet, or the socket file isn't there yet.
+            Errno::ECONNREFUSED
+                | Errno::ENOENT
+                // The guest isn't up, or its vsock transport isn't.
+                | Errno::ENODEV
+                | Errno::EHOSTUNREACH
+                | Errno::ENETUNREACH
+                | Errno::ETIMEDOUT
+                | Errno::ECONNRESET
+                | Errno::EINTR
+        ),
+        _ => false,
+    }
+}
+
+/// A call to `service`/`method` with an encoded request message, see
+/// `Client::call_raw()`.
+pub(crate) fn raw_request(service: &str, method: &str, payload: Vec<u8>, ctx: Context) -> Request {
+    Request {
+        service: service.to_string(),
+        method: method.to_string(),
+        timeout_nano: ctx.timeout_nano,
+        metadata: context::to_pb(ctx.metadata),
+        payload,
+        ..Default::default()
+    }
+}
+
+const PROBE_SERVICE: &str = "ttrpc.Probe";
+const PROBE_METHOD: &str = "Probe";
+
+/// A call to check that a ttrpc server answers on a new connection. The
+/// servers of this crate answer it themselves, others fail it as a method
+/// they don't have.
+pub(crate) fn probe_request(timeout: Duration) -> Request {
+    Request {
+        service: PROBE_SERVICE.to_string(),
+        method: PROBE_METHOD.to_string(),
+        timeout_nano: timeout.as_nanos() as i64,
+        ..Default::default()
+    }
+}
+
+/// Tells if `req` is a [`probe_request()`], which a server answers before
+/// any handler, fallback or interceptor sees it.
+pub(crate) fn is_probe(req: &Request) -> bool {
+    req.service == PROBE_SERVICE && req.method == PROBE_METHOD
+}
+
+/// Gets the outcome of a [`probe_request()`]. Any status shows the server
+/// answered, but for UNAVAILABLE, which it sends when it won't take calls.
+pub(crate) fn probe_result<T>(res: Result<T>) -> Result<()> {
+    match res {
+        Ok(_) => Ok(()),
+        Err(Error::RpcStatus(s)) if s.code() != Code::UNAVAILABLE => Ok(()),
+        Err(e @ Error::RpcStatus(_)) => Err(e),
+        Err(e) => Err(Error::Others(format!(
+            "no ttrpc server answered on the connection: {:?}",
+            e
+        ))),
+    }
+}
+
 // SOCK_CLOEXEC flag is Linux specific
 #[cfg(any(target_os = "linux", target_os = "android"))]
 pub(crate) const SOCK_CLOEXEC: SockFlag = SockFlag::SOCK_CLOEXEC;
@@ -171,11 +753,82 @@ pub(crate) fn do_bind(sockaddr: &str) -> Result<(RawFd, Domain)> {
 pub(crate) unsafe fn client_connect(sockaddr: &str) -> Result<RawFd> {
     let (fd, _, sockaddr) = make_socket((sockaddr, VMADDR_CID_HOST))?;
 
-    connect(fd, &sockaddr)?;
+    if let Err(e) = connect(fd, &sockaddr) {
+        nix::unistd::close(fd).unwrap_or(());
+        return Err(e.into());
+    }
+
+    Ok(fd)
+}
+
+/// Connects to `sockaddr` as [`client_connect()`] does, failing with
+/// ETIMEDOUT unless connected within `timeout`.
+pub(crate) unsafe fn client_connect_timeout(sockaddr: &str, timeout: Duration) -> Result<RawFd> {
+    let (fd, _, sockaddr) = make_socket((sockaddr, VMADDR_CID_HOST))?;
+
+    if let Err(e) = connect_within(fd, &sockaddr, Instant::now() + timeout) {
+        nix::unistd::close(fd).unwrap_or(());
+        return Err(e);
+    }
 
     Ok(fd)
 }
 
+// Connects without blocking, so the connect can be given up on at
+// `deadline`, and makes the socket blocking again.
+fn connect_within(fd: RawFd, addr: &SockAddr, deadline: Instant) -> Result<()> {
+    use nix::errno::Errno;
+
+    let flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+    fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+    loop {
+        match connect(fd, addr) {
+            Ok(()) => break,
+            // The listener of a unix socket has a full backlog.
+            Err(Errno::EAGAIN) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left == Duration::from_secs(0) {
+                    return Err(Error::Nix(Errno::ETIMEDOUT));
+                }
+                std::thread::sleep(left.min(Duration::from_millis(10)));
+            }
+            Err(Errno::EINPROGRESS) | Err(Errno::EINTR) => {
+                wait_writable(fd, deadline)?;
+                match getsockopt(fd, sockopt::SocketError)? {
+                    0 => break,
+                    errno => return Err(Error::Nix(Errno::from_i32(errno))),
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    fcntl(fd, FcntlArg::F_SETFL(flags))?;
+    Ok(())
+}
+
+fn wait_writable(fd: RawFd, deadline: Instant) -> Result<()> {
+    use nix::errno::Errno;
+
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left == Duration::from_secs(0) {
+            return Err(Error::Nix(Errno::ETIMEDOUT));
+        }
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+        let ms = left.as_millis().clamp(1, i32::MAX as u128) as i32;
+        match unsafe { libc::poll(&mut pollfd, 1, ms) } {
+            -1 if Errno::last() == Errno::EINTR => continue,
+            -1 => return Err(Error::Nix(Errno::last())),
+            0 => continue,
+            _ => return Ok(()),
+        }
+    }
+}
+
 macro_rules! cfg_sync {
     ($($item:item)*) => {
         $(
@@ -196,10 +849,48 @@ macro_rules! cfg_async {
     }
 }
 
+macro_rules! cfg_audit {
+    ($($item:item)*) => {
+        $(
+            #[cfg(feature = "audit")]
+            #[cfg_attr(docsrs, doc(cfg(feature = "audit")))]
+            $item
+        )*
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_raw_request() {
+        let ctx = context::with_timeout(10).with_value("Key", "v");
+        let req = raw_request("grpc.Svc", "Get", b"abc".to_vec(), ctx);
+        assert_eq!(req.service, "grpc.Svc");
+        assert_eq!(req.method, "Get");
+        assert_eq!(req.timeout_nano, 10);
+        assert_eq!(req.payload, b"abc");
+        assert_eq!(context::from_pb(&req.metadata)["key"], ["v"]);
+    }
+
+    #[test]
+    fn test_state_watch() {
+        let watch = StateWatch::new(ConnectionState::Connecting);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hook_seen = seen.clone();
+        watch.add_hook(Arc::new(move |state| hook_seen.lock().unwrap().push(state)));
+
+        watch.set(ConnectionState::Ready);
+        watch.set(ConnectionState::Ready);
+        watch.set(ConnectionState::Reconnecting);
+        assert_eq!(watch.get(), ConnectionState::Reconnecting);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![ConnectionState::Ready, ConnectionState::Reconnecting]
+        );
+    }
+
     #[cfg(any(target_os = "linux", target_os = "android"))]
     #[test]
     fn test_parse_sockaddr() {
@@ -258,4 +949,195 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_covers_call() {
+        let req = Request {
+            service: "grpc.Containerd".to_string(),
+            method: "Checkpoint".to_string(),
+            ..Default::default()
+        };
+        assert!(covers_call("grpc.Containerd", &req));
+        assert!(covers_call("grpc.Containerd/Checkpoint", &req));
+        assert!(!covers_call("grpc.Containerd/Restore", &req));
+        assert!(!covers_call("grpc.Contain", &req));
+        assert!(!covers_call("grpc.ContainerdX", &req));
+    }
+
+    #[test]
+    fn test_get_deadline() {
+        let now = Instant::now();
+        assert_eq!(get_deadline(now, 0), None);
+        assert_eq!(get_deadline(now, -1), None);
+        assert_eq!(
+            get_deadline(now, 1_000_000),
+            Some(now + Duration::from_millis(1))
+        );
+    }
+
+    #[test]
+    fn test_connect_retry_backoff() {
+        let retry = ConnectRetry {
+            timeout: Duration::from_secs(1),
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(100),
+            jitter_seed: Some(1),
+        };
+        assert_eq!(retry.backoff(0, 0.0), Duration::from_millis(5));
+        assert_eq!(retry.backoff(2, 0.0), Duration::from_millis(20));
+        assert!(retry.backoff(2, 0.99) < Duration::from_millis(40));
+        assert_eq!(retry.backoff(10, 0.0), Duration::from_millis(50));
+        assert_eq!(retry.backoff(40, 0.0), Duration::from_millis(50));
+
+        let mut jitter = Jitter::new(retry.jitter_seed);
+        assert_eq!(
+            retry.next_backoff(Duration::from_secs(2), 0, &mut jitter),
+            None
+        );
+        let backoff = retry.next_backoff(Duration::from_millis(995), 3, &mut jitter);
+        assert_eq!(backoff, Some(Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn test_jitter_seed() {
+        let (mut a, mut b) = (Jitter::new(Some(7)), Jitter::new(Some(7)));
+        for _ in 0..100 {
+            let sample = a.sample();
+            assert!((0.0..1.0).contains(&sample));
+            assert_eq!(sample, b.sample());
+        }
+        assert_ne!(Jitter::new(Some(8)).sample(), Jitter::new(Some(7)).sample());
+    }
+
+    #[test]
+    fn test_retry_policy_next_backoff() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(10),
+            jitter_seed: Some(1),
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let mut jitter = Jitter::new(policy.jitter_seed);
+        let unavailable = Error::RpcStatus(get_status(Code::UNAVAILABLE, "restarting"));
+        let backoff = policy
+            .next_backoff(1, &unavailable, None, now, &mut jitter)
+            .unwrap();
+        assert!(backoff >= Duration::from_millis(5) && backoff < Duration::from_millis(10));
+        // The same seed waits the same.
+        let mut again = Jitter::new(policy.jitter_seed);
+        assert_eq!(
+            policy.next_backoff(1, &unavailable, None, now, &mut again),
+            Some(backoff)
+        );
+        let reset = Error::Socket("reset".to_string());
+        assert!(policy
+            .next_backoff(2, &reset, None, now, &mut jitter)
+            .is_some());
+        // Out of attempts.
+        assert_eq!(
+            policy.next_backoff(3, &unavailable, None, now, &mut jitter),
+            None
+        );
+        // Not retried for the status, nor for the timeout.
+        let not_found = Error::RpcStatus(get_status(Code::NOT_FOUND, "no such task"));
+        assert_eq!(
+            policy.next_backoff(1, &not_found, None, now, &mut jitter),
+            None
+        );
+        let deadline = Some(now + Duration::from_millis(4));
+        assert_eq!(
+            policy.next_backoff(1, &unavailable, deadline, now, &mut jitter),
+            None
+        );
+        let deadline = Some(now + Duration::from_secs(1));
+        assert!(policy
+            .next_backoff(1, &unavailable, deadline, now, &mut jitter)
+            .is_some());
+    }
+
+    #[test]
+    fn test_is_transport_not_ready() {
+        assert!(is_transport_not_ready(&Error::Nix(
+            nix::Error::ECONNREFUSED
+        )));
+        assert!(is_transport_not_ready(&Error::Nix(nix::Error::ENODEV)));
+        assert!(!is_transport_not_ready(&Error::Nix(nix::Error::EACCES)));
+        assert!(!is_transport_not_ready(&Error::Others(
+            "sockaddr is not right".to_string()
+        )));
+    }
+
+    #[test]
+    fn test_probe_result() {
+        use crate::error::get_status;
+
+        assert!(probe_result(Ok(())).is_ok());
+        assert!(probe_result::<()>(Err(Error::RpcStatus(get_status(Code::NOT_FOUND, "")))).is_ok());
+        assert!(matches!(
+            probe_result::<()>(Err(Error::RpcStatus(get_status(Code::UNAVAILABLE, "")))),
+            Err(Error::RpcStatus(_))
+        ));
+        assert!(matches!(
+            probe_result::<()>(Err(Error::Socket("broken pipe".to_string()))),
+            Err(Error::Others(_))
+        ));
+    }
+
+    #[test]
+    fn test_socket_path() {
+        let path = std::env::temp_dir().join(format!("ttrpc-path-{}.sock", std::process::id()));
+        let (fd, _) = do_bind(&format!("unix://{}", path.display())).unwrap();
+        let got = get_socket_path(fd);
+        assert_eq!(got, Some(path.clone()));
+
+        nix::unistd::close(fd).unwrap();
+        remove_socket_path(got);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_client_connect_timeout() {
+        let path = std::env::temp_dir().join(format!("ttrpc-connect-{}.sock", std::process::id()));
+        let addr = format!("unix://{}", path.display());
+        let timeout = Duration::from_secs(1);
+        assert!(matches!(
+            unsafe { client_connect_timeout(&addr, timeout) },
+            Err(Error::Nix(nix::Error::ENOENT))
+        ));
+
+        let (listener, _) = do_bind(&addr).unwrap();
+        do_listen(listener).unwrap();
+        let client = unsafe { client_connect_timeout(&addr, timeout).unwrap() };
+        let flags = OFlag::from_bits_truncate(fcntl(client, FcntlArg::F_GETFL).unwrap());
+        assert!(!flags.contains(OFlag::O_NONBLOCK));
+
+        for fd in [client, listener] {
+            nix::unistd::close(fd).unwrap();
+        }
+        remove_socket_path(Some(path));
+    }
+
+    #[test]
+    fn test_connection_info() {
+        let path = std::env::temp_dir().join(format!("ttrpc-info-{}.sock", std::process::id()));
+        let addr = format!("unix://{}", path.display());
+        let (listener, _) = do_bind(&addr).unwrap();
+        do_listen(listener).unwrap();
+        let client = unsafe { client_connect(&addr).unwrap() };
+        let fd = accept(listener).unwrap();
+
+        let info = ConnectionInfo::new(fd);
+        assert_eq!(info.local_addr, Some(addr));
+        assert_eq!(info.remote_addr, None);
+        assert_eq!(
+            info.peer_cred.map(|c| c.uid),
+            Some(nix::unistd::getuid().as_raw())
+        );
+        assert!(ConnectionInfo::new(client).id > info.id);
+
+        for fd in [fd, client, listener] {
+            nix::unistd::close(fd).unwrap();
+        }
+        remove_socket_path(Some(path));
+    }
 }
@@ -0,0 +1,320 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Compression of message payloads.
+//!
+//! The flags of a frame tell whether its payload is compressed, and with
+//! which algorithm. Requests also list the algorithms the client can
+//! decompress in their metadata, under [`ACCEPT_ENCODING_KEY`], a server
+//! only compresses the responses of clients that do. A client compresses its
+//! requests only when configured to, so it must know that the server
+//! supports the algorithm.
+//!
+//! Each algorithm is built in with a cargo feature: `gzip` or `zstd`.
+//!
+//! Only the async client and server compress the messages they send. The
+//! sync ones take compressed messages, and the sync client lists the
+//! algorithms it accepts, but send theirs uncompressed.
+
+// Only the async client and server compress messages.
+#![cfg_attr(not(feature = "async"), allow(dead_code))]
+
+use crate::error::{Error, Result};
+use crate::proto::{
+    GenMessage, KeyValue, MessageHeader, Request, FLAG_COMPRESSED_GZIP, FLAG_COMPRESSED_ZSTD,
+};
+
+/// The request metadata key under which a client lists the algorithms it
+/// can decompress responses with, by name: `gzip` or `zstd`.
+pub const ACCEPT_ENCODING_KEY: &str = "ttrpc-accept-encoding";
+
+const ALGORITHMS: [Compression; 2] = [Compression::Gzip, Compression::Zstd];
+
+/// A compression algorithm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Returns true if support for the algorithm is built in.
+    pub fn is_supported(self) -> bool {
+        match self {
+            Compression::Gzip => cfg!(feature = "gzip"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    fn flag(self) -> u8 {
+        match self {
+            Compression::Gzip => FLAG_COMPRESSED_GZIP,
+            Compression::Zstd => FLAG_COMPRESSED_ZSTD,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    // Of the algorithm in a set of accepted ones.
+    pub(crate) fn accept_bit(self) -> u8 {
+        match self {
+            Compression::Gzip => 0x1,
+            Compression::Zstd => 0x2,
+        }
+    }
+
+    fn from_flags(flags: u8) -> Option<Self> {
+        if flags & FLAG_COMPRESSED_GZIP != 0 {
+            Some(Compression::Gzip)
+        } else if flags & FLAG_COMPRESSED_ZSTD != 0 {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    #[allow(unused_variables)]
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                use std::io::Write;
+
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(data)
+                    .map_err(err_to_others_err!(e, "gzip compress failed: "))?;
+                encoder
+                    .finish()
+                    .map_err(err_to_others_err!(e, "gzip compress failed: "))
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::stream::encode_all(data, 0)
+                .map_err(err_to_others_err!(e, "zstd compress failed: ")),
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::Others(format!("{:?} is not supported", self))),
+        }
+    }
+
+    fn decompress(self, data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+        // Don't let a small frame expand past what the peer could have sent
+        // uncompressed.
+        let out = self.decompress_with_limit(data, max_len as u64 + 1)?;
+        if out.len() > max_len {
+            return Err(Error::Others(format!(
+                "decompressed message exceeds maximum message size of {}",
+                max_len
+            )));
+        }
+        Ok(out)
+    }
+
+    #[allow(unused_variables)]
+    fn decompress_with_limit(self, data: &[u8], limit: u64) -> Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                use std::io::Read;
+
+                let mut out = Vec::new();
+                flate2::read::GzDecoder::new(data)
+                    .take(limit)
+                    .read_to_end(&mut out)
+                    .map_err(err_to_others_err!(e, "gzip decompress failed: "))?;
+                Ok(out)
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                use std::io::Read;
+
+                let mut out = Vec::new();
+                zstd::stream::read::Decoder::new(data)
+                    .map_err(err_to_others_err!(e, "zstd decompress failed: "))?
+                    .take(limit)
+                    .read_to_end(&mut out)
+                    .map_err(err_to_others_err!(e, "zstd decompress failed: "))?;
+                Ok(out)
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::Others(format!("{:?} is not supported", self))),
+        }
+    }
+}
+
+/// How a client or server compresses the messages it sends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompressionConfig {
+    pub algorithm: Compression,
+    /// Payloads smaller than this are sent as they are.
+    pub threshold: usize,
+}
+
+/// Overrides, for a single call, how the client compresses its request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompressionOverride {
+    /// The request is sent as it is, for payloads that are compressed
+    /// already, such as image layers.
+    Off,
+    /// The request is compressed with the config, even if the client
+    /// doesn't compress its requests, for large and compressible payloads.
+    On(CompressionConfig),
+}
+
+/// Lists every algorithm built in in the metadata of `req`.
+pub(crate) fn add_accept_encoding(req: &mut Request) {
+    for c in ALGORITHMS.iter().filter(|c| c.is_supported()) {
+        req.metadata.push(KeyValue {
+            key: ACCEPT_ENCODING_KEY.to_string(),
+            value: c.name().to_string(),
+            ..Default::default()
+        });
+    }
+}
+
+/// Gets the algorithms the client of `req` can decompress, as accept bits.
+pub(crate) fn accepted(req: &Request) -> u8 {
+    req.metadata
+        .iter()
+        .filter(|kv| kv.key == ACCEPT_ENCODING_KEY)
+        .flat_map(|kv| ALGORITHMS.iter().filter(move |c| c.name() == kv.value))
+        .fold(0, |accept, c| accept | c.accept_bit())
+}
+
+/// Compresses the payload of `msg` according to `config`, if the peer
+/// accepts the algorithm, as told by the bits of `accept`.
+pub(crate) fn compress_message(
+    msg: &mut GenMessage,
+    config: &CompressionConfig,
+    accept: u8,
+) -> Result<()> {
+    if msg.payload.len() < config.threshold || accept & config.algorithm.accept_bit() == 0 {
+        return Ok(());
+    }
+    let payload = config.algorithm.compress(&msg.payload)?;
+    // Not worth it.
+    if payload.len() >= msg.payload.len() {
+        return Ok(());
+    }
+    msg.header.length = payload.len() as u32;
+    msg.header.add_flags(config.algorithm.flag());
+    msg.payload = payload.into();
+    Ok(())
+}
+
+/// Restores the original payload of `msg` if it was compressed, as long as
+/// it is at most `max_len` bytes long.
+pub(crate) fn decompress_message(msg: &mut GenMessage, max_len: usize) -> Result<()> {
+    if let Some(payload) = decompress_payload(&mut msg.header, &msg.payload, max_len)? {
+        msg.payload = payload.into();
+    }
+    Ok(())
+}
+
+/// Gives the original payload of a message with `header`, if it was
+/// compressed, as long as it is at most `max_len` bytes long. The header is
+/// made to match.
+pub(crate) fn decompress_payload(
+    header: &mut MessageHeader,
+    payload: &[u8],
+    max_len: usize,
+) -> Result<Option<Vec<u8>>> {
+    let compression = match Compression::from_flags(header.flags) {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+    let payload = compression.decompress(payload, max_len)?;
+    header.length = payload.len() as u32;
+    header.flags &= !(FLAG_COMPRESSED_GZIP | FLAG_COMPRESSED_ZSTD);
+    Ok(Some(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::MessageHeader;
+
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    fn roundtrip(algorithm: Compression) {
+        let payload = vec![b'a'; 4096];
+        let mut msg = GenMessage {
+            header: MessageHeader::new_response(1, payload.len() as u32),
+            payload: payload.clone().into(),
+        };
+        let config = CompressionConfig {
+            algorithm,
+            threshold: 1024,
+        };
+
+        // Not accepted by the peer.
+        compress_message(&mut msg, &config, 0).unwrap();
+        assert_eq!(msg.payload, payload);
+
+        compress_message(&mut msg, &config, algorithm.accept_bit()).unwrap();
+        assert!(msg.payload.len() < payload.len());
+        assert_eq!(msg.header.length as usize, msg.payload.len());
+
+        let mut small = msg.clone();
+        assert!(decompress_message(&mut small, payload.len() - 1).is_err());
+
+        decompress_message(&mut msg, payload.len()).unwrap();
+        assert_eq!(msg.payload, payload);
+        assert_eq!(msg.header.length as usize, payload.len());
+        assert_eq!(msg.header.flags, 0);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip() {
+        roundtrip(Compression::Gzip);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd() {
+        roundtrip(Compression::Zstd);
+    }
+
+    #[test]
+    fn test_below_threshold() {
+        let mut msg = GenMessage {
+            header: MessageHeader::new_response(1, 3),
+            payload: vec![1, 2, 3].into(),
+        };
+        let config = CompressionConfig {
+            algorithm: Compression::Gzip,
+            threshold: 1024,
+        };
+        compress_message(&mut msg, &config, Compression::Gzip.accept_bit()).unwrap();
+        assert_eq!(msg.payload, vec![1, 2, 3]);
+        assert_eq!(msg.header.flags, 0);
+    }
+
+    #[test]
+    fn test_accept_encoding() {
+        let mut req = Request::new();
+        assert_eq!(accepted(&req), 0);
+
+        add_accept_encoding(&mut req);
+        let supported = ALGORITHMS
+            .iter()
+            .filter(|c| c.is_supported())
+            .fold(0, |accept, c| accept | c.accept_bit());
+        assert_eq!(accepted(&req), supported);
+
+        // Unknown algorithms are left out.
+        req.metadata.push(KeyValue {
+            key: ACCEPT_ENCODING_KEY.to_string(),
+            value: "brotli".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(accepted(&req), supported);
+    }
+}
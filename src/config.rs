@@ -0,0 +1,336 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Configuration of a server, in one place.
+//!
+//! A [`ServerConfig`] gathers the knobs of the servers, checks they make
+//! sense together, and is applied with `set_config()` of either server:
+//!
+//! ```
+//! use std::time::Duration;
+//! use ttrpc::config::ServerConfig;
+//!
+//! let config = ServerConfig::new()
+//!     .set_max_message_size(1 << 20)
+//!     .set_idle_timeout(Duration::from_secs(300))
+//!     .set_thread_count(2, 1, 8);
+//! assert!(config.validate().is_ok());
+//! ```
+//!
+//! Knobs only one flavor of server has are refused by the other one, see
+//! [`ServerConfig::validate_for()`].
+
+use std::time::Duration;
+
+use crate::common::ConnectionLimit;
+use crate::error::{Error, Result};
+use crate::frame_limit::FrameLimit;
+use crate::proto::MESSAGE_LENGTH_MAX;
+
+// The threads a sync server starts per connection, the fewest it keeps
+// waiting for requests and the most it lets wait.
+pub(crate) const DEFAULT_THREAD_COUNT_DEFAULT: usize = 3;
+pub(crate) const DEFAULT_THREAD_COUNT_MIN: usize = 1;
+pub(crate) const DEFAULT_THREAD_COUNT_MAX: usize = 5;
+
+pub(crate) const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(5000);
+
+/// How many bytes are read from a connection at once by default.
+pub(crate) const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// The flavor of server a config is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerKind {
+    Sync,
+    Async,
+}
+
+/// The knobs of a server, see the [module docs](self). Every one defaults
+/// to what a server has unless told otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerConfig {
+    pub(crate) max_message_size: usize,
+    pub(crate) max_connections: Option<(usize, ConnectionLimit)>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) write_timeout: Option<Duration>,
+    pub(crate) shutdown_timeout: Duration,
+    pub(crate) thread_count_default: usize,
+    pub(crate) thread_count_min: usize,
+    pub(crate) thread_count_max: usize,
+    pub(crate) max_concurrent_streams: usize,
+    pub(crate) read_buffer_size: usize,
+    pub(crate) memory_limit: Option<usize>,
+    pub(crate) serial_requests: bool,
+    pub(crate) request_budget: Option<usize>,
+    pub(crate) connection_request_budget: Option<usize>,
+    pub(crate) control_frame_limit: FrameLimit,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_message_size: MESSAGE_LENGTH_MAX,
+            max_connections: None,
+            idle_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            thread_count_default: DEFAULT_THREAD_COUNT_DEFAULT,
+            thread_count_min: DEFAULT_THREAD_COUNT_MIN,
+            thread_count_max: DEFAULT_THREAD_COUNT_MAX,
+            max_concurrent_streams: usize::MAX,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            memory_limit: None,
+            serial_requests: false,
+            request_budget: None,
+            connection_request_budget: None,
+            control_frame_limit: FrameLimit::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the largest message payload accepted, defaults to
+    /// [`MESSAGE_LENGTH_MAX`].
+    pub fn set_max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Caps the connections open at once to `max`, `over` telling what
+    /// becomes of the ones coming beyond it. Unlimited by default.
+    pub fn set_max_connections(mut self, max: usize, over: ConnectionLimit) -> Self {
+        self.max_connections = Some((max, over));
+        self
+    }
+
+    /// Closes the connections idle for `timeout`. Off by default.
+    pub fn set_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Limits the time a message, once it started arriving, takes to
+    /// arrive in whole. Unlimited by default.
+    pub fn set_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Limits the time writing a message takes. Unlimited by default.
+    pub fn set_write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how long a shutdown waits for the calls being handled,
+    /// defaults to 5 seconds.
+    pub fn set_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Sets the threads a sync server gives a connection: `default` to
+    /// start with, started again once fewer than `min` wait for requests,
+    /// at most `max` waiting. Defaults to 3, 1 and 5. Sync servers only.
+    pub fn set_thread_count(mut self, default: usize, min: usize, max: usize) -> Self {
+        self.thread_count_default = default;
+        self.thread_count_min = min;
+        self.thread_count_max = max;
+        self
+    }
+
+    /// Limits the calls a connection may have open at once. Unlimited by
+    /// default. Async servers only.
+    pub fn set_max_concurrent_streams(mut self, max: usize) -> Self {
+        self.max_concurrent_streams = max;
+        self
+    }
+
+    /// Buffers reads from connections by up to `size` bytes, 0 for none.
+    /// Defaults to 8 KiB. Async servers only.
+    pub fn set_read_buffer_size(mut self, size: usize) -> Self {
+        self.read_buffer_size = size;
+        self
+    }
+
+    /// Limits the bytes held by buffered messages across the server.
+    /// Unlimited by default. Async servers only.
+    pub fn set_memory_limit(mut self, limit: usize) -> Self {
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    /// Limits the bytes of the requests being handled, across the server
+    /// and per connection, refusing the requests over either limit.
+    /// Unlimited by default. Async servers only.
+    pub fn set_request_budget(mut self, total: usize, per_connection: usize) -> Self {
+        self.request_budget = Some(total);
+        self.connection_request_budget = Some(per_connection);
+        self
+    }
+
+    /// Handles the requests of a connection one at a time, in the order
+    /// they arrive. Off by default.
+    ///
+    /// A sync server then gives each connection a single thread, whatever
+    /// [`set_thread_count()`](Self::set_thread_count) says.
+    pub fn set_serial_requests(mut self, serial: bool) -> Self {
+        self.serial_requests = serial;
+        self
+    }
+
+    /// Limits the frames a connection may send per `window` that aren't
+    /// requests or data for an active stream, disconnecting it beyond that.
+    /// Defaults to 1000 per second.
+    pub fn set_control_frame_limit(mut self, max: u32, window: Duration) -> Self {
+        self.control_frame_limit = FrameLimit { max, window };
+        self
+    }
+
+    /// Checks the knobs make sense, as applying the config does.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |msg: &str| Err(Error::Others(format!("invalid server config: {}", msg)));
+
+        if self.max_message_size == 0 || self.max_message_size > u32::MAX as usize {
+            return invalid("max message size must be between 1 and 4 GiB");
+        }
+        if let Some((0, _)) = self.max_connections {
+            return invalid("max connections must be at least 1");
+        }
+        let timeouts = [self.idle_timeout, self.read_timeout, self.write_timeout];
+        if timeouts.contains(&Some(Duration::ZERO)) {
+            return invalid("timeouts must not be zero");
+        }
+        if self.thread_count_max == 0 {
+            return invalid("max thread count must be at least 1");
+        }
+        if !(self.thread_count_min <= self.thread_count_default
+            && self.thread_count_default <= self.thread_count_max)
+        {
+            return invalid("thread counts must be min <= default <= max");
+        }
+        if self.max_concurrent_streams == 0 {
+            return invalid("max concurrent streams must be at least 1");
+        }
+        if self.memory_limit == Some(0) {
+            return invalid("memory limit must not be zero");
+        }
+        if self.request_budget == Some(0) || self.connection_request_budget == Some(0) {
+            return invalid("request budgets must not be zero");
+        }
+        Ok(())
+    }
+
+    /// Checks the knobs make sense, and that a server of `kind` has all the
+    /// ones set, as applying the config does.
+    pub fn validate_for(&self, kind: ServerKind) -> Result<()> {
+        self.validate()?;
+        let unsupported = match kind {
+            ServerKind::Sync => {
+                if self.max_concurrent_streams != usize::MAX {
+                    Some("max concurrent streams")
+                } else if self.read_buffer_size != DEFAULT_READ_BUFFER_SIZE {
+                    Some("read buffer size")
+                } else if self.memory_limit.is_some() {
+                    Some("memory limit")
+                } else if self.request_budget.is_some() || self.connection_request_budget.is_some()
+                {
+                    Some("request budget")
+                } else {
+                    None
+                }
+            }
+            ServerKind::Async => {
+                let counts = (
+                    self.thread_count_default,
+                    self.thread_count_min,
+                    self.thread_count_max,
+                );
+                let defaults = (
+                    DEFAULT_THREAD_COUNT_DEFAULT,
+                    DEFAULT_THREAD_COUNT_MIN,
+                    DEFAULT_THREAD_COUNT_MAX,
+                );
+                if counts != defaults {
+                    Some("thread count")
+                } else {
+                    None
+                }
+            }
+        };
+        match unsupported {
+            Some(knob) => Err(Error::Others(format!(
+                "invalid server config: {:?} servers have no {}",
+                kind, knob
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(ServerConfig::default().validate().is_ok());
+
+        let invalid = [
+            ServerConfig::new().set_max_message_size(0),
+            ServerConfig::new().set_max_connections(0, ConnectionLimit::Reject),
+            ServerConfig::new().set_read_timeout(Duration::ZERO),
+            ServerConfig::new().set_thread_count(0, 0, 0),
+            ServerConfig::new().set_thread_count(6, 1, 5),
+            ServerConfig::new().set_thread_count(3, 4, 5),
+            ServerConfig::new().set_max_concurrent_streams(0),
+            ServerConfig::new().set_memory_limit(0),
+            ServerConfig::new().set_request_budget(1 << 20, 0),
+        ];
+        for config in invalid.iter() {
+            assert!(config.validate().is_err(), "{:?}", config);
+        }
+
+        let config = ServerConfig::new()
+            .set_thread_count(1, 1, 1)
+            .set_max_connections(1, ConnectionLimit::Wait)
+            .set_idle_timeout(Duration::from_secs(1));
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_for() {
+        let config = ServerConfig::new()
+            .set_max_message_size(1 << 20)
+            .set_idle_timeout(Duration::from_secs(1));
+        assert!(config.validate_for(ServerKind::Sync).is_ok());
+        assert!(config.validate_for(ServerKind::Async).is_ok());
+
+        let async_only = [
+            ServerConfig::new().set_max_concurrent_streams(10),
+            ServerConfig::new().set_read_buffer_size(0),
+            ServerConfig::new().set_memory_limit(1 << 20),
+            ServerConfig::new().set_request_budget(1 << 20, 1 << 10),
+        ];
+        for config in async_only.iter() {
+            assert!(config.validate_for(ServerKind::Async).is_ok());
+            assert!(
+                config.validate_for(ServerKind::Sync).is_err(),
+                "{:?}",
+                config
+            );
+        }
+
+        let sync_only = ServerConfig::new().set_thread_count(2, 1, 8);
+        assert!(sync_only.validate_for(ServerKind::Sync).is_ok());
+        assert!(sync_only.validate_for(ServerKind::Async).is_err());
+    }
+}
@@ -3,8 +3,10 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use crate::proto::KeyValue;
+use crate::proto::{KeyValue, Status};
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 #[derive(Clone, Default, Debug)]
 pub struct Context {
@@ -19,6 +21,18 @@ pub fn with_timeout(i: i64) -> Context {
     }
 }
 
+/// Makes the context of a call timing out after `timeout`.
+pub fn with_duration(timeout: Duration) -> Context {
+    Context::default().or_timeout(timeout)
+}
+
+/// Makes the context of a call timing out at `deadline`. The timeout is
+/// what's left until then once the context is made, a deadline already
+/// past times the call out right away.
+pub fn with_deadline(deadline: Instant) -> Context {
+    with_timeout(crate::common::remaining_nano(deadline, Instant::now()))
+}
+
 pub fn with_metadata(md: HashMap<String, Vec<String>>) -> Context {
     Context {
         metadata: md,
@@ -29,11 +43,10 @@ pub fn with_metadata(md: HashMap<String, Vec<String>>) -> Context {
 impl Context {
     // appends additional values to the given key.
     pub fn add(&mut self, key: String, value: String) {
-        if let Some(ref mut vl) = self.metadata.get_mut(&key) {
-            vl.push(value);
-        } else {
-            self.metadata.insert(key.to_lowercase(), vec![value]);
-        }
+        self.metadata
+            .entry(key.to_lowercase())
+            .or_default()
+            .push(value);
     }
 
     // Set sets the provided values for a given key.
@@ -41,11 +54,96 @@ impl Context {
     // If no values provided, a key will be deleted.
     pub fn set(&mut self, key: String, value: Vec<String>) {
         if value.is_empty() {
-            self.metadata.remove(&key);
+            self.metadata.remove(&key.to_lowercase());
         } else {
             self.metadata.insert(key.to_lowercase(), value);
         }
     }
+
+    /// Adds `value` to the values of `key`, sent as metadata with the
+    /// request the context is given for:
+    ///
+    /// ```
+    /// let ctx = ttrpc::context::with_timeout(0).with_value("containerd-namespace", "k8s.io");
+    /// assert_eq!(ctx.metadata["containerd-namespace"], ["k8s.io"]);
+    /// ```
+    pub fn with_value(mut self, key: &str, value: &str) -> Context {
+        self.add(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Gets the timeout of the call, none if it has none.
+    pub fn timeout(&self) -> Option<Duration> {
+        (self.timeout_nano > 0).then(|| Duration::from_nanos(self.timeout_nano as u64))
+    }
+
+    /// Sets the timeout to `timeout` unless one is set already, generated
+    /// clients use it to apply the default timeout of a method.
+    pub fn or_timeout(mut self, timeout: Duration) -> Context {
+        if self.timeout_nano == 0 {
+            self.timeout_nano = timeout.as_nanos() as i64;
+        }
+        self
+    }
+}
+
+/// Metadata set by a server handler, sent back to the client with the response.
+///
+/// Cloning shares the same underlying map, so it can be updated through the
+/// `&TtrpcContext` handlers are given.
+#[derive(Clone, Default, Debug)]
+pub struct ResponseMetadata {
+    metadata: Arc<Mutex<HashMap<String, Vec<String>>>>,
+}
+
+impl ResponseMetadata {
+    // appends additional values to the given key.
+    pub fn add(&self, key: String, value: String) {
+        self.metadata
+            .lock()
+            .unwrap()
+            .entry(key.to_lowercase())
+            .or_default()
+            .push(value);
+    }
+
+    // Set sets the provided values for a given key.
+    // The values will overwrite any existing values.
+    // If no values provided, a key will be deleted.
+    pub fn set(&self, key: String, value: Vec<String>) {
+        let mut metadata = self.metadata.lock().unwrap();
+        if value.is_empty() {
+            metadata.remove(&key.to_lowercase());
+        } else {
+            metadata.insert(key.to_lowercase(), value);
+        }
+    }
+
+    /// Take the metadata set so far, leaving it empty.
+    pub fn take(&self) -> HashMap<String, Vec<String>> {
+        std::mem::take(&mut *self.metadata.lock().unwrap())
+    }
+}
+
+/// Final status of a streaming call, set by the server handler and sent
+/// with the trailing metadata once the handler returns successfully.
+///
+/// Cloning shares the same status, like [`ResponseMetadata`].
+#[derive(Clone, Default, Debug)]
+pub struct ResponseStatus {
+    status: Arc<Mutex<Option<Status>>>,
+}
+
+impl ResponseStatus {
+    /// Sets the status, replacing any set before.
+    pub fn set(&self, status: Status) {
+        *self.status.lock().unwrap() = Some(status);
+    }
+
+    /// Take the status set so far, if any.
+    pub fn take(&self) -> Option<Status> {
+        self.status.lock().unwrap().take()
+    }
 }
 
 pub fn from_pb(kvs: &Vec<KeyValue>) -> HashMap<String, Vec<String>> {
@@ -81,6 +179,7 @@ pub fn to_pb(kvs: HashMap<String, Vec<String>>) -> Vec<KeyValue> {
 mod tests {
     use crate::context;
     use crate::proto::KeyValue;
+    use std::time::{Duration, Instant};
 
     #[test]
     fn test_metadata() {
@@ -156,5 +255,62 @@ mod tests {
         ctx.set("key1".to_string(), vec![]);
         assert_eq!(ctx.metadata.len(), 1);
         assert_eq!(ctx.metadata.get("key1"), None);
+
+        // Keys are case insensitive.
+        let ctx = context::with_timeout(0)
+            .with_value("key1", "value1-1")
+            .with_value("Key1", "value1-2");
+        assert_eq!(
+            ctx.metadata.get("key1"),
+            Some(&vec!["value1-1".to_string(), "value1-2".to_string()])
+        );
+        let mut ctx = ctx;
+        ctx.set("KEY1".to_string(), vec![]);
+        assert!(ctx.metadata.is_empty());
+    }
+
+    #[test]
+    fn test_with_duration() {
+        let ctx = context::with_duration(Duration::from_millis(5));
+        assert_eq!(ctx.timeout_nano, 5_000_000);
+        assert_eq!(ctx.timeout(), Some(Duration::from_millis(5)));
+        assert_eq!(context::Context::default().timeout(), None);
+
+        let ctx = context::with_deadline(Instant::now() + Duration::from_secs(5));
+        let timeout = ctx.timeout().unwrap();
+        assert!(timeout > Duration::from_secs(4) && timeout <= Duration::from_secs(5));
+        // A deadline past still times out, right away.
+        let ctx = context::with_deadline(Instant::now() - Duration::from_secs(1));
+        assert_eq!(ctx.timeout_nano, 1);
+    }
+
+    #[test]
+    fn test_or_timeout() {
+        let ctx = context::Context::default().or_timeout(Duration::from_millis(5));
+        assert_eq!(ctx.timeout_nano, 5_000_000);
+
+        let ctx = context::with_timeout(99).or_timeout(Duration::from_millis(5));
+        assert_eq!(ctx.timeout_nano, 99);
+    }
+
+    #[test]
+    fn test_response_metadata() {
+        let md = context::ResponseMetadata::default();
+        let shared = md.clone();
+
+        shared.add("Key1".to_string(), "value1-1".to_string());
+        shared.add("key1".to_string(), "value1-2".to_string());
+        shared.set("key2".to_string(), vec!["value2".to_string()]);
+        shared.set("key3".to_string(), vec!["value3".to_string()]);
+        shared.set("key3".to_string(), vec![]);
+
+        let taken = md.take();
+        assert_eq!(taken.len(), 2);
+        assert_eq!(
+            taken.get("key1"),
+            Some(&vec!["value1-1".to_string(), "value1-2".to_string()])
+        );
+        assert_eq!(taken.get("key2"), Some(&vec!["value2".to_string()]));
+        assert!(md.take().is_empty());
     }
 }
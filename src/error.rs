@@ -14,7 +14,7 @@
 
 //! Error and Result of ttrpc and relevant functions, macros.
 
-use crate::proto::{Code, Status};
+use crate::proto::{Any, Code, Status};
 use std::result;
 use thiserror::Error;
 
@@ -59,7 +59,70 @@ pub fn get_rpc_status(c: Code, msg: impl ToString) -> Error {
     Error::RpcStatus(get_status(c, msg))
 }
 
-const SOCK_DICONNECTED: &str = "socket disconnected";
+/// Type url of the status detail carrying a [`ShutdownReason`].
+pub const SHUTDOWN_REASON_TYPE_URL: &str = "ttrpc/ShutdownReason";
+
+/// The reason a server terminated a call while going away.
+///
+/// It is attached to the `UNAVAILABLE` status of the affected call, so a client
+/// can decide between reconnecting right away and backing off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownReason {
+    /// The server no longer accepts new calls and is draining existing ones.
+    Drain,
+    /// The server stopped waiting for in-flight calls and closed them.
+    ForceClose,
+}
+
+impl ShutdownReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShutdownReason::Drain => "drain",
+            ShutdownReason::ForceClose => "force_close",
+        }
+    }
+
+    fn from_bytes(buf: &[u8]) -> Option<Self> {
+        match buf {
+            b"drain" => Some(ShutdownReason::Drain),
+            b"force_close" => Some(ShutdownReason::ForceClose),
+            _ => None,
+        }
+    }
+}
+
+/// Get an `UNAVAILABLE` ttrpc::Status carrying a [`ShutdownReason`] and a message.
+pub fn get_shutdown_status(reason: ShutdownReason, msg: impl ToString) -> Status {
+    let mut status = get_status(Code::UNAVAILABLE, msg);
+    status.details.push(Any {
+        type_url: SHUTDOWN_REASON_TYPE_URL.to_string(),
+        value: reason.as_str().as_bytes().to_vec(),
+        ..Default::default()
+    });
+
+    status
+}
+
+/// Get the [`ShutdownReason`] carried by a ttrpc::Status, if any.
+pub fn get_shutdown_reason(status: &Status) -> Option<ShutdownReason> {
+    status
+        .details
+        .iter()
+        .find(|d| d.type_url == SHUTDOWN_REASON_TYPE_URL)
+        .and_then(|d| ShutdownReason::from_bytes(&d.value))
+}
+
+impl Error {
+    /// Returns the [`ShutdownReason`] if the call failed because the server went away.
+    pub fn shutdown_reason(&self) -> Option<ShutdownReason> {
+        match self {
+            Error::RpcStatus(status) => get_shutdown_reason(status),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) const SOCK_DICONNECTED: &str = "socket disconnected";
 pub fn sock_error_msg(size: usize, msg: String) -> Error {
     if size == 0 {
         return Error::Socket(SOCK_DICONNECTED.to_string());
@@ -81,3 +144,23 @@ macro_rules! err_to_others {
         |$e| ::ttrpc::Error::Others($s.to_string() + &$e.to_string())
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shutdown_reason() {
+        for reason in &[ShutdownReason::Drain, ShutdownReason::ForceClose] {
+            let status = get_shutdown_status(*reason, "going away");
+            assert_eq!(status.code(), Code::UNAVAILABLE);
+            assert_eq!(status.message(), "going away");
+            assert_eq!(get_shutdown_reason(&status), Some(*reason));
+            assert_eq!(Error::RpcStatus(status).shutdown_reason(), Some(*reason));
+        }
+
+        let status = get_status(Code::UNAVAILABLE, "no reason");
+        assert_eq!(get_shutdown_reason(&status), None);
+        assert_eq!(Error::Eof.shutdown_reason(), None);
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Limiting of the frames a connection sends without making progress.
+
+use std::time::{Duration, Instant};
+
+/// The most frames a connection may send per window that carry neither a
+/// request nor data for an active stream, e.g. cancellations and subscriptions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FrameLimit {
+    pub(crate) max: u32,
+    pub(crate) window: Duration,
+}
+
+impl Default for FrameLimit {
+    fn default() -> Self {
+        FrameLimit {
+            max: 1000,
+            window: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Counts the frames of one connection against a [`FrameLimit`].
+#[derive(Debug)]
+pub(crate) struct FrameCounter {
+    limit: FrameLimit,
+    window_start: Option<Instant>,
+    count: u32,
+}
+
+impl FrameCounter {
+    pub(crate) fn new(limit: FrameLimit) -> Self {
+        FrameCounter {
+            limit,
+            window_start: None,
+            count: 0,
+        }
+    }
+
+    /// Counts a frame seen at `now`, returns false once the limit is exceeded.
+    pub(crate) fn hit(&mut self, now: Instant) -> bool {
+        match self.window_start {
+            Some(start) if now.saturating_duration_since(start) < self.limit.window => {
+                self.count = self.count.saturating_add(1);
+            }
+            _ => {
+                self.window_start = Some(now);
+                self.count = 1;
+            }
+        }
+        self.count <= self.limit.max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_counter() {
+        let mut counter = FrameCounter::new(FrameLimit {
+            max: 2,
+            window: Duration::from_secs(1),
+        });
+        let now = Instant::now();
+
+        assert!(counter.hit(now));
+        assert!(counter.hit(now + Duration::from_millis(500)));
+        assert!(!counter.hit(now + Duration::from_millis(900)));
+
+        // A new window starts over.
+        assert!(counter.hit(now + Duration::from_millis(1000)));
+        assert!(counter.hit(now + Duration::from_millis(1100)));
+        assert!(!counter.hit(now + Duration::from_millis(1200)));
+    }
+}
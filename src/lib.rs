@@ -48,13 +48,32 @@ extern crate log;
 pub mod error;
 #[macro_use]
 mod common;
+mod frame_limit;
+mod spans;
 
+pub mod access_log;
+pub mod channelz;
+pub mod codec;
+pub mod compression;
+pub mod config;
 pub mod context;
+pub mod metrics;
+pub mod rate_limit;
+pub mod reflection;
+pub mod request_queue;
+pub mod restart;
+pub mod service_fn;
+pub mod stats;
 
 pub mod proto;
 #[doc(inline)]
 pub use self::proto::{Code, MessageHeader, Request, Response, Status};
 
+#[doc(inline)]
+pub use crate::common::{
+    CloseReason, ConnectRetry, ConnectionInfo, ConnectionLimit, ConnectionState, PeerCredentials,
+    RetryPolicy,
+};
 #[doc(inline)]
 pub use crate::error::{get_status, Error, Result};
 
@@ -74,3 +93,7 @@ cfg_async! {
     #[doc(hidden)]
     pub use asynchronous as r#async;
 }
+
+cfg_audit! {
+    pub mod audit;
+}
@@ -0,0 +1,194 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Metrics of the work a server does.
+//!
+//! A [`MetricsSink`], given to `set_metrics_sink()` of either server, is
+//! told of the calls, the bytes and the connections as they come and go,
+//! leaving to it how to count them and where to export them. With the
+//! `metrics-prometheus` feature, [`prometheus::PrometheusSink`] exports
+//! them to prometheus.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::proto::Code;
+
+#[cfg(feature = "metrics-prometheus")]
+#[cfg_attr(docsrs, doc(cfg(feature = "metrics-prometheus")))]
+pub mod prometheus;
+
+/// Receives the metrics of a server. All methods do nothing by default,
+/// implement the ones of interest.
+///
+/// They are called on the paths handling calls, they should be quick.
+pub trait MetricsSink: Send + Sync {
+    /// A call of `method`, such as `grpc.Containerd/Checkpoint`, starts.
+    fn request_started(&self, _method: &str) {}
+
+    /// A call of `method` ended with `code` after `latency`.
+    fn request_finished(&self, _method: &str, _code: Code, _latency: Duration) {}
+
+    /// A message of `bytes`, header included, was read off a connection.
+    fn bytes_received(&self, _bytes: usize) {}
+
+    /// A message of `bytes`, header included, was written to a connection.
+    fn bytes_sent(&self, _bytes: usize) {}
+
+    /// The server has `count` connections open.
+    fn active_connections(&self, _count: usize) {}
+
+    /// `depth` are waiting: connections with a message for a worker in
+    /// sync servers in [`ThreadingMode::SharedPool`](crate::sync::ThreadingMode),
+    /// responses to be written in async servers. Sync servers with a
+    /// thread per connection read messages as they handle them, and don't
+    /// report it.
+    fn queue_depth(&self, _depth: usize) {}
+}
+
+/// The metrics sink of a server, if any, and what it needs counted.
+#[derive(Clone, Default)]
+pub(crate) struct Metrics {
+    sink: Option<Arc<dyn MetricsSink>>,
+    active: Arc<AtomicUsize>,
+    // Responses waiting to be written, in async servers.
+    #[cfg_attr(not(feature = "async"), allow(dead_code))]
+    queued: Arc<AtomicUsize>,
+}
+
+impl Metrics {
+    pub(crate) fn new(sink: Arc<dyn MetricsSink>) -> Self {
+        Self {
+            sink: Some(sink),
+            active: Arc::default(),
+            queued: Arc::default(),
+        }
+    }
+
+    pub(crate) fn received(&self, bytes: usize) {
+        if let Some(sink) = self.sink.as_ref() {
+            sink.bytes_received(bytes);
+        }
+    }
+
+    pub(crate) fn sent(&self, bytes: usize) {
+        if let Some(sink) = self.sink.as_ref() {
+            sink.bytes_sent(bytes);
+        }
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        if let Some(sink) = self.sink.as_ref() {
+            sink.active_connections(self.active.fetch_add(1, Ordering::SeqCst) + 1);
+        }
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        if let Some(sink) = self.sink.as_ref() {
+            sink.active_connections(self.active.fetch_sub(1, Ordering::SeqCst) - 1);
+        }
+    }
+
+    pub(crate) fn queue_depth(&self, depth: usize) {
+        if let Some(sink) = self.sink.as_ref() {
+            sink.queue_depth(depth);
+        }
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn response_queued(&self) {
+        if let Some(sink) = self.sink.as_ref() {
+            sink.queue_depth(self.queued.fetch_add(1, Ordering::SeqCst) + 1);
+        }
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn response_dequeued(&self) {
+        if let Some(sink) = self.sink.as_ref() {
+            sink.queue_depth(self.queued.fetch_sub(1, Ordering::SeqCst) - 1);
+        }
+    }
+}
+
+// Tells the sink of a server of its calls, installed along with it.
+pub(crate) struct CallMetrics(pub(crate) Arc<dyn MetricsSink>);
+
+cfg_sync! {
+    impl crate::sync::Interceptor for CallMetrics {
+        fn intercept(
+            &self,
+            ctx: crate::sync::TtrpcContext,
+            req: crate::proto::Request,
+            next: crate::sync::Next<'_>,
+        ) -> crate::Result<()> {
+            let method = format!("{}/{}", req.service, req.method);
+            let started = std::time::Instant::now();
+            self.0.request_started(&method);
+
+            let (res, response) = crate::sync::interceptor::run_watched(ctx, req, next);
+            let code = crate::sync::interceptor::call_code(&res, response);
+            self.0.request_finished(&method, code, started.elapsed());
+            res
+        }
+    }
+}
+
+cfg_async! {
+    #[async_trait::async_trait]
+    impl crate::r#async::Interceptor for CallMetrics {
+        async fn intercept(
+            &self,
+            ctx: crate::r#async::TtrpcContext,
+            req: crate::proto::Request,
+            next: crate::r#async::Next<'_>,
+        ) -> crate::Result<Option<crate::proto::Response>> {
+            let method = format!("{}/{}", req.service, req.method);
+            let started = tokio::time::Instant::now();
+            self.0.request_started(&method);
+
+            let res = next.run(ctx, req).await;
+            let code = match &res {
+                Ok(Some(response)) => response.status().code(),
+                Ok(None) => Code::OK,
+                Err(_) => Code::UNKNOWN,
+            };
+            self.0.request_finished(&method, code, started.elapsed());
+            res
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Connections(Mutex<Vec<usize>>);
+
+    impl MetricsSink for Connections {
+        fn active_connections(&self, count: usize) {
+            self.0.lock().unwrap().push(count);
+        }
+    }
+
+    #[test]
+    fn test_metrics_connections() {
+        let sink = Arc::new(Connections::default());
+        let metrics = Metrics::new(sink.clone());
+        let shared = metrics.clone();
+
+        metrics.connection_opened();
+        shared.connection_opened();
+        metrics.connection_closed();
+        assert_eq!(*sink.0.lock().unwrap(), vec![1, 2, 1]);
+
+        // Without a sink, nothing is counted.
+        let metrics = Metrics::default();
+        metrics.connection_opened();
+        assert_eq!(metrics.active.load(Ordering::SeqCst), 0);
+    }
+}
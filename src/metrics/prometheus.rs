@@ -0,0 +1,175 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! A [`MetricsSink`] keeping the metrics in a prometheus registry.
+//!
+//! The metrics are named `ttrpc_server_*`:
+//!
+//! | Metric | Type | Labels |
+//! |--------|------|--------|
+//! | `ttrpc_server_requests_started_total` | counter | `method` |
+//! | `ttrpc_server_requests_total` | counter | `method`, `code` |
+//! | `ttrpc_server_request_duration_seconds` | histogram | `method` |
+//! | `ttrpc_server_received_bytes_total` | counter | |
+//! | `ttrpc_server_sent_bytes_total` | counter | |
+//! | `ttrpc_server_connections` | gauge | |
+//! | `ttrpc_server_queue_depth` | gauge | |
+//!
+//! Serving them to the scraper is left to the application, see
+//! `example/prometheus-server.rs`.
+
+use std::time::Duration;
+
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+};
+
+use super::MetricsSink;
+use crate::error::{Error, Result};
+use crate::proto::Code;
+
+/// Prometheus metrics of a server, see the [module docs](self).
+#[derive(Clone)]
+pub struct PrometheusSink {
+    started: IntCounterVec,
+    finished: IntCounterVec,
+    duration: HistogramVec,
+    received: IntCounter,
+    sent: IntCounter,
+    connections: IntGauge,
+    queue_depth: IntGauge,
+}
+
+fn opts(name: &str, help: &str) -> Opts {
+    Opts::new(name, help).namespace("ttrpc").subsystem("server")
+}
+
+impl PrometheusSink {
+    /// Makes the metrics and registers them with `registry`, failing if
+    /// they are registered already.
+    pub fn new(registry: &Registry) -> Result<Self> {
+        let sink = Self::unregistered().map_err(err_to_others_err!(e, "Make metrics failed: "))?;
+        sink.register(registry)
+            .map_err(err_to_others_err!(e, "Register metrics failed: "))?;
+        Ok(sink)
+    }
+
+    fn unregistered() -> prometheus::Result<Self> {
+        Ok(Self {
+            started: IntCounterVec::new(
+                opts("requests_started_total", "Calls started."),
+                &["method"],
+            )?,
+            finished: IntCounterVec::new(
+                opts("requests_total", "Calls finished, by status code."),
+                &["method", "code"],
+            )?,
+            duration: HistogramVec::new(
+                HistogramOpts::from(opts("request_duration_seconds", "Time taken by the calls.")),
+                &["method"],
+            )?,
+            received: IntCounter::with_opts(opts(
+                "received_bytes_total",
+                "Bytes of the messages read.",
+            ))?,
+            sent: IntCounter::with_opts(opts(
+                "sent_bytes_total",
+                "Bytes of the messages written.",
+            ))?,
+            connections: IntGauge::with_opts(opts("connections", "Connections open."))?,
+            queue_depth: IntGauge::with_opts(opts("queue_depth", "Work waiting to be taken up."))?,
+        })
+    }
+
+    fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.started.clone()))?;
+        registry.register(Box::new(self.finished.clone()))?;
+        registry.register(Box::new(self.duration.clone()))?;
+        registry.register(Box::new(self.received.clone()))?;
+        registry.register(Box::new(self.sent.clone()))?;
+        registry.register(Box::new(self.connections.clone()))?;
+        registry.register(Box::new(self.queue_depth.clone()))
+    }
+}
+
+impl MetricsSink for PrometheusSink {
+    fn request_started(&self, method: &str) {
+        self.started.with_label_values(&[method]).inc();
+    }
+
+    fn request_finished(&self, method: &str, code: Code, latency: Duration) {
+        self.finished
+            .with_label_values(&[method, &format!("{:?}", code)])
+            .inc();
+        self.duration
+            .with_label_values(&[method])
+            .observe(latency.as_secs_f64());
+    }
+
+    fn bytes_received(&self, bytes: usize) {
+        self.received.inc_by(bytes as u64);
+    }
+
+    fn bytes_sent(&self, bytes: usize) {
+        self.sent.inc_by(bytes as u64);
+    }
+
+    fn active_connections(&self, count: usize) {
+        self.connections.set(count as i64);
+    }
+
+    fn queue_depth(&self, depth: usize) {
+        self.queue_depth.set(depth as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus_sink() {
+        let registry = Registry::new();
+        let sink = PrometheusSink::new(&registry).unwrap();
+        // Twice in one registry is an error.
+        assert!(PrometheusSink::new(&registry).is_err());
+
+        sink.request_started("svc/Method");
+        sink.request_finished("svc/Method", Code::NOT_FOUND, Duration::from_millis(3));
+        sink.bytes_received(20);
+        sink.active_connections(2);
+
+        let families = registry.gather();
+        let family = |name: &str| {
+            families
+                .iter()
+                .find(|f| f.get_name() == name)
+                .unwrap_or_else(|| panic!("no {}", name))
+        };
+        let finished = &family("ttrpc_server_requests_total").get_metric()[0];
+        let labels: Vec<_> = finished
+            .get_label()
+            .iter()
+            .map(|l| (l.get_name(), l.get_value()))
+            .collect();
+        assert_eq!(
+            labels,
+            vec![("code", "NOT_FOUND"), ("method", "svc/Method")]
+        );
+        assert_eq!(finished.get_counter().get_value(), 1.0);
+        assert_eq!(
+            family("ttrpc_server_received_bytes_total").get_metric()[0]
+                .get_counter()
+                .get_value(),
+            20.0
+        );
+        assert_eq!(
+            family("ttrpc_server_connections").get_metric()[0]
+                .get_gauge()
+                .get_value(),
+            2.0
+        );
+    }
+}
@@ -11,10 +11,18 @@ mod compiled {
 pub use compiled::ttrpc::*;
 
 use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
 use protobuf::{CodedInputStream, CodedOutputStream};
 
 #[cfg(feature = "async")]
-use crate::error::{get_rpc_status, Error, Result as TtResult};
+use crate::codec::{validate_header, HeaderLimits};
+#[cfg(feature = "async")]
+use crate::error::{get_rpc_status, get_status};
+use crate::error::{Error, Result as TtResult};
+#[cfg(feature = "async")]
+use crate::r#async::buffer_pool::BufferPool;
+#[cfg(feature = "async")]
+use std::sync::Arc;
 
 pub const MESSAGE_HEADER_LENGTH: usize = 10;
 pub const MESSAGE_LENGTH_MAX: usize = 4 << 20;
@@ -22,10 +30,43 @@ pub const MESSAGE_LENGTH_MAX: usize = 4 << 20;
 pub const MESSAGE_TYPE_REQUEST: u8 = 0x1;
 pub const MESSAGE_TYPE_RESPONSE: u8 = 0x2;
 pub const MESSAGE_TYPE_DATA: u8 = 0x3;
+pub const MESSAGE_TYPE_SUBSCRIBE: u8 = 0x4;
+pub const MESSAGE_TYPE_NOTIFICATION: u8 = 0x5;
+/// Sent by a client to abandon the request or stream with the given id.
+///
+/// An extension of this crate, Go ttrpc has no such message: a client only
+/// sends it once the server listed [`CANCEL_KEY`] in a response.
+pub const MESSAGE_TYPE_CANCEL: u8 = 0x6;
+/// Sent by a server going away, the payload is a [`GoAway`].
+pub const MESSAGE_TYPE_GOAWAY: u8 = 0x7;
+/// Grants the peer more room to send data on a stream, the payload is a
+/// [`WindowUpdate`].
+pub const MESSAGE_TYPE_WINDOW_UPDATE: u8 = 0x8;
 
 pub const FLAG_REMOTE_CLOSED: u8 = 0x1;
 pub const FLAG_REMOTE_OPEN: u8 = 0x2;
 pub const FLAG_NO_DATA: u8 = 0x4;
+/// The payload is gzip compressed.
+pub const FLAG_COMPRESSED_GZIP: u8 = 0x8;
+/// The payload is zstd compressed.
+pub const FLAG_COMPRESSED_ZSTD: u8 = 0x10;
+/// Left to applications, which give them a meaning of their own on every
+/// type of message. See [`MessageHeader::set_app_flags()`].
+pub const FLAGS_APP: u8 = 0x20 | 0x40;
+/// More frames follow with the rest of the payload.
+pub const FLAG_CONTINUED: u8 = 0x80;
+
+/// The metadata key under which a client offers, in its requests, to
+/// abandon calls with [`MESSAGE_TYPE_CANCEL`] messages, and a server that
+/// handles them agrees to, in its responses. A server that doesn't, such as
+/// a Go one, still ends a call once its client goes away or its deadline
+/// passes.
+pub const CANCEL_KEY: &str = "ttrpc-cancel";
+
+/// The request metadata key under which a client asks for no response, for
+/// a one-way request. A server that doesn't know it, such as a Go one,
+/// responds all the same, and the client drops the response.
+pub const NO_RESPONSE_KEY: &str = "ttrpc-no-response";
 
 /// Message header of ttrpc.
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
@@ -97,6 +138,62 @@ impl MessageHeader {
         }
     }
 
+    /// Creates a subscribe MessageHeader from len.
+    ///
+    /// Subscriptions are not bound to a stream, so the stream_id is 0.
+    pub fn new_subscribe(len: u32) -> Self {
+        Self {
+            length: len,
+            stream_id: 0,
+            type_: MESSAGE_TYPE_SUBSCRIBE,
+            flags: 0,
+        }
+    }
+
+    /// Creates a notification MessageHeader from len.
+    ///
+    /// Notifications are not bound to a stream, so the stream_id is 0.
+    pub fn new_notification(len: u32) -> Self {
+        Self {
+            length: len,
+            stream_id: 0,
+            type_: MESSAGE_TYPE_NOTIFICATION,
+            flags: 0,
+        }
+    }
+
+    /// Creates a request cancellation message header for the stream with the given id.
+    pub fn new_cancel(stream_id: u32) -> Self {
+        Self {
+            length: 0,
+            stream_id,
+            type_: MESSAGE_TYPE_CANCEL,
+            flags: 0,
+        }
+    }
+
+    /// Creates a go away MessageHeader from len.
+    ///
+    /// It concerns the whole connection, so the stream_id is 0.
+    pub fn new_goaway(len: u32) -> Self {
+        Self {
+            length: len,
+            stream_id: 0,
+            type_: MESSAGE_TYPE_GOAWAY,
+            flags: 0,
+        }
+    }
+
+    /// Creates a window update MessageHeader from stream_id and len.
+    pub fn new_window_update(stream_id: u32, len: u32) -> Self {
+        Self {
+            length: len,
+            stream_id,
+            type_: MESSAGE_TYPE_WINDOW_UPDATE,
+            flags: 0,
+        }
+    }
+
     /// Set the stream_id of message using the given value.
     pub fn set_stream_id(&mut self, stream_id: u32) {
         self.stream_id = stream_id;
@@ -112,6 +209,25 @@ impl MessageHeader {
         self.flags |= flags;
     }
 
+    /// Gets the flags of the message left to applications, see [`FLAGS_APP`].
+    pub fn app_flags(&self) -> u8 {
+        self.flags & FLAGS_APP
+    }
+
+    /// Sets the flags of the message left to applications, keeping the
+    /// others. Fails for flags outside [`FLAGS_APP`], which ttrpc has a use
+    /// for.
+    pub fn set_app_flags(&mut self, flags: u8) -> TtResult<()> {
+        if flags & !FLAGS_APP != 0 {
+            return Err(Error::Others(format!(
+                "flags {:#x} are not left to applications",
+                flags & !FLAGS_APP
+            )));
+        }
+        self.flags = self.flags & !FLAGS_APP | flags;
+        Ok(())
+    }
+
     pub(crate) fn into_buf(self, mut buf: impl AsMut<[u8]>) {
         let buf = buf.as_mut();
         debug_assert!(buf.len() >= MESSAGE_HEADER_LENGTH);
@@ -125,6 +241,31 @@ impl MessageHeader {
     }
 }
 
+/// Writes a header and its payload, both in a single vectored write when the
+/// writer takes it all.
+#[cfg(feature = "async")]
+async fn write_frame(
+    mut writer: impl tokio::io::AsyncWriteExt + Unpin,
+    header: MessageHeader,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    use std::io::{ErrorKind, IoSlice};
+
+    let mut buf = [0u8; MESSAGE_HEADER_LENGTH];
+    header.into_buf(&mut buf[..]);
+    let header = &buf[..];
+    let mut written = 0;
+    while written < header.len() {
+        let bufs = [IoSlice::new(&header[written..]), IoSlice::new(payload)];
+        match writer.write_vectored(&bufs).await? {
+            0 => return Err(ErrorKind::WriteZero.into()),
+            n => written += n,
+        }
+    }
+    writer.write_all(&payload[written - header.len()..]).await?;
+    writer.flush().await
+}
+
 #[cfg(feature = "async")]
 impl MessageHeader {
     /// Encodes a MessageHeader to writer.
@@ -132,10 +273,8 @@ impl MessageHeader {
         &self,
         mut writer: impl tokio::io::AsyncWriteExt + Unpin,
     ) -> std::io::Result<()> {
-        writer.write_u32(self.length).await?;
-        writer.write_u32(self.stream_id).await?;
-        writer.write_u8(self.type_).await?;
-        writer.write_u8(self.flags).await?;
+        let buf: Vec<u8> = (*self).into();
+        writer.write_all(&buf).await?;
         writer.flush().await
     }
 
@@ -150,10 +289,13 @@ impl MessageHeader {
 }
 
 /// Generic message of ttrpc.
+///
+/// The payload is shared rather than copied as the message goes from the
+/// connection through chunking and streams to the handler.
 #[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub struct GenMessage {
     pub header: MessageHeader,
-    pub payload: Vec<u8>,
+    pub payload: Bytes,
 }
 
 #[cfg(feature = "async")]
@@ -163,15 +305,9 @@ impl GenMessage {
         &self,
         mut writer: impl tokio::io::AsyncWriteExt + Unpin,
     ) -> TtResult<()> {
-        self.header
-            .write_to(&mut writer)
-            .await
-            .map_err(|e| Error::Socket(e.to_string()))?;
-        writer
-            .write_all(&self.payload)
+        write_frame(&mut writer, self.header, &self.payload)
             .await
-            .map_err(|e| Error::Socket(e.to_string()))?;
-        Ok(())
+            .map_err(|e| Error::Socket(e.to_string()))
     }
 
     /// Decodes a MessageHeader from reader.
@@ -198,9 +334,90 @@ impl GenMessage {
 
         Ok(Self {
             header,
-            payload: content,
+            payload: content.into(),
         })
     }
+    /// Decodes a message whose payload is at most `max_len` bytes long.
+    ///
+    /// The payload of a larger message is skipped and a RESOURCE_EXHAUSTED
+    /// status returned along with its header, the reader can still be used.
+    /// An error means it can't.
+    pub async fn read_from_limited(
+        reader: impl tokio::io::AsyncReadExt + Unpin,
+        max_len: usize,
+    ) -> TtResult<std::result::Result<Self, (MessageHeader, Status)>> {
+        let limits = HeaderLimits::new().set_strict(false);
+        Self::read_from_checked(reader, max_len, &limits).await
+    }
+
+    /// Like [`GenMessage::read_from_limited()`], first checking the header
+    /// against `limits`. A header failing that is an error, nothing more is
+    /// read.
+    pub async fn read_from_checked(
+        reader: impl tokio::io::AsyncReadExt + Unpin,
+        max_len: usize,
+        limits: &HeaderLimits,
+    ) -> TtResult<std::result::Result<Self, (MessageHeader, Status)>> {
+        Self::read_from_pooled(reader, max_len, limits, None).await
+    }
+
+    /// Like [`GenMessage::read_from_checked()`], reading the payload into a
+    /// buffer of `pool` if given.
+    pub(crate) async fn read_from_pooled(
+        mut reader: impl tokio::io::AsyncReadExt + Unpin,
+        max_len: usize,
+        limits: &HeaderLimits,
+        pool: Option<&Arc<BufferPool>>,
+    ) -> TtResult<std::result::Result<Self, (MessageHeader, Status)>> {
+        let header = MessageHeader::read_from(&mut reader)
+            .await
+            .map_err(|e| Error::Socket(e.to_string()))?;
+        validate_header(&header, limits)?;
+
+        if header.length as usize > max_len {
+            let skipped = tokio::io::copy(
+                &mut tokio::io::AsyncReadExt::take(&mut reader, header.length as u64),
+                &mut tokio::io::sink(),
+            )
+            .await
+            .map_err(|e| Error::Socket(e.to_string()))?;
+            if skipped != header.length as u64 {
+                return Err(Error::Socket(format!(
+                    "message length {} is not {}",
+                    skipped, header.length
+                )));
+            }
+            let status = get_status(
+                Code::RESOURCE_EXHAUSTED,
+                format!(
+                    "message length {} exceed maximum message size of {}",
+                    header.length, max_len
+                ),
+            );
+            return Ok(Err((header, status)));
+        }
+
+        let payload = match pool {
+            Some(pool) => {
+                let mut content = pool.get(header.length as usize);
+                reader
+                    .read_exact(content.as_mut())
+                    .await
+                    .map_err(|e| Error::Socket(e.to_string()))?;
+                content.freeze()
+            }
+            None => {
+                let mut content = vec![0; header.length as usize];
+                reader
+                    .read_exact(&mut content)
+                    .await
+                    .map_err(|e| Error::Socket(e.to_string()))?;
+                content.into()
+            }
+        };
+
+        Ok(Ok(Self { header, payload }))
+    }
 }
 
 /// TTRPC codec, only protobuf is supported.
@@ -264,7 +481,7 @@ where
     fn try_from(msg: Message<C>) -> Result<Self, Self::Error> {
         Ok(Self {
             header: msg.header,
-            payload: msg.payload.encode()?,
+            payload: msg.payload.encode()?.into(),
         })
     }
 }
@@ -289,19 +506,13 @@ where
         &self,
         mut writer: impl tokio::io::AsyncWriteExt + Unpin,
     ) -> TtResult<()> {
-        self.header
-            .write_to(&mut writer)
-            .await
-            .map_err(|e| Error::Socket(e.to_string()))?;
         let content = self
             .payload
             .encode()
             .map_err(err_to_others_err!(e, "Encode payload failed."))?;
-        writer
-            .write_all(&content)
+        write_frame(&mut writer, self.header, &content)
             .await
-            .map_err(|e| Error::Socket(e.to_string()))?;
-        Ok(())
+            .map_err(|e| Error::Socket(e.to_string()))
     }
 
     /// Decodes a MessageHeader from reader.
@@ -360,6 +571,22 @@ mod tests {
         assert_eq!(mh.length as usize, TEST_PAYLOAD_LEN);
     }
 
+    #[test]
+    fn app_flags() {
+        let mut mh = MessageHeader::new_request(1, 0);
+        mh.set_flags(FLAG_REMOTE_CLOSED | 0x40);
+        assert_eq!(mh.app_flags(), 0x40);
+
+        mh.set_app_flags(0x20).unwrap();
+        assert_eq!(mh.app_flags(), 0x20);
+        assert_eq!(mh.flags, FLAG_REMOTE_CLOSED | 0x20);
+
+        assert!(mh.set_app_flags(FLAG_CONTINUED | 0x40).is_err());
+        assert_eq!(mh.flags, FLAG_REMOTE_CLOSED | 0x20);
+        mh.set_app_flags(0).unwrap();
+        assert_eq!(mh.flags, FLAG_REMOTE_CLOSED);
+    }
+
     #[rustfmt::skip]
     static PROTOBUF_MESSAGE_HEADER: [u8; MESSAGE_HEADER_LENGTH] = [
         0x00, 0x0, 0x0, TEST_PAYLOAD_LEN as u8, // length
@@ -444,7 +671,7 @@ mod tests {
         assert_eq!(gen.header.stream_id, 0x123456);
         assert_eq!(gen.header.type_, MESSAGE_TYPE_REQUEST);
         assert_eq!(gen.header.flags, 0xef);
-        assert_eq!(&gen.payload, &PROTOBUF_REQUEST);
+        assert_eq!(&gen.payload[..], &PROTOBUF_REQUEST);
         assert_eq!(
             &buf[MESSAGE_HEADER_LENGTH + TEST_PAYLOAD_LEN..],
             &[0x0, 0x0]
@@ -456,6 +683,144 @@ mod tests {
         assert_eq!(&*dbuf, &buf[..MESSAGE_HEADER_LENGTH + TEST_PAYLOAD_LEN]);
     }
 
+    // Takes at most `max` bytes a write, counting the writes.
+    #[cfg(feature = "async")]
+    struct Trickle {
+        buf: Vec<u8>,
+        max: usize,
+        writes: usize,
+    }
+
+    #[cfg(feature = "async")]
+    impl tokio::io::AsyncWrite for Trickle {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.poll_write_vectored(cx, &[std::io::IoSlice::new(buf)])
+        }
+
+        fn poll_write_vectored(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            bufs: &[std::io::IoSlice<'_>],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.writes += 1;
+            let mut n = 0;
+            for buf in bufs {
+                let take = buf.len().min(self.max - n);
+                self.buf.extend_from_slice(&buf[..take]);
+                n += take;
+            }
+            std::task::Poll::Ready(Ok(n))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_gen_message_vectored() {
+        let gen = GenMessage {
+            header: MessageHeader::new_request(1, PROTOBUF_REQUEST.len() as u32),
+            payload: Bytes::from_static(&PROTOBUF_REQUEST),
+        };
+        let mut expected: Vec<u8> = gen.header.into();
+        expected.extend_from_slice(&PROTOBUF_REQUEST);
+
+        // Header and payload go in a single write.
+        let mut w = Trickle {
+            buf: vec![],
+            max: usize::MAX,
+            writes: 0,
+        };
+        gen.write_to(&mut w).await.unwrap();
+        assert_eq!(w.buf, expected);
+        assert_eq!(w.writes, 1);
+
+        // And still all of them when writes are short.
+        for max in [3, MESSAGE_HEADER_LENGTH, MESSAGE_HEADER_LENGTH + 1] {
+            let mut w = Trickle {
+                buf: vec![],
+                max,
+                writes: 0,
+            };
+            gen.write_to(&mut w).await.unwrap();
+            assert_eq!(w.buf, expected);
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_gen_message_limited() {
+        let mut buf = Vec::from(PROTOBUF_MESSAGE_HEADER);
+        buf.extend_from_slice(&PROTOBUF_REQUEST);
+        buf.extend_from_slice(&PROTOBUF_MESSAGE_HEADER);
+        buf.extend_from_slice(&PROTOBUF_REQUEST);
+        let mut reader = &*buf;
+
+        // The payload is skipped, leaving the reader at the next message.
+        let (header, status) = GenMessage::read_from_limited(&mut reader, TEST_PAYLOAD_LEN - 1)
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(header.stream_id, 0x123456);
+        assert_eq!(status.code(), Code::RESOURCE_EXHAUSTED);
+
+        let gen = GenMessage::read_from_limited(&mut reader, TEST_PAYLOAD_LEN)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&gen.payload[..], &PROTOBUF_REQUEST);
+        assert!(reader.is_empty());
+
+        // A truncated payload can't be skipped.
+        let mut buf = Vec::from(MESSAGE_HEADER);
+        buf.extend_from_slice(&PROTOBUF_REQUEST);
+        let res = GenMessage::read_from_limited(&*buf, MESSAGE_LENGTH_MAX).await;
+        assert!(matches!(res, Err(Error::Socket(_))));
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_gen_message_checked() {
+        let mut buf = Vec::from(PROTOBUF_MESSAGE_HEADER);
+        buf.extend_from_slice(&PROTOBUF_REQUEST);
+
+        // A request on an even stream id, with contradicting flags.
+        let res =
+            GenMessage::read_from_checked(&*buf, MESSAGE_LENGTH_MAX, &HeaderLimits::new()).await;
+        assert!(matches!(res, Err(Error::RpcStatus(s)) if s.code() == Code::INVALID_ARGUMENT));
+
+        let limits = HeaderLimits::new().set_strict(false);
+        let gen = GenMessage::read_from_checked(&*buf, MESSAGE_LENGTH_MAX, &limits)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&gen.payload[..], &PROTOBUF_REQUEST);
+
+        let limits = limits.set_max_length(TEST_PAYLOAD_LEN as u32 - 1);
+        let res = GenMessage::read_from_checked(&*buf, MESSAGE_LENGTH_MAX, &limits).await;
+        assert!(matches!(res, Err(Error::RpcStatus(_))));
+    }
+
     #[cfg(feature = "async")]
     #[tokio::test]
     async fn async_message() {
@@ -0,0 +1,200 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Rate limiting of the calls a server handles.
+//!
+//! A [`RateLimiter`] is an interceptor, installed with `add_interceptor()` of
+//! either server. It lets calls through at a steady rate with room for
+//! bursts, and fails the others with RESOURCE_EXHAUSTED:
+//!
+//! ```
+//! use ttrpc::rate_limit::RateLimiter;
+//!
+//! // 10 calls a second per connection, up to 20 at once.
+//! let limiter = RateLimiter::new(10, 20).per_connection();
+//! // 1 checkpoint a second for the whole server.
+//! let checkpoints = RateLimiter::new(1, 1).for_calls("grpc.Containerd/Checkpoint");
+//! ```
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::common;
+use crate::error::get_status;
+use crate::proto::{Code, Request, Status};
+
+// Beyond this many buckets, the full ones are dropped when adding another.
+const MAX_IDLE_BUCKETS: usize = 64;
+
+/// Token bucket rate limiter of calls, see the [module docs](self).
+pub struct RateLimiter {
+    rate: u32,
+    burst: u32,
+    per_connection: bool,
+    name: Option<String>,
+    buckets: Mutex<HashMap<u64, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Lets `rate` calls a second through, and up to `burst` at once after
+    /// a quiet time. All calls share the limit unless told otherwise.
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: burst.max(1),
+            per_connection: false,
+            name: None,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Gives each connection a limit of its own.
+    pub fn per_connection(mut self) -> Self {
+        self.per_connection = true;
+        self
+    }
+
+    /// Limits only the calls of `name`, a service such as `grpc.Containerd`
+    /// or a method such as `grpc.Containerd/Checkpoint`.
+    pub fn for_calls(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    // Takes a token for the call `req` on `connection`, the status to fail
+    // it with if there is none left.
+    fn acquire(&self, connection: u64, req: &Request, now: Instant) -> Result<(), Status> {
+        if let Some(name) = self.name.as_deref() {
+            if !common::covers_call(name, req) {
+                return Ok(());
+            }
+        }
+        let key = if self.per_connection { connection } else { 0 };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.contains_key(&key) && buckets.len() >= MAX_IDLE_BUCKETS {
+            // A full bucket is as good as a new one, forget them.
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst as f64);
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst as f64,
+            updated: now,
+        });
+        if self.refill(bucket, now) < 1.0 {
+            return Err(get_status(
+                Code::RESOURCE_EXHAUSTED,
+                format!(
+                    "rate limit exceeded, {} calls a second are allowed",
+                    self.rate
+                ),
+            ));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    // Adds the tokens gained since the bucket was last updated.
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * self.rate as f64).min(self.burst as f64);
+        bucket.updated = now;
+        bucket.tokens
+    }
+}
+
+cfg_sync! {
+    impl crate::sync::Interceptor for RateLimiter {
+        fn intercept(
+            &self,
+            ctx: crate::sync::TtrpcContext,
+            req: Request,
+            next: crate::sync::Next<'_>,
+        ) -> crate::Result<()> {
+            self.acquire(ctx.connection.id, &req, Instant::now())
+                .map_err(crate::Error::RpcStatus)?;
+            next.run(ctx, req)
+        }
+    }
+}
+
+cfg_async! {
+    #[async_trait::async_trait]
+    impl crate::r#async::Interceptor for RateLimiter {
+        async fn intercept(
+            &self,
+            ctx: crate::r#async::TtrpcContext,
+            req: Request,
+            next: crate::r#async::Next<'_>,
+        ) -> crate::Result<Option<crate::proto::Response>> {
+            let now = tokio::time::Instant::now().into_std();
+            if let Err(status) = self.acquire(ctx.connection.id, &req, now) {
+                let mut res = crate::proto::Response::new();
+                res.set_status(status);
+                return Ok(Some(res));
+            }
+            next.run(ctx, req).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn request(method: &str) -> Request {
+        Request {
+            service: "svc".to_string(),
+            method: method.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(2, 3).for_calls("svc/Limited");
+        let now = Instant::now();
+        let limited = request("Limited");
+
+        // The burst goes through, then the calls come at the rate.
+        for _ in 0..3 {
+            assert!(limiter.acquire(1, &limited, now).is_ok());
+        }
+        let status = limiter.acquire(1, &limited, now).unwrap_err();
+        assert_eq!(status.code(), Code::RESOURCE_EXHAUSTED);
+        // Shared by all connections.
+        assert!(limiter.acquire(2, &limited, now).is_err());
+        assert!(limiter.acquire(1, &request("Other"), now).is_ok());
+
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.acquire(1, &limited, later).is_ok());
+        assert!(limiter.acquire(1, &limited, later).is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter_per_connection() {
+        let limiter = RateLimiter::new(1, 1).per_connection();
+        let now = Instant::now();
+        let req = request("Any");
+
+        assert!(limiter.acquire(1, &req, now).is_ok());
+        assert!(limiter.acquire(1, &req, now).is_err());
+        assert!(limiter.acquire(2, &req, now).is_ok());
+
+        // Full buckets are dropped to make room.
+        let later = now + Duration::from_secs(1);
+        for id in 3..3 + MAX_IDLE_BUCKETS as u64 {
+            assert!(limiter.acquire(id, &req, later).is_ok());
+        }
+        assert!(limiter.buckets.lock().unwrap().len() <= MAX_IDLE_BUCKETS);
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! List the services a server offers.
+//!
+//! A server with reflection enabled answers calls to the [`LIST_SERVICES`]
+//! method of the [`SERVICE`] service, whose request payload is empty, with a
+//! [`ListServicesResponse`] of its services and their methods. Tools can then
+//! discover what a socket offers without knowing its protos.
+
+use std::collections::BTreeMap;
+
+pub use crate::proto::{ListServicesResponse, MethodInfo, ServiceInfo};
+
+/// The name of the reflection service.
+pub const SERVICE: &str = "ttrpc.reflection.Reflection";
+/// The method of the reflection service listing the services.
+pub const LIST_SERVICES: &str = "ListServices";
+
+// Lists the methods, given as (service, method, streaming), by service.
+// Both are sorted by name.
+pub(crate) fn list_services<'a>(
+    methods: impl Iterator<Item = (&'a str, &'a str, bool)>,
+) -> ListServicesResponse {
+    let mut services: BTreeMap<&str, Vec<MethodInfo>> = BTreeMap::new();
+    for (service, method, streaming) in methods {
+        services.entry(service).or_default().push(MethodInfo {
+            name: method.to_string(),
+            streaming,
+            ..Default::default()
+        });
+    }
+
+    ListServicesResponse {
+        services: services
+            .into_iter()
+            .map(|(name, mut methods)| {
+                methods.sort_by(|a, b| a.name.cmp(&b.name));
+                ServiceInfo {
+                    name: name.to_string(),
+                    methods,
+                    ..Default::default()
+                }
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_services() {
+        let methods = vec![
+            ("b.Svc", "Watch", true),
+            ("a.Svc", "Get", false),
+            ("b.Svc", "Get", false),
+        ];
+        let list = list_services(methods.into_iter());
+
+        let names: Vec<_> = list.services.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["a.Svc", "b.Svc"]);
+        let methods: Vec<_> = list.services[1]
+            .methods
+            .iter()
+            .map(|m| (m.name.as_str(), m.streaming))
+            .collect();
+        assert_eq!(methods, [("Get", false), ("Watch", true)]);
+    }
+}
@@ -0,0 +1,229 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! A bounded queue of the calls waiting for a handler.
+//!
+//! A [`RequestQueue`] is an interceptor, installed with `add_interceptor()`
+//! of either server. It lets `max_running` calls run at once, queues up to
+//! `max_queued` more, and answers the calls beyond with RESOURCE_EXHAUSTED
+//! rather than letting them pile up. Clones share the queue, keep one to
+//! follow its [`depth()`](RequestQueue::depth):
+//!
+//! ```
+//! use ttrpc::request_queue::RequestQueue;
+//!
+//! let queue = RequestQueue::new(8, 32);
+//! # #[cfg(feature = "sync")]
+//! let server = ttrpc::Server::new().add_interceptor(queue.clone());
+//! assert_eq!(queue.depth(), 0);
+//! ```
+//!
+//! A queued call holds on to the thread handling it in sync servers, give
+//! them more threads than `max_running`, up to `max_running + max_queued`
+//! for the queue to fill before they run out.
+
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::error::get_status;
+use crate::proto::{Code, Status};
+
+/// A bounded queue of calls, see the [module docs](self).
+#[derive(Clone)]
+pub struct RequestQueue(Arc<Inner>);
+
+struct Inner {
+    max_running: usize,
+    max_queued: usize,
+    state: Mutex<State>,
+    // Wakes up the sync calls waiting, on a call ending.
+    done: Condvar,
+    // Wakes up the async calls waiting, on a call ending.
+    #[cfg(feature = "async")]
+    released: tokio::sync::Notify,
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    queued: usize,
+}
+
+impl RequestQueue {
+    pub fn new(max_running: usize, max_queued: usize) -> Self {
+        Self(Arc::new(Inner {
+            max_running,
+            max_queued,
+            state: Mutex::new(State::default()),
+            done: Condvar::new(),
+            #[cfg(feature = "async")]
+            released: tokio::sync::Notify::new(),
+        }))
+    }
+
+    /// The calls let run at once.
+    pub fn max_running(&self) -> usize {
+        self.0.max_running
+    }
+
+    /// The calls let wait, beyond the ones running.
+    pub fn max_queued(&self) -> usize {
+        self.0.max_queued
+    }
+
+    /// The calls running now.
+    pub fn running(&self) -> usize {
+        self.0.state.lock().unwrap().running
+    }
+
+    /// The calls waiting now.
+    pub fn depth(&self) -> usize {
+        self.0.state.lock().unwrap().queued
+    }
+
+    // Runs the call at once if there's room, else queues it if there's room
+    // for that. Returns whether it runs, or the status it's refused with.
+    fn enter(&self) -> Result<bool, Status> {
+        let mut state = self.0.state.lock().unwrap();
+        if state.running < self.0.max_running {
+            state.running += 1;
+            return Ok(true);
+        }
+        if state.queued >= self.0.max_queued {
+            return Err(get_status(
+                Code::RESOURCE_EXHAUSTED,
+                format!(
+                    "the request queue is full, {} calls are waiting",
+                    state.queued
+                ),
+            ));
+        }
+        state.queued += 1;
+        Ok(false)
+    }
+}
+
+impl Inner {
+    // Takes a queued call off the queue to run it, if there's room.
+    fn run_queued(&self, state: &mut State) -> bool {
+        if state.running >= self.max_running {
+            return false;
+        }
+        state.running += 1;
+        state.queued -= 1;
+        true
+    }
+}
+
+// Counts a call as running until dropped, even by a panicking handler.
+struct Running<'a>(&'a Inner);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().running -= 1;
+        self.0.done.notify_one();
+        #[cfg(feature = "async")]
+        self.0.released.notify_waiters();
+    }
+}
+
+cfg_sync! {
+    impl RequestQueue {
+        fn run_blocking(&self) -> Result<Running<'_>, Status> {
+            if !self.enter()? {
+                let mut state = self.0.state.lock().unwrap();
+                while !self.0.run_queued(&mut state) {
+                    state = self.0.done.wait(state).unwrap();
+                }
+            }
+            Ok(Running(&self.0))
+        }
+    }
+
+    impl crate::sync::Interceptor for RequestQueue {
+        fn intercept(
+            &self,
+            ctx: crate::sync::TtrpcContext,
+            req: crate::proto::Request,
+            next: crate::sync::Next<'_>,
+        ) -> crate::Result<()> {
+            let _running = self.run_blocking().map_err(crate::Error::RpcStatus)?;
+            next.run(ctx, req)
+        }
+    }
+}
+
+cfg_async! {
+    // Takes a queued call off the queue if dropped before running, such as
+    // when the call is cancelled.
+    struct Queued<'a>(Option<&'a Inner>);
+
+    impl Drop for Queued<'_> {
+        fn drop(&mut self) {
+            if let Some(inner) = self.0 {
+                inner.state.lock().unwrap().queued -= 1;
+            }
+        }
+    }
+
+    impl RequestQueue {
+        async fn run(&self) -> Result<Running<'_>, Status> {
+            if !self.enter()? {
+                let mut queued = Queued(Some(&self.0));
+                loop {
+                    let released = self.0.released.notified();
+                    if self.0.run_queued(&mut self.0.state.lock().unwrap()) {
+                        queued.0 = None;
+                        break;
+                    }
+                    released.await;
+                }
+            }
+            Ok(Running(&self.0))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl crate::r#async::Interceptor for RequestQueue {
+        async fn intercept(
+            &self,
+            ctx: crate::r#async::TtrpcContext,
+            req: crate::proto::Request,
+            next: crate::r#async::Next<'_>,
+        ) -> crate::Result<Option<crate::proto::Response>> {
+            let _running = match self.run().await {
+                Ok(running) => running,
+                Err(status) => {
+                    let mut res = crate::proto::Response::new();
+                    res.set_status(status);
+                    return Ok(Some(res));
+                }
+            };
+            next.run(ctx, req).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_queue_bounds() {
+        let queue = RequestQueue::new(1, 1);
+        assert_eq!(queue.enter(), Ok(true));
+        assert_eq!(queue.enter(), Ok(false));
+        assert_eq!((queue.running(), queue.depth()), (1, 1));
+
+        let refused = queue.enter().unwrap_err();
+        assert_eq!(refused.code(), Code::RESOURCE_EXHAUSTED);
+
+        // The queued call runs once the running one is done.
+        let inner = &queue.0;
+        assert!(!inner.run_queued(&mut inner.state.lock().unwrap()));
+        drop(Running(inner));
+        assert!(inner.run_queued(&mut inner.state.lock().unwrap()));
+        assert_eq!((queue.running(), queue.depth()), (1, 0));
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Hand a server listener over to a new process across exec.
+//!
+//! The running process exports the listener of its server, passes the encoded
+//! [`ListenerState`] to the new process (conventionally through the
+//! [`LISTENER_STATE_ENV`] environment variable) and execs it. The new process
+//! rebuilds its server from that state, so the socket never goes away and clients
+//! keep connecting while the binary is upgraded.
+
+use std::os::unix::io::RawFd;
+
+use crate::common::{self, Domain};
+use crate::error::{Error, Result};
+
+/// Environment variable conventionally used to pass a [`ListenerState`] across exec.
+pub const LISTENER_STATE_ENV: &str = "TTRPC_LISTENER_STATE";
+
+/// The state needed to rebuild a server listener in another process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListenerState {
+    fd: RawFd,
+    domain: Domain,
+}
+
+impl ListenerState {
+    /// Captures the state of a listening fd, clearing FD_CLOEXEC if `inheritable`.
+    pub(crate) fn export(fd: RawFd, inheritable: bool) -> Result<Self> {
+        let domain = common::get_domain(fd)?;
+        if inheritable {
+            common::set_fd_inheritable(fd, true)?;
+        }
+        Ok(Self { fd, domain })
+    }
+
+    /// Takes the listening fd back, so it is not leaked into further execs.
+    pub(crate) fn import(&self) -> Result<RawFd> {
+        common::set_fd_inheritable(self.fd, false)?;
+        Ok(self.fd)
+    }
+
+    /// The listening fd.
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn domain(&self) -> Domain {
+        self.domain
+    }
+
+    /// Encodes the state into a string that can be passed to the new process.
+    pub fn encode(&self) -> String {
+        let scheme = match self.domain {
+            Domain::Unix => "unix",
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Domain::Vsock => "vsock",
+        };
+        format!("{}:{}", scheme, self.fd)
+    }
+
+    /// Decodes a state produced by [`ListenerState::encode()`].
+    pub fn decode(s: &str) -> Result<Self> {
+        let err = || Error::Others(format!("invalid listener state {:?}", s));
+
+        let (scheme, fd) = s.split_once(':').ok_or_else(err)?;
+        let domain = match scheme {
+            "unix" => Domain::Unix,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            "vsock" => Domain::Vsock,
+            _ => return Err(err()),
+        };
+        let fd = fd.parse().map_err(|_| err())?;
+
+        Ok(Self { fd, domain })
+    }
+
+    /// Reads the state from [`LISTENER_STATE_ENV`], if it is set.
+    pub fn from_env() -> Result<Option<Self>> {
+        match std::env::var(LISTENER_STATE_ENV) {
+            Ok(s) => Self::decode(&s).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listener_state_codec() {
+        let state = ListenerState {
+            fd: 3,
+            domain: Domain::Unix,
+        };
+        assert_eq!(state.encode(), "unix:3");
+        assert_eq!(ListenerState::decode("unix:3").unwrap(), state);
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        assert_eq!(
+            ListenerState::decode("vsock:7").unwrap().domain,
+            Domain::Vsock
+        );
+
+        for s in &["", "unix", "unix:", "unix:abc", "tcp:3"] {
+            assert!(ListenerState::decode(s).is_err());
+        }
+    }
+}
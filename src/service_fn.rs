@@ -0,0 +1,220 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Method handlers made of closures.
+//!
+//! Tiny services and tests can do without a .proto file and generated
+//! traits: a closure taking the request and returning the response, along
+//! with the [`Codec`] of their payloads, makes the handler of a method
+//! given to `register_method()` of either server.
+//!
+//! ```
+//! # #[cfg(feature = "sync")]
+//! # {
+//! use ttrpc::service_fn::{self, Raw};
+//!
+//! let server = ttrpc::Server::new().register_method(
+//!     "example.Echo",
+//!     "Echo",
+//!     service_fn::sync_method(Raw, |_ctx, req: Vec<u8>| Ok(req)),
+//! );
+//! # }
+//! ```
+
+use std::marker::PhantomData;
+
+use crate::error::{get_rpc_status, Error, Result};
+use crate::proto::Code;
+
+/// Decodes the payloads of requests and encodes the payloads of responses
+/// of type `T`.
+pub trait Codec<T>: Send + Sync {
+    fn decode(&self, buf: &[u8]) -> Result<T>;
+    fn encode(&self, msg: &T) -> Result<Vec<u8>>;
+}
+
+/// Payloads as they are, `Vec<u8>`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Raw;
+
+impl Codec<Vec<u8>> for Raw {
+    fn decode(&self, buf: &[u8]) -> Result<Vec<u8>> {
+        Ok(buf.to_vec())
+    }
+
+    fn encode(&self, msg: &Vec<u8>) -> Result<Vec<u8>> {
+        Ok(msg.clone())
+    }
+}
+
+/// Protobuf messages.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Protobuf;
+
+impl<M: protobuf::Message> Codec<M> for Protobuf {
+    fn decode(&self, buf: &[u8]) -> Result<M> {
+        M::parse_from_bytes(buf).map_err(|e| {
+            get_rpc_status(
+                Code::INVALID_ARGUMENT,
+                format!("failed to decode request: {}", e),
+            )
+        })
+    }
+
+    fn encode(&self, msg: &M) -> Result<Vec<u8>> {
+        msg.write_to_bytes()
+            .map_err(err_to_others_err!(e, "failed to encode response: "))
+    }
+}
+
+/// Handler of a method calling a closure, see [`sync_method()`] and
+/// [`async_method()`].
+pub struct FnHandler<C, F, Req, Res> {
+    codec: C,
+    f: F,
+    _msgs: PhantomData<fn(Req) -> Res>,
+}
+
+// The response to a call, `rep` encoded, or its error.
+pub(crate) fn response<C, Res>(codec: &C, rep: Result<Res>) -> crate::proto::Response
+where
+    C: Codec<Res>,
+{
+    let mut res = crate::proto::Response::new();
+    match rep.and_then(|rep| codec.encode(&rep)) {
+        Ok(payload) => {
+            res.set_status(crate::get_status(Code::OK, ""));
+            res.payload = payload;
+        }
+        Err(Error::RpcStatus(s)) => res.set_status(s),
+        Err(e) => res.set_status(crate::get_status(Code::UNKNOWN, format!("{:?}", e))),
+    }
+    res
+}
+
+cfg_sync! {
+    /// Makes the handler of a method of a sync server calling `f`.
+    pub fn sync_method<C, F, Req, Res>(codec: C, f: F) -> FnHandler<C, F, Req, Res>
+    where
+        C: Codec<Req> + Codec<Res>,
+        F: Fn(&crate::sync::TtrpcContext, Req) -> Result<Res> + Send + Sync,
+    {
+        FnHandler {
+            codec,
+            f,
+            _msgs: PhantomData,
+        }
+    }
+
+    impl<C, F, Req, Res> crate::sync::MethodHandler for FnHandler<C, F, Req, Res>
+    where
+        C: Codec<Req> + Codec<Res>,
+        F: Fn(&crate::sync::TtrpcContext, Req) -> Result<Res> + Send + Sync,
+    {
+        fn handler(
+            &self,
+            ctx: crate::sync::TtrpcContext,
+            req: crate::proto::Request,
+        ) -> Result<()> {
+            let rep = self
+                .codec
+                .decode(&req.payload)
+                .and_then(|req| (self.f)(&ctx, req));
+            respond(&self.codec, ctx, rep)
+        }
+    }
+
+    // Sends the response to a call of a sync server, `rep` encoded or its
+    // error.
+    pub(crate) fn respond<C, Res>(
+        codec: &C,
+        ctx: crate::sync::TtrpcContext,
+        rep: Result<Res>,
+    ) -> Result<()>
+    where
+        C: Codec<Res>,
+    {
+        let mut res = response(codec, rep);
+        if ctx.deadline_exceeded() {
+            // The client has already given up, don't send it a stale result.
+            res = crate::proto::Response::new();
+            res.set_status(crate::get_status(Code::DEADLINE_EXCEEDED, "timeout"));
+        }
+        res.set_metadata(crate::context::to_pb(ctx.response_metadata.take()));
+        crate::sync::response_to_channel(ctx.mh.stream_id, res, ctx.res_tx)
+    }
+}
+
+cfg_async! {
+    /// Makes the handler of a method of an async server calling `f`.
+    pub fn async_method<C, F, Fut, Req, Res>(codec: C, f: F) -> FnHandler<C, F, Req, Res>
+    where
+        C: Codec<Req> + Codec<Res>,
+        F: Fn(crate::r#async::TtrpcContext, Req) -> Fut + Send + Sync,
+        Fut: std::future::Future<Output = Result<Res>> + Send + 'static,
+    {
+        FnHandler {
+            codec,
+            f,
+            _msgs: PhantomData,
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl<C, F, Fut, Req, Res> crate::r#async::MethodHandler for FnHandler<C, F, Req, Res>
+    where
+        C: Codec<Req> + Codec<Res>,
+        F: Fn(crate::r#async::TtrpcContext, Req) -> Fut + Send + Sync,
+        Fut: std::future::Future<Output = Result<Res>> + Send + 'static,
+        Req: Send + 'static,
+        Res: 'static,
+    {
+        async fn handler(
+            &self,
+            ctx: crate::r#async::TtrpcContext,
+            req: crate::proto::Request,
+        ) -> Result<crate::proto::Response> {
+            let metadata = ctx.response_metadata.clone();
+            let rep = match self.codec.decode(&req.payload) {
+                Ok(req) => (self.f)(ctx, req).await,
+                Err(e) => Err(e),
+            };
+            let mut res = response(&self.codec, rep);
+            res.set_metadata(crate::context::to_pb(metadata.take()));
+            Ok(res)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::Status;
+
+    #[test]
+    fn test_codecs() {
+        let raw: Vec<u8> = Raw.decode(b"ping").unwrap();
+        assert_eq!(Raw.encode(&raw).unwrap(), b"ping");
+
+        let status = crate::get_status(Code::NOT_FOUND, "gone");
+        let buf = Protobuf.encode(&status).unwrap();
+        let decoded: Status = Protobuf.decode(&buf).unwrap();
+        assert_eq!(decoded, status);
+
+        let res: Result<Status> = Protobuf.decode(b"\xff");
+        assert!(matches!(res, Err(Error::RpcStatus(s)) if s.code() == Code::INVALID_ARGUMENT));
+    }
+
+    #[test]
+    fn test_response() {
+        let res = response(&Raw, Ok(b"pong".to_vec()));
+        assert_eq!(res.status().code(), Code::OK);
+        assert_eq!(res.payload, b"pong");
+
+        let res = response::<_, Vec<u8>>(&Raw, Err(get_rpc_status(Code::PERMISSION_DENIED, "no")));
+        assert_eq!(res.status().code(), Code::PERMISSION_DENIED);
+        assert!(res.payload.is_empty());
+    }
+}
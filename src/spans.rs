@@ -0,0 +1,138 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Tracing spans of the calls, and events of the connections, made with the
+//! `tracing` feature. Without it they are nothing and cost nothing.
+//!
+//! Call spans are named `ttrpc.call` and follow the OpenTelemetry naming
+//! of RPC attributes: `otel.kind`, `rpc.system`, `rpc.service` and
+//! `rpc.method`, then `stream_id`, `conn.id` and the peer credentials of
+//! servers where known.
+
+use crate::common::ConnectionInfo;
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Entered;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub(crate) fn enter(&self) -> Entered {
+        Entered
+    }
+}
+
+/// Which side of the call a span is made on.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Kind {
+    Client,
+    Server,
+}
+
+impl Kind {
+    #[cfg(feature = "tracing")]
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Client => "client",
+            Kind::Server => "server",
+        }
+    }
+}
+
+/// Makes the span of a call of `service`/`method`, which has `stream_id`
+/// and goes over `conn` when they are known.
+#[cfg(feature = "tracing")]
+pub(crate) fn call(
+    kind: Kind,
+    service: &str,
+    method: &str,
+    stream_id: Option<u32>,
+    conn: Option<&ConnectionInfo>,
+) -> Span {
+    let span = tracing::info_span!(
+        "ttrpc.call",
+        otel.kind = kind.as_str(),
+        rpc.system = "ttrpc",
+        rpc.service = service,
+        rpc.method = method,
+        stream_id = tracing::field::Empty,
+        conn.id = tracing::field::Empty,
+        peer.pid = tracing::field::Empty,
+        peer.uid = tracing::field::Empty,
+        peer.gid = tracing::field::Empty,
+    );
+    if let Some(stream_id) = stream_id {
+        span.record("stream_id", stream_id);
+    }
+    if let Some(conn) = conn {
+        span.record("conn.id", conn.id);
+        if let Some(peer) = conn.peer_cred {
+            if let Some(pid) = peer.pid {
+                span.record("peer.pid", pid);
+            }
+            span.record("peer.uid", peer.uid);
+            span.record("peer.gid", peer.gid);
+        }
+    }
+    span
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn call(
+    _kind: Kind,
+    _service: &str,
+    _method: &str,
+    _stream_id: Option<u32>,
+    _conn: Option<&ConnectionInfo>,
+) -> Span {
+    Span
+}
+
+/// Tells of connection `conn` being set up.
+pub(crate) fn connection_opened(kind: Kind, conn: &ConnectionInfo) {
+    #[cfg(feature = "tracing")]
+    tracing::info!(
+        otel.kind = kind.as_str(),
+        conn.id = conn.id,
+        local_addr = ?conn.local_addr,
+        remote_addr = ?conn.remote_addr,
+        peer = ?conn.peer_cred,
+        "ttrpc connection opened"
+    );
+    #[cfg(not(feature = "tracing"))]
+    let _ = (kind, conn);
+}
+
+/// Tells of connection `conn` being torn down, and why.
+pub(crate) fn connection_closed(kind: Kind, conn: &ConnectionInfo, reason: &dyn std::fmt::Debug) {
+    #[cfg(feature = "tracing")]
+    tracing::info!(
+        otel.kind = kind.as_str(),
+        conn.id = conn.id,
+        reason = ?reason,
+        "ttrpc connection closed"
+    );
+    #[cfg(not(feature = "tracing"))]
+    let _ = (kind, conn, reason);
+}
+
+cfg_async! {
+    /// Runs `fut` in `span`.
+    #[cfg(feature = "tracing")]
+    pub(crate) async fn instrument<F: std::future::Future>(span: Span, fut: F) -> F::Output {
+        tracing::Instrument::instrument(fut, span).await
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) async fn instrument<F: std::future::Future>(_span: Span, fut: F) -> F::Output {
+        fut.await
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Counters of transport level events, kept per address family for the
+//! whole process.
+//!
+//! They help telling platform specific transport problems apart from
+//! application errors. Accepts are counted by both servers, retries and
+//! short reads and writes by the sync transport, the async one leaves them
+//! to tokio.
+
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::common::{self, Domain};
+
+/// Address family of a transport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Unix,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    Vsock,
+}
+
+/// A snapshot of the counters of a transport.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TransportStats {
+    /// Connections accepted by servers.
+    pub accepted: u64,
+    /// Failed accepts, the connection never reached the server.
+    pub accept_errors: u64,
+    /// Reads and writes retried after being interrupted by a signal.
+    pub interrupted: u64,
+    /// Reads returning less than asked for, continued with another one.
+    pub partial_reads: u64,
+    /// Writes taking less than given, continued with another one.
+    pub partial_writes: u64,
+    /// Connections closed right after being accepted, the server having
+    /// too many already.
+    pub rejected: u64,
+}
+
+/// Gets the counters of `transport`.
+pub fn transport_stats(transport: Transport) -> TransportStats {
+    let counters = match transport {
+        Transport::Unix => &UNIX,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        Transport::Vsock => &VSOCK,
+    };
+    counters.snapshot()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Counter {
+    Accepted,
+    AcceptErrors,
+    Interrupted,
+    PartialReads,
+    PartialWrites,
+    Rejected,
+}
+
+struct Counters([AtomicU64; 6]);
+
+impl Counters {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const fn new() -> Self {
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Counters([ZERO; 6])
+    }
+
+    fn get(&self, counter: Counter) -> u64 {
+        self.0[counter as usize].load(Ordering::Relaxed)
+    }
+
+    fn snapshot(&self) -> TransportStats {
+        TransportStats {
+            accepted: self.get(Counter::Accepted),
+            accept_errors: self.get(Counter::AcceptErrors),
+            interrupted: self.get(Counter::Interrupted),
+            partial_reads: self.get(Counter::PartialReads),
+            partial_writes: self.get(Counter::PartialWrites),
+            rejected: self.get(Counter::Rejected),
+        }
+    }
+}
+
+static UNIX: Counters = Counters::new();
+#[cfg(any(target_os = "linux", target_os = "android"))]
+static VSOCK: Counters = Counters::new();
+
+/// Counts an event on a transport of `domain`.
+pub(crate) fn record(domain: Domain, counter: Counter) {
+    let counters = match domain {
+        Domain::Unix => &UNIX,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        Domain::Vsock => &VSOCK,
+    };
+    counters.0[counter as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts an event on the connection `fd`, looking up its address family.
+/// Only meant for events off the fast path.
+pub(crate) fn record_fd(fd: RawFd, counter: Counter) {
+    if let Ok(domain) = common::get_domain(fd) {
+        record(domain, counter);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::socket::{socketpair, AddressFamily, SockFlag, SockType};
+
+    #[test]
+    fn test_record() {
+        let before = transport_stats(Transport::Unix);
+
+        record(Domain::Unix, Counter::Accepted);
+        let (a, b) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        record_fd(a, Counter::PartialReads);
+        nix::unistd::close(a).unwrap();
+        nix::unistd::close(b).unwrap();
+
+        // Other tests may count events too.
+        let after = transport_stats(Transport::Unix);
+        assert!(after.accepted > before.accepted);
+        assert!(after.partial_reads > before.partial_reads);
+    }
+}
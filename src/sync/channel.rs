@@ -12,17 +12,83 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::sys::socket::*;
+use nix::sys::time::{TimeVal, TimeValLike};
+use nix::sys::uio::IoVec;
+use nix::unistd::close;
 use std::os::unix::io::RawFd;
+use std::time::Duration;
 
-use crate::error::{get_rpc_status, sock_error_msg, Error, Result};
-use crate::proto::{Code, MessageHeader, MESSAGE_HEADER_LENGTH, MESSAGE_LENGTH_MAX};
+use crate::codec::{validate_header, HeaderLimits};
+use crate::compression;
+use crate::error::{get_status, sock_error_msg, Error, Result};
+use crate::proto::{Code, MessageHeader, Status, MESSAGE_HEADER_LENGTH};
+use crate::stats::{self, Counter};
+
+/// The most file descriptors a single message can carry.
+pub const MAX_MESSAGE_FDS: usize = 16;
+
+// The payload of a message, or the status to answer a too large one with.
+pub(crate) type Body = std::result::Result<Vec<u8>, Status>;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const RECV_FLAGS: MsgFlags = MsgFlags::MSG_CMSG_CLOEXEC;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const RECV_FLAGS: MsgFlags = MsgFlags::empty();
+
+pub(crate) fn close_fds(fds: &[RawFd]) {
+    for &fd in fds {
+        close(fd).unwrap_or_else(|e| warn!("failed to close passed fd {}: {:?}", fd, e));
+    }
+}
 
 fn retryable(e: nix::Error) -> bool {
     use ::nix::Error;
     e == Error::EINTR || e == Error::EAGAIN
 }
 
+// A blocking socket only fails with EAGAIN once its SO_RCVTIMEO or
+// SO_SNDTIMEO expired.
+fn timed_out(fd: RawFd, e: nix::Error) -> bool {
+    e == nix::Error::EAGAIN
+        && fcntl(fd, FcntlArg::F_GETFL)
+            .is_ok_and(|flags| !OFlag::from_bits_truncate(flags).contains(OFlag::O_NONBLOCK))
+}
+
+// Part of a frame may have gone through when reading or writing it timed
+// out, so nothing can follow on the connection.
+fn timeout_error(fd: RawFd) -> Error {
+    shutdown(fd, Shutdown::Both).unwrap_or(());
+    Error::Socket("timed out in the middle of a message".to_string())
+}
+
+/// Limits how long a read or a write on socket `fd` may block, `None`
+/// leaving the limit as it is. Reads and writes of messages then fail with
+/// [`Error::Socket`] rather than block longer, shutting the connection
+/// down, but waiting for a message to start isn't limited.
+pub(crate) fn set_io_timeouts(
+    fd: RawFd,
+    read: Option<Duration>,
+    write: Option<Duration>,
+) -> Result<()> {
+    let timeval =
+        |timeout: Duration| TimeVal::microseconds(timeout.as_micros().min(i64::MAX as u128) as i64);
+    if let Some(read) = read {
+        setsockopt(fd, sockopt::ReceiveTimeout, &timeval(read))?;
+    }
+    if let Some(write) = write {
+        setsockopt(fd, sockopt::SendTimeout, &timeval(write))?;
+    }
+    Ok(())
+}
+
+fn count_retry(fd: RawFd, e: nix::Error) {
+    if e == nix::Error::EINTR {
+        stats::record_fd(fd, Counter::Interrupted);
+    }
+}
+
 fn read_count(fd: RawFd, count: usize) -> Result<Vec<u8>> {
     let mut v: Vec<u8> = vec![0; count];
     let mut len = 0;
@@ -39,10 +105,14 @@ fn read_count(fd: RawFd, count: usize) -> Result<Vec<u8>> {
                 if len == count || l == 0 {
                     break;
                 }
+                stats::record_fd(fd, Counter::PartialReads);
             }
 
+            Err(e) if timed_out(fd, e) => return Err(timeout_error(fd)),
+
             Err(e) if retryable(e) => {
                 // Should retry
+                count_retry(fd, e);
             }
 
             Err(e) => {
@@ -68,10 +138,14 @@ fn write_count(fd: RawFd, buf: &[u8], count: usize) -> Result<usize> {
                 if len == count {
                     break;
                 }
+                stats::record_fd(fd, Counter::PartialWrites);
             }
 
+            Err(e) if timed_out(fd, e) => return Err(timeout_error(fd)),
+
             Err(e) if retryable(e) => {
                 // Should retry
+                count_retry(fd, e);
             }
 
             Err(e) => {
@@ -83,10 +157,60 @@ fn write_count(fd: RawFd, buf: &[u8], count: usize) -> Result<usize> {
     Ok(len)
 }
 
-fn read_message_header(fd: RawFd) -> Result<MessageHeader> {
-    let buf = read_count(fd, MESSAGE_HEADER_LENGTH)?;
-    let size = buf.len();
+// Like a single recv(), also returning the fds passed along with the data.
+fn recv_with_fds(fd: RawFd, buf: &mut [u8]) -> Result<(usize, Vec<RawFd>)> {
+    let mut cmsg_buf = nix::cmsg_space!([RawFd; MAX_MESSAGE_FDS]);
+
+    loop {
+        let iov = [IoVec::from_mut_slice(&mut *buf)];
+        match recvmsg(fd, &iov, Some(&mut cmsg_buf), RECV_FLAGS) {
+            Ok(msg) => {
+                let mut fds = Vec::new();
+                for cmsg in msg.cmsgs() {
+                    if let ControlMessageOwned::ScmRights(received) = cmsg {
+                        fds.extend(received);
+                    }
+                }
+                if msg.flags.contains(MsgFlags::MSG_CTRUNC) {
+                    close_fds(&fds);
+                    return Err(Error::Socket(format!(
+                        "peer passed more than {} fds",
+                        MAX_MESSAGE_FDS
+                    )));
+                }
+                return Ok((msg.bytes, fds));
+            }
+
+            Err(e) if retryable(e) => {
+                // Should retry
+                count_retry(fd, e);
+            }
+
+            Err(e) => {
+                return Err(Error::Socket(e.to_string()));
+            }
+        }
+    }
+}
+
+// The fds passed with a message arrive with the first bytes of its header.
+fn read_message_header(fd: RawFd) -> Result<(MessageHeader, Vec<RawFd>)> {
+    let mut buf = vec![0; MESSAGE_HEADER_LENGTH];
+    let (mut size, fds) = recv_with_fds(fd, &mut buf)?;
+    if size > 0 && size < MESSAGE_HEADER_LENGTH {
+        stats::record_fd(fd, Counter::PartialReads);
+        let rest = match read_count(fd, MESSAGE_HEADER_LENGTH - size) {
+            Ok(rest) => rest,
+            Err(e) => {
+                close_fds(&fds);
+                return Err(e);
+            }
+        };
+        buf[size..size + rest.len()].copy_from_slice(&rest);
+        size += rest.len();
+    }
     if size != MESSAGE_HEADER_LENGTH {
+        close_fds(&fds);
         return Err(sock_error_msg(
             size,
             format!("Message header length {} is too small", size),
@@ -95,21 +219,82 @@ fn read_message_header(fd: RawFd) -> Result<MessageHeader> {
 
     let mh = MessageHeader::from(&buf);
 
-    Ok(mh)
+    Ok((mh, fds))
 }
 
-pub fn read_message(fd: RawFd) -> Result<(MessageHeader, Vec<u8>)> {
-    let mh = read_message_header(fd)?;
-    trace!("Got Message header {:?}", mh);
+// Reads and drops `count` bytes, so the next read starts at a frame boundary.
+fn discard_count(fd: RawFd, mut count: usize) -> Result<()> {
+    const CHUNK: usize = 64 * 1024;
+
+    while count > 0 {
+        let want = count.min(CHUNK);
+        let size = read_count(fd, want)?.len();
+        if size != want {
+            return Err(sock_error_msg(
+                size,
+                format!("Message length {} is not {}", size, want),
+            ));
+        }
+        count -= size;
+    }
+
+    Ok(())
+}
 
-    if mh.length > MESSAGE_LENGTH_MAX as u32 {
-        return Err(get_rpc_status(
-            Code::INVALID_ARGUMENT,
+/// Reads a message whose payload is at most `max_len` bytes long.
+///
+/// The payload of a larger message is skipped and a RESOURCE_EXHAUSTED
+/// status returned in its place, the connection can still be used. An error
+/// means it can't. A compressed payload is given decompressed, with the
+/// header to match, or an INVALID_ARGUMENT status if it can't be.
+///
+/// File descriptors passed along with the message are closed, see
+/// [`read_message_with_fds()`] to get them.
+#[cfg(test)]
+pub fn read_message(fd: RawFd, max_len: usize) -> Result<(MessageHeader, Body)> {
+    let (mh, buf, fds) = read_message_with_fds(fd, max_len)?;
+    if !fds.is_empty() {
+        debug!("Dropping {} fds passed with {:?}", fds.len(), mh);
+        close_fds(&fds);
+    }
+
+    Ok((mh, buf))
+}
+
+/// Like [`read_message()`], also returning the file descriptors passed along
+/// with the message. They belong to the caller.
+pub fn read_message_with_fds(
+    fd: RawFd,
+    max_len: usize,
+) -> Result<(MessageHeader, Body, Vec<RawFd>)> {
+    let (mut mh, fds) = read_message_header(fd)?;
+    trace!("Got Message header {:?} with fds {:?}", mh, fds);
+
+    // A bad header means the frames can't be told apart any more.
+    if let Err(e) = validate_header(&mh, &HeaderLimits::default()) {
+        close_fds(&fds);
+        return Err(e);
+    }
+
+    match read_message_body(fd, &mut mh, max_len) {
+        Ok(buf) => Ok((mh, buf, fds)),
+        Err(e) => {
+            close_fds(&fds);
+            Err(e)
+        }
+    }
+}
+
+fn read_message_body(fd: RawFd, mh: &mut MessageHeader, max_len: usize) -> Result<Body> {
+    if mh.length as usize > max_len {
+        discard_count(fd, mh.length as usize)?;
+        return Ok(Err(get_status(
+            Code::RESOURCE_EXHAUSTED,
             format!(
                 "message length {} exceed maximum message size of {}",
-                mh.length, MESSAGE_LENGTH_MAX
+                mh.length, max_len
             ),
-        ));
+        )));
     }
 
     let buf = read_count(fd, mh.length as usize)?;
@@ -122,28 +307,72 @@ pub fn read_message(fd: RawFd) -> Result<(MessageHeader, Vec<u8>)> {
     }
     trace!("Got Message body {:?}", buf);
 
-    Ok((mh, buf))
+    match compression::decompress_payload(mh, &buf, max_len) {
+        Ok(payload) => Ok(Ok(payload.unwrap_or(buf))),
+        Err(e) => Ok(Err(get_status(Code::INVALID_ARGUMENT, e))),
+    }
 }
 
-fn write_message_header(fd: RawFd, mh: MessageHeader) -> Result<()> {
-    let buf: Vec<u8> = mh.into();
+// Sends the header and the body of a message in a single call, along with
+// `fds`, returns how many bytes were sent.
+fn send_vectored(fd: RawFd, header: &[u8], body: &[u8], fds: &[RawFd]) -> Result<usize> {
+    let iov = [IoVec::from_slice(header), IoVec::from_slice(body)];
+    let scm_rights = [ControlMessage::ScmRights(fds)];
+    let cmsgs: &[ControlMessage] = if fds.is_empty() { &[] } else { &scm_rights };
 
-    let size = write_count(fd, &buf, MESSAGE_HEADER_LENGTH)?;
-    if size != MESSAGE_HEADER_LENGTH {
-        return Err(sock_error_msg(
-            size,
-            format!("Send Message header length size {} is not right", size),
-        ));
-    }
+    loop {
+        match sendmsg(fd, &iov, cmsgs, MsgFlags::empty(), None) {
+            Ok(l) => return Ok(l),
 
-    Ok(())
+            Err(e) if timed_out(fd, e) => return Err(timeout_error(fd)),
+
+            Err(e) if retryable(e) => {
+                // Should retry
+                count_retry(fd, e);
+            }
+
+            Err(e) => {
+                return Err(Error::Socket(e.to_string()));
+            }
+        }
+    }
 }
 
+#[cfg(test)]
 pub fn write_message(fd: RawFd, mh: MessageHeader, buf: Vec<u8>) -> Result<()> {
-    write_message_header(fd, mh)?;
+    write_message_with_fds(fd, mh, buf, &[])
+}
+
+/// Writes a message passing `fds` along with it, which only unix sockets
+/// support. The fds stay open on this side.
+pub fn write_message_with_fds(
+    fd: RawFd,
+    mh: MessageHeader,
+    buf: Vec<u8>,
+    fds: &[RawFd],
+) -> Result<()> {
+    if fds.len() > MAX_MESSAGE_FDS {
+        return Err(Error::Others(format!(
+            "can't pass {} fds, the maximum is {}",
+            fds.len(),
+            MAX_MESSAGE_FDS
+        )));
+    }
+    let header: Vec<u8> = mh.into();
 
-    let size = write_count(fd, &buf, buf.len())?;
-    if size != buf.len() {
+    // The fds go with the first byte sent, whatever is left follows.
+    let mut size = send_vectored(fd, &header, &buf, fds)?;
+    if size < MESSAGE_HEADER_LENGTH + buf.len() {
+        stats::record_fd(fd, Counter::PartialWrites);
+    }
+    if size < MESSAGE_HEADER_LENGTH {
+        size += write_count(fd, &header[size..], MESSAGE_HEADER_LENGTH - size)?;
+    }
+    if size != MESSAGE_HEADER_LENGTH + buf.len() {
+        let sent = size - MESSAGE_HEADER_LENGTH;
+        size += write_count(fd, &buf[sent..], buf.len() - sent)?;
+    }
+    if size != MESSAGE_HEADER_LENGTH + buf.len() {
         return Err(sock_error_msg(
             size,
             format!("Send Message length size {} is not right", size),
@@ -152,3 +381,208 @@ pub fn write_message(fd: RawFd, mh: MessageHeader, buf: Vec<u8>) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::MESSAGE_LENGTH_MAX;
+    use nix::unistd::{pipe, read, write};
+
+    #[test]
+    fn test_pass_fds() {
+        let (a, b) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        let (rfd, wfd) = pipe().unwrap();
+
+        let mh = MessageHeader::new_request(1, 3);
+        write_message_with_fds(a, mh, b"abc".to_vec(), &[rfd]).unwrap();
+        close(rfd).unwrap();
+
+        let (got, buf, fds) = read_message_with_fds(b, MESSAGE_LENGTH_MAX).unwrap();
+        assert_eq!(got, mh);
+        assert_eq!(buf.unwrap(), b"abc");
+        assert_eq!(fds.len(), 1);
+
+        // The received fd is the read end of the same pipe.
+        write(wfd, b"x").unwrap();
+        let mut out = [0; 1];
+        assert_eq!(read(fds[0], &mut out).unwrap(), 1);
+        assert_eq!(&out, b"x");
+        close_fds(&fds);
+
+        let too_many = vec![wfd; MAX_MESSAGE_FDS + 1];
+        assert!(write_message_with_fds(a, mh, vec![], &too_many).is_err());
+
+        // Messages without fds still read the same.
+        write_message(a, MessageHeader::new_request(3, 0), vec![]).unwrap();
+        let (got, buf) = read_message(b, MESSAGE_LENGTH_MAX).unwrap();
+        assert_eq!(got.stream_id, 3);
+        assert!(buf.unwrap().is_empty());
+
+        close_fds(&[a, b, wfd]);
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        let (a, b) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+
+        let mut mh = MessageHeader::new_request(1, 3);
+        mh.type_ = 0xff;
+        write_message(a, mh, b"abc".to_vec()).unwrap();
+        assert!(matches!(
+            read_message(b, MESSAGE_LENGTH_MAX),
+            Err(Error::RpcStatus(s)) if s.code() == Code::INVALID_ARGUMENT
+        ));
+
+        close_fds(&[a, b]);
+    }
+
+    #[test]
+    fn test_partial_io() {
+        let (a, b) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        // Small buffers so a large message takes many sends and recvs.
+        setsockopt(a, sockopt::SndBuf, &4096).unwrap();
+        setsockopt(b, sockopt::RcvBuf, &4096).unwrap();
+
+        let body: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+        let expected = body.clone();
+        let writer = std::thread::spawn(move || {
+            write_message(a, MessageHeader::new_request(1, body.len() as u32), body).unwrap();
+
+            // A header split across writes.
+            let buf: Vec<u8> = MessageHeader::new_request(3, 3).into();
+            send(a, &buf[..4], MsgFlags::empty()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            send(a, &buf[4..], MsgFlags::empty()).unwrap();
+            send(a, b"a", MsgFlags::empty()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            send(a, b"bc", MsgFlags::empty()).unwrap();
+            a
+        });
+
+        let (mh, buf) = read_message(b, MESSAGE_LENGTH_MAX).unwrap();
+        assert_eq!(mh.length as usize, expected.len());
+        assert!(buf.unwrap() == expected);
+
+        let (mh, buf) = read_message(b, MESSAGE_LENGTH_MAX).unwrap();
+        assert_eq!(mh.stream_id, 3);
+        assert_eq!(buf.unwrap(), b"abc");
+
+        let a = writer.join().unwrap();
+        close_fds(&[a, b]);
+    }
+
+    #[test]
+    fn test_short_message() {
+        let (a, b) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+
+        // The peer goes away in the middle of a message.
+        let buf: Vec<u8> = MessageHeader::new_request(1, 3).into();
+        send(a, &buf, MsgFlags::empty()).unwrap();
+        send(a, b"a", MsgFlags::empty()).unwrap();
+        close(a).unwrap();
+        assert!(read_message(b, MESSAGE_LENGTH_MAX).is_err());
+
+        close(b).unwrap();
+    }
+
+    #[test]
+    fn test_io_timeouts() {
+        let (a, b) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        let timeout = Some(Duration::from_millis(50));
+        set_io_timeouts(b, timeout, timeout).unwrap();
+
+        // The peer stalls in the middle of a message.
+        let buf: Vec<u8> = MessageHeader::new_request(1, 3).into();
+        send(a, &buf, MsgFlags::empty()).unwrap();
+        send(a, b"a", MsgFlags::empty()).unwrap();
+        assert!(matches!(
+            read_message(b, MESSAGE_LENGTH_MAX),
+            Err(Error::Socket(_))
+        ));
+        // The connection is shut down.
+        assert!(write_message(b, MessageHeader::new_request(3, 0), vec![]).is_err());
+        close_fds(&[a, b]);
+
+        // The peer doesn't read.
+        let (a, b) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        set_io_timeouts(b, timeout, timeout).unwrap();
+        let mh = MessageHeader::new_request(1, MESSAGE_LENGTH_MAX as u32);
+        assert!(matches!(
+            write_message(b, mh, vec![0; MESSAGE_LENGTH_MAX]),
+            Err(Error::Socket(_))
+        ));
+        close_fds(&[a, b]);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_read_compressed() {
+        use crate::proto::FLAG_COMPRESSED_GZIP;
+        use std::io::Write;
+
+        let (a, b) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        let payload = vec![b'a'; 4096];
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&payload).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut mh = MessageHeader::new_request(1, compressed.len() as u32);
+        mh.add_flags(FLAG_COMPRESSED_GZIP);
+        write_message(a, mh, compressed.clone()).unwrap();
+        let (got, buf) = read_message(b, MESSAGE_LENGTH_MAX).unwrap();
+        assert_eq!(got.flags, 0);
+        assert_eq!(got.length as usize, payload.len());
+        assert_eq!(buf.unwrap(), payload);
+
+        // Larger than allowed once decompressed, the connection is kept.
+        write_message(a, mh, compressed).unwrap();
+        let (_, buf) = read_message(b, payload.len() - 1).unwrap();
+        assert_eq!(buf.unwrap_err().code(), Code::INVALID_ARGUMENT);
+        write_message(a, MessageHeader::new_request(3, 0), vec![]).unwrap();
+        assert_eq!(read_message(b, MESSAGE_LENGTH_MAX).unwrap().0.stream_id, 3);
+
+        close_fds(&[a, b]);
+    }
+}
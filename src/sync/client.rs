@@ -18,27 +18,213 @@ use nix::sys::socket::*;
 use nix::unistd::close;
 use std::collections::HashMap;
 use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 use std::{io, thread};
 
 #[cfg(target_os = "macos")]
 use crate::common::set_fd_close_exec;
-use crate::common::{client_connect, SOCK_CLOEXEC};
-use crate::error::{Error, Result};
-use crate::proto::{Code, Codec, MessageHeader, Request, Response, MESSAGE_TYPE_RESPONSE};
-use crate::sync::channel::{read_message, write_message};
-use std::time::Duration;
+use crate::common::{
+    self, client_connect, client_connect_timeout, is_transport_not_ready, CloseReason,
+    ConnectRetry, ConnectionInfo, ConnectionState, Jitter, RetryPolicy, StateWatch, SOCK_CLOEXEC,
+};
+use crate::compression;
+use crate::context::Context;
+use crate::error::{get_rpc_status, get_shutdown_status, Error, Result, ShutdownReason};
+use crate::proto::{
+    Code, Codec, GoAway, MessageHeader, Request, Response, MESSAGE_LENGTH_MAX, MESSAGE_TYPE_GOAWAY,
+    MESSAGE_TYPE_RESPONSE,
+};
+use crate::spans::{self, Kind};
+use crate::sync::channel::{
+    close_fds, read_message_with_fds, set_io_timeouts, write_message_with_fds,
+};
+use crate::sync::interceptor::{ClientInterceptor, ClientNext};
+use std::time::{Duration, Instant};
 
-type Sender = mpsc::Sender<(Vec<u8>, mpsc::SyncSender<Result<Vec<u8>>>)>;
-type Receiver = mpsc::Receiver<(Vec<u8>, mpsc::SyncSender<Result<Vec<u8>>>)>;
+type Reply = Result<(Vec<u8>, Vec<RawFd>)>;
+type Sender = mpsc::Sender<Outgoing>;
+type Receiver = mpsc::Receiver<Outgoing>;
+
+// What the sender thread writes.
+enum Outgoing {
+    // A request, one-way if it has no reply channel. The stream id it gets
+    // is told to the cancel handle of the call, if any.
+    Request(
+        Vec<u8>,
+        Vec<RawFd>,
+        Option<mpsc::SyncSender<Reply>>,
+        Option<CancelHandle>,
+    ),
+    // The cancel of the request on a stream.
+    Cancel(u32),
+}
+
+/// Cancels a call of a [`Client`] from another thread, see
+/// [`Client::request_with_cancel()`].
+///
+/// Cancelling fails the call with `CANCELLED` right away and tells the
+/// server to stop handling it, if the server agreed to calls being
+/// cancelled (see [`CANCEL_KEY`](crate::proto::CANCEL_KEY)). The connection
+/// is kept.
+#[derive(Clone, Default)]
+pub struct CancelHandle {
+    state: Arc<Mutex<CancelState>>,
+}
+
+#[derive(Default)]
+struct CancelState {
+    cancelled: bool,
+    // Set while the call is made.
+    reply: Option<mpsc::SyncSender<Reply>>,
+    sender: Option<Sender>,
+    // Set once the request is written.
+    stream_id: Option<u32>,
+}
+
+impl CancelHandle {
+    pub fn new() -> CancelHandle {
+        CancelHandle::default()
+    }
+
+    /// Cancels the call, if it's still being made.
+    pub fn cancel(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.cancelled {
+            return;
+        }
+        state.cancelled = true;
+        if let Some(reply) = state.reply.take() {
+            reply.try_send(Err(cancelled_error())).ok();
+        }
+        if let (Some(sender), Some(stream_id)) = (state.sender.take(), state.stream_id) {
+            sender.send(Outgoing::Cancel(stream_id)).ok();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.lock().unwrap().cancelled
+    }
+
+    // Called before the request is queued, fails if already cancelled.
+    fn start(&self, reply: mpsc::SyncSender<Reply>, sender: Sender) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.cancelled {
+            return Err(cancelled_error());
+        }
+        state.reply = Some(reply);
+        state.sender = Some(sender);
+        state.stream_id = None;
+        Ok(())
+    }
+
+    // Called by the sender thread before writing the request, returns false
+    // if it shouldn't be written as the call got cancelled.
+    fn written(&self, stream_id: u32) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.stream_id = Some(stream_id);
+        !state.cancelled
+    }
+
+    fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.reply = None;
+        state.sender = None;
+        state.stream_id = None;
+    }
+}
 
 /// A ttrpc Client (sync).
 #[derive(Clone)]
 pub struct Client {
-    _fd: RawFd,
-    sender_tx: Sender,
-    _client_close: Arc<ClientClose>,
+    conn: Arc<Mutex<ConnSlot>>,
+    // Notified once a call is done connecting.
+    dialed: Arc<Condvar>,
+    max_message_size: Arc<AtomicUsize>,
+    timeouts: Arc<Mutex<Timeouts>>,
+    dialer: Option<Arc<Dialer>>,
+    retry: Option<Arc<RetryPolicy>>,
+    interceptors: Arc<Vec<Arc<dyn ClientInterceptor>>>,
+    state: StateWatch,
+}
+
+// The connection of a client, shared with its clones.
+#[derive(Default)]
+struct ConnSlot {
+    // None until a lazy client connects.
+    conn: Option<Arc<Conn>>,
+    // Set while a call connects, out of the lock so the client can be used
+    // meanwhile. The other calls wait for it.
+    dialing: bool,
+    // Why connecting last failed, for the calls that waited for it.
+    dial_error: Option<Error>,
+}
+
+// A connection of a client, replaced by a new one when reconnecting.
+struct Conn {
+    fd: RawFd,
+    sender_tx: Mutex<Sender>,
+    _client_close: ClientClose,
+    info: Arc<ConnectionInfo>,
+    going_away: Arc<AtomicBool>,
+    // Set once the server agreed to calls being cancelled.
+    server_cancels: Arc<AtomicBool>,
+    // Set once the connection dropped.
+    broken: Arc<AtomicBool>,
+}
+
+// The io timeouts set on the client, set again on a new connection.
+#[derive(Clone, Copy, Default)]
+struct Timeouts {
+    read: Option<Duration>,
+    write: Option<Duration>,
+}
+
+// Where a client connects, on its first call or again once its connection
+// dropped.
+struct Dialer {
+    sockaddr: String,
+    retry: ConnectRetry,
+    reconnect: bool,
+}
+
+fn cancelled_error() -> Error {
+    get_rpc_status(Code::CANCELLED, "the call was cancelled")
+}
+
+// The error of a call the server won't handle as it's going away.
+fn going_away_error() -> Error {
+    Error::RpcStatus(get_shutdown_status(
+        ShutdownReason::Drain,
+        "the server is going away",
+    ))
+}
+
+// Connects to `sockaddr`, retrying while nothing listens on it yet as set
+// out by `retry`. An attempt is given up on once the timeout of `retry`
+// passes, so an unanswered vsock connect doesn't block past it.
+fn connect_retrying(sockaddr: &str, retry: &ConnectRetry) -> Result<RawFd> {
+    let started = Instant::now();
+    let mut jitter = Jitter::new(retry.jitter_seed);
+    let mut attempt = 0;
+    loop {
+        let left = retry.timeout.saturating_sub(started.elapsed());
+        match unsafe { client_connect_timeout(sockaddr, left) } {
+            Ok(fd) => return Ok(fd),
+            Err(e) if is_transport_not_ready(&e) => {
+                match retry.next_backoff(started.elapsed(), attempt, &mut jitter) {
+                    Some(backoff) => {
+                        trace!("{} is not ready, retry in {:?}: {}", sockaddr, backoff, e);
+                        thread::sleep(backoff);
+                    }
+                    None => return Err(e),
+                }
+            }
+            Err(e) => return Err(e),
+        }
+        attempt += 1;
+    }
 }
 
 impl Client {
@@ -47,8 +233,531 @@ impl Client {
         Ok(Self::new(fd))
     }
 
+    /// Connects to `sockaddr`, failing with ETIMEDOUT unless connected
+    /// within `timeout`, as a vsock connect may not be answered for long.
+    pub fn connect_timeout(sockaddr: &str, timeout: Duration) -> Result<Client> {
+        let fd = unsafe { client_connect_timeout(sockaddr, timeout)? };
+        Ok(Self::new(fd))
+    }
+
+    /// Connects to `sockaddr`, retrying while nothing listens on it yet as
+    /// set out by `retry`: this waits for a server that isn't ready, such
+    /// as one yet to bind its address. Connecting fails once the timeout of
+    /// `retry` passes.
+    pub fn connect_with_retry(sockaddr: &str, retry: ConnectRetry) -> Result<Client> {
+        Ok(Self::new(connect_retrying(sockaddr, &retry)?))
+    }
+
+    /// Connects to `sockaddr` as [`Client::connect_with_retry()`] does, and
+    /// connects again the same way on the first call made once the
+    /// connection dropped.
+    ///
+    /// The calls pending on the dropped connection fail, the next ones are
+    /// made on the new connection. A call fails with the error of
+    /// connecting if it can't be made within the timeout of `retry`, the
+    /// next call tries again. The clones of the client share the connection.
+    pub fn connect_with_reconnect(sockaddr: &str, retry: ConnectRetry) -> Result<Client> {
+        let mut client = Self::connect_with_retry(sockaddr, retry)?;
+        client.dialer = Some(Arc::new(Dialer {
+            sockaddr: sockaddr.to_string(),
+            retry,
+            reconnect: true,
+        }));
+        Ok(client)
+    }
+
+    /// Makes a client connecting to `sockaddr` on its first call, as
+    /// [`Client::connect_with_retry()`] does, rather than right away.
+    ///
+    /// Making clients for many servers is then cheap, and doesn't fail for
+    /// the ones not up yet. A call fails with the error of connecting if it
+    /// fails, the next call tries again. The connection isn't made again
+    /// once it dropped.
+    pub fn connect_lazy(sockaddr: &str, retry: ConnectRetry) -> Client {
+        Client {
+            conn: Arc::default(),
+            dialed: Arc::default(),
+            max_message_size: Arc::new(AtomicUsize::new(MESSAGE_LENGTH_MAX)),
+            timeouts: Arc::default(),
+            dialer: Some(Arc::new(Dialer {
+                sockaddr: sockaddr.to_string(),
+                retry,
+                reconnect: false,
+            })),
+            retry: None,
+            interceptors: Arc::default(),
+            state: StateWatch::new(ConnectionState::Disconnected),
+        }
+    }
+
+    /// Connects to `sockaddr` and checks a ttrpc server answers on it within
+    /// `timeout`, see [`Client::verify()`].
+    pub fn connect_verified(sockaddr: &str, timeout: Duration) -> Result<Client> {
+        let client = Self::connect(sockaddr)?;
+        client.verify(timeout)?;
+        Ok(client)
+    }
+
+    /// Checks a ttrpc server answers on the connection within `timeout`,
+    /// with a call the servers of this crate answer before any handler sees
+    /// it, and other servers fail as a method they don't have.
+    ///
+    /// Some transports accept connections no server will serve, a vsock
+    /// port in a guest still booting for instance, and the first call then
+    /// fails with a write error. This fails early instead, or with an
+    /// UNAVAILABLE status if the server won't take calls.
+    pub fn verify(&self, timeout: Duration) -> Result<()> {
+        common::probe_result(self.request(common::probe_request(timeout)))
+    }
+
     /// Initialize a new [`Client`] from raw file descriptor.
     pub fn new(fd: RawFd) -> Client {
+        let max_message_size = Arc::new(AtomicUsize::new(MESSAGE_LENGTH_MAX));
+        let state = StateWatch::new(ConnectionState::Ready);
+        let conn = Conn::new(fd, max_message_size.clone(), state.clone());
+        Client {
+            conn: Arc::new(Mutex::new(ConnSlot {
+                conn: Some(Arc::new(conn)),
+                ..Default::default()
+            })),
+            dialed: Arc::default(),
+            max_message_size,
+            timeouts: Arc::default(),
+            dialer: None,
+            retry: None,
+            interceptors: Arc::default(),
+            state,
+        }
+    }
+
+    // The connection to make calls on, connecting first for a lazy client,
+    // or again if it dropped and the client reconnects.
+    fn conn(&self) -> Result<Arc<Conn>> {
+        let mut slot = self.conn.lock().unwrap();
+        let dialer = match self.dialer.as_ref() {
+            Some(dialer) => dialer,
+            // Only a client with a dialer starts without a connection.
+            None => return Ok(slot.conn.clone().unwrap()),
+        };
+        if slot.dialing {
+            // Another call is connecting, this one fails if that fails.
+            slot = self.dialed.wait_while(slot, |slot| slot.dialing).unwrap();
+            if let Some(e) = slot.dial_error.as_ref() {
+                return Err(e.clone());
+            }
+            return Ok(slot.conn.clone().unwrap());
+        }
+        let dial = match slot.conn.as_ref() {
+            Some(conn) => dialer.reconnect && conn.broken.load(Ordering::Relaxed),
+            None => true,
+        };
+        if !dial {
+            return Ok(slot.conn.clone().unwrap());
+        }
+
+        self.state.set(if slot.conn.is_some() {
+            ConnectionState::Reconnecting
+        } else {
+            ConnectionState::Connecting
+        });
+        slot.dialing = true;
+        drop(slot);
+        let dialed = connect_retrying(&dialer.sockaddr, &dialer.retry);
+        let mut slot = self.conn.lock().unwrap();
+        slot.dialing = false;
+        self.dialed.notify_all();
+        // The io timeouts are set under the lock, so none set meanwhile is
+        // missed.
+        let timeouts = *self.timeouts.lock().unwrap();
+        let dialed =
+            dialed.and_then(
+                |fd| match set_io_timeouts(fd, timeouts.read, timeouts.write) {
+                    Ok(()) => Ok(fd),
+                    Err(e) => {
+                        close(fd).unwrap_or(());
+                        Err(e)
+                    }
+                },
+            );
+        match dialed {
+            Ok(fd) => {
+                debug!("Connected to {}", dialer.sockaddr);
+                let conn = Arc::new(Conn::new(
+                    fd,
+                    self.max_message_size.clone(),
+                    self.state.clone(),
+                ));
+                slot.conn = Some(conn.clone());
+                slot.dial_error = None;
+                self.state.set(ConnectionState::Ready);
+                Ok(conn)
+            }
+            Err(e) => {
+                slot.dial_error = Some(e.clone());
+                self.state.set(ConnectionState::Disconnected);
+                Err(e)
+            }
+        }
+    }
+
+    /// Returns the state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        self.state.get()
+    }
+
+    /// Calls `f` with the new state each time the state of the connection
+    /// changes, from the thread changing it: the one connecting, or the one
+    /// reading responses when the connection drops.
+    ///
+    /// The setting is shared with the clones of the client.
+    pub fn on_state_change<F>(self, f: F) -> Client
+    where
+        F: Fn(ConnectionState) + Send + Sync + 'static,
+    {
+        self.state.add_hook(Arc::new(f));
+        self
+    }
+
+    /// Returns true once the server said it's going away, new calls then
+    /// fail right away with an UNAVAILABLE status and
+    /// [`ShutdownReason::Drain`]. The caller should connect again, possibly
+    /// elsewhere.
+    pub fn is_going_away(&self) -> bool {
+        let slot = self.conn.lock().unwrap();
+        slot.conn
+            .as_ref()
+            .is_some_and(|conn| conn.going_away.load(Ordering::Relaxed))
+    }
+
+    /// Sets the largest response payload the client accepts, defaults to
+    /// [`MESSAGE_LENGTH_MAX`]. A request whose response is larger fails with
+    /// RESOURCE_EXHAUSTED.
+    ///
+    /// The setting is shared with the clones of the client.
+    pub fn set_max_message_size(self, size: usize) -> Client {
+        self.max_message_size.store(size, Ordering::Relaxed);
+        self
+    }
+
+    /// Closes the connection if a response, once it started arriving,
+    /// didn't arrive in whole within `timeout`. Waiting for responses isn't
+    /// limited by this.
+    pub fn set_read_timeout(self, timeout: Duration) -> Result<Client> {
+        let slot = self.conn.lock().unwrap();
+        if let Some(conn) = slot.conn.as_ref() {
+            set_io_timeouts(conn.fd, Some(timeout), None)?;
+        }
+        self.timeouts.lock().unwrap().read = Some(timeout);
+        drop(slot);
+        Ok(self)
+    }
+
+    /// Closes the connection if writing a request took longer than
+    /// `timeout`, as when the server stopped reading. The calls pending
+    /// then fail.
+    pub fn set_write_timeout(self, timeout: Duration) -> Result<Client> {
+        let slot = self.conn.lock().unwrap();
+        if let Some(conn) = slot.conn.as_ref() {
+            set_io_timeouts(conn.fd, None, Some(timeout))?;
+        }
+        self.timeouts.lock().unwrap().write = Some(timeout);
+        drop(slot);
+        Ok(self)
+    }
+
+    /// Retries the calls made with this client as set out by `policy`.
+    ///
+    /// Only calls to methods safe to call more than once should be retried:
+    /// the setting isn't shared with the clones of the client, set it on a
+    /// clone kept for those. One-way requests aren't retried.
+    pub fn set_retry_policy(mut self, policy: RetryPolicy) -> Client {
+        self.retry = Some(Arc::new(policy));
+        self
+    }
+
+    /// Adds an interceptor wrapping the calls made with this client.
+    /// Interceptors run in the order they are added, the first one seeing
+    /// the calls first.
+    ///
+    /// The setting isn't shared with the clones made before.
+    pub fn add_interceptor(mut self, interceptor: impl ClientInterceptor + 'static) -> Client {
+        Arc::make_mut(&mut self.interceptors).push(Arc::new(interceptor));
+        self
+    }
+
+    pub fn request(&self, req: Request) -> Result<Response> {
+        let (res, fds) = self.request_with_fds(req, &[])?;
+        if !fds.is_empty() {
+            debug!("Dropping {} fds passed with the response", fds.len());
+            close_fds(&fds);
+        }
+
+        Ok(res)
+    }
+
+    /// Calls `method` of `service`, such as `grpc.Containerd` and `Version`,
+    /// with `payload` as the encoded request message, and returns the
+    /// encoded response message. The timeout and metadata of the call are
+    /// taken from `ctx`.
+    ///
+    /// This makes calls without generated code, for tools like CLIs,
+    /// fuzzers or gateways. The call goes through the interceptors and is
+    /// retried like any other.
+    pub fn call_raw(
+        &self,
+        service: &str,
+        method: &str,
+        payload: Vec<u8>,
+        ctx: Context,
+    ) -> Result<Vec<u8>> {
+        let req = common::raw_request(service, method, payload, ctx);
+        Ok(self.request(req)?.payload)
+    }
+
+    /// Sends `req` passing `fds` along with it, returns the response and the
+    /// fds passed with it, which belong to the caller. Passing fds only
+    /// works over unix sockets, at most [`MAX_MESSAGE_FDS`] of them at once.
+    ///
+    /// The passed fds stay open on this side.
+    ///
+    /// [`MAX_MESSAGE_FDS`]: crate::sync::MAX_MESSAGE_FDS
+    pub fn request_with_fds(&self, req: Request, fds: &[RawFd]) -> Result<(Response, Vec<RawFd>)> {
+        self.call(req, fds, None)
+    }
+
+    /// Like [`request()`](Client::request), but the call can be cancelled
+    /// with `cancel` from another thread. A handle cancels a single call.
+    pub fn request_with_cancel(&self, req: Request, cancel: &CancelHandle) -> Result<Response> {
+        let result = self.call(req, &[], Some(cancel));
+        cancel.finish();
+        let (res, fds) = result?;
+        if !fds.is_empty() {
+            debug!("Dropping {} fds passed with the response", fds.len());
+            close_fds(&fds);
+        }
+
+        Ok(res)
+    }
+
+    // Makes a call through the interceptors.
+    fn call(
+        &self,
+        req: Request,
+        fds: &[RawFd],
+        cancel: Option<&CancelHandle>,
+    ) -> Result<(Response, Vec<RawFd>)> {
+        if self.interceptors.is_empty() {
+            return self.call_retrying(req, fds, cancel);
+        }
+        let mut res_fds = Vec::new();
+        let res = ClientNext::new(&self.interceptors, |req| {
+            let (res, fds) = self.call_retrying(req, fds, cancel)?;
+            res_fds = fds;
+            Ok(res)
+        })
+        .run(req);
+        match res {
+            Ok(res) => Ok((res, res_fds)),
+            Err(e) => {
+                // An interceptor failed the call after it was made.
+                close_fds(&res_fds);
+                Err(e)
+            }
+        }
+    }
+
+    fn call_retrying(
+        &self,
+        mut req: Request,
+        fds: &[RawFd],
+        cancel: Option<&CancelHandle>,
+    ) -> Result<(Response, Vec<RawFd>)> {
+        compression::add_accept_encoding(&mut req);
+        common::add_cancel_key(&mut req.metadata);
+        let policy = match self.retry.as_ref() {
+            Some(policy) => policy,
+            None => return self.request_once(&req, fds, cancel),
+        };
+        let deadline = common::get_deadline(Instant::now(), req.timeout_nano);
+        let mut jitter = Jitter::new(policy.jitter_seed);
+        let mut attempt = 1;
+        loop {
+            let e = match self.request_once(&req, fds, cancel) {
+                Ok(res) => return Ok(res),
+                Err(e) => e,
+            };
+            if cancel.is_some_and(|c| c.is_cancelled()) {
+                return Err(e);
+            }
+            let backoff =
+                match policy.next_backoff(attempt, &e, deadline, Instant::now(), &mut jitter) {
+                    Some(backoff) => backoff,
+                    None => return Err(e),
+                };
+            debug!("Retrying {} in {:?}: {:?}", req.method, backoff, e);
+            thread::sleep(backoff);
+            if let Some(deadline) = deadline {
+                req.timeout_nano = common::remaining_nano(deadline, Instant::now());
+            }
+            attempt += 1;
+        }
+    }
+
+    fn request_once(
+        &self,
+        req: &Request,
+        fds: &[RawFd],
+        cancel: Option<&CancelHandle>,
+    ) -> Result<(Response, Vec<RawFd>)> {
+        let conn = self.conn()?;
+        // The stream id is only given by the sender thread.
+        let span = spans::call(
+            Kind::Client,
+            &req.service,
+            &req.method,
+            None,
+            Some(conn.info.as_ref()),
+        );
+        let _entered = span.enter();
+        if conn.going_away.load(Ordering::Relaxed) {
+            return Err(going_away_error());
+        }
+        let buf = req.encode().map_err(err_to_others_err!(e, ""))?;
+
+        // Room for the reply a cancel gives while nobody waits on it yet.
+        let (tx, rx) = mpsc::sync_channel(1);
+
+        let sender_tx = conn.sender_tx.lock().unwrap();
+        if let Some(cancel) = cancel {
+            cancel.start(tx.clone(), sender_tx.clone())?;
+        }
+        sender_tx
+            .send(Outgoing::Request(
+                buf,
+                fds.to_vec(),
+                Some(tx),
+                cancel.cloned(),
+            ))
+            .map_err(err_to_others_err!(e, "Send packet to sender error "))?;
+        drop(sender_tx);
+
+        let result = if req.timeout_nano == 0 {
+            rx.recv()
+                .map_err(err_to_others_err!(e, "Receive packet from recver error: "))?
+        } else {
+            rx.recv_timeout(Duration::from_nanos(req.timeout_nano as u64))
+                .map_err(err_to_others_err!(
+                    e,
+                    "Receive packet from recver timeout: "
+                ))?
+        };
+
+        let (buf, fds) = result?;
+        let res = match Response::decode(buf) {
+            Ok(res) => res,
+            Err(e) => {
+                close_fds(&fds);
+                return Err(Error::Others(format!("Unpack response error {:?}", e)));
+            }
+        };
+        if common::has_cancel_key(&res.metadata) {
+            conn.server_cancels.store(true, Ordering::Relaxed);
+        }
+
+        let status = res.status();
+        if status.code() != Code::OK {
+            close_fds(&fds);
+            return Err(Error::RpcStatus((*status).clone()));
+        }
+
+        Ok((res, fds))
+    }
+
+    /// Sends a one-way request, which the server handles without
+    /// responding.
+    ///
+    /// Returns once the request is queued for sending: whether it succeeds
+    /// is only known to the server. A server without support for one-way
+    /// requests, such as a Go one, responds all the same, and the response
+    /// is dropped.
+    pub fn notify(&self, mut req: Request) -> Result<()> {
+        let conn = self.conn()?;
+        if conn.going_away.load(Ordering::Relaxed) {
+            return Err(going_away_error());
+        }
+        common::add_no_response_key(&mut req);
+        let buf = req.encode().map_err(err_to_others_err!(e, ""))?;
+
+        let res = conn
+            .sender_tx
+            .lock()
+            .unwrap()
+            .send(Outgoing::Request(buf, Vec::new(), None, None))
+            .map_err(err_to_others_err!(e, "Send packet to sender error "));
+        res
+    }
+}
+
+/// Clients calls are spread over round-robin, connected to one server or
+/// several serving the same services.
+///
+/// The messages of a connection are written and read one after the other,
+/// so a large one holds up the calls behind it: spreading the calls over
+/// several connections avoids that. A call made on a client whose
+/// connection dropped fails, make the pool of clients that reconnect to
+/// avoid it. The clones of a pool share the clients.
+#[derive(Clone)]
+pub struct ClientPool {
+    clients: Arc<Vec<Client>>,
+    next: Arc<AtomicUsize>,
+}
+
+impl ClientPool {
+    /// Makes a pool of `clients`, failing if there are none.
+    pub fn new(clients: Vec<Client>) -> Result<ClientPool> {
+        if clients.is_empty() {
+            return Err(Error::Others("A client pool needs clients".to_string()));
+        }
+        Ok(ClientPool {
+            clients: Arc::new(clients),
+            next: Arc::default(),
+        })
+    }
+
+    /// Makes a pool of `size` connections to `sockaddr`.
+    pub fn connect(sockaddr: &str, size: usize) -> Result<ClientPool> {
+        Self::connect_all(&[sockaddr], size)
+    }
+
+    /// Makes a pool of `size` connections to each of `sockaddrs`.
+    pub fn connect_all(sockaddrs: &[&str], size: usize) -> Result<ClientPool> {
+        let mut clients = Vec::with_capacity(sockaddrs.len() * size);
+        for sockaddr in sockaddrs {
+            for _ in 0..size {
+                clients.push(Client::connect(sockaddr)?);
+            }
+        }
+        Self::new(clients)
+    }
+
+    /// Returns the next client in turn, to make a call with.
+    pub fn client(&self) -> Client {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        self.clients[next % self.clients.len()].clone()
+    }
+
+    pub fn clients(&self) -> &[Client] {
+        &self.clients
+    }
+
+    /// Makes the call with the next client in turn.
+    pub fn request(&self, req: Request) -> Result<Response> {
+        self.client().request(req)
+    }
+}
+
+impl Conn {
+    fn new(fd: RawFd, max_message_size: Arc<AtomicUsize>, state: StateWatch) -> Conn {
         let (sender_tx, rx): (Sender, Receiver) = mpsc::channel();
 
         let (recver_fd, close_fd) =
@@ -62,25 +771,63 @@ impl Client {
             set_fd_close_exec(close_fd).unwrap();
         }
 
-        let client_close = Arc::new(ClientClose { fd, close_fd });
+        let info = Arc::new(ConnectionInfo::new(fd));
+        spans::connection_opened(Kind::Client, &info);
+        let client_close = ClientClose {
+            fd,
+            close_fd,
+            info: info.clone(),
+        };
 
         let recver_map_orig = Arc::new(Mutex::new(HashMap::new()));
+        let going_away = Arc::new(AtomicBool::new(false));
+        let server_cancels = Arc::new(AtomicBool::new(false));
+        let broken = Arc::new(AtomicBool::new(false));
 
         //Sender
         let recver_map = recver_map_orig.clone();
+        let sender_cancels = server_cancels.clone();
         thread::spawn(move || {
             let mut stream_id: u32 = 1;
-            for (buf, recver_tx) in rx.iter() {
+            for out in rx.iter() {
+                let (buf, fds, recver_tx, cancel) = match out {
+                    Outgoing::Request(buf, fds, recver_tx, cancel) => (buf, fds, recver_tx, cancel),
+                    Outgoing::Cancel(stream_id) => {
+                        recver_map.lock().unwrap().remove(&stream_id);
+                        if !sender_cancels.load(Ordering::Relaxed) {
+                            continue;
+                        }
+                        let mh = MessageHeader::new_cancel(stream_id);
+                        if let Err(e) = write_message_with_fds(fd, mh, Vec::new(), &[]) {
+                            debug!("Failed to cancel stream {}: {:?}", stream_id, e);
+                        }
+                        continue;
+                    }
+                };
                 let current_stream_id = stream_id;
                 stream_id += 2;
+                let mut mh = MessageHeader::new_request(0, buf.len() as u32);
+                mh.set_stream_id(current_stream_id);
+                let recver_tx = match recver_tx {
+                    Some(recver_tx) => recver_tx,
+                    None => {
+                        if let Err(e) = write_message_with_fds(fd, mh, buf, &fds) {
+                            error!("Failed to send one-way request: {:?}", e);
+                        }
+                        continue;
+                    }
+                };
+                if let Some(cancel) = cancel {
+                    if !cancel.written(current_stream_id) {
+                        continue;
+                    }
+                }
                 //Put current_stream_id and recver_tx to recver_map
                 {
                     let mut map = recver_map.lock().unwrap();
                     map.insert(current_stream_id, recver_tx.clone());
                 }
-                let mut mh = MessageHeader::new_request(0, buf.len() as u32);
-                mh.set_stream_id(current_stream_id);
-                if let Err(e) = write_message(fd, mh, buf) {
+                if let Err(e) = write_message_with_fds(fd, mh, buf, &fds) {
                     //Remove current_stream_id and recver_tx to recver_map
                     {
                         let mut map = recver_map.lock().unwrap();
@@ -95,6 +842,9 @@ impl Client {
         });
 
         //Recver
+        let max_len = max_message_size.clone();
+        let recver_going_away = going_away.clone();
+        let recver_broken = broken.clone();
         thread::spawn(move || {
             let mut pollers = vec![
                 libc::pollfd {
@@ -141,40 +891,78 @@ impl Client {
 
                 let mh;
                 let buf;
-                match read_message(fd) {
-                    Ok((x, y)) => {
+                let fds;
+                match read_message_with_fds(fd, max_len.load(Ordering::Relaxed)) {
+                    Ok((x, y, z)) => {
                         mh = x;
                         buf = y;
+                        fds = z;
                     }
-                    Err(x) => match x {
-                        Error::Socket(y) => {
-                            trace!("Socket error {}", y);
-                            let mut map = recver_map_orig.lock().unwrap();
-                            for (_, recver_tx) in map.iter_mut() {
-                                recver_tx
-                                    .send(Err(Error::Socket(format!("socket error {}", y))))
-                                    .unwrap_or_else(|e| {
-                                        error!("The request has returned error {:?}", e)
-                                    });
-                            }
-                            map.clear();
-                            break;
-                        }
-                        _ => {
-                            trace!("Others error {:?}", x);
-                            continue;
+                    // A socket error, or a frame header that leaves the rest
+                    // of the stream unreadable.
+                    Err(x) => {
+                        trace!("Read error {:?}", x);
+                        let x = match x {
+                            Error::Socket(y) => Error::Socket(format!("socket error {}", y)),
+                            x => x,
+                        };
+                        let mut map = recver_map_orig.lock().unwrap();
+                        for (_, recver_tx) in map.iter_mut() {
+                            recver_tx.send(Err(x.clone())).unwrap_or_else(|e| {
+                                error!("The request has returned error {:?}", e)
+                            });
                         }
-                    },
+                        map.clear();
+                        break;
+                    }
                 };
                 let mut map = recver_map_orig.lock().unwrap();
+                if mh.type_ == MESSAGE_TYPE_GOAWAY {
+                    close_fds(&fds);
+                    let goaway = match buf.as_ref().map(GoAway::decode) {
+                        Ok(Ok(g)) => g,
+                        res => {
+                            debug!("Recver got malformed go away {:?}: {:?}", mh, res);
+                            continue;
+                        }
+                    };
+                    debug!(
+                        "Server is going away after stream id {}",
+                        goaway.last_stream_id
+                    );
+                    recver_going_away.store(true, Ordering::Relaxed);
+                    map.retain(|&id, recver_tx| {
+                        if id <= goaway.last_stream_id {
+                            return true;
+                        }
+                        recver_tx
+                            .send(Err(going_away_error()))
+                            .unwrap_or_else(|_e| error!("The request has returned"));
+                        false
+                    });
+                    continue;
+                }
                 let recver_tx = match map.get(&mh.stream_id) {
                     Some(tx) => tx,
                     None => {
                         debug!("Recver got unknown packet {:?} {:?}", mh, buf);
+                        close_fds(&fds);
+                        continue;
+                    }
+                };
+                let buf = match buf {
+                    Ok(buf) => buf,
+                    Err(status) => {
+                        close_fds(&fds);
+                        recver_tx
+                            .send(Err(Error::RpcStatus(status)))
+                            .unwrap_or_else(|_e| error!("The request has returned"));
+                        map.remove(&mh.stream_id);
                         continue;
                     }
                 };
                 if mh.type_ != MESSAGE_TYPE_RESPONSE {
+                    close_fds(&fds);
                     recver_tx
                         .send(Err(Error::Others(format!(
                             "Recver got malformed packet {:?} {:?}",
@@ -184,9 +972,12 @@ impl Client {
                     continue;
                 }
 
-                recver_tx
-                    .send(Ok(buf))
-                    .unwrap_or_else(|_e| error!("The request has returned"));
+                recver_tx.send(Ok((buf, fds))).unwrap_or_else(|e| {
+                    error!("The request has returned");
+                    if let Ok((_, fds)) = e.0 {
+                        close_fds(&fds);
+                    }
+                });
 
                 map.remove(&mh.stream_id);
             }
@@ -198,57 +989,279 @@ impl Client {
                 )
             });
 
+            // The calls left are failed rather than left to notice the reply
+            // channel is gone, a cancel handle keeps it open.
+            for (_, recver_tx) in recver_map_orig.lock().unwrap().drain() {
+                let e = Error::Socket("the connection is closed".to_string());
+                recver_tx.try_send(Err(e)).ok();
+            }
+            state.set(ConnectionState::Disconnected);
+            recver_broken.store(true, Ordering::Relaxed);
             trace!("Recver quit");
         });
 
-        Client {
-            _fd: fd,
-            sender_tx,
+        Conn {
+            fd,
+            sender_tx: Mutex::new(sender_tx),
             _client_close: client_close,
+            info,
+            going_away,
+            server_cancels,
+            broken,
         }
     }
-    pub fn request(&self, req: Request) -> Result<Response> {
-        let buf = req.encode().map_err(err_to_others_err!(e, ""))?;
-
-        let (tx, rx) = mpsc::sync_channel(0);
-
-        self.sender_tx
-            .send((buf, tx))
-            .map_err(err_to_others_err!(e, "Send packet to sender error "))?;
-
-        let result = if req.timeout_nano == 0 {
-            rx.recv()
-                .map_err(err_to_others_err!(e, "Receive packet from recver error: "))?
-        } else {
-            rx.recv_timeout(Duration::from_nanos(req.timeout_nano as u64))
-                .map_err(err_to_others_err!(
-                    e,
-                    "Receive packet from recver timeout: "
-                ))?
-        };
-
-        let buf = result?;
-        let res =
-            Response::decode(buf).map_err(err_to_others_err!(e, "Unpack response error "))?;
-
-        let status = res.status();
-        if status.code() != Code::OK {
-            return Err(Error::RpcStatus((*status).clone()));
-        }
-
-        Ok(res)
-    }
 }
 
 struct ClientClose {
     fd: RawFd,
     close_fd: RawFd,
+    info: Arc<ConnectionInfo>,
 }
 
 impl Drop for ClientClose {
     fn drop(&mut self) {
         close(self.close_fd).unwrap();
         close(self.fd).unwrap();
+        spans::connection_closed(Kind::Client, &self.info, &CloseReason::Client);
         trace!("All client is droped");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service_fn::{self, Raw};
+    use crate::sync::Server;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixListener;
+    use std::path::Path;
+
+    fn server(path: &Path) -> Server {
+        std::fs::remove_file(path).ok();
+        let listener = UnixListener::bind(path).unwrap();
+        let mut server = Server::new()
+            .add_listener(listener.into_raw_fd())
+            .unwrap()
+            .register_method(
+                "test.Svc",
+                "Ping",
+                service_fn::sync_method(Raw, |_ctx, req: Vec<u8>| Ok(req)),
+            );
+        server.start().unwrap();
+        server
+    }
+
+    fn ping() -> Request {
+        Request {
+            service: "test.Svc".to_string(),
+            method: "Ping".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn wait_for(client: &Client, state: ConnectionState) {
+        while client.state() != state {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let path =
+            std::env::temp_dir().join(format!("ttrpc-reconnect-{}.sock", std::process::id()));
+        let first = server(&path);
+        let retry = ConnectRetry {
+            timeout: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let client =
+            Client::connect_with_reconnect(&format!("unix://{}", path.display()), retry).unwrap();
+        client.request(ping()).unwrap();
+
+        first.shutdown();
+        std::fs::remove_file(&path).unwrap();
+        wait_for(&client, ConnectionState::Disconnected);
+        let caller = client.clone();
+        let reconnecting = thread::spawn(move || caller.request(ping()));
+        wait_for(&client, ConnectionState::Reconnecting);
+        // Connecting doesn't hold up the client meanwhile.
+        let started = Instant::now();
+        assert!(!client.is_going_away());
+        let client = client.set_write_timeout(Duration::from_secs(5)).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(100));
+        // A call made meanwhile fails with the attempt to connect.
+        assert!(client.request(ping()).is_err());
+        assert!(reconnecting.join().unwrap().is_err());
+
+        // The next call connects again.
+        let second = server(&path);
+        client.request(ping()).unwrap();
+        assert_eq!(client.state(), ConnectionState::Ready);
+
+        second.shutdown();
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_connect_lazy() {
+        let path = std::env::temp_dir().join(format!("ttrpc-lazy-{}.sock", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let retry = ConnectRetry {
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let client = Client::connect_lazy(&format!("unix://{}", path.display()), retry);
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+
+        // Nothing listens, each call tries to connect and fails.
+        for _ in 0..2 {
+            assert!(client.request(ping()).is_err());
+            assert_eq!(client.state(), ConnectionState::Disconnected);
+        }
+
+        let server = server(&path);
+        client.request(ping()).unwrap();
+        assert_eq!(client.state(), ConnectionState::Ready);
+
+        server.shutdown();
+        std::fs::remove_file(&path).ok();
+    }
+
+    fn is_cancelled(e: &Error) -> bool {
+        matches!(e, Error::RpcStatus(s) if s.code() == Code::CANCELLED)
+    }
+
+    #[test]
+    fn test_cancel_handle() {
+        let path = std::env::temp_dir().join(format!("ttrpc-cancel-{}.sock", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let (started_tx, started) = mpsc::channel();
+        let (ended_tx, ended) = mpsc::channel();
+        let (started_tx, ended_tx) = (Mutex::new(started_tx), Mutex::new(ended_tx));
+        let listener = UnixListener::bind(&path).unwrap();
+        let mut server = Server::new()
+            .add_listener(listener.into_raw_fd())
+            .unwrap()
+            .register_method(
+                "test.Svc",
+                "Ping",
+                service_fn::sync_method(Raw, |_ctx, req: Vec<u8>| Ok(req)),
+            )
+            .register_method(
+                "test.Svc",
+                "Block",
+                service_fn::sync_method(Raw, move |ctx, req: Vec<u8>| {
+                    started_tx.lock().unwrap().send(()).unwrap();
+                    let started = Instant::now();
+                    while !ctx.cancel.is_cancelled() && started.elapsed() < Duration::from_secs(5) {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    ended_tx
+                        .lock()
+                        .unwrap()
+                        .send(ctx.cancel.is_cancelled())
+                        .unwrap();
+                    Ok(req)
+                }),
+            );
+        server.start().unwrap();
+        let client = Client::connect(&format!("unix://{}", path.display())).unwrap();
+        // The server agrees to calls being cancelled in its first response.
+        client.request(ping()).unwrap();
+
+        // Cancelled before the call, the request isn't written.
+        let cancel = CancelHandle::new();
+        cancel.cancel();
+        let block = Request {
+            method: "Block".to_string(),
+            ..ping()
+        };
+        let err = client
+            .request_with_cancel(block.clone(), &cancel)
+            .unwrap_err();
+        assert!(is_cancelled(&err), "{:?}", err);
+        assert!(started.recv_timeout(Duration::from_millis(50)).is_err());
+
+        // Cancelled once written, the call fails and the server stops it.
+        let cancel = CancelHandle::new();
+        let call = thread::spawn({
+            let (client, cancel) = (client.clone(), cancel.clone());
+            move || client.request_with_cancel(block, &cancel)
+        });
+        started.recv_timeout(Duration::from_secs(5)).unwrap();
+        cancel.cancel();
+        let err = call.join().unwrap().unwrap_err();
+        assert!(is_cancelled(&err), "{:?}", err);
+        assert!(ended.recv_timeout(Duration::from_secs(5)).unwrap());
+
+        // Cancelled once answered, nothing happens.
+        let cancel = CancelHandle::new();
+        client.request_with_cancel(ping(), &cancel).unwrap();
+        cancel.cancel();
+        assert!(cancel.is_cancelled());
+        client.request(ping()).unwrap();
+
+        server.shutdown();
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_client_pool() {
+        let path = std::env::temp_dir().join(format!("ttrpc-pool-{}.sock", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let listener = UnixListener::bind(&path).unwrap();
+        let mut server = Server::new()
+            .add_listener(listener.into_raw_fd())
+            .unwrap()
+            .register_method(
+                "test.Svc",
+                "Id",
+                service_fn::sync_method(Raw, |ctx, _req: Vec<u8>| {
+                    Ok(ctx.connection.id.to_be_bytes().to_vec())
+                }),
+            )
+            .register_method(
+                "test.Svc",
+                "Break",
+                service_fn::sync_method(Raw, |ctx, req: Vec<u8>| {
+                    shutdown(ctx.fd, Shutdown::Both).unwrap();
+                    Ok(req)
+                }),
+            );
+        server.start().unwrap();
+        let addr = format!("unix://{}", path.display());
+        let clients = (0..3)
+            .map(|_| Client::connect_with_reconnect(&addr, ConnectRetry::default()).unwrap())
+            .collect();
+        let pool = ClientPool::new(clients).unwrap();
+        let ids = || -> Vec<Vec<u8>> {
+            let req = Request {
+                method: "Id".to_string(),
+                ..ping()
+            };
+            (0..3)
+                .map(|_| pool.request(req.clone()).unwrap().payload)
+                .collect()
+        };
+
+        // The calls go round the connections.
+        let first = ids();
+        assert_eq!(ids(), first);
+        assert!(first[0] != first[1] && first[1] != first[2] && first[0] != first[2]);
+
+        // A client whose connection broke connects again.
+        let broken = &pool.clients()[0];
+        let req = Request {
+            method: "Break".to_string(),
+            ..ping()
+        };
+        assert!(broken.request(req).is_err());
+        wait_for(broken, ConnectionState::Disconnected);
+        let next = ids();
+        assert_ne!(next[0], first[0]);
+        assert_eq!(next[1..], first[1..]);
+
+        server.shutdown();
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Interceptors, wrapping the calls a server handles or a client makes.
+
+use protobuf::Message;
+use std::sync::mpsc::{channel, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::common::{self, PeerCredentials};
+use crate::error::{get_rpc_status, Error, Result};
+use crate::proto::{Code, Request, Response, MESSAGE_TYPE_RESPONSE};
+use crate::sync::utils::{MethodHandler, TtrpcContext};
+
+/// Wraps every call of a [`Server`](crate::sync::Server), see
+/// [`Server::add_interceptor()`](crate::sync::Server::add_interceptor).
+///
+/// An interceptor sees the method, metadata and payload of a call before
+/// the handler does. It may change them, fail the call, or pass it on with
+/// [`Next::run()`]. This makes room for authorization, logging or metrics
+/// without touching generated code.
+///
+/// The handler sends the response itself, so interceptors don't see it.
+/// Failing with [`Error::RpcStatus`](crate::Error::RpcStatus) before
+/// passing the call on answers the client with that status, other errors
+/// close the connection as they do when returned by handlers.
+pub trait Interceptor: Send + Sync {
+    fn intercept(&self, ctx: TtrpcContext, req: Request, next: Next<'_>) -> Result<()>;
+}
+
+/// The rest of the interceptors of a call, then its handler.
+pub struct Next<'a> {
+    interceptors: &'a [Arc<dyn Interceptor>],
+    handler: &'a (dyn MethodHandler + Send + Sync),
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        interceptors: &'a [Arc<dyn Interceptor>],
+        handler: &'a (dyn MethodHandler + Send + Sync),
+    ) -> Self {
+        Self {
+            interceptors,
+            handler,
+        }
+    }
+
+    /// Passes the call on to the next interceptor, or to the handler.
+    pub fn run(self, ctx: TtrpcContext, req: Request) -> Result<()> {
+        match self.interceptors.split_first() {
+            Some((interceptor, interceptors)) => {
+                let next = Next {
+                    interceptors,
+                    handler: self.handler,
+                };
+                interceptor.intercept(ctx, req, next)
+            }
+            None => self.handler.handler(ctx, req),
+        }
+    }
+}
+
+/// Wraps every call made with a [`Client`](crate::sync::Client), see
+/// [`Client::add_interceptor()`](crate::sync::Client::add_interceptor).
+///
+/// An interceptor sees the method, metadata and payload of a call before it
+/// is sent. It may change them, to add an auth token for instance, fail the
+/// call, or pass it on with [`ClientNext::run()`] and look at the response
+/// or error. Retries happen within the chain, one-way requests don't go
+/// through it.
+pub trait ClientInterceptor: Send + Sync {
+    fn intercept(&self, req: Request, next: ClientNext<'_>) -> Result<Response>;
+}
+
+/// The rest of the interceptors of a call, then the call itself.
+pub struct ClientNext<'a> {
+    interceptors: &'a [Arc<dyn ClientInterceptor>],
+    call: Box<dyn FnOnce(Request) -> Result<Response> + 'a>,
+}
+
+impl<'a> ClientNext<'a> {
+    pub(crate) fn new(
+        interceptors: &'a [Arc<dyn ClientInterceptor>],
+        call: impl FnOnce(Request) -> Result<Response> + 'a,
+    ) -> Self {
+        Self {
+            interceptors,
+            call: Box::new(call),
+        }
+    }
+
+    /// Passes the call on to the next interceptor, or makes it.
+    pub fn run(self, req: Request) -> Result<Response> {
+        match self.interceptors.split_first() {
+            Some((interceptor, interceptors)) => {
+                let next = ClientNext {
+                    interceptors,
+                    call: self.call,
+                };
+                interceptor.intercept(req, next)
+            }
+            None => (self.call)(req),
+        }
+    }
+}
+
+// Runs the rest of the chain of a call, looking at the messages the handler
+// sends on their way to the client. Also gives the size and status code of
+// the response, if the handler sent one before returning.
+pub(crate) fn run_watched(
+    mut ctx: TtrpcContext,
+    req: Request,
+    next: Next<'_>,
+) -> (Result<()>, Option<(usize, Code)>) {
+    let (res_tx, res_rx) = channel();
+    let client_tx = std::mem::replace(&mut ctx.res_tx, res_tx);
+    let res = next.run(ctx, req);
+
+    let mut response = None;
+    loop {
+        match res_rx.try_recv() {
+            Ok((mh, buf)) => {
+                if mh.type_ == MESSAGE_TYPE_RESPONSE {
+                    let code = Response::parse_from_bytes(&buf)
+                        .map_or(Code::OK, |response| response.status().code());
+                    response = Some((buf.len(), code));
+                }
+                client_tx.send((mh, buf)).ok();
+            }
+            Err(TryRecvError::Empty) => {
+                // The handler kept the sender to respond later, from a
+                // thread of its own.
+                thread::spawn(move || {
+                    for msg in res_rx {
+                        client_tx.send(msg).ok();
+                    }
+                });
+                break;
+            }
+            Err(TryRecvError::Disconnected) => break,
+        }
+    }
+    (res, response)
+}
+
+// The status code a call ended with, given what run_watched() saw.
+pub(crate) fn call_code(res: &Result<()>, response: Option<(usize, Code)>) -> Code {
+    match res {
+        Err(Error::RpcStatus(status)) => status.code(),
+        Err(_) => Code::UNKNOWN,
+        Ok(()) => response.map_or(Code::OK, |(_, code)| code),
+    }
+}
+
+// Caps the calls of a service or method running at once, see
+// Server::set_concurrency_limit().
+pub(crate) struct ConcurrencyLimit {
+    name: String,
+    max: usize,
+    queue_timeout: Duration,
+    running: Mutex<usize>,
+    done: Condvar,
+}
+
+impl ConcurrencyLimit {
+    pub(crate) fn new(name: &str, max: usize, queue_timeout: Duration) -> Self {
+        Self {
+            name: name.to_string(),
+            max,
+            queue_timeout,
+            running: Mutex::new(0),
+            done: Condvar::new(),
+        }
+    }
+}
+
+// Counts a call as running until dropped, even by a panicking handler.
+struct Running<'a>(&'a ConcurrencyLimit);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap() -= 1;
+        self.0.done.notify_one();
+    }
+}
+
+impl Interceptor for ConcurrencyLimit {
+    fn intercept(&self, ctx: TtrpcContext, req: Request, next: Next<'_>) -> Result<()> {
+        if !common::covers_call(&self.name, &req) {
+            return next.run(ctx, req);
+        }
+        let _running = {
+            let running = self.running.lock().unwrap();
+            let (mut running, _) = self
+                .done
+                .wait_timeout_while(running, self.queue_timeout, |running| *running >= self.max)
+                .unwrap();
+            if *running >= self.max {
+                return Err(get_rpc_status(
+                    Code::RESOURCE_EXHAUSTED,
+                    format!(
+                        "too many calls to {} at once, the limit is {}",
+                        self.name, self.max
+                    ),
+                ));
+            }
+            *running += 1;
+            Running(self)
+        };
+        next.run(ctx, req)
+    }
+}
+
+// The authorizer of a server, see Server::set_authorizer().
+pub(crate) struct Authorize<F>(pub(crate) F);
+
+impl<F> Interceptor for Authorize<F>
+where
+    F: Fn(Option<PeerCredentials>, &str) -> bool + Send + Sync,
+{
+    fn intercept(&self, ctx: TtrpcContext, req: Request, next: Next<'_>) -> Result<()> {
+        let path = format!("{}/{}", req.service, req.method);
+        if !(self.0)(ctx.peer_cred, &path) {
+            debug!("{} denied to peer {:?}", path, ctx.peer_cred);
+            return Err(get_rpc_status(
+                Code::PERMISSION_DENIED,
+                format!("{} is not allowed", path),
+            ));
+        }
+        next.run(ctx, req)
+    }
+}
@@ -7,14 +7,22 @@
 
 mod channel;
 mod client;
+pub(crate) mod interceptor;
+mod pool;
+mod raw;
 mod server;
+mod threads;
 
 #[macro_use]
 mod utils;
 
-pub use client::Client;
-pub use server::Server;
+pub use channel::MAX_MESSAGE_FDS;
+pub use client::{CancelHandle, Client, ClientPool};
+pub use interceptor::{ClientInterceptor, ClientNext, Interceptor, Next};
+pub use raw::RawService;
+pub use server::{Server, ThreadingMode};
+pub use threads::ThreadConfig;
 
 #[doc(hidden)]
 pub use utils::response_to_channel;
-pub use utils::{MethodHandler, TtrpcContext};
+pub use utils::{CancellationToken, MethodHandler, ResponseFds, TtrpcContext};
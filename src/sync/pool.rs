@@ -0,0 +1,923 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Shared worker pool serving all the connections of a sync server.
+//!
+//! A poller thread waits on every idle connection and hands a readable one
+//! to the workers. A worker reads one message off it, hands the connection
+//! back to the poller and then handles the message, so only one message of
+//! a connection is read at a time but several may be handled at once.
+//!
+//! Workers are added while readable connections wait for one, up to a
+//! maximum, and the ones beyond the minimum leave once idle for a while.
+//! Once too many connections wait, the poller stops looking for more.
+
+use nix::fcntl::OFlag;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::sys::socket::{self, Shutdown};
+use nix::unistd::*;
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+use crate::common::set_fd_close_exec;
+use crate::common::{CloseReason, Closing, ConnectionHooks, ConnectionInfo};
+use crate::config::ServerConfig;
+use crate::error::{Error, Result};
+use crate::frame_limit::FrameCounter;
+use crate::proto::{MessageHeader, MESSAGE_HEADER_LENGTH};
+use crate::sync::channel::{close_fds, read_message_with_fds, write_message_with_fds};
+use crate::sync::interceptor::Interceptor;
+use crate::sync::raw::RawServices;
+use crate::sync::server::{flooding, handle_message, Methods, FLOODING};
+use crate::sync::threads::ThreadConfig;
+use crate::sync::utils::{Cancels, ResponseFds};
+use crate::MethodHandler;
+
+type Interceptors = Arc<Vec<Arc<dyn Interceptor>>>;
+
+// How long a worker beyond the minimum waits for a connection before leaving.
+const IDLE_WORKER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many workers a pool has, and how many connections may wait for one.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PoolLimits {
+    pub(crate) min_workers: usize,
+    pub(crate) max_workers: usize,
+    pub(crate) queue_length: usize,
+}
+
+enum Command {
+    Add(RawFd, Arc<ConnectionInfo>, Closing),
+    // The connection can be polled again.
+    Rearm(RawFd),
+    Remove(RawFd),
+    Quit,
+}
+
+// A connection served by the pool. The fd goes to the reaper once the
+// poller and all the workers are done with it.
+struct Conn {
+    fd: RawFd,
+    info: Arc<ConnectionInfo>,
+    res_tx: Mutex<Sender<(MessageHeader, Vec<u8>)>>,
+    // Also serializes the writes of responses.
+    res_rx: Mutex<Receiver<(MessageHeader, Vec<u8>)>>,
+    response_fds: ResponseFds,
+    cancels: Cancels,
+    closing: Closing,
+    hooks: ConnectionHooks,
+    frames: Mutex<FrameCounter>,
+    reaper_tx: Mutex<Sender<RawFd>>,
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        self.hooks.closed(&self.info, &self.closing.take());
+        self.reaper_tx
+            .lock()
+            .unwrap()
+            .send(self.fd)
+            .unwrap_or_else(|e| warn!("failed to reap fd {}: {}", self.fd, e));
+    }
+}
+
+/// Sends commands to the poller.
+#[derive(Clone)]
+pub(crate) struct Waker {
+    tx: Sender<Command>,
+    wake_fd: RawFd,
+}
+
+impl Waker {
+    fn send(&self, cmd: Command) {
+        if self.tx.send(cmd).is_err() {
+            // The poller is gone.
+            return;
+        }
+        // The pipe being full is fine, the poller is woken up already.
+        write(self.wake_fd, &[0]).ok();
+    }
+
+    /// Hands a new connection to the pool.
+    pub(crate) fn add(&self, fd: RawFd, info: Arc<ConnectionInfo>, closing: Closing) {
+        self.send(Command::Add(fd, info, closing));
+    }
+}
+
+// What the poller and the workers share.
+struct Shared {
+    tasks: Mutex<Receiver<Arc<Conn>>>,
+    waker: Waker,
+    methods: Methods,
+    interceptors: Interceptors,
+    fallback: Option<Arc<dyn MethodHandler + Send + Sync>>,
+    raw_services: RawServices,
+    config: Arc<ServerConfig>,
+    hooks: ConnectionHooks,
+    threads: ThreadConfig,
+    limits: PoolLimits,
+    // Workers running, and how many of them wait for a connection.
+    workers: AtomicUsize,
+    idle_workers: AtomicUsize,
+    // Connections handed to the workers, not taken yet.
+    queued: AtomicUsize,
+}
+
+// Starts a worker, which drops `done` as it exits.
+fn spawn_worker(shared: &Arc<Shared>, done: &Sender<()>) -> Result<()> {
+    let worker = shared.clone();
+    let done = done.clone();
+    shared.workers.fetch_add(1, Ordering::SeqCst);
+    shared
+        .threads
+        .spawn("pool_worker", move || {
+            work(&worker);
+            drop(done);
+        })
+        .map(drop)
+        .map_err(|e| {
+            shared.workers.fetch_sub(1, Ordering::SeqCst);
+            Error::Others(format!("failed to spawn pool worker: {}", e))
+        })
+}
+
+pub(crate) struct Pool {
+    waker: Waker,
+    wake_fds: (RawFd, RawFd),
+    poller: Option<JoinHandle<()>>,
+}
+
+impl Pool {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        limits: PoolLimits,
+        methods: Methods,
+        interceptors: Interceptors,
+        fallback: Option<Arc<dyn MethodHandler + Send + Sync>>,
+        raw_services: RawServices,
+        config: Arc<ServerConfig>,
+        hooks: ConnectionHooks,
+        threads: ThreadConfig,
+        reaper_tx: Sender<RawFd>,
+    ) -> Result<Pool> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let (rfd, wfd) = pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)?;
+
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let (rfd, wfd) = {
+            let (rfd, wfd) = pipe()?;
+            for &fd in &[rfd, wfd] {
+                set_fd_close_exec(fd)?;
+                fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+            }
+            (rfd, wfd)
+        };
+        let (tx, rx) = channel();
+        let waker = Waker { tx, wake_fd: wfd };
+
+        let (task_tx, task_rx) = channel();
+        let shared = Arc::new(Shared {
+            tasks: Mutex::new(task_rx),
+            waker: waker.clone(),
+            methods,
+            interceptors,
+            fallback,
+            raw_services,
+            config,
+            hooks,
+            threads,
+            limits,
+            workers: AtomicUsize::new(0),
+            idle_workers: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+        });
+        let (done_tx, done_rx) = channel();
+        for _ in 0..limits.min_workers {
+            spawn_worker(&shared, &done_tx)?;
+        }
+
+        let polled = shared.clone();
+        let poller = shared
+            .threads
+            .spawn("pool_poller", move || {
+                run_poller(rfd, rx, task_tx, reaper_tx, &polled, &done_tx);
+                // Let the workers finish what they have, the connections are
+                // reaped as they let go of them.
+                drop(done_tx);
+                done_rx.recv().unwrap_or(());
+                info!("pool poller exited");
+            })
+            .map_err(err_to_others_err!(e, "failed to spawn pool poller: "))?;
+
+        Ok(Pool {
+            waker,
+            wake_fds: (rfd, wfd),
+            poller: Some(poller),
+        })
+    }
+
+    pub(crate) fn waker(&self) -> Waker {
+        self.waker.clone()
+    }
+
+    /// Stops the pool once the workers are done with the messages they have.
+    pub(crate) fn shutdown(mut self) {
+        self.waker.send(Command::Quit);
+        if let Some(poller) = self.poller.take() {
+            poller.join().unwrap_or(());
+        }
+        close(self.wake_fds.0).unwrap_or(());
+        close(self.wake_fds.1).unwrap_or(());
+    }
+}
+
+fn run_poller(
+    wake_fd: RawFd,
+    rx: Receiver<Command>,
+    task_tx: Sender<Arc<Conn>>,
+    reaper_tx: Sender<RawFd>,
+    shared: &Arc<Shared>,
+    done: &Sender<()>,
+) {
+    let PoolLimits {
+        max_workers,
+        queue_length,
+        ..
+    } = shared.limits;
+    let idle_timeout = shared.config.idle_timeout;
+    let mut conns: HashMap<RawFd, Arc<Conn>> = HashMap::new();
+    // The connections waiting for a message, since when.
+    let mut idle: HashMap<RawFd, Instant> = HashMap::new();
+    let mut pollers = Vec::new();
+
+    loop {
+        pollers.clear();
+        pollers.push(libc::pollfd {
+            fd: wake_fd,
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        // The workers wake the poller up as they take connections.
+        let mut full = shared.queued.load(Ordering::SeqCst) >= queue_length;
+        if !full {
+            pollers.extend(idle.keys().map(|&fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            }));
+        }
+
+        // Wake up in time to close the first connection going idle.
+        let timeout = match idle_timeout {
+            Some(_) if full => -1,
+            Some(timeout) => idle
+                .values()
+                .min()
+                .map(|since| {
+                    let left = (*since + timeout).saturating_duration_since(Instant::now());
+                    (left.as_millis() + 1).min(libc::c_int::MAX as u128) as libc::c_int
+                })
+                .unwrap_or(-1),
+            None => -1,
+        };
+
+        let returned = unsafe {
+            let pollers: &mut [libc::pollfd] = &mut pollers;
+            libc::poll(
+                pollers as *mut _ as *mut libc::pollfd,
+                pollers.len() as _,
+                timeout,
+            )
+        };
+        if returned == -1 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EINTR) {
+                continue;
+            }
+            error!("fatal error in pool poller: {:?}", err);
+            break;
+        }
+
+        for p in &pollers[1..] {
+            if p.revents == 0 {
+                continue;
+            }
+            if shared.queued.load(Ordering::SeqCst) >= queue_length {
+                full = true;
+                break;
+            }
+            if let Some(conn) = conns.get(&p.fd) {
+                idle.remove(&p.fd);
+                let queued = shared.queued.fetch_add(1, Ordering::SeqCst) + 1;
+                shared.hooks.metrics.queue_depth(queued);
+                if task_tx.send(conn.clone()).is_err() {
+                    return;
+                }
+                if shared.idle_workers.load(Ordering::SeqCst) < queued
+                    && shared.workers.load(Ordering::SeqCst) < max_workers
+                {
+                    spawn_worker(shared, done).unwrap_or_else(|e| warn!("{}", e));
+                }
+            }
+        }
+
+        // Connections left waiting for a worker aren't idle.
+        if let (Some(timeout), false) = (idle_timeout, full) {
+            let now = Instant::now();
+            idle.retain(|fd, since| {
+                if now < *since + timeout {
+                    return true;
+                }
+                // Waiting on calls isn't idling, look again later.
+                if conns
+                    .get(fd)
+                    .is_some_and(|conn| Arc::strong_count(conn) > 1)
+                {
+                    *since = now;
+                    return true;
+                }
+                debug!("fd {} idle for {:?}, closing it", fd, timeout);
+                if let Some(conn) = conns.get(fd) {
+                    conn.closing.set(CloseReason::Idle);
+                }
+                socket::shutdown(*fd, Shutdown::Both).unwrap_or(());
+                conns.remove(fd);
+                false
+            });
+        }
+
+        if pollers[0].revents == 0 {
+            continue;
+        }
+        let mut buf = [0; 64];
+        while let Ok(n) = read(wake_fd, &mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+        for cmd in rx.try_iter() {
+            match cmd {
+                Command::Add(fd, info, closing) => {
+                    let (res_tx, res_rx) = channel();
+                    let conn = Conn {
+                        fd,
+                        info,
+                        res_tx: Mutex::new(res_tx),
+                        res_rx: Mutex::new(res_rx),
+                        response_fds: ResponseFds::default(),
+                        cancels: Cancels::default(),
+                        closing,
+                        hooks: shared.hooks.clone(),
+                        frames: Mutex::new(FrameCounter::new(shared.config.control_frame_limit)),
+                        reaper_tx: Mutex::new(reaper_tx.clone()),
+                    };
+                    conns.insert(fd, Arc::new(conn));
+                    idle.insert(fd, Instant::now());
+                }
+                Command::Rearm(fd) => {
+                    if conns.contains_key(&fd) {
+                        idle.insert(fd, Instant::now());
+                    }
+                }
+                Command::Remove(fd) => {
+                    conns.remove(&fd);
+                    idle.remove(&fd);
+                }
+                Command::Quit => {
+                    for conn in conns.values() {
+                        conn.closing.set(CloseReason::Shutdown);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn work(shared: &Shared) {
+    let waker = &shared.waker;
+    loop {
+        shared.idle_workers.fetch_add(1, Ordering::SeqCst);
+        let task = shared
+            .tasks
+            .lock()
+            .unwrap()
+            .recv_timeout(IDLE_WORKER_TIMEOUT);
+        shared.idle_workers.fetch_sub(1, Ordering::SeqCst);
+        let conn = match task {
+            Ok(conn) => conn,
+            Err(RecvTimeoutError::Timeout) => {
+                // Leave if there are more workers than the minimum.
+                let min = shared.limits.min_workers;
+                let left = shared
+                    .workers
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                        (n > min).then(|| n - 1)
+                    });
+                if left.is_ok() {
+                    break;
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let queued = shared.queued.fetch_sub(1, Ordering::SeqCst) - 1;
+        shared.hooks.metrics.queue_depth(queued);
+        let fd = conn.fd;
+
+        let (mh, buf, fds) = match read_message_with_fds(fd, shared.config.max_message_size) {
+            Ok(x) => {
+                shared
+                    .hooks
+                    .received(&conn.info, MESSAGE_HEADER_LENGTH + x.0.length as usize);
+                x
+            }
+            // A socket error, or a frame header that leaves the rest of the
+            // stream unreadable.
+            Err(e) => {
+                trace!("Read error {:?}", e);
+                conn.closing.set(CloseReason::from_error(&e));
+                conn.cancels.cancel_all();
+                waker.send(Command::Remove(fd));
+                continue;
+            }
+        };
+        if flooding(&conn.frames, fd, &mh) {
+            close_fds(&fds);
+            conn.closing.set(CloseReason::Error(FLOODING.to_string()));
+            // Let the client know, the poller will see the connection close.
+            socket::shutdown(fd, Shutdown::Both).unwrap_or(());
+            conn.cancels.cancel_all();
+            waker.send(Command::Remove(fd));
+            continue;
+        }
+        conn.cancels.received(&mh);
+        // Serial connections are only polled again once the request is done.
+        if !shared.config.serial_requests {
+            waker.send(Command::Rearm(fd));
+        }
+
+        let res_tx = conn.res_tx.lock().unwrap().clone();
+        let res = handle_message(
+            fd,
+            &conn.info,
+            &shared.methods,
+            &shared.interceptors,
+            shared.fallback.as_deref(),
+            &shared.raw_services,
+            &res_tx,
+            &conn.response_fds,
+            &conn.cancels,
+            mh,
+            buf,
+            fds,
+        );
+        let mut ok = match res {
+            Ok(()) => true,
+            Err(e) => {
+                conn.closing.set(CloseReason::from_error(&e));
+                false
+            }
+        };
+        let res_rx = conn.res_rx.lock().unwrap();
+        for (mh, buf) in res_rx.try_iter() {
+            let fds = conn.response_fds.take(mh.stream_id);
+            let size = MESSAGE_HEADER_LENGTH + buf.len();
+            let res = write_message_with_fds(fd, mh, buf, &fds);
+            close_fds(&fds);
+            if let Err(e) = res {
+                error!("write_message got {:?}", e);
+                conn.closing.set(CloseReason::from_error(&e));
+                ok = false;
+                break;
+            }
+            shared.hooks.sent(&conn.info, size);
+        }
+        if !ok {
+            // Let the client know, the poller will see the connection close.
+            socket::shutdown(fd, Shutdown::Both).unwrap_or(());
+            conn.cancels.cancel_all();
+            waker.send(Command::Remove(fd));
+        } else if shared.config.serial_requests {
+            waker.send(Command::Rearm(fd));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::get_status;
+    use crate::proto::{Code, Codec, Request, Response, MESSAGE_LENGTH_MAX, MESSAGE_TYPE_DATA};
+    use crate::sync::channel::{read_message, write_message};
+    use crate::sync::utils::response_to_channel;
+    use crate::TtrpcContext;
+    use nix::sys::socket::{socketpair, AddressFamily, SockFlag, SockType};
+    use std::sync::RwLock;
+    use std::thread;
+    use std::time::Duration;
+
+    struct Echo;
+
+    impl MethodHandler for Echo {
+        fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<()> {
+            let mut res = Response::new();
+            res.payload = req.payload;
+            response_to_channel(ctx.mh.stream_id, res, ctx.res_tx)
+        }
+    }
+
+    // Holds the call until it's cancelled.
+    struct Hold;
+
+    impl MethodHandler for Hold {
+        fn handler(&self, ctx: TtrpcContext, _req: Request) -> Result<()> {
+            while !ctx.cancel.is_cancelled() {
+                thread::sleep(Duration::from_millis(10));
+            }
+            let mut res = Response::new();
+            res.set_status(get_status(Code::CANCELLED, "cancelled"));
+            response_to_channel(ctx.mh.stream_id, res, ctx.res_tx)
+        }
+    }
+
+    struct Panic;
+
+    impl MethodHandler for Panic {
+        fn handler(&self, _ctx: TtrpcContext, _req: Request) -> Result<()> {
+            panic!("oops");
+        }
+    }
+
+    #[test]
+    fn test_pool() {
+        let mut methods: HashMap<String, Arc<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert("/test.Echo/Echo".to_string(), Arc::new(Echo));
+        let (reaper_tx, reaper_rx) = channel();
+        // Workers are started as the requests come.
+        let limits = PoolLimits {
+            min_workers: 0,
+            max_workers: 2,
+            queue_length: 4,
+        };
+        let pool = Pool::new(
+            limits,
+            Arc::new(RwLock::new(methods)),
+            Arc::new(Vec::new()),
+            Some(Arc::new(Echo)),
+            RawServices::default(),
+            Arc::default(),
+            ConnectionHooks::default(),
+            ThreadConfig::default(),
+            reaper_tx,
+        )
+        .unwrap();
+
+        let (server_fd, client_fd) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        pool.waker().add(
+            server_fd,
+            Arc::new(ConnectionInfo::new(server_fd)),
+            Closing::default(),
+        );
+
+        // The unknown service goes to the fallback handler.
+        for (stream_id, service) in [(1, "test.Echo"), (3, "test.Unknown")] {
+            let req = Request {
+                service: service.to_string(),
+                method: "Echo".to_string(),
+                payload: vec![stream_id as u8],
+                ..Default::default()
+            };
+            let buf = req.encode().unwrap();
+            let mh = MessageHeader::new_request(stream_id, buf.len() as u32);
+            write_message(client_fd, mh, buf).unwrap();
+
+            let (mh, buf) = read_message(client_fd, MESSAGE_LENGTH_MAX).unwrap();
+            assert_eq!(mh.stream_id, stream_id);
+            let res = Response::decode(buf.unwrap()).unwrap();
+            assert_eq!(res.payload, vec![stream_id as u8]);
+        }
+
+        // The connection is reaped once the client goes away.
+        close(client_fd).unwrap();
+        assert_eq!(reaper_rx.recv().unwrap(), server_fd);
+        close(server_fd).unwrap();
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_pool_cancel() {
+        let mut methods: HashMap<String, Arc<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert("/test.Echo/Hold".to_string(), Arc::new(Hold));
+        let (reaper_tx, reaper_rx) = channel();
+        // One worker holds the call, the other reads the cancellation.
+        let limits = PoolLimits {
+            min_workers: 2,
+            max_workers: 2,
+            queue_length: 2,
+        };
+        let pool = Pool::new(
+            limits,
+            Arc::new(RwLock::new(methods)),
+            Arc::new(Vec::new()),
+            None,
+            RawServices::default(),
+            Arc::default(),
+            ConnectionHooks::default(),
+            ThreadConfig::default(),
+            reaper_tx,
+        )
+        .unwrap();
+
+        let (server_fd, client_fd) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        pool.waker().add(
+            server_fd,
+            Arc::new(ConnectionInfo::new(server_fd)),
+            Closing::default(),
+        );
+
+        let req = Request {
+            service: "test.Echo".to_string(),
+            method: "Hold".to_string(),
+            ..Default::default()
+        };
+        let buf = req.encode().unwrap();
+        write_message(
+            client_fd,
+            MessageHeader::new_request(1, buf.len() as u32),
+            buf,
+        )
+        .unwrap();
+        write_message(client_fd, MessageHeader::new_cancel(1), Vec::new()).unwrap();
+
+        let (mh, buf) = read_message(client_fd, MESSAGE_LENGTH_MAX).unwrap();
+        assert_eq!(mh.stream_id, 1);
+        let res = Response::decode(buf.unwrap()).unwrap();
+        assert_eq!(res.status().code(), Code::CANCELLED);
+
+        close(client_fd).unwrap();
+        assert_eq!(reaper_rx.recv().unwrap(), server_fd);
+        close(server_fd).unwrap();
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_pool_handler_panic() {
+        let mut methods: HashMap<String, Arc<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert("/test.Echo/Echo".to_string(), Arc::new(Echo));
+        methods.insert("/test.Echo/Panic".to_string(), Arc::new(Panic));
+        let (reaper_tx, reaper_rx) = channel();
+        let limits = PoolLimits {
+            min_workers: 1,
+            max_workers: 1,
+            queue_length: 1,
+        };
+        let pool = Pool::new(
+            limits,
+            Arc::new(RwLock::new(methods)),
+            Arc::new(Vec::new()),
+            None,
+            RawServices::default(),
+            Arc::default(),
+            ConnectionHooks::default(),
+            ThreadConfig::default(),
+            reaper_tx,
+        )
+        .unwrap();
+
+        let (server_fd, client_fd) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        pool.waker().add(
+            server_fd,
+            Arc::new(ConnectionInfo::new(server_fd)),
+            Closing::default(),
+        );
+
+        // The call fails, the connection and the worker go on.
+        for (stream_id, method) in [(1, "Panic"), (3, "Echo")] {
+            let req = Request {
+                service: "test.Echo".to_string(),
+                method: method.to_string(),
+                ..Default::default()
+            };
+            let buf = req.encode().unwrap();
+            let mh = MessageHeader::new_request(stream_id, buf.len() as u32);
+            write_message(client_fd, mh, buf).unwrap();
+
+            let (mh, buf) = read_message(client_fd, MESSAGE_LENGTH_MAX).unwrap();
+            assert_eq!(mh.stream_id, stream_id);
+            let res = Response::decode(buf.unwrap()).unwrap();
+            let code = if method == "Panic" {
+                Code::INTERNAL
+            } else {
+                Code::OK
+            };
+            assert_eq!(res.status().code(), code);
+        }
+
+        close(client_fd).unwrap();
+        assert_eq!(reaper_rx.recv().unwrap(), server_fd);
+        close(server_fd).unwrap();
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_pool_idle_timeout() {
+        let (reaper_tx, reaper_rx) = channel();
+        let limits = PoolLimits {
+            min_workers: 1,
+            max_workers: 1,
+            queue_length: 1,
+        };
+        let (closed_tx, closed_rx) = channel();
+        let closed_tx = Mutex::new(closed_tx);
+        let hooks = ConnectionHooks {
+            opened: None,
+            closed: Some(Arc::new(move |_: &ConnectionInfo, reason: &CloseReason| {
+                closed_tx.lock().unwrap().send(reason.clone()).unwrap();
+            })),
+            ..Default::default()
+        };
+        let pool = Pool::new(
+            limits,
+            Methods::default(),
+            Arc::new(Vec::new()),
+            None,
+            RawServices::default(),
+            Arc::new(ServerConfig::new().set_idle_timeout(Duration::from_millis(100))),
+            hooks,
+            ThreadConfig::default(),
+            reaper_tx,
+        )
+        .unwrap();
+
+        let (server_fd, client_fd) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        pool.waker().add(
+            server_fd,
+            Arc::new(ConnectionInfo::new(server_fd)),
+            Closing::default(),
+        );
+
+        // The client keeps the connection open, but sends nothing.
+        let reaped = reaper_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(reaped, server_fd);
+        assert_eq!(closed_rx.recv().unwrap(), CloseReason::Idle);
+        assert_eq!(read(client_fd, &mut [0; 1]).unwrap(), 0);
+        close(server_fd).unwrap();
+        close(client_fd).unwrap();
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_pool_control_frame_limit() {
+        let (reaper_tx, reaper_rx) = channel();
+        let config = ServerConfig::new().set_control_frame_limit(2, Duration::from_secs(60));
+        let limits = PoolLimits {
+            min_workers: 1,
+            max_workers: 1,
+            queue_length: 1,
+        };
+        let (closed_tx, closed_rx) = channel();
+        let closed_tx = Mutex::new(closed_tx);
+        let hooks = ConnectionHooks {
+            opened: None,
+            closed: Some(Arc::new(move |_: &ConnectionInfo, reason: &CloseReason| {
+                closed_tx.lock().unwrap().send(reason.clone()).unwrap();
+            })),
+            ..Default::default()
+        };
+        let pool = Pool::new(
+            limits,
+            Methods::default(),
+            Arc::new(Vec::new()),
+            None,
+            RawServices::default(),
+            Arc::new(config),
+            hooks,
+            ThreadConfig::default(),
+            reaper_tx,
+        )
+        .unwrap();
+
+        let (server_fd, client_fd) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        pool.waker().add(
+            server_fd,
+            Arc::new(ConnectionInfo::new(server_fd)),
+            Closing::default(),
+        );
+
+        // Data frames for streams the server doesn't know, the third one is
+        // too many.
+        for stream_id in [1, 3, 5] {
+            let mh = MessageHeader {
+                length: 0,
+                stream_id,
+                type_: MESSAGE_TYPE_DATA,
+                flags: 0,
+            };
+            write_message(client_fd, mh, Vec::new()).unwrap();
+        }
+        let reaped = reaper_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(reaped, server_fd);
+        assert_eq!(
+            closed_rx.recv().unwrap(),
+            CloseReason::Error(FLOODING.to_string())
+        );
+        close(server_fd).unwrap();
+        close(client_fd).unwrap();
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_pool_invalid_header() {
+        let (reaper_tx, reaper_rx) = channel();
+        let limits = PoolLimits {
+            min_workers: 1,
+            max_workers: 1,
+            queue_length: 1,
+        };
+        let (closed_tx, closed_rx) = channel();
+        let closed_tx = Mutex::new(closed_tx);
+        let hooks = ConnectionHooks {
+            opened: None,
+            closed: Some(Arc::new(move |_: &ConnectionInfo, reason: &CloseReason| {
+                closed_tx.lock().unwrap().send(reason.clone()).unwrap();
+            })),
+            ..Default::default()
+        };
+        let pool = Pool::new(
+            limits,
+            Methods::default(),
+            Arc::new(Vec::new()),
+            None,
+            RawServices::default(),
+            Arc::default(),
+            hooks,
+            ThreadConfig::default(),
+            reaper_tx,
+        )
+        .unwrap();
+
+        let (server_fd, client_fd) = socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap();
+        pool.waker().add(
+            server_fd,
+            Arc::new(ConnectionInfo::new(server_fd)),
+            Closing::default(),
+        );
+
+        // Requests go on odd stream ids, the frames that follow can't be
+        // trusted any more.
+        write_message(client_fd, MessageHeader::new_request(2, 0), Vec::new()).unwrap();
+        let reaped = reaper_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(reaped, server_fd);
+        assert!(matches!(closed_rx.recv().unwrap(), CloseReason::Error(_)));
+        close(server_fd).unwrap();
+        close(client_fd).unwrap();
+        pool.shutdown();
+    }
+}
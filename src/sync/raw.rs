@@ -0,0 +1,92 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Services handling the calls to any method under a prefix, as bytes.
+
+use std::cmp::Reverse;
+use std::sync::Arc;
+
+use crate::error::Result;
+use crate::proto::Request;
+use crate::service_fn::{self, Raw};
+use crate::sync::utils::{MethodHandler, TtrpcContext};
+
+/// Handles the calls to the methods of the services under a prefix, see
+/// [`Server::register_raw_service()`](crate::sync::Server::register_raw_service).
+///
+/// It gets the service, method and payload of a call as they are, and
+/// answers with the payload of the response, so proxies and protocol
+/// translators need not know the methods they serve. Failing with
+/// [`Error::RpcStatus`](crate::Error::RpcStatus) answers the client with
+/// that status.
+pub trait RawService: Send + Sync {
+    fn call(
+        &self,
+        ctx: &TtrpcContext,
+        service: &str,
+        method: &str,
+        payload: Vec<u8>,
+    ) -> Result<Vec<u8>>;
+}
+
+struct RawHandler<S>(S);
+
+impl<S: RawService> MethodHandler for RawHandler<S> {
+    fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<()> {
+        let rep = self.0.call(&ctx, &req.service, &req.method, req.payload);
+        service_fn::respond(&Raw, ctx, rep)
+    }
+}
+
+/// The raw services of a server, by prefix.
+#[derive(Clone, Default)]
+pub(crate) struct RawServices(Arc<Vec<(String, Arc<dyn MethodHandler + Send + Sync>)>>);
+
+impl RawServices {
+    pub(crate) fn add(&mut self, prefix: &str, service: impl RawService + 'static) {
+        let services = Arc::get_mut(&mut self.0).unwrap();
+        services.push((prefix.to_string(), Arc::new(RawHandler(service))));
+        // The longest prefix matching a service wins.
+        services.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+    }
+
+    /// Returns the handler of the calls to `service`, if under a prefix.
+    pub(crate) fn get(&self, service: &str) -> Option<&(dyn MethodHandler + Send + Sync)> {
+        self.0
+            .iter()
+            .find(|(prefix, _)| service.starts_with(prefix.as_str()))
+            .map(|(_, handler)| handler.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Named(&'static str);
+
+    impl RawService for Named {
+        fn call(&self, _: &TtrpcContext, _: &str, _: &str, _: Vec<u8>) -> Result<Vec<u8>> {
+            Ok(self.0.as_bytes().to_vec())
+        }
+    }
+
+    #[test]
+    fn test_raw_services_prefix() {
+        let mut services = RawServices::default();
+        services.add("containerd.", Named("containerd"));
+        services.add("containerd.services.tasks.", Named("tasks"));
+
+        assert!(services.get("grpc.health.v1.Health").is_none());
+        assert!(services.get("containerd.services.tasks.v1.Tasks").is_some());
+        assert!(services
+            .get("containerd.services.images.v1.Images")
+            .is_some());
+        assert_eq!(
+            services.0[0].0, "containerd.services.tasks.",
+            "longest prefix first"
+        );
+    }
+}
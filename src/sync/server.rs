@@ -19,54 +19,133 @@ use nix::unistd::*;
 use protobuf::{CodedInputStream, Message};
 use std::collections::HashMap;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock, Weak};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
 use std::{io, thread};
 
 use super::utils::response_to_channel;
-use crate::common;
+use crate::channelz::{Channelz, InFlightCalls};
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
 use crate::common::set_fd_close_exec;
+use crate::common::{
+    self, CloseReason, Closing, ConnectionHooks, ConnectionInfo, ConnectionLimit, PeerCredentials,
+};
+use crate::config::{ServerConfig, ServerKind};
 use crate::context;
 use crate::error::{get_status, Error, Result};
-use crate::proto::{Code, MessageHeader, Request, Response, MESSAGE_TYPE_REQUEST};
-use crate::sync::channel::{read_message, write_message};
+use crate::frame_limit::{FrameCounter, FrameLimit};
+use crate::metrics::{CallMetrics, Metrics, MetricsSink};
+use crate::proto::{
+    Code, Codec, MessageHeader, Request, Response, CANCEL_KEY, MESSAGE_HEADER_LENGTH,
+    MESSAGE_TYPE_CANCEL, MESSAGE_TYPE_REQUEST,
+};
+use crate::reflection;
+use crate::restart::ListenerState;
+use crate::spans::{self, Kind};
+use crate::stats::{self, Counter};
+use crate::sync::channel::{
+    close_fds, read_message_with_fds, set_io_timeouts, write_message_with_fds, Body,
+};
+use crate::sync::interceptor::{Authorize, ConcurrencyLimit, Interceptor, Next};
+use crate::sync::pool::{Pool, PoolLimits};
+use crate::sync::raw::{RawService, RawServices};
+use crate::sync::threads::ThreadConfig;
+use crate::sync::utils::{Cancels, ResponseFds};
 use crate::{MethodHandler, TtrpcContext};
 
-// poll_queue will create WAIT_THREAD_COUNT_DEFAULT threads in begin.
-// If wait thread count < WAIT_THREAD_COUNT_MIN, create number to WAIT_THREAD_COUNT_DEFAULT.
-// If wait thread count > WAIT_THREAD_COUNT_MAX, wait thread will quit to WAIT_THREAD_COUNT_DEFAULT.
-const DEFAULT_WAIT_THREAD_COUNT_DEFAULT: usize = 3;
-const DEFAULT_WAIT_THREAD_COUNT_MIN: usize = 1;
-const DEFAULT_WAIT_THREAD_COUNT_MAX: usize = 5;
+// How often a listener over its connection limit checks for room.
+const CONNECTION_LIMIT_POLL_MS: libc::c_int = 100;
 
 type MessageSender = Sender<(MessageHeader, Vec<u8>)>;
 type MessageReceiver = Receiver<(MessageHeader, Vec<u8>)>;
+// The methods of a server by path, which may change while it's running.
+// Calls hold on to the method they were dispatched to.
+pub(crate) type Methods = Arc<RwLock<HashMap<String, Arc<dyn MethodHandler + Send + Sync>>>>;
+
+// Answers reflection::LIST_SERVICES with the services of the server.
+struct ListServices(Weak<RwLock<HashMap<String, Arc<dyn MethodHandler + Send + Sync>>>>);
+
+impl MethodHandler for ListServices {
+    fn handler(&self, ctx: TtrpcContext, _req: Request) -> Result<()> {
+        let methods = self
+            .0
+            .upgrade()
+            .ok_or_else(|| Error::Others("the server is gone".to_string()))?;
+        let list = {
+            let methods = methods.read().unwrap();
+            // Paths are /service/method, sync servers have no streams.
+            reflection::list_services(methods.keys().filter_map(|path| {
+                let (service, method) = path.strip_prefix('/')?.split_once('/')?;
+                Some((service, method, false))
+            }))
+        };
+        let mut res = Response::new();
+        res.set_status(get_status(Code::OK, ""));
+        res.payload = list
+            .encode()
+            .map_err(err_to_others_err!(e, "Encode ListServicesResponse failed."))?;
+        response_to_channel(ctx.mh.stream_id, res, ctx.res_tx)
+    }
+}
+
+/// How a sync [`Server`] spreads its connections over threads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadingMode {
+    /// Every connection gets threads of its own, see
+    /// [`Server::set_thread_count_default()`] and friends. The default.
+    PerConnection,
+    /// All connections share from `min_workers` up to `max_workers`
+    /// threads, plus one polling them for requests. Suits many mostly idle
+    /// connections.
+    ///
+    /// Up to `queue_length` connections with a request wait for a worker,
+    /// no more requests are read while that many wait. The workers beyond
+    /// the minimum leave once idle for a while.
+    SharedPool {
+        min_workers: usize,
+        max_workers: usize,
+        queue_length: usize,
+    },
+}
 
 /// A ttrpc Server (sync).
 pub struct Server {
     listeners: Vec<RawFd>,
     monitor_fd: (RawFd, RawFd),
     listener_quit_flag: Arc<AtomicBool>,
+    // Whether the listener takes the connections already waiting once told to quit.
+    listener_drain_flag: Arc<AtomicBool>,
     connections: Arc<Mutex<HashMap<RawFd, Connection>>>,
-    methods: Arc<HashMap<String, Box<dyn MethodHandler + Send + Sync>>>,
+    methods: Methods,
+    interceptors: Arc<Vec<Arc<dyn Interceptor>>>,
+    fallback: Option<Arc<dyn MethodHandler + Send + Sync>>,
+    raw_services: RawServices,
     handler: Option<JoinHandle<()>>,
     reaper: Option<(Sender<i32>, JoinHandle<()>)>,
-    thread_count_default: usize,
-    thread_count_min: usize,
-    thread_count_max: usize,
+    config: ServerConfig,
+    threading: ThreadingMode,
+    listener_threads: ThreadConfig,
+    worker_threads: ThreadConfig,
+    pool: Option<Pool>,
+    hooks: ConnectionHooks,
+    // Started, but not accepting connections for now.
+    accept_paused: bool,
 }
 
 struct Connection {
     fd: RawFd,
     quit: Arc<AtomicBool>,
+    closing: Closing,
     handler: Option<JoinHandle<()>>,
 }
 
 impl Connection {
     fn close(&self) {
+        self.closing.set(CloseReason::Shutdown);
         self.quit.store(true, Ordering::SeqCst);
         // in case the connection had closed
         socket::shutdown(self.fd, Shutdown::Read).unwrap_or(());
@@ -75,30 +154,208 @@ impl Connection {
 
 struct ThreadS<'a> {
     fd: RawFd,
+    info: Arc<ConnectionInfo>,
     fdlock: &'a Arc<Mutex<()>>,
     wtc: &'a Arc<AtomicUsize>,
     quit: &'a Arc<AtomicBool>,
-    methods: &'a Arc<HashMap<String, Box<dyn MethodHandler + Send + Sync>>>,
+    methods: &'a Methods,
+    interceptors: &'a Arc<Vec<Arc<dyn Interceptor>>>,
+    fallback: &'a Option<Arc<dyn MethodHandler + Send + Sync>>,
+    raw_services: &'a RawServices,
     res_tx: &'a MessageSender,
+    response_fds: &'a ResponseFds,
+    cancels: &'a Cancels,
+    closing: &'a Closing,
+    hooks: &'a ConnectionHooks,
+    frames: &'a Arc<Mutex<FrameCounter>>,
     control_tx: &'a SyncSender<()>,
+    // How many calls are being handled.
+    busy: &'a Arc<AtomicUsize>,
     default: usize,
     min: usize,
     max: usize,
+    config: &'a Arc<ServerConfig>,
+    threads: &'a ThreadConfig,
+}
+
+// Why a connection going over its control frame limit is closed.
+pub(crate) const FLOODING: &str = "too many control frames";
+
+// Counts a frame of connection `fd` against its control frame limit, true
+// once it's over. There are no streams, so every frame but a request counts.
+pub(crate) fn flooding(frames: &Mutex<FrameCounter>, fd: RawFd, mh: &MessageHeader) -> bool {
+    if mh.type_ == MESSAGE_TYPE_REQUEST || frames.lock().unwrap().hit(Instant::now()) {
+        return false;
+    }
+    warn!("fd {} sent too many control frames, disconnecting it", fd);
+    true
+}
+
+// Handles a message read off connection `fd`, an error means the connection
+// should be closed.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn handle_message(
+    fd: RawFd,
+    info: &Arc<ConnectionInfo>,
+    methods: &RwLock<HashMap<String, Arc<dyn MethodHandler + Send + Sync>>>,
+    interceptors: &[Arc<dyn Interceptor>],
+    fallback: Option<&(dyn MethodHandler + Send + Sync)>,
+    raw_services: &RawServices,
+    res_tx: &MessageSender,
+    response_fds: &ResponseFds,
+    cancels: &Cancels,
+    mh: MessageHeader,
+    buf: Body,
+    fds: Vec<RawFd>,
+) -> Result<()> {
+    if mh.type_ == MESSAGE_TYPE_CANCEL {
+        cancels.cancel(mh.stream_id);
+    }
+    if mh.type_ != MESSAGE_TYPE_REQUEST {
+        close_fds(&fds);
+        return Ok(());
+    }
+    // Whether the request is one-way is only known once it's decoded.
+    let respond_status = |status, one_way: bool| {
+        cancels.done(mh.stream_id);
+        close_fds(&fds);
+        if one_way {
+            debug!(
+                "Stream id {}: one-way request failed: {:?}",
+                mh.stream_id, status
+            );
+            return Ok(());
+        }
+        let mut res = Response::new();
+        res.set_status(status);
+        response_to_channel(mh.stream_id, res, res_tx.clone()).map_err(|x| {
+            debug!("response_to_channel get error {:?}", x);
+            x
+        })
+    };
+
+    let buf = match buf {
+        Ok(buf) => buf,
+        Err(status) => return respond_status(status, false),
+    };
+    let mut s = CodedInputStream::from_bytes(&buf);
+    let mut req = Request::new();
+    if let Err(x) = req.merge_from(&mut s) {
+        return respond_status(get_status(Code::INVALID_ARGUMENT, x.to_string()), false);
+    }
+    let one_way = common::is_one_way(&mh, &req);
+    let received = Instant::now();
+    trace!("Got Message request {:?}", req);
+    let span = spans::call(
+        Kind::Server,
+        &req.service,
+        &req.method,
+        Some(mh.stream_id),
+        Some(info.as_ref()),
+    );
+    let _entered = span.enter();
+
+    if common::is_probe(&req) {
+        cancels.done(mh.stream_id);
+        close_fds(&fds);
+        if one_way {
+            return Ok(());
+        }
+        return response_to_channel(mh.stream_id, Response::new(), res_tx.clone());
+    }
+
+    let path = format!("/{}/{}", req.service, req.method);
+    let method = methods.read().unwrap().get(&path).cloned();
+    let method = method.as_deref().or_else(|| raw_services.get(&req.service));
+    let method = match method.or(fallback) {
+        Some(x) => x,
+        None => {
+            return respond_status(
+                get_status(Code::INVALID_ARGUMENT, format!("{} does not exist", path)),
+                one_way,
+            )
+        }
+    };
+    // The response of a one-way request goes nowhere.
+    let (discard_tx, _discard_rx) = channel();
+    let deadline = common::get_deadline(received, req.timeout_nano);
+    let ctx = TtrpcContext {
+        fd,
+        mh,
+        res_tx: if one_way { discard_tx } else { res_tx.clone() },
+        metadata: context::from_pb(&req.metadata),
+        timeout_nano: req.timeout_nano,
+        peer_cred: info.peer_cred,
+        connection: info.clone(),
+        received: SystemTime::now(),
+        response_metadata: Default::default(),
+        deadline,
+        fds,
+        response_fds: response_fds.clone(),
+        cancel: cancels.start(mh.stream_id, deadline),
+    };
+    if common::has_cancel_key(&req.metadata) {
+        ctx.response_metadata
+            .add(CANCEL_KEY.to_string(), "1".to_string());
+    }
+    // A panicking handler only fails its call.
+    let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+        Next::new(interceptors, method).run(ctx, req)
+    }));
+    cancels.done(mh.stream_id);
+    let handled =
+        handled.unwrap_or_else(|panic| Err(Error::RpcStatus(common::panic_status(&path, panic))));
+    let res = match handled {
+        Err(Error::RpcStatus(status)) if !one_way => {
+            let mut res = Response::new();
+            res.set_status(status);
+            response_to_channel(mh.stream_id, res, res_tx.clone())
+        }
+        Err(Error::RpcStatus(status)) => {
+            debug!(
+                "Stream id {}: one-way request failed: {:?}",
+                mh.stream_id, status
+            );
+            Ok(())
+        }
+        res => res.map_err(|x| {
+            debug!("method handle {} get error {:?}", path, x);
+            x
+        }),
+    };
+    if one_way {
+        close_fds(&response_fds.take(mh.stream_id));
+    }
+    res
 }
 
 #[allow(clippy::too_many_arguments)]
 fn start_method_handler_thread(
     fd: RawFd,
+    info: Arc<ConnectionInfo>,
     fdlock: Arc<Mutex<()>>,
     wtc: Arc<AtomicUsize>,
     quit: Arc<AtomicBool>,
-    methods: Arc<HashMap<String, Box<dyn MethodHandler + Send + Sync>>>,
+    methods: Methods,
+    interceptors: Arc<Vec<Arc<dyn Interceptor>>>,
+    fallback: Option<Arc<dyn MethodHandler + Send + Sync>>,
+    raw_services: RawServices,
     res_tx: MessageSender,
+    response_fds: ResponseFds,
+    cancels: Cancels,
+    closing: Closing,
+    hooks: ConnectionHooks,
+    frames: Arc<Mutex<FrameCounter>>,
     control_tx: SyncSender<()>,
+    busy: Arc<AtomicUsize>,
     min: usize,
     max: usize,
+    config: Arc<ServerConfig>,
+    threads: &ThreadConfig,
 ) {
-    thread::spawn(move || {
+    let (failed_wtc, failed_busy) = (wtc.clone(), busy.clone());
+    let (failed_quit, failed_closing) = (quit.clone(), closing.clone());
+    let spawned = threads.spawn("method_handler", move || {
         while !quit.load(Ordering::SeqCst) {
             let c = wtc.fetch_add(1, Ordering::SeqCst) + 1;
             if c > max {
@@ -116,7 +373,18 @@ fn start_method_handler_thread(
                         .unwrap_or_else(|err| trace!("Failed to send {:?}", err));
                     break;
                 }
-                result = read_message(fd);
+                if let Some(timeout) = config.idle_timeout {
+                    if !wait_message(fd, timeout, &busy) {
+                        debug!("fd {} idle for {:?}, closing it", fd, timeout);
+                        closing.set(CloseReason::Idle);
+                        // The read below sees the connection close.
+                        socket::shutdown(fd, Shutdown::Both).unwrap_or(());
+                    }
+                }
+                result = read_message_with_fds(fd, config.max_message_size);
+                if let Ok((mh, _, _)) = result.as_ref() {
+                    cancels.received(mh);
+                }
             }
 
             if quit.load(Ordering::SeqCst) {
@@ -135,86 +403,57 @@ fn start_method_handler_thread(
                     .unwrap_or_else(|err| trace!("Failed to send {:?}", err));
             }
 
-            let mh;
-            let buf;
-            match result {
-                Ok((x, y)) => {
-                    mh = x;
-                    buf = y;
+            let (mh, buf, fds) = match result {
+                Ok(x) => {
+                    hooks.received(&info, MESSAGE_HEADER_LENGTH + x.0.length as usize);
+                    x
                 }
-                Err(x) => match x {
-                    Error::Socket(y) => {
-                        trace!("Socket error {}", y);
-                        quit.store(true, Ordering::SeqCst);
-                        // the client connection would be closed and
-                        // the connection dealing main thread would
-                        // have exited.
-                        control_tx
-                            .send(())
-                            .unwrap_or_else(|err| trace!("Failed to send {:?}", err));
-                        trace!("Socket error send control_tx");
-                        break;
-                    }
-                    _ => {
-                        trace!("Others error {:?}", x);
-                        continue;
-                    }
-                },
-            }
-
-            if mh.type_ != MESSAGE_TYPE_REQUEST {
-                continue;
-            }
-            let mut s = CodedInputStream::from_bytes(&buf);
-            let mut req = Request::new();
-            if let Err(x) = req.merge_from(&mut s) {
-                let status = get_status(Code::INVALID_ARGUMENT, x.to_string());
-                let mut res = Response::new();
-                res.set_status(status);
-                if let Err(x) = response_to_channel(mh.stream_id, res, res_tx.clone()) {
-                    debug!("response_to_channel get error {:?}", x);
+                // A socket error, or a frame header that leaves the rest of
+                // the stream unreadable.
+                Err(e) => {
+                    trace!("Read error {:?}", e);
+                    closing.set(CloseReason::from_error(&e));
                     quit.store(true, Ordering::SeqCst);
                     // the client connection would be closed and
-                    // the connection dealing main thread would have
-                    // exited.
+                    // the connection dealing main thread would
+                    // have exited.
                     control_tx
                         .send(())
                         .unwrap_or_else(|err| trace!("Failed to send {:?}", err));
+                    trace!("Read error send control_tx");
                     break;
                 }
-                continue;
-            }
-            trace!("Got Message request {:?}", req);
-
-            let path = format!("/{}/{}", req.service, req.method);
-            let method = if let Some(x) = methods.get(&path) {
-                x
-            } else {
-                let status = get_status(Code::INVALID_ARGUMENT, format!("{} does not exist", path));
-                let mut res = Response::new();
-                res.set_status(status);
-                if let Err(x) = response_to_channel(mh.stream_id, res, res_tx.clone()) {
-                    info!("response_to_channel get error {:?}", x);
-                    quit.store(true, Ordering::SeqCst);
-                    // the client connection would be closed and
-                    // the connection dealing main thread would have
-                    // exited.
-                    control_tx
-                        .send(())
-                        .unwrap_or_else(|err| trace!("Failed to send {:?}", err));
-                    break;
-                }
-                continue;
             };
-            let ctx = TtrpcContext {
+            if flooding(&frames, fd, &mh) {
+                close_fds(&fds);
+                closing.set(CloseReason::Error(FLOODING.to_string()));
+                quit.store(true, Ordering::SeqCst);
+                // Wakes up the other threads reading the connection.
+                socket::shutdown(fd, Shutdown::Both).unwrap_or(());
+                control_tx
+                    .send(())
+                    .unwrap_or_else(|err| trace!("Failed to send {:?}", err));
+                break;
+            }
+
+            busy.fetch_add(1, Ordering::SeqCst);
+            let res = handle_message(
                 fd,
+                &info,
+                &methods,
+                &interceptors,
+                fallback.as_deref(),
+                &raw_services,
+                &res_tx,
+                &response_fds,
+                &cancels,
                 mh,
-                res_tx: res_tx.clone(),
-                metadata: context::from_pb(&req.metadata),
-                timeout_nano: req.timeout_nano,
-            };
-            if let Err(x) = method.handler(ctx, req) {
-                debug!("method handle {} get error {:?}", path, x);
+                buf,
+                fds,
+            );
+            busy.fetch_sub(1, Ordering::SeqCst);
+            if let Err(e) = res {
+                closing.set(CloseReason::from_error(&e));
                 quit.store(true, Ordering::SeqCst);
                 // the client connection would be closed and
                 // the connection dealing main thread would have
@@ -226,6 +465,15 @@ fn start_method_handler_thread(
             }
         }
     });
+    if let Err(e) = spawned {
+        error!("failed to spawn method handler thread: {}", e);
+        // Without a thread to read them, the requests would wait forever.
+        if failed_wtc.load(Ordering::SeqCst) == 0 && failed_busy.load(Ordering::SeqCst) == 0 {
+            failed_closing.set(CloseReason::Error(e.to_string()));
+            failed_quit.store(true, Ordering::SeqCst);
+            socket::shutdown(fd, Shutdown::Both).unwrap_or(());
+        }
+    }
 }
 
 fn start_method_handler_threads(num: usize, ts: &ThreadS) {
@@ -235,18 +483,51 @@ fn start_method_handler_threads(num: usize, ts: &ThreadS) {
         }
         start_method_handler_thread(
             ts.fd,
+            ts.info.clone(),
             ts.fdlock.clone(),
             ts.wtc.clone(),
             ts.quit.clone(),
             ts.methods.clone(),
+            ts.interceptors.clone(),
+            ts.fallback.clone(),
+            ts.raw_services.clone(),
             ts.res_tx.clone(),
+            ts.response_fds.clone(),
+            ts.cancels.clone(),
+            ts.closing.clone(),
+            ts.hooks.clone(),
+            ts.frames.clone(),
             ts.control_tx.clone(),
+            ts.busy.clone(),
             ts.min,
             ts.max,
+            ts.config.clone(),
+            ts.threads,
         );
     }
 }
 
+// Waits for connection `fd` to have a message, false once it had none for
+// `timeout` while no call of it was being handled.
+fn wait_message(fd: RawFd, timeout: Duration, busy: &AtomicUsize) -> bool {
+    let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    loop {
+        let mut pollers = [libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        let returned = unsafe { libc::poll(pollers.as_mut_ptr(), 1, timeout) };
+        // Errors are left to the read.
+        if returned != 0 {
+            return true;
+        }
+        if busy.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+    }
+}
+
 fn check_method_handler_threads(ts: &ThreadS) {
     let c = ts.wtc.load(Ordering::SeqCst);
     if c < ts.min {
@@ -260,13 +541,21 @@ impl Default for Server {
             listeners: Vec::with_capacity(1),
             monitor_fd: (-1, -1),
             listener_quit_flag: Arc::new(AtomicBool::new(false)),
+            listener_drain_flag: Arc::new(AtomicBool::new(false)),
             connections: Arc::new(Mutex::new(HashMap::new())),
-            methods: Arc::new(HashMap::new()),
+            methods: Methods::default(),
+            interceptors: Arc::new(Vec::new()),
+            fallback: None,
+            raw_services: RawServices::default(),
             handler: None,
             reaper: None,
-            thread_count_default: DEFAULT_WAIT_THREAD_COUNT_DEFAULT,
-            thread_count_min: DEFAULT_WAIT_THREAD_COUNT_MIN,
-            thread_count_max: DEFAULT_WAIT_THREAD_COUNT_MAX,
+            config: ServerConfig::default(),
+            threading: ThreadingMode::PerConnection,
+            listener_threads: ThreadConfig::default(),
+            worker_threads: ThreadConfig::default(),
+            pool: None,
+            hooks: ConnectionHooks::default(),
+            accept_paused: false,
         }
     }
 }
@@ -296,30 +585,283 @@ impl Server {
         Ok(self)
     }
 
+    /// Exports the listener so that a new process can take it over after exec.
+    ///
+    /// If `inheritable` is true, FD_CLOEXEC is cleared on the listener fd.
+    pub fn export_listener(&self, inheritable: bool) -> Result<ListenerState> {
+        let fd = *self
+            .listeners
+            .first()
+            .ok_or_else(|| Error::Others("ttrpc-rust not bind".to_string()))?;
+        ListenerState::export(fd, inheritable)
+    }
+
+    /// Creates a Server from a listener exported by the previous process.
+    pub fn from_listener_state(state: &ListenerState) -> Result<Server> {
+        let fd = state.import()?;
+        Server::new().add_listener(fd)
+    }
+
     pub fn register_service(
-        mut self,
+        self,
         methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>>,
     ) -> Server {
-        let mut_methods = Arc::get_mut(&mut self.methods).unwrap();
-        mut_methods.extend(methods);
+        self.add_services(methods);
+        self
+    }
+
+    /// Registers `handler` as the handler of `method` of `service`, see
+    /// [`service_fn`](crate::service_fn).
+    pub fn register_method(
+        self,
+        service: &str,
+        method: &str,
+        handler: impl MethodHandler + Send + Sync + 'static,
+    ) -> Server {
+        self.methods
+            .write()
+            .unwrap()
+            .insert(format!("/{}/{}", service, method), Arc::new(handler));
+        self
+    }
+
+    /// Adds the methods of services to the server, which may be running,
+    /// replacing the ones with the same paths. New calls are dispatched to
+    /// them.
+    pub fn add_services(&self, methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>>) {
+        let methods = methods
+            .into_iter()
+            .map(|(path, method)| (path, Arc::from(method)));
+        self.methods.write().unwrap().extend(methods);
+    }
+
+    /// Removes the methods of service `name` from the server, which may be
+    /// running, returning whether it had any. Calls already dispatched to
+    /// them complete, new ones are handled as calls to unknown methods.
+    pub fn remove_service(&self, name: &str) -> bool {
+        let prefix = format!("/{}/", name);
+        let mut methods = self.methods.write().unwrap();
+        let count = methods.len();
+        methods.retain(|path, _| !path.starts_with(&prefix));
+        methods.len() != count
+    }
+
+    /// Adds the [`reflection`] service, listing the services of the server.
+    pub fn enable_reflection(self) -> Server {
+        let path = format!("/{}/{}", reflection::SERVICE, reflection::LIST_SERVICES);
+        let method: Box<dyn MethodHandler + Send + Sync> =
+            Box::new(ListServices(Arc::downgrade(&self.methods)));
+        self.register_service(HashMap::from([(path, method)]))
+    }
+
+    /// Adds an interceptor wrapping the calls to all methods. Interceptors
+    /// run in the order they are added, the first one seeing the calls first.
+    pub fn add_interceptor(mut self, interceptor: impl Interceptor + 'static) -> Server {
+        let interceptors = Arc::get_mut(&mut self.interceptors).unwrap();
+        interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Sets the hook deciding, for every call, whether the peer may call
+    /// the method. It gets the credentials of the peer, none over vsock,
+    /// and the method such as `grpc.Containerd/Checkpoint`. Denied calls
+    /// fail with PERMISSION_DENIED before any interceptor sees them.
+    pub fn set_authorizer<F>(mut self, authorizer: F) -> Server
+    where
+        F: Fn(Option<PeerCredentials>, &str) -> bool + Send + Sync + 'static,
+    {
+        let interceptors = Arc::get_mut(&mut self.interceptors).unwrap();
+        interceptors.insert(0, Arc::new(Authorize(authorizer)));
+        self
+    }
+
+    /// Calls `hook` with every connection accepted, before handling any
+    /// of its calls. It runs on the listening thread, so it should be quick.
+    pub fn on_connection_opened(
+        mut self,
+        hook: impl Fn(&ConnectionInfo) + Send + Sync + 'static,
+    ) -> Server {
+        self.hooks.opened = Some(Arc::new(hook));
         self
     }
 
+    /// Calls `hook` with every connection closed and why, once its calls
+    /// are done. Per-client state can be cleaned up there.
+    pub fn on_connection_closed(
+        mut self,
+        hook: impl Fn(&ConnectionInfo, &CloseReason) + Send + Sync + 'static,
+    ) -> Server {
+        self.hooks.closed = Some(Arc::new(hook));
+        self
+    }
+
+    /// Reports the calls, bytes and connections of the server to `sink`.
+    /// Calls are timed from before any interceptor sees them.
+    pub fn set_metrics_sink(mut self, sink: impl MetricsSink + 'static) -> Server {
+        let sink: Arc<dyn MetricsSink> = Arc::new(sink);
+        self.hooks.metrics = Metrics::new(sink.clone());
+        let interceptors = Arc::get_mut(&mut self.interceptors).unwrap();
+        interceptors.insert(0, Arc::new(CallMetrics(sink)));
+        self
+    }
+
+    /// Keeps track of the connections and calls of the server in
+    /// `channelz`, see [`channelz`](crate::channelz).
+    pub fn set_channelz(mut self, channelz: Channelz) -> Server {
+        self.hooks.channelz = Some(channelz.clone());
+        let interceptors = Arc::get_mut(&mut self.interceptors).unwrap();
+        interceptors.insert(0, Arc::new(InFlightCalls(channelz)));
+        self
+    }
+
+    /// Caps the calls of `name` running at once to `max`. `name` is a
+    /// service such as `grpc.Containerd`, or a method such as
+    /// `grpc.Containerd/Checkpoint`. Calls beyond the cap wait up to
+    /// `queue_timeout` for one to finish, then fail with RESOURCE_EXHAUSTED.
+    ///
+    /// The cap is an interceptor, running in order with the others added.
+    pub fn set_concurrency_limit(self, name: &str, max: usize, queue_timeout: Duration) -> Server {
+        self.add_interceptor(ConcurrencyLimit::new(name, max, queue_timeout))
+    }
+
     pub fn set_thread_count_default(mut self, count: usize) -> Server {
-        self.thread_count_default = count;
+        self.config.thread_count_default = count;
         self
     }
 
     pub fn set_thread_count_min(mut self, count: usize) -> Server {
-        self.thread_count_min = count;
+        self.config.thread_count_min = count;
         self
     }
 
     pub fn set_thread_count_max(mut self, count: usize) -> Server {
-        self.thread_count_max = count;
+        self.config.thread_count_max = count;
+        self
+    }
+
+    /// Limit the frames a connection may send per `window` that aren't
+    /// requests, such as cancellations and frames of unknown types.
+    ///
+    /// Such frames are cheap to send but still cost the server work, a
+    /// connection going over the limit is disconnected. Defaults to 1000 per
+    /// second.
+    pub fn set_control_frame_limit(mut self, max: u32, window: Duration) -> Server {
+        self.config.control_frame_limit = FrameLimit { max, window };
         self
     }
 
+    /// Handles the requests of a connection one at a time, in the order
+    /// they arrive, for services whose correctness depends on it. Each
+    /// connection gets a single thread, or a single worker at a time in
+    /// [`ThreadingMode::SharedPool`]. Off by default.
+    ///
+    /// This overrides the [`set_thread_count_default()`](Server::set_thread_count_default),
+    /// `_min()` and `_max()` counts, a connection's are all 1 while it's on.
+    pub fn set_serial_requests(mut self, serial: bool) -> Server {
+        self.config.serial_requests = serial;
+        self
+    }
+
+    /// Sets the largest message payload the server accepts, defaults to
+    /// [`MESSAGE_LENGTH_MAX`](crate::proto::MESSAGE_LENGTH_MAX). Larger
+    /// requests are answered with RESOURCE_EXHAUSTED.
+    pub fn set_max_message_size(mut self, size: usize) -> Server {
+        self.config.max_message_size = size;
+        self
+    }
+
+    /// Sets the handler of the calls to methods no registered service has,
+    /// which otherwise fail. It gets the request as is, service, method and
+    /// payload, so it can forward or translate the call, and answers it like
+    /// the handler of a method.
+    pub fn set_fallback_handler(
+        mut self,
+        handler: impl MethodHandler + Send + Sync + 'static,
+    ) -> Server {
+        self.fallback = Some(Arc::new(handler));
+        self
+    }
+
+    /// Registers `service` as the handler of the calls to methods no
+    /// registered service has, of the services whose names start with
+    /// `prefix`. The longest matching prefix wins, then the fallback handler
+    /// gets the calls no raw service takes.
+    pub fn register_raw_service(
+        mut self,
+        prefix: &str,
+        service: impl RawService + 'static,
+    ) -> Server {
+        self.raw_services.add(prefix, service);
+        self
+    }
+
+    /// Sets how long [`Server::disconnect()`] waits for the calls being
+    /// handled to complete, defaults to 5 seconds. The connections still
+    /// busy then are shut down, dropping the responses of their calls.
+    pub fn set_shutdown_timeout(mut self, timeout: Duration) -> Server {
+        self.config.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Caps the connections open at once to `max`, `over` telling what
+    /// becomes of the ones coming beyond it. Unlimited by default.
+    pub fn set_max_connections(mut self, max: usize, over: ConnectionLimit) -> Server {
+        self.config.max_connections = Some((max, over));
+        self
+    }
+
+    /// Closes the connections which have sent nothing for `timeout` while
+    /// no call of theirs was being handled, so that abandoned clients don't
+    /// hold on to threads. Off by default.
+    pub fn set_idle_timeout(mut self, timeout: Duration) -> Server {
+        self.config.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Closes the connections from which a message, once it started
+    /// arriving, didn't arrive in whole within `timeout`. Waiting for the
+    /// next message isn't limited, see [`Server::set_idle_timeout()`].
+    pub fn set_read_timeout(mut self, timeout: Duration) -> Server {
+        self.config.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Closes the connections on which writing a message took longer than
+    /// `timeout`, so that clients not reading their responses don't keep
+    /// threads blocked forever.
+    pub fn set_write_timeout(mut self, timeout: Duration) -> Server {
+        self.config.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how connections are spread over threads, defaults to
+    /// [`ThreadingMode::PerConnection`].
+    pub fn set_threading_mode(mut self, mode: ThreadingMode) -> Server {
+        self.threading = mode;
+        self
+    }
+
+    /// Sets how the threads accepting and reaping connections are made.
+    pub fn set_listener_threads(mut self, config: ThreadConfig) -> Server {
+        self.listener_threads = config;
+        self
+    }
+
+    /// Sets how the threads serving connections, reading requests, running
+    /// handlers and writing responses, are made.
+    pub fn set_worker_threads(mut self, config: ThreadConfig) -> Server {
+        self.worker_threads = config;
+        self
+    }
+
+    /// Applies the knobs of `config` once [validated](ServerConfig::validate_for),
+    /// failing if it sets any only async servers have.
+    pub fn set_config(mut self, config: &ServerConfig) -> Result<Server> {
+        config.validate_for(ServerKind::Sync)?;
+        self.config = config.clone();
+        Ok(self)
+    }
+
     pub fn start_listen(&mut self) -> Result<()> {
         let connections = self.connections.clone();
 
@@ -328,6 +870,7 @@ impl Server {
         }
 
         self.listener_quit_flag.store(false, Ordering::SeqCst);
+        self.listener_drain_flag.store(false, Ordering::SeqCst);
 
         #[cfg(any(target_os = "linux", target_os = "android"))]
         let fds = pipe2(nix::fcntl::OFlag::O_CLOEXEC)?;
@@ -341,34 +884,56 @@ impl Server {
         };
 
         self.monitor_fd = fds;
+        if let Some(channelz) = self.hooks.channelz.as_ref() {
+            channelz.started();
+        }
 
         let listener = self.listeners[0];
+        let domain = common::get_domain(listener).ok();
+        let count = move |counter| {
+            if let Some(domain) = domain {
+                stats::record(domain, counter);
+            }
+        };
 
         let methods = self.methods.clone();
-        let default = self.thread_count_default;
-        let min = self.thread_count_min;
-        let max = self.thread_count_max;
+        let interceptors = self.interceptors.clone();
+        let fallback = self.fallback.clone();
+        let raw_services = self.raw_services.clone();
+        let config = Arc::new(self.config.clone());
+        // A single thread handles the requests of a serial connection, in
+        // order. With no minimum of threads waiting for requests, no other
+        // one is started while it handles one.
+        let (default, min, max) = if config.serial_requests {
+            (1, 0, 1)
+        } else {
+            (
+                config.thread_count_default,
+                config.thread_count_min,
+                config.thread_count_max,
+            )
+        };
         let listener_quit_flag = self.listener_quit_flag.clone();
+        let listener_drain_flag = self.listener_drain_flag.clone();
         let monitor_fd = self.monitor_fd.0;
+        let hooks = self.hooks.clone();
+        let worker_threads = self.worker_threads.clone();
 
         let reaper_tx = match self.reaper.take() {
             None => {
                 let reaper_connections = connections.clone();
                 let (reaper_tx, reaper_rx) = channel();
-                let reaper_handler = thread::Builder::new()
-                    .name("reaper".into())
-                    .spawn(move || {
+                let reaper_handler = self
+                    .listener_threads
+                    .spawn("reaper", move || {
                         for fd in reaper_rx.iter() {
-                            reaper_connections
-                                .lock()
-                                .unwrap()
-                                .remove(&fd)
-                                .map(|mut cn| {
-                                    cn.handler.take().map(|handler| {
-                                        handler.join().unwrap();
-                                        close(fd).unwrap();
-                                    })
-                                });
+                            if let Some(mut cn) = reaper_connections.lock().unwrap().remove(&fd) {
+                                // Connections served by the pool have no handler.
+                                if let Some(handler) = cn.handler.take() {
+                                    handler.join().unwrap();
+                                }
+                                close(fd).unwrap();
+                            }
                         }
                         info!("reaper thread exited");
                     })
@@ -383,9 +948,49 @@ impl Server {
             }
         };
 
-        let handler = thread::Builder::new()
-            .name("listener_loop".into())
-            .spawn(move || {
+        let pool = match self.threading {
+            ThreadingMode::PerConnection => None,
+            ThreadingMode::SharedPool {
+                min_workers,
+                max_workers,
+                queue_length,
+            } => {
+                if max_workers == 0 || min_workers > max_workers {
+                    return Err(Error::Others(format!(
+                        "the shared pool can't have from {} to {} workers",
+                        min_workers, max_workers
+                    )));
+                }
+                if queue_length == 0 {
+                    return Err(Error::Others(
+                        "the shared pool queue can't be empty".to_string(),
+                    ));
+                }
+                if self.pool.is_none() {
+                    let limits = PoolLimits {
+                        min_workers,
+                        max_workers,
+                        queue_length,
+                    };
+                    self.pool = Some(Pool::new(
+                        limits,
+                        self.methods.clone(),
+                        self.interceptors.clone(),
+                        self.fallback.clone(),
+                        self.raw_services.clone(),
+                        config.clone(),
+                        self.hooks.clone(),
+                        self.worker_threads.clone(),
+                        reaper_tx.clone(),
+                    )?);
+                }
+                self.pool.as_ref().map(Pool::waker)
+            }
+        };
+
+        let handler = self
+            .listener_threads
+            .spawn("listener_loop", move || {
                 let mut pollers = vec![
                     libc::pollfd {
                         fd: monitor_fd,
@@ -400,17 +1005,37 @@ impl Server {
                 ];
 
                 loop {
-                    if listener_quit_flag.load(Ordering::SeqCst) {
+                    let draining = listener_quit_flag.load(Ordering::SeqCst);
+                    if draining && !listener_drain_flag.load(Ordering::SeqCst) {
                         info!("listener shutdown for quit flag");
                         break;
                     }
 
+                    let full = |over| {
+                        config.max_connections.is_some_and(|(max, o)| {
+                            o == over && connections.lock().unwrap().len() >= max
+                        })
+                    };
+                    // Over the limit, leave the clients waiting and check for
+                    // room once in a while.
+                    let waiting = full(ConnectionLimit::Wait);
+                    let last = pollers.len() - 1;
+                    pollers[last].events = if waiting { 0 } else { libc::POLLIN };
+
+                    // While draining, only take the connections already waiting.
+                    let timeout = if draining {
+                        0
+                    } else if waiting {
+                        CONNECTION_LIMIT_POLL_MS
+                    } else {
+                        -1
+                    };
                     let returned = unsafe {
                         let pollers: &mut [libc::pollfd] = &mut pollers;
                         libc::poll(
                             pollers as *mut _ as *mut libc::pollfd,
                             pollers.len() as _,
-                            -1,
+                            timeout,
                         )
                     };
 
@@ -422,17 +1047,18 @@ impl Server {
 
                         error!("fatal error in listener_loop:{:?}", err);
                         break;
-                    } else if returned < 1 {
-                        continue;
                     }
 
-                    if pollers[0].revents != 0 || pollers[pollers.len() - 1].revents == 0 {
+                    if pollers[pollers.len() - 1].revents == 0 {
+                        if draining {
+                            info!("listener drained");
+                            break;
+                        }
                         continue;
                     }
 
-                    if listener_quit_flag.load(Ordering::SeqCst) {
-                        info!("listener shutdown for quit flag");
-                        break;
+                    if !draining && pollers[0].revents != 0 {
+                        continue;
                     }
 
                     #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -440,6 +1066,7 @@ impl Server {
                         Ok(fd) => fd,
                         Err(e) => {
                             error!("failed to accept error {:?}", e);
+                            count(Counter::AcceptErrors);
                             break;
                         }
                     };
@@ -452,63 +1079,149 @@ impl Server {
                         Ok(fd) => {
                             if let Err(err) = set_fd_close_exec(fd) {
                                 error!("fcntl failed after accept: {:?}", err);
+                                count(Counter::AcceptErrors);
                                 break;
                             };
                             fd
                         }
                         Err(e) => {
                             error!("failed to accept error {:?}", e);
+                            count(Counter::AcceptErrors);
                             break;
                         }
                     };
+                    count(Counter::Accepted);
+
+                    if full(ConnectionLimit::Reject) {
+                        warn!(
+                            "closing connection fd {}, the server has {} already",
+                            fd,
+                            config.max_connections.unwrap().0
+                        );
+                        count(Counter::Rejected);
+                        close(fd).unwrap_or(());
+                        continue;
+                    }
+
+                    if let Err(e) = set_io_timeouts(fd, config.read_timeout, config.write_timeout) {
+                        warn!("failed to set timeouts of fd {}: {:?}", fd, e);
+                    }
+
+                    let info = Arc::new(ConnectionInfo::new(fd));
+                    hooks.opened(&info);
+                    let closing = Closing::default();
+
+                    if let Some(pool) = pool.as_ref() {
+                        connections.lock().unwrap().insert(
+                            fd,
+                            Connection {
+                                fd,
+                                handler: None,
+                                quit: Arc::new(AtomicBool::new(false)),
+                                closing: closing.clone(),
+                            },
+                        );
+                        pool.add(fd, info, closing);
+                        continue;
+                    }
 
                     let methods = methods.clone();
+                    let interceptors = interceptors.clone();
+                    let fallback = fallback.clone();
+                    let raw_services = raw_services.clone();
                     let quit = Arc::new(AtomicBool::new(false));
                     let child_quit = quit.clone();
                     let reaper_tx_child = reaper_tx.clone();
-
-                    let handler = thread::Builder::new()
-                        .name("client_handler".into())
-                        .spawn(move || {
+                    let child_closing = closing.clone();
+                    // To close the connection with, should it get no thread.
+                    let (conn_info, conn_hooks) = (info.clone(), hooks.clone());
+                    let hooks = hooks.clone();
+                    let threads = worker_threads.clone();
+                    let config = config.clone();
+
+                    let handler = worker_threads
+                        .spawn("client_handler", move || {
                             debug!("Got new client");
                             // Start response thread
                             let quit_res = child_quit.clone();
                             let (res_tx, res_rx): (MessageSender, MessageReceiver) = channel();
-                            let handler = thread::spawn(move || {
+                            let response_fds = ResponseFds::default();
+                            let cancels = Cancels::default();
+                            let writer_fds = response_fds.clone();
+                            let writer_closing = child_closing.clone();
+                            let writer_hooks = hooks.clone();
+                            let writer_info = info.clone();
+                            let writer = threads.spawn("response_writer", move || {
                                 for r in res_rx.iter() {
                                     trace!("response thread get {:?}", r);
-                                    if let Err(e) = write_message(fd, r.0, r.1) {
+                                    let fds = writer_fds.take(r.0.stream_id);
+                                    let size = MESSAGE_HEADER_LENGTH + r.1.len();
+                                    let res = write_message_with_fds(fd, r.0, r.1, &fds);
+                                    close_fds(&fds);
+                                    if let Err(e) = res {
                                         error!("write_message got {:?}", e);
+                                        writer_closing.set(CloseReason::from_error(&e));
                                         quit_res.store(true, Ordering::SeqCst);
                                         break;
                                     }
+                                    writer_hooks.sent(&writer_info, size);
                                 }
 
                                 trace!("response thread quit");
                             });
+                            let handler = match writer {
+                                Ok(handler) => handler,
+                                Err(e) => {
+                                    error!("closing connection fd {}, no response thread", fd);
+                                    child_closing.set(CloseReason::Error(e.to_string()));
+                                    hooks.closed(&info, &child_closing.take());
+                                    reaper_tx_child.send(fd).unwrap();
+                                    return;
+                                }
+                            };
 
                             let (control_tx, control_rx): (SyncSender<()>, Receiver<()>) =
                                 sync_channel(0);
                             let ts = ThreadS {
                                 fd,
+                                info: info.clone(),
                                 fdlock: &Arc::new(Mutex::new(())),
                                 wtc: &Arc::new(AtomicUsize::new(0)),
                                 methods: &methods,
+                                interceptors: &interceptors,
+                                fallback: &fallback,
+                                raw_services: &raw_services,
                                 res_tx: &res_tx,
+                                response_fds: &response_fds,
+                                cancels: &cancels,
+                                closing: &child_closing,
+                                hooks: &hooks,
+                                frames: &Arc::new(Mutex::new(FrameCounter::new(
+                                    config.control_frame_limit,
+                                ))),
                                 control_tx: &control_tx,
+                                busy: &Arc::new(AtomicUsize::new(0)),
                                 quit: &child_quit,
                                 default,
                                 min,
                                 max,
+                                config: &config,
+                                threads: &threads,
                             };
                             start_method_handler_threads(ts.default, &ts);
 
                             while !child_quit.load(Ordering::SeqCst) {
                                 check_method_handler_threads(&ts);
+                                // It may have no thread left to wake it up.
+                                if child_quit.load(Ordering::SeqCst) {
+                                    break;
+                                }
                                 if control_rx.recv().is_err() {
                                     break;
                                 }
                             }
+                            // Handlers still running won't be answered.
+                            cancels.cancel_all();
                             // drop the control_rx, thus all of the method handler threads would
                             // terminated.
                             drop(control_rx);
@@ -517,11 +1230,21 @@ impl Server {
                             handler.join().unwrap_or(());
                             // client_handler should not close fd before exit
                             // , which prevent fd reuse issue.
+                            hooks.closed(&info, &child_closing.take());
                             reaper_tx_child.send(fd).unwrap();
 
                             debug!("client thread quit");
                         })
-                        .unwrap();
+                        .map_err(|e| CloseReason::Error(e.to_string()));
+                    let handler = match handler {
+                        Ok(handler) => handler,
+                        Err(reason) => {
+                            error!("closing connection fd {}, no thread to serve it", fd);
+                            conn_hooks.closed(&conn_info, &reason);
+                            close(fd).unwrap_or(());
+                            continue;
+                        }
+                    };
 
                     let mut cns = connections.lock().unwrap();
                     cns.insert(
@@ -530,6 +1253,7 @@ impl Server {
                             fd,
                             handler: Some(handler),
                             quit: quit.clone(),
+                            closing,
                         },
                     );
                 } // end loop
@@ -546,12 +1270,12 @@ impl Server {
     }
 
     pub fn start(&mut self) -> Result<()> {
-        if self.thread_count_default >= self.thread_count_max {
+        if self.config.thread_count_default >= self.config.thread_count_max {
             return Err(Error::Others(
                 "thread_count_default should smaller than thread_count_max".to_string(),
             ));
         }
-        if self.thread_count_default <= self.thread_count_min {
+        if self.config.thread_count_default <= self.config.thread_count_min {
             return Err(Error::Others(
                 "thread_count_default should biger than thread_count_min".to_string(),
             ));
@@ -561,7 +1285,39 @@ impl Server {
         Ok(())
     }
 
+    /// Stops accepting connections for now, keeping the listener and the
+    /// connections open. Clients connecting meanwhile wait to be accepted
+    /// until [`Server::resume_accept()`].
+    pub fn pause_accept(&mut self) {
+        if self.handler.is_none() {
+            return;
+        }
+        self.stop_listener(false);
+        self.accept_paused = true;
+        info!("server paused accepting connections");
+    }
+
+    /// Accepts connections again after [`Server::pause_accept()`].
+    pub fn resume_accept(&mut self) -> Result<()> {
+        if !self.accept_paused {
+            return Ok(());
+        }
+        self.start_listen()?;
+        self.accept_paused = false;
+        info!("server resumed accepting connections");
+        Ok(())
+    }
+
     pub fn stop_listen(mut self) -> Self {
+        // A paused listener is stopped already.
+        if !std::mem::replace(&mut self.accept_paused, false) {
+            self.stop_listener(false);
+        }
+        self
+    }
+
+    fn stop_listener(&mut self, drain: bool) {
+        self.listener_drain_flag.store(drain, Ordering::SeqCst);
         self.listener_quit_flag.store(true, Ordering::SeqCst);
         close(self.monitor_fd.1).unwrap_or_else(|e| {
             warn!(
@@ -573,8 +1329,46 @@ impl Server {
         if let Some(handler) = self.handler.take() {
             handler.join().unwrap();
         }
+        // The listener thread polled the read end, it's done with it now.
+        close(self.monitor_fd.0).unwrap_or_else(|e| {
+            warn!(
+                "failed to close monitor fd: {} with error: {}",
+                self.monitor_fd.0, e
+            )
+        });
+        self.monitor_fd = (-1, -1);
         info!("listener thread stopped");
-        self
+    }
+
+    /// Moves the server to `sockaddr` without dropping its connections.
+    ///
+    /// Once listening on the new address, the connections already waiting
+    /// on the old one are accepted, then it's closed and the file of a unix
+    /// socket is removed. A server that isn't started yet only swaps its
+    /// listener.
+    pub fn rebind(&mut self, sockaddr: &str) -> Result<()> {
+        let (fd, _) = common::do_bind(sockaddr)?;
+        if let Err(e) = common::do_listen(fd) {
+            close(fd).unwrap_or(());
+            return Err(e);
+        }
+
+        let running = self.handler.is_some();
+        if running {
+            self.stop_listener(true);
+        }
+        for old in self.listeners.drain(..) {
+            let path = common::get_socket_path(old);
+            close(old).unwrap_or_else(|e| warn!("failed to close listener {}: {}", old, e));
+            common::remove_socket_path(path);
+        }
+        self.listeners.push(fd);
+        info!("server rebound to {}", sockaddr);
+
+        if running {
+            self.start_listen()?;
+        }
+        Ok(())
     }
 
     pub fn disconnect(mut self) {
@@ -589,13 +1383,43 @@ impl Server {
         drop(connections);
         info!("connections closed");
 
-        if let Some(r) = self.reaper.take() {
-            drop(r.0);
-            r.1.join().unwrap();
+        if let Some(pool) = self.pool.take() {
+            pool.shutdown();
+        }
+        info!("pool stopped");
+
+        if let Some((reaper_tx, reaper)) = self.reaper.take() {
+            drop(reaper_tx);
+            // The reaper is done once all the connections are.
+            let (done_tx, done_rx) = channel();
+            thread::spawn(move || {
+                reaper.join().unwrap();
+                done_tx.send(()).unwrap_or(());
+            });
+            if done_rx.recv_timeout(self.config.shutdown_timeout).is_err() {
+                let connections = self.connections.lock().unwrap();
+                warn!(
+                    "{} connections still busy after {:?}, shutting them down",
+                    connections.len(),
+                    self.config.shutdown_timeout
+                );
+                for fd in connections.keys() {
+                    socket::shutdown(*fd, Shutdown::Both).unwrap_or(());
+                }
+                drop(connections);
+                // Calls still running aren't interrupted, their threads are
+                // waited for.
+                done_rx.recv().unwrap_or(());
+            }
         }
         info!("reaper thread stopped");
     }
 
+    /// Stops accepting connections and closes the ones open.
+    ///
+    /// The calls being handled get up to the timeout set with
+    /// [`Server::set_shutdown_timeout()`] to complete before their
+    /// connections are shut down. Returns once the connections are closed.
     pub fn shutdown(self) {
         self.stop_listen().disconnect();
     }
@@ -612,3 +1436,440 @@ impl AsRawFd for Server {
         self.listeners[0]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::MESSAGE_TYPE_DATA;
+    use crate::service_fn::{self, Raw};
+    use crate::sync::channel::{read_message, write_message};
+    use crate::sync::Client;
+    use std::io::{Read, Write};
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    // Responds with the request, then tells the test which call it handled.
+    struct Echo(Mutex<Sender<u32>>);
+
+    impl MethodHandler for Echo {
+        fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<()> {
+            let stream_id = ctx.mh.stream_id;
+            let res = Response {
+                payload: req.payload,
+                ..Default::default()
+            };
+            response_to_channel(stream_id, res, ctx.res_tx)?;
+            self.0.lock().unwrap().send(stream_id).unwrap();
+            Ok(())
+        }
+    }
+
+    fn write_request(conn: &UnixStream, stream_id: u32, req: Request) {
+        let buf = req.encode().unwrap();
+        let mh = MessageHeader::new_request(stream_id, buf.len() as u32);
+        write_message(conn.as_raw_fd(), mh, buf).unwrap();
+    }
+
+    // A server listening on a socket unique to the test, and the socket path.
+    fn server(name: &str) -> (Server, std::path::PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("ttrpc-sync-{}-{}.sock", name, std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = Server::new().add_listener(listener.into_raw_fd()).unwrap();
+        (server, path)
+    }
+
+    #[test]
+    fn test_control_frame_limit() {
+        let (server, path) = server("control-frame-limit");
+        let mut server = server.set_control_frame_limit(2, Duration::from_secs(60));
+        server.start().unwrap();
+
+        let mut conn = UnixStream::connect(&path).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        for _ in 0..3 {
+            let mh = MessageHeader {
+                length: 0,
+                stream_id: 1,
+                type_: MESSAGE_TYPE_DATA,
+                flags: 0,
+            };
+            write_message(conn.as_raw_fd(), mh, Vec::new()).unwrap();
+        }
+        // The third frame is over the limit, the server hangs up.
+        let mut buf = [0; 1];
+        assert_eq!(conn.read(&mut buf).unwrap(), 0);
+
+        server.shutdown();
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_probe() {
+        let fallback_calls = Arc::new(AtomicUsize::new(0));
+        let calls = fallback_calls.clone();
+        let (server, path) = server("probe");
+        let mut server =
+            server.set_fallback_handler(service_fn::sync_method(Raw, move |_ctx, req: Vec<u8>| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Ok(req)
+            }));
+        server.start().unwrap();
+        let client = Client::connect(&format!("unix://{}", path.display())).unwrap();
+
+        // Answered by the server, the fallback handler doesn't see it.
+        client.verify(Duration::from_secs(5)).unwrap();
+        assert_eq!(fallback_calls.load(Ordering::SeqCst), 0);
+        let req = Request {
+            service: "test.Svc".to_string(),
+            method: "Other".to_string(),
+            ..Default::default()
+        };
+        client.request(req).unwrap();
+        assert_eq!(fallback_calls.load(Ordering::SeqCst), 1);
+
+        server.shutdown();
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_cancel_before_start() {
+        let cancels = Cancels::default();
+        let mut mh = MessageHeader::new_request(1, 0);
+        // Another thread reads the cancel before the request is handled.
+        cancels.received(&mh);
+        cancels.cancel(1);
+        assert!(cancels.start(1, None).is_cancelled());
+        cancels.done(1);
+
+        mh.set_stream_id(3);
+        cancels.received(&mh);
+        let token = cancels.start(3, None);
+        assert!(!token.is_cancelled());
+        cancels.cancel(3);
+        assert!(token.is_cancelled());
+        cancels.done(3);
+        // The cancel of a call that's over is dropped.
+        cancels.cancel(3);
+        assert!(!cancels.start(3, None).is_cancelled());
+    }
+
+    #[test]
+    fn test_one_way() {
+        let (handled_tx, handled) = channel();
+        let (server, path) = server("one-way");
+        let mut server = server.register_method("test.Svc", "Echo", Echo(Mutex::new(handled_tx)));
+        server.start().unwrap();
+
+        let conn = UnixStream::connect(&path).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let req = Request {
+            service: "test.Svc".to_string(),
+            method: "Echo".to_string(),
+            ..Default::default()
+        };
+        let mut one_way = req.clone();
+        common::add_no_response_key(&mut one_way);
+        write_request(&conn, 1, one_way);
+        // Any response to the notification is queued before the handler
+        // says it's done, so it would be written ahead of the next one.
+        assert_eq!(handled.recv().unwrap(), 1);
+        write_request(&conn, 3, req);
+        assert_eq!(handled.recv().unwrap(), 3);
+        let (mh, _) = read_message(conn.as_raw_fd(), crate::proto::MESSAGE_LENGTH_MAX).unwrap();
+        assert_eq!(mh.stream_id, 3);
+
+        server.shutdown();
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_pause_accept() {
+        let (handled_tx, _handled) = channel();
+        let (server, path) = server("pause-accept");
+        let mut server = server.register_method("test.Svc", "Echo", Echo(Mutex::new(handled_tx)));
+        server.start().unwrap();
+        let req = Request {
+            service: "test.Svc".to_string(),
+            method: "Echo".to_string(),
+            ..Default::default()
+        };
+        let client = Client::connect(&format!("unix://{}", path.display())).unwrap();
+        client.request(req.clone()).unwrap();
+
+        server.pause_accept();
+        // The client connects, but its connection waits to be accepted.
+        let conn = UnixStream::connect(&path).unwrap();
+        write_request(&conn, 1, req.clone());
+        conn.set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let err = (&conn).read(&mut [0; 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        // The connections open are served on.
+        client.request(req).unwrap();
+
+        server.resume_accept().unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (mh, _) = read_message(conn.as_raw_fd(), crate::proto::MESSAGE_LENGTH_MAX).unwrap();
+        assert_eq!(mh.stream_id, 1);
+
+        server.shutdown();
+        std::fs::remove_file(&path).ok();
+    }
+
+    fn max_connections_server(name: &str, over: ConnectionLimit) -> (Server, std::path::PathBuf) {
+        let (server, path) = server(name);
+        let mut server = server.set_max_connections(1, over).register_method(
+            "test.Svc",
+            "Ping",
+            service_fn::sync_method(Raw, |_ctx, req: Vec<u8>| Ok(req)),
+        );
+        server.start().unwrap();
+        (server, path)
+    }
+
+    // Connects and makes a call, so the connection is known to be accepted.
+    fn ping(path: &std::path::Path) -> UnixStream {
+        let conn = UnixStream::connect(path).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write_request(&conn, 1, ping_request());
+        let (mh, _) = read_message(conn.as_raw_fd(), crate::proto::MESSAGE_LENGTH_MAX).unwrap();
+        assert_eq!(mh.stream_id, 1);
+        conn
+    }
+
+    fn ping_request() -> Request {
+        Request {
+            service: "test.Svc".to_string(),
+            method: "Ping".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_max_connections_wait() {
+        let (server, path) = max_connections_server("max-wait", ConnectionLimit::Wait);
+        let first = ping(&path);
+
+        let waiting = UnixStream::connect(&path).unwrap();
+        write_request(&waiting, 1, ping_request());
+        waiting
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let err = (&waiting).read(&mut [0; 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        // There's room once the first connection closes.
+        drop(first);
+        waiting
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (mh, _) = read_message(waiting.as_raw_fd(), crate::proto::MESSAGE_LENGTH_MAX).unwrap();
+        assert_eq!(mh.stream_id, 1);
+
+        server.shutdown();
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_max_connections_reject() {
+        let (server, path) = max_connections_server("max-reject", ConnectionLimit::Reject);
+        let first = ping(&path);
+
+        let rejected = UnixStream::connect(&path).unwrap();
+        rejected
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!((&rejected).read(&mut [0; 1]).unwrap(), 0);
+        // The connection open is served on.
+        write_request(&first, 3, ping_request());
+        let (mh, _) = read_message(first.as_raw_fd(), crate::proto::MESSAGE_LENGTH_MAX).unwrap();
+        assert_eq!(mh.stream_id, 3);
+
+        drop(first);
+        server.shutdown();
+        std::fs::remove_file(&path).ok();
+    }
+
+    struct Named(&'static str);
+
+    impl RawService for Named {
+        fn call(&self, _: &TtrpcContext, _: &str, _: &str, _: Vec<u8>) -> Result<Vec<u8>> {
+            Ok(self.0.as_bytes().to_vec())
+        }
+    }
+
+    #[test]
+    fn test_dispatch_order() {
+        let (server, path) = server("dispatch-order");
+        let mut server = server
+            .register_method(
+                "test.Svc",
+                "Ping",
+                service_fn::sync_method(Raw, |_ctx, _req: Vec<u8>| Ok(b"method".to_vec())),
+            )
+            .register_raw_service("test.", Named("raw"))
+            .set_fallback_handler(service_fn::sync_method(Raw, |_ctx, _req: Vec<u8>| {
+                Ok(b"fallback".to_vec())
+            }));
+        server.start().unwrap();
+        let client = Client::connect(&format!("unix://{}", path.display())).unwrap();
+
+        // Registered methods first, then raw services, then the fallback.
+        for (service, method, handler) in [
+            ("test.Svc", "Ping", "method"),
+            ("test.Svc", "Other", "raw"),
+            ("other.Svc", "Ping", "fallback"),
+        ] {
+            let req = Request {
+                service: service.to_string(),
+                method: method.to_string(),
+                ..Default::default()
+            };
+            let res = client.request(req).unwrap();
+            assert_eq!(res.payload, handler.as_bytes(), "{}/{}", service, method);
+        }
+
+        server.shutdown();
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_add_services() {
+        let (mut server, path) = server("add-services");
+        server.start().unwrap();
+        let client = Client::connect(&format!("unix://{}", path.display())).unwrap();
+        assert!(client.request(ping_request()).is_err());
+
+        // Calls on the connections open already see the new methods.
+        let method: Box<dyn MethodHandler + Send + Sync> =
+            Box::new(service_fn::sync_method(Raw, |_ctx, req: Vec<u8>| Ok(req)));
+        server.add_services(HashMap::from([("/test.Svc/Ping".to_string(), method)]));
+        client.request(ping_request()).unwrap();
+
+        assert!(server.remove_service("test.Svc"));
+        assert!(client.request(ping_request()).is_err());
+
+        server.shutdown();
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_close_reasons() {
+        let (opened_tx, opened) = channel();
+        let (closed_tx, closed) = channel();
+        let (opened_tx, closed_tx) = (Mutex::new(opened_tx), Mutex::new(closed_tx));
+        let (server, path) = server("close-reasons");
+        let mut server = server
+            .set_idle_timeout(Duration::from_millis(200))
+            .on_connection_opened(move |_info| opened_tx.lock().unwrap().send(()).unwrap())
+            .on_connection_closed(move |_info, reason| {
+                closed_tx.lock().unwrap().send(reason.clone()).unwrap()
+            });
+        server.start().unwrap();
+        let timeout = Duration::from_secs(5);
+
+        drop(UnixStream::connect(&path).unwrap());
+        assert_eq!(closed.recv_timeout(timeout).unwrap(), CloseReason::Client);
+
+        // A header cut short.
+        let mut conn = UnixStream::connect(&path).unwrap();
+        conn.write_all(&[0; 3]).unwrap();
+        conn.shutdown(std::net::Shutdown::Write).unwrap();
+        let reason = closed.recv_timeout(timeout).unwrap();
+        assert!(matches!(reason, CloseReason::Error(_)), "{:?}", reason);
+
+        let _idle = UnixStream::connect(&path).unwrap();
+        assert_eq!(closed.recv_timeout(timeout).unwrap(), CloseReason::Idle);
+
+        let _open = UnixStream::connect(&path).unwrap();
+        opened.iter().nth(3).unwrap();
+        server.shutdown();
+        assert_eq!(closed.recv_timeout(timeout).unwrap(), CloseReason::Shutdown);
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_serial_requests() {
+        let pool = ThreadingMode::SharedPool {
+            min_workers: 2,
+            max_workers: 4,
+            queue_length: 16,
+        };
+        for (name, mode) in [
+            ("serial", ThreadingMode::PerConnection),
+            ("serial-pool", pool),
+        ] {
+            serial_requests(name, mode);
+        }
+    }
+
+    fn serial_requests(name: &str, mode: ThreadingMode) {
+        let (server, path) = server(name);
+        let mut server = server
+            .set_threading_mode(mode)
+            .set_serial_requests(true)
+            .register_method(
+                "test.Svc",
+                "Ping",
+                service_fn::sync_method(Raw, |_ctx, req: Vec<u8>| {
+                    thread::sleep(Duration::from_millis(req[0].into()));
+                    Ok(req)
+                }),
+            );
+        server.start().unwrap();
+
+        let conn = UnixStream::connect(&path).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        // The first call is the slowest, it'd be answered last if the calls
+        // were handled at once.
+        for (stream_id, sleep_ms) in [(1, 50), (3, 0), (5, 0)] {
+            let mut req = ping_request();
+            req.payload = vec![sleep_ms];
+            write_request(&conn, stream_id, req);
+        }
+        let stream_ids: Vec<_> = (0..3)
+            .map(|_| {
+                let (mh, _) =
+                    read_message(conn.as_raw_fd(), crate::proto::MESSAGE_LENGTH_MAX).unwrap();
+                mh.stream_id
+            })
+            .collect();
+        assert_eq!(stream_ids, [1, 3, 5], "{}", name);
+
+        server.shutdown();
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_spawn_failure() {
+        let (failed_tx, failed) = channel();
+        let (closed_tx, closed) = channel();
+        let (failed_tx, closed_tx) = (Mutex::new(failed_tx), Mutex::new(closed_tx));
+        // A stack larger than the address space can't be mapped.
+        let threads = ThreadConfig::new()
+            .set_stack_size(1 << 50)
+            .on_spawn_failure(move |_name, _e| failed_tx.lock().unwrap().send(()).unwrap());
+        let (server, path) = server("spawn-failure");
+        let mut server =
+            server
+                .set_worker_threads(threads)
+                .on_connection_closed(move |_info, reason| {
+                    closed_tx.lock().unwrap().send(reason.clone()).unwrap()
+                });
+        server.start().unwrap();
+
+        // The connection gets no thread to serve it, it's closed.
+        let conn = UnixStream::connect(&path).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!((&conn).read(&mut [0; 1]).unwrap(), 0);
+        failed.recv_timeout(Duration::from_secs(5)).unwrap();
+        let reason = closed.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(reason, CloseReason::Error(_)), "{:?}", reason);
+
+        server.shutdown();
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! How the threads of a sync server are made.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+type SpawnFailureHook = Arc<dyn Fn(&str, &io::Error) + Send + Sync>;
+
+/// The names, stack size and CPU affinity of threads of a sync server, see
+/// [`Server::set_listener_threads()`](crate::sync::Server::set_listener_threads)
+/// and [`Server::set_worker_threads()`](crate::sync::Server::set_worker_threads).
+///
+/// A thread that can't be spawned, such as with EAGAIN once the process
+/// hits its limit of threads, is retried as set by
+/// [`set_spawn_retries()`](ThreadConfig::set_spawn_retries), none by
+/// default. Should it still fail, the hook set by
+/// [`on_spawn_failure()`](ThreadConfig::on_spawn_failure) is called and the
+/// server sheds the work the thread was for: a connection without a thread
+/// to serve it is closed, and the requests of a connection that has some
+/// already wait for them.
+#[derive(Clone, Default)]
+pub struct ThreadConfig {
+    name_prefix: String,
+    stack_size: Option<usize>,
+    cpus: Option<Vec<usize>>,
+    spawn_retries: u32,
+    spawn_backoff: Duration,
+    on_spawn_failure: Option<SpawnFailureHook>,
+}
+
+impl ThreadConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Prefixes the names of the threads with `prefix`, such as `shim-` for
+    /// `shim-pool_worker`. Linux only shows the first 15 bytes of a name.
+    pub fn set_name_prefix(mut self, prefix: &str) -> Self {
+        self.name_prefix = prefix.to_string();
+        self
+    }
+
+    /// Sets the stack size of the threads, the default of std otherwise.
+    pub fn set_stack_size(mut self, size: usize) -> Self {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// Pins the threads to `cpus`, on Linux and Android only.
+    pub fn set_cpu_affinity(mut self, cpus: &[usize]) -> Self {
+        self.cpus = Some(cpus.to_vec());
+        self
+    }
+
+    /// Retries spawning a thread up to `retries` times, waiting `backoff`
+    /// before the first retry and twice as long before each next one. The
+    /// thread spawning it, such as the listener, waits meanwhile.
+    pub fn set_spawn_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.spawn_retries = retries;
+        self.spawn_backoff = backoff;
+        self
+    }
+
+    /// Calls `hook` with the name of a thread and the error, when the thread
+    /// can't be spawned even after the retries.
+    pub fn on_spawn_failure(
+        mut self,
+        hook: impl Fn(&str, &io::Error) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_spawn_failure = Some(Arc::new(hook));
+        self
+    }
+
+    pub(crate) fn spawn<F, T>(&self, name: &str, f: F) -> io::Result<JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let name = format!("{}{}", self.name_prefix, name);
+        // Kept out of the thread, a failed spawn drops what it is given.
+        let f = Arc::new(Mutex::new(Some(f)));
+        let mut retries = self.spawn_retries;
+        let mut backoff = self.spawn_backoff;
+        loop {
+            let mut builder = thread::Builder::new().name(name.clone());
+            if let Some(size) = self.stack_size {
+                builder = builder.stack_size(size);
+            }
+            let (f, cpus) = (f.clone(), self.cpus.clone());
+            let res = builder.spawn(move || {
+                if let Some(cpus) = cpus {
+                    set_affinity(&cpus);
+                }
+                let f = f.lock().unwrap().take().unwrap();
+                f()
+            });
+            match res {
+                Ok(handle) => return Ok(handle),
+                Err(e) if retries > 0 => {
+                    warn!(
+                        "failed to spawn thread {}, retrying in {:?}: {}",
+                        name, backoff, e
+                    );
+                    thread::sleep(backoff);
+                    retries -= 1;
+                    backoff *= 2;
+                }
+                Err(e) => {
+                    if let Some(hook) = self.on_spawn_failure.as_ref() {
+                        hook(&name, &e);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_affinity(cpus: &[usize]) {
+    use nix::sched::{sched_setaffinity, CpuSet};
+    use nix::unistd::Pid;
+
+    let mut set = CpuSet::new();
+    for &cpu in cpus {
+        if let Err(e) = set.set(cpu) {
+            warn!("can't pin a thread to cpu {}: {}", cpu, e);
+        }
+    }
+    if let Err(e) = sched_setaffinity(Pid::from_raw(0), &set) {
+        warn!("failed to set the cpu affinity of a thread: {}", e);
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_affinity(_cpus: &[usize]) {
+    warn!("cpu affinity is not supported on this platform");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thread_config_spawn() {
+        let config = ThreadConfig::new()
+            .set_name_prefix("shim-")
+            .set_stack_size(256 * 1024);
+        let name = config
+            .spawn("worker", || thread::current().name().map(String::from))
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(name.as_deref(), Some("shim-worker"));
+
+        let name = ThreadConfig::new()
+            .spawn("worker", || thread::current().name().map(String::from))
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(name.as_deref(), Some("worker"));
+    }
+
+    #[test]
+    fn test_thread_config_spawn_failure() {
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let hook_failures = failures.clone();
+        // A stack larger than the address space can't be mapped.
+        let config = ThreadConfig::new()
+            .set_stack_size(1 << 50)
+            .set_spawn_retries(2, Duration::from_millis(10))
+            .on_spawn_failure(move |name, _e| hook_failures.lock().unwrap().push(name.to_string()));
+
+        let started = std::time::Instant::now();
+        assert!(config.spawn("worker", || ()).is_err());
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        assert_eq!(*failures.lock().unwrap(), ["worker"]);
+    }
+}
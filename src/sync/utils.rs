@@ -3,10 +3,17 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use crate::common::{ConnectionInfo, PeerCredentials};
+use crate::context::ResponseMetadata;
 use crate::error::{Error, Result};
-use crate::proto::{MessageHeader, Request, Response, MESSAGE_TYPE_RESPONSE};
+use crate::proto::{MessageHeader, Request, Response, MESSAGE_TYPE_REQUEST, MESSAGE_TYPE_RESPONSE};
+use crate::sync::channel::close_fds;
 use protobuf::Message;
 use std::collections::HashMap;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 /// Response message through a channel.
 /// Eventually  the message will sent to Client.
@@ -64,6 +71,15 @@ macro_rules! request_handler {
                 }
             },
         }
+        if $ctx.deadline_exceeded() {
+            // The client has already given up, don't send it a stale result.
+            res = ::ttrpc::Response::new();
+            res.set_status(::ttrpc::get_status(
+                ::ttrpc::Code::DEADLINE_EXCEEDED,
+                "timeout".to_string(),
+            ));
+        }
+        res.set_metadata(::ttrpc::context::to_pb($ctx.response_metadata.take()));
         ::ttrpc::response_to_channel($ctx.mh.stream_id, res, $ctx.res_tx)?
     };
 }
@@ -102,6 +118,149 @@ pub struct TtrpcContext {
     pub res_tx: std::sync::mpsc::Sender<(MessageHeader, Vec<u8>)>,
     pub metadata: HashMap<String, Vec<String>>,
     pub timeout_nano: i64,
+    /// Credentials of the connected peer, if the transport provides them.
+    pub peer_cred: Option<PeerCredentials>,
+    /// The connection the request came in on.
+    pub connection: Arc<ConnectionInfo>,
+    /// When the request was received.
+    pub received: SystemTime,
+    /// Metadata to send back to the client with the response.
+    pub response_metadata: ResponseMetadata,
+    /// When the call times out, derived from `timeout_nano` on receipt of the request.
+    pub deadline: Option<Instant>,
+    /// File descriptors the client passed with the request. They belong to
+    /// the handler, which has to close them.
+    pub fds: Vec<RawFd>,
+    /// File descriptors to pass back with the response, see
+    /// [`TtrpcContext::attach_fds()`].
+    pub response_fds: ResponseFds,
+    /// Fires once the call is over, so long-running handlers can stop early.
+    pub cancel: CancellationToken,
+}
+
+impl TtrpcContext {
+    /// Returns true if the call has a deadline and it has passed. Handlers
+    /// doing long work can check this to give up early.
+    pub fn deadline_exceeded(&self) -> bool {
+        self.deadline.is_some_and(|d| d <= Instant::now())
+    }
+
+    /// Returns how long is left until the deadline, zero once it has passed,
+    /// or none if the call has no deadline. Handlers can pass it on to the
+    /// calls they make.
+    pub fn time_remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|d| d.saturating_duration_since(Instant::now()))
+    }
+
+    /// Passes `fds` to the client along with the response, which only works
+    /// over unix sockets. The fds are closed once sent.
+    pub fn attach_fds(&self, fds: Vec<RawFd>) {
+        self.response_fds.attach(self.mh.stream_id, fds);
+    }
+}
+
+/// File descriptors waiting to be passed along with the responses of a
+/// connection, closed once sent or with the connection.
+#[derive(Clone, Debug, Default)]
+pub struct ResponseFds(Arc<Mutex<PendingFds>>);
+
+#[derive(Debug, Default)]
+struct PendingFds(HashMap<u32, Vec<RawFd>>);
+
+impl Drop for PendingFds {
+    fn drop(&mut self) {
+        for fds in self.0.values() {
+            close_fds(fds);
+        }
+    }
+}
+
+impl ResponseFds {
+    fn attach(&self, stream_id: u32, fds: Vec<RawFd>) {
+        self.0
+            .lock()
+            .unwrap()
+            .0
+            .entry(stream_id)
+            .or_default()
+            .extend(fds);
+    }
+
+    /// Takes the fds to send with the response to `stream_id`.
+    pub(crate) fn take(&self, stream_id: u32) -> Vec<RawFd> {
+        self.0
+            .lock()
+            .unwrap()
+            .0
+            .remove(&stream_id)
+            .unwrap_or_default()
+    }
+}
+
+/// Tells a handler its call is over: the client cancelled it or went away,
+/// or its deadline passed.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    /// Returns true once the call is cancelled. A client going away is only
+    /// seen when the server next reads from the connection.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst) || self.deadline.is_some_and(|d| d <= Instant::now())
+    }
+
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Cancellation tokens of the calls a connection is handling.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Cancels(Arc<Mutex<HashMap<u32, CancellationToken>>>);
+
+impl Cancels {
+    /// Makes the token of the call of `mh` as soon as its request is read,
+    /// before the next message can be: a cancel read by another thread
+    /// ahead of [`Cancels::start()`] is then kept. The token is kept until
+    /// [`Cancels::done()`].
+    pub(crate) fn received(&self, mh: &MessageHeader) {
+        if mh.type_ == MESSAGE_TYPE_REQUEST {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(mh.stream_id, CancellationToken::default());
+        }
+    }
+
+    /// Gives the token of the call `stream_id` to its handler, cancelled
+    /// already if the client cancelled the call.
+    pub(crate) fn start(&self, stream_id: u32, deadline: Option<Instant>) -> CancellationToken {
+        let mut tokens = self.0.lock().unwrap();
+        let token = tokens.entry(stream_id).or_default();
+        token.deadline = deadline;
+        token.clone()
+    }
+
+    pub(crate) fn done(&self, stream_id: u32) {
+        self.0.lock().unwrap().remove(&stream_id);
+    }
+
+    pub(crate) fn cancel(&self, stream_id: u32) {
+        if let Some(token) = self.0.lock().unwrap().get(&stream_id) {
+            token.cancel();
+        }
+    }
+
+    /// Cancels all the calls, the connection is closing.
+    pub(crate) fn cancel_all(&self) {
+        for (_, token) in self.0.lock().unwrap().drain() {
+            token.cancel();
+        }
+    }
 }
 
 /// Trait that implements handler which is a proxy to the desired method (sync).
@@ -0,0 +1,84 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! The servers don't leak file descriptors as they stop and start
+//! listening.
+//!
+//! The tests count the descriptors open in the process, so they live in a
+//! test binary of their own and run one at a time.
+
+#![cfg(target_os = "linux")]
+
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+const CYCLES: usize = 20;
+
+static SERIAL: Mutex<()> = Mutex::new(());
+
+fn open_fds() -> usize {
+    std::fs::read_dir("/proc/self/fd").unwrap().count()
+}
+
+fn listener(name: &str) -> (UnixListener, PathBuf) {
+    let path = std::env::temp_dir().join(format!(
+        "ttrpc-fd-leak-{}-{}.sock",
+        name,
+        std::process::id()
+    ));
+    std::fs::remove_file(&path).ok();
+    (UnixListener::bind(&path).unwrap(), path)
+}
+
+#[cfg(feature = "sync")]
+#[test]
+fn test_sync_pause_resume() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let (listener, path) = listener("sync");
+    let mut server = ttrpc::Server::new()
+        .add_listener(listener.into_raw_fd())
+        .unwrap();
+    server.start().unwrap();
+
+    let before = open_fds();
+    for _ in 0..CYCLES {
+        server.pause_accept();
+        server.resume_accept().unwrap();
+    }
+    assert_eq!(open_fds(), before);
+
+    server.shutdown();
+    std::fs::remove_file(&path).ok();
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async_pause_resume() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let (listener, path) = listener("async");
+        let mut server = ttrpc::r#async::Server::new()
+            .add_listener(listener.into_raw_fd())
+            .unwrap()
+            .set_domain_unix();
+        server.start().await.unwrap();
+
+        let before = open_fds();
+        for _ in 0..CYCLES {
+            server.pause_accept().await;
+            server.resume_accept().await.unwrap();
+        }
+        assert_eq!(open_fds(), before);
+
+        server.shutdown().await.unwrap();
+        std::fs::remove_file(&path).ok();
+    });
+}
@@ -0,0 +1,5 @@
+module github.com/containerd/ttrpc-rust/tests/go-interop
+
+go 1.19
+
+require github.com/containerd/ttrpc v1.2.2
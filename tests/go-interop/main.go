@@ -0,0 +1,252 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+// Command go-interop serves and calls the interop service with the
+// reference Go ttrpc implementation, see tests/go_interop.rs.
+//
+//	go-interop server <socket path>
+//	go-interop client <socket path>
+//
+// The client exits with a non-zero status on the first check failing.
+package main
+
+import (
+	"context"
+	"errors"
+	"fmt"
+	"io"
+	"net"
+	"os"
+	"strings"
+
+	"github.com/containerd/ttrpc"
+	"google.golang.org/genproto/googleapis/rpc/status"
+	"google.golang.org/grpc/codes"
+	grpcstatus "google.golang.org/grpc/status"
+)
+
+const service = "ttrpc.interop.Interop"
+
+func main() {
+	if len(os.Args) != 3 {
+		fmt.Fprintln(os.Stderr, "usage: go-interop server|client <socket path>")
+		os.Exit(2)
+	}
+
+	var err error
+	switch os.Args[1] {
+	case "server":
+		err = serve(os.Args[2])
+	case "client":
+		err = check(os.Args[2])
+	default:
+		err = fmt.Errorf("unknown mode %q", os.Args[1])
+	}
+	if err != nil {
+		fmt.Fprintln(os.Stderr, err)
+		os.Exit(1)
+	}
+}
+
+// echo answers with the request, the values of the "echo" metadata key
+// appended to its message.
+func echo(req *status.Status, md []string) *status.Status {
+	msg := req.Message
+	if len(md) > 0 {
+		msg += "|" + strings.Join(md, ",")
+	}
+	return &status.Status{Code: req.Code, Message: msg}
+}
+
+func serve(path string) error {
+	os.Remove(path)
+	l, err := net.Listen("unix", path)
+	if err != nil {
+		return err
+	}
+	s, err := ttrpc.NewServer()
+	if err != nil {
+		return err
+	}
+
+	s.RegisterService(service, &ttrpc.ServiceDesc{
+		Methods: map[string]ttrpc.Method{
+			"Echo": func(ctx context.Context, unmarshal func(interface{}) error) (interface{}, error) {
+				req := &status.Status{}
+				if err := unmarshal(req); err != nil {
+					return nil, err
+				}
+				md, _ := ttrpc.GetMetadata(ctx)
+				return echo(req, md["echo"]), nil
+			},
+			// Fails with the code and message of the request.
+			"Fail": func(ctx context.Context, unmarshal func(interface{}) error) (interface{}, error) {
+				req := &status.Status{}
+				if err := unmarshal(req); err != nil {
+					return nil, err
+				}
+				return nil, grpcstatus.Error(codes.Code(req.Code), req.Message)
+			},
+		},
+		Streams: map[string]ttrpc.Stream{
+			// Sends as many messages as the code of the request.
+			"ServerStream": {
+				Handler: func(ctx context.Context, ss ttrpc.StreamServer) (interface{}, error) {
+					req := &status.Status{}
+					if err := ss.RecvMsg(req); err != nil {
+						return nil, err
+					}
+					for i := int32(0); i < req.Code; i++ {
+						if err := ss.SendMsg(&status.Status{Code: i}); err != nil {
+							return nil, err
+						}
+					}
+					return nil, nil
+				},
+				StreamingServer: true,
+			},
+			// Answers with the count and the joined messages of the requests.
+			"ClientStream": {
+				Handler: func(ctx context.Context, ss ttrpc.StreamServer) (interface{}, error) {
+					var msgs []string
+					for {
+						req := &status.Status{}
+						if err := ss.RecvMsg(req); err != nil {
+							if errors.Is(err, io.EOF) {
+								break
+							}
+							return nil, err
+						}
+						msgs = append(msgs, req.Message)
+					}
+					return &status.Status{Code: int32(len(msgs)), Message: strings.Join(msgs, "")}, nil
+				},
+				StreamingClient: true,
+			},
+			// Echoes every request.
+			"Duplex": {
+				Handler: func(ctx context.Context, ss ttrpc.StreamServer) (interface{}, error) {
+					for {
+						req := &status.Status{}
+						if err := ss.RecvMsg(req); err != nil {
+							if errors.Is(err, io.EOF) {
+								return nil, nil
+							}
+							return nil, err
+						}
+						if err := ss.SendMsg(req); err != nil {
+							return nil, err
+						}
+					}
+				},
+				StreamingClient: true,
+				StreamingServer: true,
+			},
+		},
+	})
+
+	return s.Serve(context.Background(), l)
+}
+
+func expectCode(err error, code codes.Code, msg string) error {
+	st, ok := grpcstatus.FromError(err)
+	if !ok || st.Code() != code || (msg != "" && st.Message() != msg) {
+		return fmt.Errorf("expected %v %q, got %v", code, msg, err)
+	}
+	return nil
+}
+
+func check(path string) error {
+	conn, err := net.Dial("unix", path)
+	if err != nil {
+		return err
+	}
+	client := ttrpc.NewClient(conn)
+	defer client.Close()
+	ctx := ttrpc.WithMetadata(context.Background(), ttrpc.MD{"echo": {"meta"}})
+
+	resp := &status.Status{}
+	if err := client.Call(ctx, service, "Echo", &status.Status{Code: 7, Message: "hello"}, resp); err != nil {
+		return fmt.Errorf("Echo: %w", err)
+	}
+	if resp.Code != 7 || resp.Message != "hello|meta" {
+		return fmt.Errorf("Echo: got %v", resp)
+	}
+
+	err = client.Call(ctx, service, "Fail", &status.Status{Code: int32(codes.NotFound), Message: "gone"}, resp)
+	if err := expectCode(err, codes.NotFound, "gone"); err != nil {
+		return fmt.Errorf("Fail: %w", err)
+	}
+	err = client.Call(ctx, service, "Missing", &status.Status{}, resp)
+	if err := expectCode(err, codes.Unimplemented, ""); err != nil {
+		return fmt.Errorf("Missing: %w", err)
+	}
+
+	// A request with code 0 is encoded as nothing.
+	for _, n := range []int32{3, 0} {
+		desc := &ttrpc.StreamDesc{StreamingServer: true}
+		stream, err := client.NewStream(ctx, desc, service, "ServerStream", &status.Status{Code: n})
+		if err != nil {
+			return fmt.Errorf("ServerStream: %w", err)
+		}
+		for i := int32(0); ; i++ {
+			msg := &status.Status{}
+			err := stream.RecvMsg(msg)
+			if errors.Is(err, io.EOF) && i == n {
+				break
+			}
+			if err != nil {
+				return fmt.Errorf("ServerStream %d: message %d: %w", n, i, err)
+			}
+			if i >= n || msg.Code != i {
+				return fmt.Errorf("ServerStream %d: message %d: got %v", n, i, msg)
+			}
+		}
+	}
+
+	desc := &ttrpc.StreamDesc{StreamingClient: true}
+	stream, err := client.NewStream(ctx, desc, service, "ClientStream", nil)
+	if err != nil {
+		return fmt.Errorf("ClientStream: %w", err)
+	}
+	for _, msg := range []string{"a", "b", "c"} {
+		if err := stream.SendMsg(&status.Status{Message: msg}); err != nil {
+			return fmt.Errorf("ClientStream: %w", err)
+		}
+	}
+	if err := stream.CloseSend(); err != nil {
+		return fmt.Errorf("ClientStream: %w", err)
+	}
+	if err := stream.RecvMsg(resp); err != nil {
+		return fmt.Errorf("ClientStream: %w", err)
+	}
+	if resp.Code != 3 || resp.Message != "abc" {
+		return fmt.Errorf("ClientStream: got %v", resp)
+	}
+
+	desc = &ttrpc.StreamDesc{StreamingClient: true, StreamingServer: true}
+	stream, err = client.NewStream(ctx, desc, service, "Duplex", nil)
+	if err != nil {
+		return fmt.Errorf("Duplex: %w", err)
+	}
+	for _, msg := range []string{"x", "y"} {
+		if err := stream.SendMsg(&status.Status{Message: msg}); err != nil {
+			return fmt.Errorf("Duplex: %w", err)
+		}
+		if err := stream.RecvMsg(resp); err != nil {
+			return fmt.Errorf("Duplex: %w", err)
+		}
+		if resp.Message != msg {
+			return fmt.Errorf("Duplex: got %v", resp)
+		}
+	}
+	if err := stream.CloseSend(); err != nil {
+		return fmt.Errorf("Duplex: %w", err)
+	}
+	if err := stream.RecvMsg(resp); !errors.Is(err, io.EOF) {
+		return fmt.Errorf("Duplex: expected the end of the stream, got %v", err)
+	}
+
+	return nil
+}
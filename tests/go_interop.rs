@@ -0,0 +1,288 @@
+// Copyright 2022 Alibaba Cloud. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Interoperability with the reference Go ttrpc implementation.
+//!
+//! The Go side lives in `tests/go-interop` and serves or calls the
+//! `ttrpc.interop.Interop` service, using `google.rpc.Status` (wire compatible
+//! with [`ttrpc::Status`]) as both request and response:
+//!
+//! - `Echo` answers with the request, the values of the `echo` metadata key
+//!   appended to its message after a `|`.
+//! - `Fail` fails with the code and message of the request.
+//! - `ServerStream` sends as many messages as the code of the request, with
+//!   codes counting from 0.
+//! - `ClientStream` answers with the number and the concatenated messages of
+//!   the requests.
+//! - `Duplex` echoes every request.
+//!
+//! The tests need a Go toolchain and network access to fetch the Go module,
+//! so they are ignored by default. Run them with `make interop`.
+
+#![cfg(feature = "async")]
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Once};
+
+use async_trait::async_trait;
+use protobuf::EnumOrUnknown;
+use ttrpc::proto::Codec;
+use ttrpc::r#async::{
+    Client, ClientStream, ClientStreamReceiver, ClientStreamSender, MethodHandler, Server,
+    ServerStream, ServerStreamReceiver, ServerStreamSender, Service, StreamHandler, StreamInner,
+    TtrpcContext,
+};
+use ttrpc::{context, get_status, Code, ConnectRetry, Request, Response, Status};
+
+const SERVICE: &str = "ttrpc.interop.Interop";
+
+/// Builds the Go program once and returns its path.
+fn go_interop() -> PathBuf {
+    static BUILD: Once = Once::new();
+    let bin = Path::new(env!("CARGO_TARGET_TMPDIR")).join("go-interop");
+    BUILD.call_once(|| {
+        let status = Command::new("go")
+            .args(["build", "-mod=mod", "-o"])
+            .arg(&bin)
+            .current_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/go-interop"))
+            .status()
+            .expect("go toolchain");
+        assert!(status.success(), "failed to build the Go interop program");
+    });
+    bin
+}
+
+fn socket(name: &str) -> String {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = std::fs::remove_file(&path);
+    format!("unix://{}", path.display())
+}
+
+fn status(code: i32, message: &str) -> Status {
+    let mut s = Status::new();
+    s.code = EnumOrUnknown::from_i32(code);
+    s.message = message.to_string();
+    s
+}
+
+fn request(method: &str, req: &Status) -> Request {
+    let mut md = HashMap::new();
+    md.insert("echo".to_string(), vec!["meta".to_string()]);
+    Request {
+        service: SERVICE.to_string(),
+        method: method.to_string(),
+        payload: req.encode().unwrap(),
+        metadata: context::to_pb(md),
+        ..Default::default()
+    }
+}
+
+fn response(s: &Status) -> ttrpc::Result<Response> {
+    let mut res = Response::new();
+    res.set_status(get_status(Code::OK, ""));
+    res.payload = s
+        .encode()
+        .map_err(|e| ttrpc::Error::Others(e.to_string()))?;
+    Ok(res)
+}
+
+#[tokio::test]
+#[ignore = "needs a Go toolchain, run with `make interop`"]
+async fn rust_client_go_server() {
+    let bin = go_interop();
+    let sock = socket("go-server.sock");
+    let mut server = Command::new(bin)
+        .arg("server")
+        .arg(sock.trim_start_matches("unix://"))
+        .spawn()
+        .unwrap();
+
+    let client = Client::connect_with_retry(&sock, ConnectRetry::default())
+        .await
+        .unwrap();
+
+    // Unary, with metadata.
+    let res = client
+        .request(request("Echo", &status(7, "hello")))
+        .await
+        .unwrap();
+    let s = Status::decode(&res.payload).unwrap();
+    assert_eq!((s.code.value(), s.message.as_str()), (7, "hello|meta"));
+
+    // Errors carry the status of the Go handler.
+    match client
+        .request(request("Fail", &status(Code::NOT_FOUND as i32, "gone")))
+        .await
+    {
+        Err(ttrpc::Error::RpcStatus(s)) => {
+            assert_eq!(s.code(), Code::NOT_FOUND);
+            assert_eq!(s.message, "gone");
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+    match client.request(request("Missing", &Status::new())).await {
+        Err(ttrpc::Error::RpcStatus(s)) => assert_eq!(s.code(), Code::UNIMPLEMENTED),
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    // Server streaming, including a request encoded as nothing.
+    for n in [3, 0] {
+        let inner = client
+            .new_stream(request("ServerStream", &status(n, "")), false, true)
+            .await
+            .unwrap();
+        let mut stream = ClientStreamReceiver::<Status>::new(inner);
+        for i in 0..n {
+            assert_eq!(stream.recv().await.unwrap().unwrap().code.value(), i);
+        }
+        assert!(matches!(stream.recv().await, Ok(None)));
+    }
+
+    // Client streaming.
+    let inner = client
+        .new_stream(request("ClientStream", &Status::new()), true, false)
+        .await
+        .unwrap();
+    let mut stream = ClientStreamSender::<Status, Status>::new(inner);
+    for m in ["a", "b", "c"] {
+        stream.send(&status(0, m)).await.unwrap();
+    }
+    let s = stream.close_and_recv().await.unwrap();
+    assert_eq!((s.code.value(), s.message.as_str()), (3, "abc"));
+
+    // Duplex streaming.
+    let inner = client
+        .new_stream(request("Duplex", &Status::new()), true, true)
+        .await
+        .unwrap();
+    let mut stream = ClientStream::<Status, Status>::new(inner);
+    for m in ["x", "y"] {
+        stream.send(&status(0, m)).await.unwrap();
+        assert_eq!(stream.recv().await.unwrap().message, m);
+    }
+    stream.close_send().await.unwrap();
+    assert!(matches!(stream.recv().await, Err(ttrpc::Error::Eof)));
+
+    client
+        .close(std::time::Duration::from_secs(1))
+        .await
+        .unwrap();
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+struct Echo;
+
+#[async_trait]
+impl MethodHandler for Echo {
+    async fn handler(&self, ctx: TtrpcContext, req: Request) -> ttrpc::Result<Response> {
+        let mut s =
+            Status::decode(&req.payload).map_err(|e| ttrpc::Error::Others(e.to_string()))?;
+        if let Some(md) = ctx.metadata.get("echo") {
+            s.message = format!("{}|{}", s.message, md.join(","));
+        }
+        response(&s)
+    }
+}
+
+struct Fail;
+
+#[async_trait]
+impl MethodHandler for Fail {
+    async fn handler(&self, _ctx: TtrpcContext, req: Request) -> ttrpc::Result<Response> {
+        let s = Status::decode(&req.payload).map_err(|e| ttrpc::Error::Others(e.to_string()))?;
+        Err(ttrpc::Error::RpcStatus(s))
+    }
+}
+
+struct ServerStreaming;
+
+#[async_trait]
+impl StreamHandler for ServerStreaming {
+    async fn handler(
+        &self,
+        _ctx: TtrpcContext,
+        mut inner: StreamInner,
+    ) -> ttrpc::Result<Option<Response>> {
+        let req =
+            Status::decode(inner.recv().await?).map_err(|e| ttrpc::Error::Others(e.to_string()))?;
+        let stream = ServerStreamSender::<Status>::new(inner);
+        for i in 0..req.code.value() {
+            stream.send(&status(i, "")).await?;
+        }
+        Ok(None)
+    }
+}
+
+struct ClientStreaming;
+
+#[async_trait]
+impl StreamHandler for ClientStreaming {
+    async fn handler(
+        &self,
+        _ctx: TtrpcContext,
+        inner: StreamInner,
+    ) -> ttrpc::Result<Option<Response>> {
+        let mut stream = ServerStreamReceiver::<Status>::new(inner);
+        let mut res = Status::new();
+        while let Some(s) = stream.recv().await? {
+            res.code = EnumOrUnknown::from_i32(res.code.value() + 1);
+            res.message.push_str(&s.message);
+        }
+        response(&res).map(Some)
+    }
+}
+
+struct Duplex;
+
+#[async_trait]
+impl StreamHandler for Duplex {
+    async fn handler(
+        &self,
+        _ctx: TtrpcContext,
+        inner: StreamInner,
+    ) -> ttrpc::Result<Option<Response>> {
+        let mut stream = ServerStream::<Status, Status>::new(inner);
+        while let Some(s) = stream.recv().await? {
+            stream.send(&s).await?;
+        }
+        Ok(None)
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs a Go toolchain, run with `make interop`"]
+async fn go_client_rust_server() {
+    let bin = go_interop();
+    let sock = socket("rust-server.sock");
+
+    let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+    methods.insert("Echo".to_string(), Box::new(Echo));
+    methods.insert("Fail".to_string(), Box::new(Fail));
+    let mut streams: HashMap<String, Arc<dyn StreamHandler + Send + Sync>> = HashMap::new();
+    streams.insert("ServerStream".to_string(), Arc::new(ServerStreaming));
+    streams.insert("ClientStream".to_string(), Arc::new(ClientStreaming));
+    streams.insert("Duplex".to_string(), Arc::new(Duplex));
+    let mut services = HashMap::new();
+    services.insert(SERVICE.to_string(), Service { methods, streams });
+
+    let mut server = Server::new()
+        .bind(&sock)
+        .unwrap()
+        .register_service(services);
+    server.start().await.unwrap();
+
+    let path = sock.trim_start_matches("unix://").to_string();
+    let status = tokio::task::spawn_blocking(move || {
+        Command::new(bin).arg("client").arg(path).status().unwrap()
+    })
+    .await
+    .unwrap();
+    assert!(status.success(), "the Go client checks failed");
+
+    server.close().await.unwrap();
+}